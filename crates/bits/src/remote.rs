@@ -0,0 +1,435 @@
+// Subcommands that control a running server through its HTTP API.
+
+use std::{io::Write, net::SocketAddr, time::Duration};
+
+use clap::{Args, Parser, Subcommand};
+use futures::{StreamExt, TryStreamExt};
+use librqbit::{
+    api::{PeerStatsFilter, PeerStatsFilterState, TorrentDetailsResponse},
+    http_api_client::HttpApiClient,
+    TorrentStats,
+};
+use serde::Serialize;
+use size_format::SizeFormatterBinary as SF;
+
+#[derive(Args)]
+pub struct OutputOpts {
+    /// Print JSON instead of a table.
+    #[arg(long)]
+    json: bool,
+}
+
+#[derive(Parser)]
+pub struct ListOpts {
+    #[command(flatten)]
+    output: OutputOpts,
+}
+
+#[derive(Parser)]
+pub struct TorrentOpts {
+    /// The torrent id, as printed by "list".
+    id: usize,
+
+    #[command(flatten)]
+    output: OutputOpts,
+}
+
+#[derive(Parser)]
+pub struct TorrentIdsOpts {
+    /// The torrent ids, as printed by "list".
+    #[arg(required = true)]
+    ids: Vec<usize>,
+}
+
+#[derive(Parser)]
+pub struct PeersOpts {
+    /// The torrent id, as printed by "list".
+    id: usize,
+
+    /// Include peers that are not connected.
+    #[arg(short, long)]
+    all: bool,
+
+    #[command(flatten)]
+    output: OutputOpts,
+
+    #[command(subcommand)]
+    subcommand: Option<PeersSubcommand>,
+}
+
+#[derive(Subcommand)]
+enum PeersSubcommand {
+    /// Connect to these peers, unless the torrent knows them already.
+    Add {
+        #[arg(required = true)]
+        addrs: Vec<SocketAddr>,
+    },
+    /// Disconnect the peer. The torrent may connect to it again later.
+    Disconnect { addr: SocketAddr },
+    /// Ban the peer's IP in all torrents.
+    Ban {
+        addr: SocketAddr,
+        /// Lift the ban after this long, e.g. "1h". Permanent without it.
+        #[arg(long, value_parser = parse_duration::parse)]
+        duration: Option<Duration>,
+    },
+}
+
+#[derive(Parser)]
+pub struct DhtOpts {
+    #[command(subcommand)]
+    subcommand: DhtSubcommand,
+}
+
+#[derive(Subcommand)]
+enum DhtSubcommand {
+    /// DHT node id, routing table size and outstanding requests.
+    Stats(OutputOpts),
+}
+
+// How many torrents to fetch the details and stats of at once, not to flood the server
+// when there are many.
+pub(crate) const MAX_CONCURRENT_TORRENT_REQUESTS: usize = 8;
+
+#[derive(Clone, Copy)]
+pub enum TorrentAction {
+    Pause,
+    Start,
+    Forget,
+    Delete,
+}
+
+// Print rows under headers with aligned columns, the last column is not padded.
+fn print_table<const N: usize>(headers: [&str; N], rows: &[[String; N]]) -> anyhow::Result<()> {
+    let mut widths = headers.map(|h| h.len());
+    for row in rows {
+        for (w, cell) in widths.iter_mut().zip(row.iter()) {
+            *w = (*w).max(cell.chars().count());
+        }
+    }
+    // Not println!(), see print_json().
+    let mut stdout = std::io::stdout().lock();
+    let mut print_row = |cells: &mut dyn Iterator<Item = &str>| {
+        let line = cells
+            .zip(widths.iter())
+            .map(|(cell, width)| format!("{cell:width$}"))
+            .collect::<Vec<_>>()
+            .join("  ");
+        writeln!(stdout, "{}", line.trim_end())
+    };
+    print_row(&mut headers.iter().copied())?;
+    for row in rows {
+        print_row(&mut row.iter().map(|s| s.as_str()))?;
+    }
+    Ok(())
+}
+
+fn print_json(value: &impl Serialize) -> anyhow::Result<()> {
+    // Not println!(), which panics if the pipe was closed, e.g. by "head".
+    let mut stdout = std::io::stdout().lock();
+    serde_json::to_writer_pretty(&mut stdout, value)?;
+    writeln!(stdout)?;
+    Ok(())
+}
+
+fn progress(stats: &TorrentStats) -> String {
+    stats.progress_percent_human_readable().to_string()
+}
+
+fn speeds(stats: &TorrentStats) -> (String, String) {
+    match &stats.live {
+        Some(live) => (
+            live.download_speed.to_string(),
+            live.upload_speed.to_string(),
+        ),
+        None => (String::new(), String::new()),
+    }
+}
+
+#[derive(Serialize)]
+struct ListItem {
+    id: usize,
+    info_hash: String,
+    name: Option<String>,
+    stats: TorrentStats,
+}
+
+pub async fn list(client: &HttpApiClient, opts: &ListOpts) -> anyhow::Result<()> {
+    let torrents = client.list_torrents().await?.torrents;
+    let items = futures::stream::iter(torrents)
+        .map(|t| async move {
+            let (details, stats) =
+                futures::try_join!(client.torrent_details(t.id), client.torrent_stats(t.id))?;
+            anyhow::Ok(ListItem {
+                id: t.id,
+                info_hash: t.info_hash,
+                name: details.name,
+                stats,
+            })
+        })
+        .buffered(MAX_CONCURRENT_TORRENT_REQUESTS)
+        .try_collect::<Vec<_>>()
+        .await?;
+
+    if opts.output.json {
+        return print_json(&items);
+    }
+    let rows = items
+        .iter()
+        .map(|item| {
+            let (down, up) = speeds(&item.stats);
+            let peers = item
+                .stats
+                .live
+                .as_ref()
+                .map(|l| l.snapshot.peer_stats.live.to_string())
+                .unwrap_or_default();
+            [
+                item.id.to_string(),
+                item.stats.state.to_string(),
+                progress(&item.stats),
+                SF::new(item.stats.total_bytes).to_string(),
+                down,
+                up,
+                peers,
+                item.name.clone().unwrap_or_default(),
+            ]
+        })
+        .collect::<Vec<_>>();
+    print_table(
+        [
+            "ID", "STATE", "PROGRESS", "SIZE", "DOWN", "UP", "PEERS", "NAME",
+        ],
+        &rows,
+    )?;
+    Ok(())
+}
+
+#[derive(Serialize)]
+struct Info {
+    id: usize,
+    #[serde(flatten)]
+    details: TorrentDetailsResponse,
+}
+
+pub async fn info(client: &HttpApiClient, opts: &TorrentOpts) -> anyhow::Result<()> {
+    let details = client.torrent_details(opts.id).await?;
+    if opts.output.json {
+        return print_json(&Info {
+            id: opts.id,
+            details,
+        });
+    }
+    let mut stdout = std::io::stdout().lock();
+    writeln!(stdout, "id:        {}", opts.id)?;
+    writeln!(
+        stdout,
+        "name:      {}",
+        details.name.as_deref().unwrap_or_default()
+    )?;
+    writeln!(stdout, "info hash: {}", details.info_hash)?;
+    writeln!(stdout)?;
+    let rows = details
+        .files
+        .iter()
+        .enumerate()
+        .map(|(idx, f)| {
+            [
+                idx.to_string(),
+                SF::new(f.length).to_string(),
+                f.priority.to_string(),
+                f.name.clone(),
+            ]
+        })
+        .collect::<Vec<_>>();
+    print_table(["FILE", "SIZE", "PRIORITY", "PATH"], &rows)?;
+    Ok(())
+}
+
+pub async fn stats(client: &HttpApiClient, opts: &TorrentOpts) -> anyhow::Result<()> {
+    let stats = client.torrent_stats(opts.id).await?;
+    if opts.output.json {
+        return print_json(&stats);
+    }
+    let mut rows = vec![
+        ["state".to_owned(), stats.state.to_string()],
+        ["progress".to_owned(), progress(&stats)],
+        [
+            "downloaded".to_owned(),
+            stats.progress_bytes_human_readable().to_string(),
+        ],
+        [
+            "uploaded".to_owned(),
+            SF::new(stats.uploaded_bytes).to_string(),
+        ],
+        ["finished".to_owned(), stats.finished.to_string()],
+    ];
+    if let Some(error) = &stats.error {
+        rows.push(["error".to_owned(), error.clone()]);
+    }
+    if let Some(live) = &stats.live {
+        let peers = &live.snapshot.peer_stats;
+        rows.extend([
+            ["download speed".to_owned(), live.download_speed.to_string()],
+            ["upload speed".to_owned(), live.upload_speed.to_string()],
+            [
+                "eta".to_owned(),
+                live.time_remaining
+                    .as_ref()
+                    .map(|t| t.to_string())
+                    .unwrap_or_default(),
+            ],
+            [
+                "peers".to_owned(),
+                format!(
+                    "{} live, {} connecting, {} queued ({} by connection limits), {} dead",
+                    peers.live, peers.connecting, peers.queued, peers.queued_by_limit, peers.dead
+                ),
+            ],
+            [
+                "peer sources".to_owned(),
+                format!(
+                    "{} tracker, {} DHT, {} incoming, {} initial, {} manual",
+                    peers.sources.tracker,
+                    peers.sources.dht,
+                    peers.sources.incoming,
+                    peers.sources.initial_peers,
+                    peers.sources.manual
+                ),
+            ],
+            [
+                "hash failures".to_owned(),
+                live.snapshot.hash_failed_pieces.to_string(),
+            ],
+        ]);
+    }
+    if let Some(moving) = &stats.moving {
+        rows.push([
+            "moving".to_owned(),
+            format!(
+                "to {:?}: {} / {}",
+                moving.destination,
+                SF::new(moving.moved_bytes),
+                SF::new(moving.total_bytes)
+            ),
+        ]);
+    }
+    print_table(["STAT", "VALUE"], &rows)?;
+    Ok(())
+}
+
+pub async fn torrent_action(
+    client: &HttpApiClient,
+    opts: &TorrentIdsOpts,
+    action: TorrentAction,
+) -> anyhow::Result<()> {
+    for &id in &opts.ids {
+        let (result, done) = match action {
+            TorrentAction::Pause => (client.pause(id).await, "paused"),
+            TorrentAction::Start => (client.start(id).await, "started"),
+            TorrentAction::Forget => (client.forget(id).await, "forgot"),
+            TorrentAction::Delete => (client.delete(id).await, "deleted"),
+        };
+        result?;
+        println!("{done} {id}");
+    }
+    Ok(())
+}
+
+pub async fn peers(client: &HttpApiClient, opts: &PeersOpts) -> anyhow::Result<()> {
+    match &opts.subcommand {
+        None => {}
+        Some(PeersSubcommand::Add { addrs }) => {
+            let resp = client.add_peers(opts.id, addrs.clone()).await?;
+            println!(
+                "added {}, skipped {} already known or blocked",
+                resp.added, resp.skipped
+            );
+            return Ok(());
+        }
+        Some(PeersSubcommand::Disconnect { addr }) => {
+            client.disconnect_peer(opts.id, *addr).await?;
+            println!("disconnected {addr}");
+            return Ok(());
+        }
+        Some(PeersSubcommand::Ban { addr, duration }) => {
+            client.ban_peer(opts.id, *addr, *duration).await?;
+            match duration {
+                Some(d) => println!("banned {} for {d:?}", addr.ip()),
+                None => println!("banned {}", addr.ip()),
+            }
+            return Ok(());
+        }
+    }
+
+    let filter = PeerStatsFilter {
+        state: if opts.all {
+            PeerStatsFilterState::All
+        } else {
+            PeerStatsFilterState::Live
+        },
+    };
+    let snapshot = client.peer_stats(opts.id, filter).await?;
+    if opts.output.json {
+        return print_json(&snapshot);
+    }
+    let mut peers = snapshot.peers.into_iter().collect::<Vec<_>>();
+    peers.sort_by(|a, b| b.1.counters.fetched_bytes.cmp(&a.1.counters.fetched_bytes));
+    let rows = peers
+        .into_iter()
+        .map(|(addr, p)| {
+            [
+                addr,
+                p.state.into_owned(),
+                p.source.to_string(),
+                p.direction.map(|d| d.to_string()).unwrap_or_default(),
+                p.client.unwrap_or_default(),
+                SF::new(p.counters.fetched_bytes).to_string(),
+                p.counters.downloaded_and_checked_pieces.to_string(),
+                p.counters.errors.to_string(),
+                p.counters.hash_failures.to_string(),
+            ]
+        })
+        .collect::<Vec<_>>();
+    print_table(
+        [
+            "ADDR",
+            "STATE",
+            "SOURCE",
+            "DIR",
+            "CLIENT",
+            "FETCHED",
+            "PIECES",
+            "ERRORS",
+            "HASH FAILS",
+        ],
+        &rows,
+    )?;
+    Ok(())
+}
+
+pub async fn dht(client: &HttpApiClient, opts: &DhtOpts) -> anyhow::Result<()> {
+    match &opts.subcommand {
+        DhtSubcommand::Stats(output) => {
+            let stats = client.dht_stats().await?;
+            if output.json {
+                return print_json(&stats);
+            }
+            print_table(
+                ["STAT", "VALUE"],
+                &[
+                    ["id".to_owned(), stats.id.as_string()],
+                    [
+                        "routing table size".to_owned(),
+                        stats.routing_table_size.to_string(),
+                    ],
+                    [
+                        "outstanding requests".to_owned(),
+                        stats.outstanding_requests.to_string(),
+                    ],
+                ],
+            )?;
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,627 @@
+// A terminal dashboard for "download --tui" and "top". It polls the torrents once a
+// second, either from the session in this process or from a server over the HTTP API.
+
+use std::{io, sync::Arc, time::Duration};
+
+use anyhow::Context;
+use crossterm::{
+    event::{Event, EventStream, KeyCode, KeyEvent, KeyEventKind, KeyModifiers},
+    execute,
+    terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
+};
+use futures::{StreamExt, TryStreamExt};
+use librqbit::{
+    api::{PeerStatsFilter, PeerStatsFilterState, PeerStatsSnapshot, TorrentDetailsResponse},
+    http_api_client::HttpApiClient,
+    Api, TorrentStats, TorrentStatsState,
+};
+use ratatui::{
+    backend::CrosstermBackend,
+    layout::{Constraint, Layout, Rect},
+    style::{Color, Modifier, Style, Stylize},
+    text::Line,
+    widgets::{Block, Borders, Gauge, Paragraph, Row, Table, TableState, Tabs},
+    Frame, Terminal,
+};
+use size_format::SizeFormatterBinary as SF;
+
+use crate::remote::MAX_CONCURRENT_TORRENT_REQUESTS;
+
+const REFRESH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Where the dashboard gets its data from.
+pub enum TuiSource {
+    Local(Api),
+    Remote(HttpApiClient),
+}
+
+struct TorrentRow {
+    id: usize,
+    name: String,
+    stats: TorrentStats,
+}
+
+#[derive(Clone, Copy)]
+enum Action {
+    Pause,
+    Start,
+    Delete,
+}
+
+impl TuiSource {
+    async fn torrents(&self) -> anyhow::Result<Vec<TorrentRow>> {
+        let ids = match self {
+            TuiSource::Local(api) => api.api_torrent_list().torrents,
+            TuiSource::Remote(client) => client.list_torrents().await?.torrents,
+        }
+        .into_iter()
+        .map(|t| t.id);
+        let rows = futures::stream::iter(ids)
+            .map(|id| async move {
+                let (details, stats) = futures::try_join!(self.details(id), self.stats(id))?;
+                anyhow::Ok(TorrentRow {
+                    id,
+                    name: details.name.unwrap_or_else(|| details.info_hash.clone()),
+                    stats,
+                })
+            })
+            .buffered(MAX_CONCURRENT_TORRENT_REQUESTS)
+            .try_collect::<Vec<_>>()
+            .await?;
+        Ok(rows)
+    }
+
+    async fn details(&self, id: usize) -> anyhow::Result<TorrentDetailsResponse> {
+        match self {
+            TuiSource::Local(api) => Ok(api.api_torrent_details(id)?),
+            TuiSource::Remote(client) => client.torrent_details(id).await,
+        }
+    }
+
+    async fn stats(&self, id: usize) -> anyhow::Result<TorrentStats> {
+        match self {
+            TuiSource::Local(api) => Ok(api.api_stats_v1(id)?),
+            TuiSource::Remote(client) => client.torrent_stats(id).await,
+        }
+    }
+
+    async fn peers(&self, id: usize) -> anyhow::Result<PeerStatsSnapshot> {
+        let filter = PeerStatsFilter {
+            state: PeerStatsFilterState::Live,
+        };
+        match self {
+            TuiSource::Local(api) => Ok(api.api_peer_stats(id, filter)?),
+            TuiSource::Remote(client) => client.peer_stats(id, filter).await,
+        }
+    }
+
+    async fn action(&self, id: usize, action: Action) -> anyhow::Result<()> {
+        match (self, action) {
+            (TuiSource::Local(api), Action::Pause) => {
+                api.api_torrent_action_pause(id).map(|_| ())?
+            }
+            (TuiSource::Local(api), Action::Start) => {
+                api.api_torrent_action_start(id).map(|_| ())?
+            }
+            (TuiSource::Local(api), Action::Delete) => {
+                api.api_torrent_action_delete(id).map(|_| ())?
+            }
+            (TuiSource::Remote(client), Action::Pause) => client.pause(id).await?,
+            (TuiSource::Remote(client), Action::Start) => client.start(id).await?,
+            (TuiSource::Remote(client), Action::Delete) => client.delete(id).await?,
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Tab {
+    Files,
+    Peers,
+    Trackers,
+}
+
+impl Tab {
+    const ALL: [Tab; 3] = [Tab::Files, Tab::Peers, Tab::Trackers];
+
+    fn title(self) -> &'static str {
+        match self {
+            Tab::Files => "Files",
+            Tab::Peers => "Peers",
+            Tab::Trackers => "Trackers",
+        }
+    }
+
+    fn next(self) -> Self {
+        match self {
+            Tab::Files => Tab::Peers,
+            Tab::Peers => Tab::Trackers,
+            Tab::Trackers => Tab::Files,
+        }
+    }
+}
+
+struct Drilldown {
+    id: usize,
+    tab: Tab,
+    details: Option<TorrentDetailsResponse>,
+    peers: Option<PeerStatsSnapshot>,
+}
+
+struct App {
+    source: TuiSource,
+    torrents: Vec<TorrentRow>,
+    table: TableState,
+    drilldown: Option<Drilldown>,
+    // The torrent waiting for "y" to be deleted.
+    confirm_delete: Option<usize>,
+    // The last error or action result, shown at the bottom.
+    status: Option<String>,
+    quit: bool,
+}
+
+impl App {
+    fn selected_id(&self) -> Option<usize> {
+        if let Some(d) = &self.drilldown {
+            return Some(d.id);
+        }
+        self.table
+            .selected()
+            .and_then(|idx| self.torrents.get(idx))
+            .map(|t| t.id)
+    }
+
+    async fn refresh(&mut self) {
+        match self.source.torrents().await {
+            Ok(torrents) => self.torrents = torrents,
+            Err(e) => self.status = Some(format!("error: {}", one_line(&format!("{e:#}")))),
+        }
+        let selected = match self.torrents.len() {
+            0 => None,
+            len => Some(self.table.selected().unwrap_or(0).min(len - 1)),
+        };
+        self.table.select(selected);
+
+        let Some(d) = &mut self.drilldown else {
+            return;
+        };
+        if !self.torrents.iter().any(|t| t.id == d.id) {
+            self.drilldown = None;
+            return;
+        }
+        // Files and trackers don't change much, only peers are polled.
+        if d.details.is_none() || d.tab == Tab::Files {
+            d.details = self.source.details(d.id).await.ok();
+        }
+        if d.tab == Tab::Peers {
+            // Not live torrents have no peers.
+            d.peers = self.source.peers(d.id).await.ok();
+        }
+    }
+
+    async fn run_action(&mut self, id: usize, action: Action) {
+        let done = match action {
+            Action::Pause => "paused",
+            Action::Start => "resumed",
+            Action::Delete => "deleted",
+        };
+        self.status = Some(match self.source.action(id, action).await {
+            Ok(()) => format!("{done} torrent {id}"),
+            Err(e) => format!("error: {}", one_line(&format!("{e:#}"))),
+        });
+        self.refresh().await;
+    }
+
+    async fn on_key(&mut self, key: KeyEvent) {
+        if key.kind != KeyEventKind::Press {
+            return;
+        }
+        if let Some(id) = self.confirm_delete.take() {
+            if matches!(key.code, KeyCode::Char('y') | KeyCode::Char('Y')) {
+                self.drilldown = None;
+                self.run_action(id, Action::Delete).await;
+            } else {
+                self.status = Some("not deleted".to_owned());
+            }
+            return;
+        }
+        match key.code {
+            KeyCode::Char('q') => self.quit = true,
+            KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => self.quit = true,
+            KeyCode::Esc => {
+                if self.drilldown.take().is_none() {
+                    self.quit = true;
+                }
+            }
+            // Only goes back, it's too easy to hit by accident to quit with it.
+            KeyCode::Backspace => self.drilldown = None,
+            KeyCode::Up | KeyCode::Char('k') if self.drilldown.is_none() => {
+                let idx = self.table.selected().unwrap_or(0).saturating_sub(1);
+                self.table.select(Some(idx));
+            }
+            KeyCode::Down | KeyCode::Char('j') if self.drilldown.is_none() => {
+                let last = self.torrents.len().saturating_sub(1);
+                let idx = self.table.selected().map(|i| i + 1).unwrap_or(0).min(last);
+                self.table.select(Some(idx));
+            }
+            KeyCode::Enter if self.drilldown.is_none() => {
+                if let Some(id) = self.selected_id() {
+                    self.drilldown = Some(Drilldown {
+                        id,
+                        tab: Tab::Files,
+                        details: None,
+                        peers: None,
+                    });
+                    self.refresh().await;
+                }
+            }
+            KeyCode::Tab | KeyCode::Char('1'..='3') => {
+                if let Some(d) = &mut self.drilldown {
+                    d.tab = match key.code {
+                        KeyCode::Char(c @ '1'..='3') => Tab::ALL[c as usize - '1' as usize],
+                        _ => d.tab.next(),
+                    };
+                    self.refresh().await;
+                }
+            }
+            KeyCode::Char('p') => {
+                if let Some(id) = self.selected_id() {
+                    self.run_action(id, Action::Pause).await;
+                }
+            }
+            KeyCode::Char('r') => {
+                if let Some(id) = self.selected_id() {
+                    self.run_action(id, Action::Start).await;
+                }
+            }
+            KeyCode::Char('d') => {
+                if let Some(id) = self.selected_id() {
+                    self.confirm_delete = Some(id);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+// Errors are often printed with "{:?}", keep only the message chain so they fit on a
+// line.
+fn one_line(error: &str) -> String {
+    error
+        .lines()
+        .map(str::trim)
+        .take_while(|l| !l.starts_with("Stack backtrace"))
+        .filter(|l| !l.is_empty() && *l != "Caused by:")
+        .map(|l| {
+            l.trim_start_matches(|c: char| c.is_ascii_digit() || c == ':')
+                .trim()
+        })
+        .collect::<Vec<_>>()
+        .join(": ")
+}
+
+fn progress_ratio(stats: &TorrentStats) -> f64 {
+    if stats.finished || stats.total_bytes == 0 {
+        return 1f64;
+    }
+    (stats.progress_bytes as f64 / stats.total_bytes as f64).clamp(0f64, 1f64)
+}
+
+fn progress_bar(ratio: f64, width: usize) -> String {
+    let filled = (ratio * width as f64).round() as usize;
+    format!(
+        "{}{} {:5.1}%",
+        "█".repeat(filled),
+        "░".repeat(width - filled),
+        ratio * 100f64
+    )
+}
+
+fn state_color(state: TorrentStatsState) -> Color {
+    match state {
+        TorrentStatsState::Initializing => Color::Yellow,
+        TorrentStatsState::Live => Color::Green,
+        TorrentStatsState::Paused => Color::DarkGray,
+        TorrentStatsState::Error => Color::Red,
+    }
+}
+
+struct Speeds {
+    down: String,
+    up: String,
+    eta: String,
+    peers: String,
+}
+
+fn speeds(stats: &TorrentStats) -> Speeds {
+    match &stats.live {
+        Some(live) => Speeds {
+            down: live.download_speed.to_string(),
+            up: live.upload_speed.to_string(),
+            eta: match &live.time_remaining {
+                Some(t) if !stats.finished => t.to_string(),
+                _ => String::new(),
+            },
+            peers: live.snapshot.peer_stats.live.to_string(),
+        },
+        None => Speeds {
+            down: String::new(),
+            up: String::new(),
+            eta: String::new(),
+            peers: String::new(),
+        },
+    }
+}
+
+fn draw_torrents(f: &mut Frame, app: &mut App, area: Rect) {
+    let rows = app.torrents.iter().map(|t| {
+        let s = speeds(&t.stats);
+        Row::new(vec![
+            t.id.to_string(),
+            t.stats.state.to_string(),
+            progress_bar(progress_ratio(&t.stats), 20),
+            SF::new(t.stats.total_bytes).to_string(),
+            s.down,
+            s.up,
+            s.eta,
+            s.peers,
+            t.name.clone(),
+        ])
+        .style(Style::default().fg(state_color(t.stats.state)))
+    });
+    let table = Table::new(
+        rows,
+        [
+            Constraint::Length(4),
+            Constraint::Length(12),
+            Constraint::Length(27),
+            Constraint::Length(10),
+            Constraint::Length(12),
+            Constraint::Length(12),
+            Constraint::Length(9),
+            Constraint::Length(6),
+            Constraint::Min(10),
+        ],
+    )
+    .header(
+        Row::new(vec![
+            "ID", "STATE", "PROGRESS", "SIZE", "DOWN", "UP", "ETA", "PEERS", "NAME",
+        ])
+        .add_modifier(Modifier::BOLD),
+    )
+    .highlight_style(Style::default().add_modifier(Modifier::REVERSED))
+    .block(Block::default().borders(Borders::ALL).title(" Torrents "));
+    f.render_stateful_widget(table, area, &mut app.table);
+}
+
+fn draw_drilldown(f: &mut Frame, app: &App, d: &Drilldown, area: Rect) {
+    let [summary_area, gauge_area, tabs_area, body_area] = Layout::vertical([
+        Constraint::Length(3),
+        Constraint::Length(1),
+        Constraint::Length(1),
+        Constraint::Min(0),
+    ])
+    .areas(area);
+
+    let Some(torrent) = app.torrents.iter().find(|t| t.id == d.id) else {
+        return;
+    };
+    let stats = &torrent.stats;
+    let s = speeds(stats);
+    let mut summary = vec![
+        Line::from(format!("[{}] {}", torrent.id, torrent.name)).bold(),
+        Line::from(format!(
+            "{} | {} | ↓ {} | ↑ {} ({}) | peers {}{}",
+            stats.state,
+            stats.progress_bytes_human_readable(),
+            s.down,
+            s.up,
+            SF::new(stats.uploaded_bytes),
+            s.peers,
+            if s.eta.is_empty() {
+                String::new()
+            } else {
+                format!(" | ETA {}", s.eta)
+            }
+        )),
+    ];
+    if let Some(error) = &stats.error {
+        summary.push(Line::from(format!("error: {}", one_line(error))).fg(Color::Red));
+    }
+    f.render_widget(Paragraph::new(summary), summary_area);
+    f.render_widget(
+        Gauge::default()
+            .gauge_style(Style::default().fg(state_color(stats.state)))
+            .ratio(progress_ratio(stats)),
+        gauge_area,
+    );
+    f.render_widget(
+        Tabs::new(Tab::ALL.map(Tab::title))
+            .select(Tab::ALL.iter().position(|t| *t == d.tab).unwrap_or(0))
+            .highlight_style(Style::default().add_modifier(Modifier::REVERSED)),
+        tabs_area,
+    );
+
+    let block = Block::default().borders(Borders::ALL);
+    match d.tab {
+        Tab::Files => {
+            let rows = d.details.iter().flat_map(|details| {
+                details.files.iter().enumerate().map(|(idx, file)| {
+                    Row::new(vec![
+                        idx.to_string(),
+                        SF::new(file.length).to_string(),
+                        file.priority.to_string(),
+                        file.name.clone(),
+                    ])
+                })
+            });
+            let table = Table::new(
+                rows,
+                [
+                    Constraint::Length(5),
+                    Constraint::Length(10),
+                    Constraint::Length(9),
+                    Constraint::Min(10),
+                ],
+            )
+            .header(Row::new(vec!["FILE", "SIZE", "PRIORITY", "PATH"]).add_modifier(Modifier::BOLD))
+            .block(block);
+            f.render_widget(table, body_area);
+        }
+        Tab::Peers => {
+            let mut peers = d
+                .peers
+                .iter()
+                .flat_map(|p| p.peers.iter())
+                .collect::<Vec<_>>();
+            peers.sort_by(|a, b| b.1.counters.fetched_bytes.cmp(&a.1.counters.fetched_bytes));
+            let rows = peers.into_iter().map(|(addr, p)| {
+                Row::new(vec![
+                    addr.clone(),
+                    p.state.to_string(),
+                    p.source.to_string(),
+                    p.direction.map(|d| d.to_string()).unwrap_or_default(),
+                    p.client.clone().unwrap_or_default(),
+                    SF::new(p.counters.fetched_bytes).to_string(),
+                    p.counters.downloaded_and_checked_pieces.to_string(),
+                    p.counters.errors.to_string(),
+                    p.counters.hash_failures.to_string(),
+                ])
+            });
+            let table = Table::new(
+                rows,
+                [
+                    Constraint::Length(47),
+                    Constraint::Length(11),
+                    Constraint::Length(14),
+                    Constraint::Length(4),
+                    Constraint::Length(22),
+                    Constraint::Length(10),
+                    Constraint::Length(7),
+                    Constraint::Length(7),
+                    Constraint::Length(10),
+                ],
+            )
+            .header(
+                Row::new(vec![
+                    "ADDR",
+                    "STATE",
+                    "SOURCE",
+                    "DIR",
+                    "CLIENT",
+                    "FETCHED",
+                    "PIECES",
+                    "ERRORS",
+                    "HASH FAILS",
+                ])
+                .add_modifier(Modifier::BOLD),
+            )
+            .block(block);
+            f.render_widget(table, body_area);
+        }
+        Tab::Trackers => {
+            let lines = d
+                .details
+                .iter()
+                .flat_map(|details| details.trackers.iter())
+                .map(|t| Line::from(t.as_str()))
+                .collect::<Vec<_>>();
+            f.render_widget(Paragraph::new(lines).block(block), body_area);
+        }
+    }
+}
+
+fn draw(f: &mut Frame, app: &mut App) {
+    let [main_area, status_area] =
+        Layout::vertical([Constraint::Min(0), Constraint::Length(1)]).areas(f.size());
+
+    if let Some(d) = &app.drilldown {
+        draw_drilldown(f, app, d, main_area);
+    } else {
+        draw_torrents(f, app, main_area);
+    }
+
+    let status = if let Some(id) = app.confirm_delete {
+        Line::from(format!("Delete torrent {id} and its files? [y/N]")).fg(Color::Red)
+    } else {
+        let keys = if app.drilldown.is_some() {
+            "tab/1-3: switch tab  p: pause  r: resume  d: delete  esc: back  q: quit"
+        } else {
+            "↑↓: select  enter: details  p: pause  r: resume  d: delete  q: quit"
+        };
+        match &app.status {
+            Some(status) => Line::from(format!("{status} | {keys}")),
+            None => Line::from(keys),
+        }
+    };
+    f.render_widget(Paragraph::new(status), status_area);
+}
+
+// Restores the terminal even if the dashboard panics or errors out.
+struct TerminalGuard {
+    // Puts back the panic hook that was there before the dashboard started.
+    restore_hook: Option<Box<dyn FnOnce() + Send>>,
+}
+
+impl TerminalGuard {
+    fn new() -> anyhow::Result<Self> {
+        enable_raw_mode().context("error enabling raw mode")?;
+        execute!(io::stdout(), EnterAlternateScreen)?;
+        let previous_hook = Arc::new(std::panic::take_hook());
+        let hook = previous_hook.clone();
+        std::panic::set_hook(Box::new(move |info| {
+            let _ = disable_raw_mode();
+            let _ = execute!(io::stdout(), LeaveAlternateScreen);
+            hook(info);
+        }));
+        Ok(Self {
+            restore_hook: Some(Box::new(move || {
+                std::panic::set_hook(Box::new(move |info| previous_hook(info)))
+            })),
+        })
+    }
+}
+
+impl Drop for TerminalGuard {
+    fn drop(&mut self) {
+        let _ = disable_raw_mode();
+        let _ = execute!(io::stdout(), LeaveAlternateScreen);
+        // Changing the hook while panicking panics again.
+        if let Some(restore_hook) = self.restore_hook.take() {
+            if !std::thread::panicking() {
+                restore_hook();
+            }
+        }
+    }
+}
+
+/// Run the dashboard until the user quits.
+pub async fn run(source: TuiSource) -> anyhow::Result<()> {
+    let _guard = TerminalGuard::new()?;
+    let mut terminal = Terminal::new(CrosstermBackend::new(io::stdout()))?;
+    let mut app = App {
+        source,
+        torrents: Vec::new(),
+        table: TableState::default(),
+        drilldown: None,
+        confirm_delete: None,
+        status: None,
+        quit: false,
+    };
+    let mut events = EventStream::new();
+    let mut interval = tokio::time::interval(REFRESH_INTERVAL);
+
+    while !app.quit {
+        tokio::select! {
+            _ = interval.tick() => app.refresh().await,
+            event = events.next() => match event {
+                Some(Ok(Event::Key(key))) => app.on_key(key).await,
+                Some(Ok(_)) => {}
+                Some(Err(e)) => return Err(e).context("error reading terminal events"),
+                None => break,
+            },
+        }
+        terminal.draw(|f| draw(f, &mut app))?;
+    }
+    Ok(())
+}
@@ -31,20 +31,18 @@ use leaky_bucket::RateLimiter;
 use librqbit_core::{
     hash_id::Id20,
     peer_id::generate_peer_id,
+    socks5::{DatagramSocket, Socks5Proxy},
     spawn_utils::{spawn, spawn_with_cancel},
 };
 use parking_lot::RwLock;
 
-use serde::Serialize;
-use tokio::{
-    net::UdpSocket,
-    sync::mpsc::{channel, unbounded_channel, Sender, UnboundedReceiver, UnboundedSender},
-};
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc::{channel, unbounded_channel, Sender, UnboundedReceiver, UnboundedSender};
 
 use tokio_util::sync::CancellationToken;
 use tracing::{debug, debug_span, error, error_span, info, trace, warn, Instrument};
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct DhtStats {
     #[serde(serialize_with = "crate::utils::serialize_id20")]
     pub id: Id20,
@@ -842,7 +840,7 @@ impl core::fmt::Debug for ResponseOrError {
 }
 
 struct DhtWorker {
-    socket: UdpSocket,
+    socket: DatagramSocket,
     dht: Arc<DhtState>,
 }
 
@@ -1008,7 +1006,7 @@ impl DhtWorker {
 
     async fn framer(
         &self,
-        socket: &UdpSocket,
+        socket: &DatagramSocket,
         mut input_rx: UnboundedReceiver<WorkerSendRequest>,
         output_tx: Sender<(Message<ByteString>, SocketAddr)>,
     ) -> anyhow::Result<()> {
@@ -1035,7 +1033,7 @@ impl DhtWorker {
                 if let Err(e) = socket.send_to(&buf, addr).await {
                     debug!("error sending to {addr}: {e:?}");
                     if let Some(tid) = our_tid {
-                        self.on_send_error(tid, addr, e.into());
+                        self.on_send_error(tid, addr, e);
                     }
                 }
             }
@@ -1137,6 +1135,9 @@ pub struct DhtConfig {
     pub listen_addr: Option<SocketAddr>,
     pub peer_store: Option<PeerStore>,
     pub cancellation_token: Option<CancellationToken>,
+    /// Send and receive through this proxy's UDP relay. Only the IP of listen_addr is used
+    /// then, to reach the proxy from.
+    pub socks5_proxy: Option<Socks5Proxy>,
 }
 
 impl DhtState {
@@ -1150,14 +1151,13 @@ impl DhtState {
     #[inline(never)]
     pub fn with_config(mut config: DhtConfig) -> BoxFuture<'static, anyhow::Result<Arc<Self>>> {
         async move {
-            let socket = match config.listen_addr {
-                Some(addr) => UdpSocket::bind(addr)
-                    .await
-                    .with_context(|| format!("error binding socket, address {addr}")),
-                None => UdpSocket::bind("0.0.0.0:0")
-                    .await
-                    .context("error binding socket, address 0.0.0.0:0"),
-            }?;
+            let socket = DatagramSocket::bind(
+                config
+                    .listen_addr
+                    .unwrap_or_else(|| SocketAddr::from(([0, 0, 0, 0], 0))),
+                config.socks5_proxy.as_ref(),
+            )
+            .await?;
 
             let listen_addr = socket
                 .local_addr()
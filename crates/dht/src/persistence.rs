@@ -3,11 +3,12 @@
 use futures::future::BoxFuture;
 use futures::FutureExt;
 use librqbit_core::directories::get_configuration_directory;
+use librqbit_core::socks5::Socks5Proxy;
 use librqbit_core::spawn_utils::spawn_with_cancel;
 use serde::{Deserialize, Serialize};
 use std::fs::OpenOptions;
 use std::io::{BufReader, BufWriter};
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
 use std::path::{Path, PathBuf};
 use std::time::Duration;
 use tokio_util::sync::CancellationToken;
@@ -23,6 +24,9 @@ use crate::{Dht, DhtConfig, DhtState};
 pub struct PersistentDhtConfig {
     pub dump_interval: Option<Duration>,
     pub config_filename: Option<PathBuf>,
+    /// Listen on this IP, keeping the stored port.
+    pub listen_ip: Option<IpAddr>,
+    pub socks5_proxy: Option<Socks5Proxy>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -129,6 +133,10 @@ impl PersistentDht {
             let (listen_addr, routing_table, peer_store) = de
                 .map(|de| (Some(de.addr), Some(de.table), de.peer_store))
                 .unwrap_or((None, None, None));
+            let listen_addr = match config.listen_ip {
+                Some(ip) => Some(SocketAddr::new(ip, listen_addr.map_or(0, |a| a.port()))),
+                None => listen_addr,
+            };
             let peer_id = routing_table.as_ref().map(|r| r.id());
 
             let dht_config = DhtConfig {
@@ -137,6 +145,7 @@ impl PersistentDht {
                 listen_addr,
                 peer_store,
                 cancellation_token,
+                socks5_proxy: config.socks5_proxy.take(),
                 ..Default::default()
             };
             let dht = DhtState::with_config(dht_config).await?;
@@ -1,28 +1,48 @@
-use std::{net::SocketAddr, sync::Arc};
+use std::{
+    collections::BTreeMap,
+    io::SeekFrom,
+    net::{IpAddr, SocketAddr},
+    ops::Range,
+    path::PathBuf,
+    sync::Arc,
+    time::Duration,
+};
 
 use anyhow::Context;
 use buffers::ByteString;
 use dht::{DhtStats, Id20};
-use futures::Stream;
+use futures::{stream::BoxStream, Stream};
 use http::StatusCode;
+use itertools::Itertools;
 use librqbit_core::torrent_metainfo::TorrentMetaV1Info;
 use serde::{Deserialize, Serialize};
-use tokio::sync::mpsc::UnboundedSender;
+use tokio::{
+    io::{AsyncReadExt, AsyncSeekExt},
+    sync::mpsc::UnboundedSender,
+};
 use tokio_stream::wrappers::{errors::BroadcastStreamRecvError, BroadcastStream};
+use tokio_util::io::ReaderStream;
 use tracing::warn;
 
 use crate::{
     api_error::{ApiError, ApiErrorExt},
+    connection_limits::ConnectionStats,
+    disk_cache::DiskCacheStats,
+    events::SessionEvent,
+    file_priority::FilePriority,
+    ip_filter::{IpFilterStats, PeerBan},
     session::{
         AddTorrent, AddTorrentOptions, AddTorrentResponse, ListOnlyResponse, Session, TorrentId,
     },
-    torrent_state::{
-        peer::stats::snapshot::{PeerStatsFilter, PeerStatsSnapshot},
-        ManagedTorrentHandle,
-    },
+    storage::DestinationExists,
+    torrent_state::ManagedTorrentHandle,
     tracing_subscriber_config_utils::LineBroadcast,
 };
 
+pub use crate::torrent_state::peer::stats::snapshot::{
+    PeerCounters, PeerStats, PeerStatsFilter, PeerStatsFilterState, PeerStatsSnapshot,
+};
+pub use crate::torrent_state::peer::{PeerDirection, PeerSource};
 pub use crate::torrent_state::stats::{LiveStats, TorrentStats};
 
 pub type Result<T> = std::result::Result<T, ApiError>;
@@ -73,9 +93,125 @@ impl Api {
 
     pub fn api_torrent_details(&self, idx: TorrentId) -> Result<TorrentDetailsResponse> {
         let handle = self.mgr_handle(idx)?;
-        let info_hash = handle.info().info_hash;
-        let only_files = handle.only_files();
-        make_torrent_details(&info_hash, &handle.info().info, only_files.as_deref())
+        make_handle_details(&handle)
+    }
+
+    /// Change the priorities of some of the torrent's files. Files not mentioned in the
+    /// request keep their current priority.
+    pub fn api_torrent_set_file_priorities(
+        &self,
+        idx: TorrentId,
+        req: TorrentFilePrioritiesRequest,
+    ) -> Result<TorrentDetailsResponse> {
+        let handle = self.mgr_handle(idx)?;
+        let mut file_priorities = handle.file_priorities();
+        for (file_idx, priority) in req.files {
+            let p = file_priorities
+                .get_mut(file_idx)
+                .with_context(|| format!("file id {} is out of range", file_idx))
+                .with_error_status_code(StatusCode::BAD_REQUEST)?;
+            *p = priority;
+        }
+        handle
+            .set_file_priorities(file_priorities)
+            .context("error setting file priorities")
+            .with_error_status_code(StatusCode::BAD_REQUEST)?;
+        make_handle_details(&handle)
+    }
+
+    pub fn api_torrent_set_sequential(
+        &self,
+        idx: TorrentId,
+        sequential: bool,
+    ) -> Result<EmptyJsonResponse> {
+        let handle = self.mgr_handle(idx)?;
+        handle
+            .set_sequential(sequential)
+            .context("error switching sequential mode")
+            .with_error_status_code(StatusCode::BAD_REQUEST)?;
+        Ok(Default::default())
+    }
+
+    pub async fn api_torrent_move(
+        &self,
+        idx: TorrentId,
+        req: TorrentMoveRequest,
+    ) -> Result<EmptyJsonResponse> {
+        let handle = self.mgr_handle(idx)?;
+        self.session
+            .move_torrent(&handle, req.output_folder.into())
+            .await
+            .map_err(|e| {
+                // Nothing was moved, the files at the destination need to be dealt with first.
+                let status = match e.downcast_ref::<DestinationExists>() {
+                    Some(_) => StatusCode::CONFLICT,
+                    None => StatusCode::INTERNAL_SERVER_ERROR,
+                };
+                ApiError::new_from_anyhow(status, e.context("error moving torrent data"))
+            })?;
+        Ok(Default::default())
+    }
+
+    /// Rename some of the torrent's files. Files not mentioned in the request keep
+    /// their current paths.
+    pub async fn api_torrent_rename_files(
+        &self,
+        idx: TorrentId,
+        req: TorrentRenameFilesRequest,
+    ) -> Result<TorrentDetailsResponse> {
+        let handle = self.mgr_handle(idx)?;
+        let renames = req
+            .files
+            .into_iter()
+            .map(|(file_idx, path)| (file_idx, path.map(PathBuf::from)))
+            .collect();
+        self.session
+            .rename_files(&handle, renames)
+            .await
+            .context("error renaming files")
+            .with_error_status_code(StatusCode::BAD_REQUEST)?;
+        make_handle_details(&handle)
+    }
+
+    /// Stream a byte range of a file, waiting for pieces as needed.
+    /// The torrent has to be live, and the file selected for download.
+    pub async fn api_stream(
+        &self,
+        idx: TorrentId,
+        file_id: usize,
+        range: Option<Range<u64>>,
+    ) -> Result<FileStreamResponse> {
+        let handle = self.mgr_handle(idx)?;
+        let mut file = handle
+            .open_file(file_id)
+            .context("error opening file")
+            .with_error_status_code(StatusCode::BAD_REQUEST)?;
+        let file_length = file.len();
+        let range = range.unwrap_or(0..file_length);
+        if range.start > range.end || range.end > file_length {
+            return Err(ApiError::new_from_text(
+                StatusCode::RANGE_NOT_SATISFIABLE,
+                "requested range is not satisfiable",
+            ));
+        }
+        let filename = handle
+            .info()
+            .relative_file_paths()
+            .context("error getting file paths")?
+            .get(file_id)
+            .and_then(|p| p.file_name())
+            .map(|f| f.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        file.seek(SeekFrom::Start(range.start))
+            .await
+            .context("error seeking")?;
+        let stream = ReaderStream::new(file.take(range.end - range.start));
+        Ok(FileStreamResponse {
+            filename,
+            file_length,
+            range,
+            stream: Box::pin(stream),
+        })
     }
 
     pub fn api_peer_stats(
@@ -90,6 +226,63 @@ impl Api {
             .per_peer_stats_snapshot(filter))
     }
 
+    /// Connect to these peers, unless the torrent knows them already.
+    pub fn api_add_peers(
+        &self,
+        idx: TorrentId,
+        req: TorrentAddPeersRequest,
+    ) -> Result<TorrentAddPeersResponse> {
+        let live = self
+            .mgr_handle(idx)?
+            .live()
+            .context("torrent not live")
+            .with_error_status_code(StatusCode::BAD_REQUEST)?;
+        let mut resp = TorrentAddPeersResponse::default();
+        for addr in req.peers {
+            if live.add_peer_if_not_seen(addr, PeerSource::Manual)? {
+                resp.added += 1;
+            } else {
+                resp.skipped += 1;
+            }
+        }
+        Ok(resp)
+    }
+
+    pub fn api_disconnect_peer(
+        &self,
+        idx: TorrentId,
+        addr: SocketAddr,
+    ) -> Result<EmptyJsonResponse> {
+        let live = self
+            .mgr_handle(idx)?
+            .live()
+            .context("torrent not live")
+            .with_error_status_code(StatusCode::BAD_REQUEST)?;
+        if !live.disconnect_peer(addr) {
+            return Err(ApiError::new_from_text(
+                StatusCode::NOT_FOUND,
+                "peer is not connected",
+            ));
+        }
+        Ok(Default::default())
+    }
+
+    /// Ban the peer's IP in the whole session, and disconnect it from all torrents.
+    pub fn api_ban_peer(
+        &self,
+        idx: TorrentId,
+        addr: SocketAddr,
+        opts: PeerBanOptions,
+    ) -> Result<EmptyJsonResponse> {
+        self.mgr_handle(idx)?;
+        self.session.ban_peer(
+            addr.ip(),
+            "banned manually".to_owned(),
+            opts.duration_secs.map(Duration::from_secs),
+        );
+        Ok(Default::default())
+    }
+
     pub fn api_torrent_action_pause(&self, idx: TorrentId) -> Result<EmptyJsonResponse> {
         let handle = self.mgr_handle(idx)?;
         handle
@@ -147,6 +340,20 @@ impl Api {
             .context("line_rx wasn't set")?)
     }
 
+    /// Session and torrent metrics in the Prometheus text format.
+    pub fn api_metrics(&self) -> String {
+        crate::metrics::render(&self.session)
+    }
+
+    pub fn api_events_stream(
+        &self,
+    ) -> impl Stream<Item = std::result::Result<SessionEvent, BroadcastStreamRecvError>>
+           + Send
+           + Sync
+           + 'static {
+        BroadcastStream::new(self.session.subscribe())
+    }
+
     pub async fn api_add_torrent(
         &self,
         add: AddTorrent<'_>,
@@ -164,7 +371,7 @@ impl Api {
                     "{:?} is already managed, id={}, downloaded to {:?}",
                     managed.info_hash(),
                     id,
-                    &managed.info().out_dir
+                    managed.info().out_dir()
                 ))
                 .with_error_status_code(StatusCode::CONFLICT);
             }
@@ -178,20 +385,27 @@ impl Api {
                 id: None,
                 output_folder: output_folder.to_string_lossy().into_owned(),
                 seen_peers: Some(seen_peers),
-                details: make_torrent_details(&info_hash, &info, only_files.as_deref())
-                    .context("error making torrent details")?,
+                details: make_torrent_details(
+                    &info_hash,
+                    &info,
+                    &FilePriority::from_only_files(
+                        info.iter_file_lengths()
+                            .context("error iterating file lengths")?
+                            .count(),
+                        only_files.as_deref(),
+                    ),
+                    &Default::default(),
+                    Vec::new(),
+                )
+                .context("error making torrent details")?,
             },
             AddTorrentResponse::Added(id, handle) => {
-                let details = make_torrent_details(
-                    &handle.info_hash(),
-                    &handle.info().info,
-                    handle.only_files().as_deref(),
-                )
-                .context("error making torrent details")?;
+                let details =
+                    make_handle_details(&handle).context("error making torrent details")?;
                 ApiAddTorrentResponse {
                     id: Some(id),
                     details,
-                    output_folder: handle.info().out_dir.to_string_lossy().into_owned(),
+                    output_folder: handle.info().out_dir().to_string_lossy().into_owned(),
                     seen_peers: None,
                 }
             }
@@ -207,6 +421,37 @@ impl Api {
             .ok_or(ApiError::dht_disabled())
     }
 
+    pub fn api_disk_cache_stats(&self) -> DiskCacheStats {
+        self.session.disk_cache_stats()
+    }
+
+    pub fn api_connection_stats(&self) -> ConnectionStats {
+        self.session.connection_stats()
+    }
+
+    pub fn api_ip_filter_stats(&self) -> IpFilterStats {
+        self.session.ip_filter_stats()
+    }
+
+    /// Read the session's IP filter files again.
+    pub fn api_ip_filter_reload(&self) -> Result<IpFilterStats> {
+        Ok(self.session.reload_ip_filter(None)?)
+    }
+
+    pub fn api_peer_bans(&self) -> Vec<PeerBan> {
+        self.session.peer_bans()
+    }
+
+    pub fn api_unban_peer(&self, ip: IpAddr) -> Result<EmptyJsonResponse> {
+        if !self.session.unban_peer(ip) {
+            return Err(ApiError::new_from_text(
+                StatusCode::NOT_FOUND,
+                "peer is not banned",
+            ));
+        }
+        Ok(Default::default())
+    }
+
     pub fn api_dht_table(&self) -> Result<impl Serialize> {
         let dht = self.session.get_dht().ok_or(ApiError::dht_disabled())?;
         Ok(dht.with_routing_table(|r| r.clone()))
@@ -229,13 +474,13 @@ impl Api {
     }
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, Deserialize)]
 pub struct TorrentListResponseItem {
     pub id: usize,
     pub info_hash: String,
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, Deserialize)]
 pub struct TorrentListResponse {
     pub torrents: Vec<TorrentListResponseItem>,
 }
@@ -246,16 +491,66 @@ pub struct TorrentDetailsResponseFile {
     pub components: Vec<String>,
     pub length: u64,
     pub included: bool,
+    #[serde(default)]
+    pub priority: FilePriority,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+pub struct TorrentMoveRequest {
+    /// The new output folder. Files keep their paths relative to it.
+    pub output_folder: String,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+pub struct TorrentRenameFilesRequest {
+    /// File index to its new path relative to the output folder.
+    /// null reverts to the path from the torrent.
+    pub files: BTreeMap<usize, Option<String>>,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+pub struct TorrentFilePrioritiesRequest {
+    /// File index to its new priority.
+    pub files: BTreeMap<usize, FilePriority>,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+pub struct TorrentAddPeersRequest {
+    pub peers: Vec<SocketAddr>,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+pub struct TorrentAddPeersResponse {
+    pub added: usize,
+    /// Already known to the torrent, or blocked by the IP filter or a ban.
+    pub skipped: usize,
 }
 
-#[derive(Default, Serialize)]
+#[derive(Default, Serialize, Deserialize)]
+pub struct PeerBanOptions {
+    /// Lift the ban after this many seconds. The ban is permanent without it.
+    pub duration_secs: Option<u64>,
+}
+
+#[derive(Default, Serialize, Deserialize)]
 pub struct EmptyJsonResponse {}
 
+pub struct FileStreamResponse {
+    pub filename: String,
+    pub file_length: u64,
+    /// The byte range of the file the stream yields.
+    pub range: Range<u64>,
+    pub stream: BoxStream<'static, std::io::Result<bytes::Bytes>>,
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct TorrentDetailsResponse {
     pub info_hash: String,
     pub name: Option<String>,
     pub files: Vec<TorrentDetailsResponseFile>,
+    /// Tracker URLs. Empty for "list_only" responses.
+    #[serde(default)]
+    pub trackers: Vec<String>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -266,30 +561,53 @@ pub struct ApiAddTorrentResponse {
     pub seen_peers: Option<Vec<SocketAddr>>,
 }
 
+fn make_handle_details(handle: &ManagedTorrentHandle) -> Result<TorrentDetailsResponse> {
+    make_torrent_details(
+        &handle.info_hash(),
+        &handle.info().info,
+        &handle.file_priorities(),
+        &handle.info().file_path_overrides(),
+        handle.info().trackers.iter().cloned().sorted().collect(),
+    )
+}
+
 fn make_torrent_details(
     info_hash: &Id20,
     info: &TorrentMetaV1Info<ByteString>,
-    only_files: Option<&[usize]>,
+    file_priorities: &[FilePriority],
+    file_path_overrides: &BTreeMap<usize, PathBuf>,
+    trackers: Vec<String>,
 ) -> Result<TorrentDetailsResponse> {
     let files = info
         .iter_filenames_and_lengths()
         .context("error iterating filenames and lengths")?
         .enumerate()
         .map(|(idx, (filename_it, length))| {
-            let name = match filename_it.to_string() {
-                Ok(s) => s,
-                Err(err) => {
-                    warn!("error reading filename: {:?}", err);
-                    "<INVALID NAME>".to_string()
+            let (name, components) = match file_path_overrides.get(&idx) {
+                Some(path) => (
+                    path.to_string_lossy().into_owned(),
+                    path.iter()
+                        .map(|c| c.to_string_lossy().into_owned())
+                        .collect(),
+                ),
+                None => {
+                    let name = match filename_it.to_string() {
+                        Ok(s) => s,
+                        Err(err) => {
+                            warn!("error reading filename: {:?}", err);
+                            "<INVALID NAME>".to_string()
+                        }
+                    };
+                    (name, filename_it.to_vec().unwrap_or_default())
                 }
             };
-            let components = filename_it.to_vec().unwrap_or_default();
-            let included = only_files.map(|o| o.contains(&idx)).unwrap_or(true);
+            let priority = file_priorities.get(idx).copied().unwrap_or_default();
             TorrentDetailsResponseFile {
                 name,
                 components,
                 length,
-                included,
+                included: !priority.is_skip(),
+                priority,
             }
         })
         .collect();
@@ -297,5 +615,6 @@ fn make_torrent_details(
         info_hash: info_hash.as_string(),
         name: info.name.as_ref().map(|b| b.to_string()),
         files,
+        trackers,
     })
 }
@@ -2,7 +2,10 @@ use librqbit_core::lengths::{ChunkInfo, Lengths, ValidPieceIndex};
 use peer_binary_protocol::Piece;
 use tracing::{debug, trace};
 
-use crate::type_aliases::BF;
+use crate::{
+    file_priority::{piece_priority, FilePriority},
+    type_aliases::BF,
+};
 
 pub struct ChunkTracker {
     // This forms the basis of a "queue" to pull from.
@@ -25,6 +28,20 @@ pub struct ChunkTracker {
     // What pieces to download first.
     priority_piece_ids: Vec<usize>,
 
+    // In sequential mode, the first and last pieces of each selected file.
+    // These replace priority_piece_ids.
+    sequential_piece_ids: Option<Vec<usize>>,
+
+    // Set for the pieces in priority_piece_ids or sequential_piece_ids, whichever is used.
+    priority_piece_mask: BF,
+
+    // The priority of each piece, computed from file priorities.
+    // Pieces of higher priority files are requested first.
+    piece_priorities: Vec<FilePriority>,
+
+    // Selected pieces, highest priority first, computed from piece_priorities.
+    pieces_by_priority: Vec<usize>,
+
     total_selected_bytes: u64,
 }
 
@@ -53,6 +70,28 @@ fn compute_chunk_status(lengths: &Lengths, needed_pieces: &BF) -> BF {
     chunk_bf
 }
 
+fn compute_pieces_by_priority(piece_priorities: &[FilePriority]) -> Vec<usize> {
+    let mut pieces = piece_priorities
+        .iter()
+        .enumerate()
+        .filter(|(_, p)| !p.is_skip())
+        .map(|(id, _)| id)
+        .collect::<Vec<_>>();
+    // Stable, so pieces of the same priority stay in order.
+    pieces.sort_by_key(|id| std::cmp::Reverse(piece_priorities[*id]));
+    pieces
+}
+
+fn compute_piece_mask(lengths: &Lengths, piece_ids: &[usize]) -> BF {
+    let mut mask = BF::from_vec(vec![0u8; lengths.piece_bitfield_bytes()]);
+    for id in piece_ids.iter().copied() {
+        if let Some(mut b) = mask.get_mut(id) {
+            *b = true;
+        }
+    }
+    mask
+}
+
 pub enum ChunkMarkingResult {
     PreviouslyCompleted,
     NotCompleted,
@@ -65,6 +104,7 @@ impl ChunkTracker {
         have_pieces: BF,
         lengths: Lengths,
         total_selected_bytes: u64,
+        piece_priorities: Vec<FilePriority>,
     ) -> Self {
         // TODO: ideally this needs to be a list based on needed files, e.g.
         // last needed piece for each file. But let's keep simple for now.
@@ -80,13 +120,17 @@ impl ChunkTracker {
         // The last pieces first. Often important information is stored in the last piece.
         // E.g. if it's a video file, than the last piece often contains some index, or just
         // players look into it, and it's better be there.
-        let priority_piece_ids = last_needed_piece_id.into_iter().collect();
+        let priority_piece_ids = last_needed_piece_id.into_iter().collect::<Vec<_>>();
         Self {
             chunk_status: compute_chunk_status(&lengths, &needed_pieces),
             needed_pieces,
-            lengths,
             have: have_pieces,
+            priority_piece_mask: compute_piece_mask(&lengths, &priority_piece_ids),
+            lengths,
             priority_piece_ids,
+            sequential_piece_ids: None,
+            pieces_by_priority: compute_pieces_by_priority(&piece_priorities),
+            piece_priorities,
             total_selected_bytes,
         }
     }
@@ -102,6 +146,35 @@ impl ChunkTracker {
     pub fn get_have_pieces(&self) -> &BF {
         &self.have
     }
+    pub fn is_piece_have(&self, index: ValidPieceIndex) -> bool {
+        self.have
+            .get(index.get() as usize)
+            .map(|r| *r)
+            .unwrap_or_default()
+    }
+
+    pub fn is_piece_needed(&self, index: ValidPieceIndex) -> bool {
+        self.needed_pieces
+            .get(index.get() as usize)
+            .map(|r| *r)
+            .unwrap_or_default()
+    }
+
+    /// Switch sequential mode on (Some) or off (None).
+    ///
+    /// In sequential mode pieces are downloaded in order, after the given ones. Pass the
+    /// first and last pieces of each file there, as players usually look for headers
+    /// and indices there.
+    pub fn set_sequential_piece_ids(&mut self, piece_ids: Option<Vec<usize>>) {
+        self.sequential_piece_ids = piece_ids;
+        self.priority_piece_mask = compute_piece_mask(
+            &self.lengths,
+            self.sequential_piece_ids
+                .as_ref()
+                .unwrap_or(&self.priority_piece_ids),
+        );
+    }
+
     pub fn reserve_needed_piece(&mut self, index: ValidPieceIndex) {
         self.needed_pieces.set(index.get() as usize, false)
     }
@@ -127,15 +200,68 @@ impl ChunkTracker {
     }
 
     pub fn iter_needed_pieces(&self) -> impl Iterator<Item = usize> + '_ {
-        self.priority_piece_ids
+        let priority_piece_ids = self
+            .sequential_piece_ids
+            .as_ref()
+            .unwrap_or(&self.priority_piece_ids);
+        let by_priority = self
+            .pieces_by_priority
+            .iter()
+            .copied()
+            .filter(move |id| self.needed_pieces[*id] && !self.priority_piece_mask[*id]);
+        priority_piece_ids
             .iter()
             .copied()
             .filter(move |piece_id| self.needed_pieces[*piece_id])
-            .chain(
-                self.needed_pieces
-                    .iter_ones()
-                    .filter(move |id| !self.priority_piece_ids.contains(id)),
-            )
+            .chain(by_priority)
+    }
+
+    /// Change which pieces are selected for download, and in what order.
+    ///
+    /// Pieces that were not selected before and are not in-flight become needed.
+    /// Pieces that are not selected anymore are removed from the queue, but the ones
+    /// in-flight are let to finish.
+    ///
+    /// Returns the number of bytes that remain to be downloaded.
+    pub fn update_piece_priorities(
+        &mut self,
+        piece_priorities: Vec<FilePriority>,
+        is_inflight: impl Fn(ValidPieceIndex) -> bool,
+    ) -> u64 {
+        let mut total_selected_bytes = 0u64;
+        let mut remaining_bytes = 0u64;
+        for piece_info in self.lengths.iter_piece_infos() {
+            let index = piece_info.piece_index;
+            let idx = index.get() as usize;
+            let old = self
+                .piece_priorities
+                .get(idx)
+                .copied()
+                .unwrap_or(FilePriority::Skip);
+            let new = piece_priorities
+                .get(idx)
+                .copied()
+                .unwrap_or(FilePriority::Skip);
+            if new.is_skip() {
+                if !old.is_skip() {
+                    self.needed_pieces.set(idx, false);
+                }
+                continue;
+            }
+            total_selected_bytes += piece_info.len as u64;
+            if self.have[idx] {
+                continue;
+            }
+            remaining_bytes += piece_info.len as u64;
+            if old.is_skip() && !is_inflight(index) {
+                self.needed_pieces.set(idx, true);
+                self.reset_piece_chunks(index);
+            }
+        }
+        self.pieces_by_priority = compute_pieces_by_priority(&piece_priorities);
+        self.piece_priorities = piece_priorities;
+        self.total_selected_bytes = total_selected_bytes;
+        remaining_bytes
     }
 
     // None if wrong chunk
@@ -157,6 +283,10 @@ impl ChunkTracker {
         Some(true)
     }
 
+    pub fn is_piece_selected(&self, index: ValidPieceIndex) -> bool {
+        !piece_priority(&self.piece_priorities, index).is_skip()
+    }
+
     pub fn mark_piece_broken_if_not_have(&mut self, index: ValidPieceIndex) {
         if self
             .have
@@ -166,8 +296,18 @@ impl ChunkTracker {
         {
             return;
         }
+        if !self.is_piece_selected(index) {
+            debug!("piece={} is broken, but not selected anymore", index);
+            return;
+        }
         debug!("remarking piece={} as broken", index);
         self.needed_pieces.set(index.get() as usize, true);
+        self.reset_piece_chunks(index);
+    }
+
+    /// Forget which chunks of the piece were received, so that all of them are
+    /// downloaded again.
+    pub fn reset_piece_chunks(&mut self, index: ValidPieceIndex) {
         if let Some(s) = self.chunk_status.get_mut(self.lengths.chunk_range(index)) {
             s.fill(false);
         }
@@ -216,3 +356,38 @@ impl ChunkTracker {
         Some(ChunkMarkingResult::NotCompleted)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use librqbit_core::lengths::Lengths;
+
+    use super::ChunkTracker;
+    use crate::{file_priority::FilePriority as P, type_aliases::BF};
+
+    #[test]
+    fn test_iter_needed_pieces_by_priority() {
+        // 6 pieces, all needed.
+        let lengths = Lengths::new(6 * 16384, 16384, None).unwrap();
+        let mut needed = BF::from_vec(vec![0u8; lengths.piece_bitfield_bytes()]);
+        needed.get_mut(0..6).unwrap().fill(true);
+        let have = BF::from_vec(vec![0u8; lengths.piece_bitfield_bytes()]);
+        let prios = vec![P::Low, P::Normal, P::High, P::Normal, P::High, P::Low];
+        let mut ct = ChunkTracker::new(needed, have, lengths, 6 * 16384, prios);
+
+        // The last piece goes first, then by priority.
+        let order = |ct: &ChunkTracker| ct.iter_needed_pieces().collect::<Vec<_>>();
+        assert_eq!(order(&ct), vec![5, 2, 4, 1, 3, 0]);
+
+        ct.reserve_needed_piece(lengths.validate_piece_index(2).unwrap());
+        assert_eq!(order(&ct), vec![5, 4, 1, 3, 0]);
+
+        ct.set_sequential_piece_ids(Some(vec![3]));
+        assert_eq!(order(&ct), vec![3, 4, 1, 0, 5]);
+
+        ct.update_piece_priorities(
+            vec![P::High, P::Skip, P::Low, P::Normal, P::Low, P::Skip],
+            |_| false,
+        );
+        assert_eq!(order(&ct), vec![3, 0, 4]);
+    }
+}
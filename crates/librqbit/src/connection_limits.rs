@@ -0,0 +1,118 @@
+// Limits on peer connections, so that a session with many torrents doesn't run out of file
+// descriptors or flood the network with connects.
+//
+// Every connection takes a permit from its torrent and one from the session. Outgoing ones
+// also hold a half-open permit until the TCP connection is established.
+
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// Limits on peer connections. Peers over the limits wait in the torrent's queue, incoming
+/// connections over them are closed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ConnectionLimits {
+    /// Connections of all torrents together.
+    pub max_connections: usize,
+    pub max_connections_per_torrent: usize,
+    /// Outgoing connects that haven't finished yet.
+    pub max_half_open: usize,
+    /// When a torrent has peers waiting for a connection slot, disconnect the peers that
+    /// sent it (almost) nothing for a while to make room.
+    pub replace_idle_peers: bool,
+}
+
+impl Default for ConnectionLimits {
+    fn default() -> Self {
+        Self {
+            max_connections: 500,
+            max_connections_per_torrent: 128,
+            max_half_open: 32,
+            replace_idle_peers: true,
+        }
+    }
+}
+
+/// Session-wide connection counts.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct ConnectionStats {
+    pub limits: ConnectionLimits,
+    pub connections: usize,
+    pub half_open: usize,
+    /// Incoming connections closed because the session was at max_connections, or the
+    /// torrent at max_connections_per_torrent.
+    pub rejected_incoming: u64,
+    /// Idle or slow peers disconnected to make room for others.
+    pub replaced_peers: u64,
+}
+
+pub(crate) struct ConnectionLimiter {
+    limits: ConnectionLimits,
+    connections: Arc<Semaphore>,
+    half_open: Arc<Semaphore>,
+    rejected_incoming: AtomicU64,
+    replaced_peers: AtomicU64,
+}
+
+impl Default for ConnectionLimiter {
+    fn default() -> Self {
+        Self::new(Default::default())
+    }
+}
+
+impl ConnectionLimiter {
+    pub fn new(limits: ConnectionLimits) -> Self {
+        let permits = |n: usize| Arc::new(Semaphore::new(n.min(Semaphore::MAX_PERMITS)));
+        Self {
+            limits,
+            connections: permits(limits.max_connections),
+            half_open: permits(limits.max_half_open),
+            rejected_incoming: Default::default(),
+            replaced_peers: Default::default(),
+        }
+    }
+
+    pub fn limits(&self) -> &ConnectionLimits {
+        &self.limits
+    }
+
+    pub fn try_acquire(&self) -> Option<OwnedSemaphorePermit> {
+        self.connections.clone().try_acquire_owned().ok()
+    }
+
+    pub async fn acquire(&self) -> anyhow::Result<OwnedSemaphorePermit> {
+        Ok(self.connections.clone().acquire_owned().await?)
+    }
+
+    pub fn try_acquire_half_open(&self) -> Option<OwnedSemaphorePermit> {
+        self.half_open.clone().try_acquire_owned().ok()
+    }
+
+    pub async fn acquire_half_open(&self) -> anyhow::Result<OwnedSemaphorePermit> {
+        Ok(self.half_open.clone().acquire_owned().await?)
+    }
+
+    pub fn on_rejected_incoming(&self) {
+        self.rejected_incoming.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn on_replaced_peer(&self) {
+        self.replaced_peers.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn stats(&self) -> ConnectionStats {
+        let used = |s: &Semaphore, max: usize| max.saturating_sub(s.available_permits());
+        ConnectionStats {
+            limits: self.limits,
+            connections: used(&self.connections, self.limits.max_connections),
+            half_open: used(&self.half_open, self.limits.max_half_open),
+            rejected_incoming: self.rejected_incoming.load(Ordering::Relaxed),
+            replaced_peers: self.replaced_peers.load(Ordering::Relaxed),
+        }
+    }
+}
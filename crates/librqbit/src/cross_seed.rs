@@ -0,0 +1,647 @@
+// Cross-seeding: reusing data that is already on disk, e.g. the same content downloaded
+// from another tracker under different names.
+//
+// Files in the search folders are matched to the torrent's files by length, preferring
+// the same file name, and then verified by piece hashes. Verified files are hard-linked
+// into the output folder, so that the initial check finds them there and the torrent
+// starts seeding without downloading.
+
+use std::{
+    collections::{HashMap, HashSet},
+    fs::File,
+    io::{Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Context};
+use buffers::ByteString;
+use librqbit_core::{lengths::Lengths, torrent_metainfo::TorrentMetaV1Info};
+use sha1w::{ISha1, Sha1};
+use tracing::{debug, info, warn};
+
+use crate::file_priority::FilePriority;
+
+// Files in "dirs", recursively, that have one of the "lengths". Symlinks are not followed.
+fn index_files(dirs: &[PathBuf], lengths: &HashSet<u64>) -> HashMap<u64, Vec<PathBuf>> {
+    let mut result: HashMap<u64, Vec<PathBuf>> = HashMap::new();
+    let mut stack = dirs.to_vec();
+    while let Some(dir) = stack.pop() {
+        let entries = match std::fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(e) => {
+                warn!(?dir, error=?e, "error listing folder");
+                continue;
+            }
+        };
+        for entry in entries.flatten() {
+            let file_type = match entry.file_type() {
+                Ok(t) => t,
+                Err(_) => continue,
+            };
+            if file_type.is_dir() {
+                stack.push(entry.path());
+                continue;
+            }
+            if !file_type.is_file() {
+                continue;
+            }
+            if let Ok(meta) = entry.metadata() {
+                if lengths.contains(&meta.len()) {
+                    result.entry(meta.len()).or_default().push(entry.path());
+                }
+            }
+        }
+    }
+    result
+}
+
+// Higher is a better candidate for a torrent file at "path".
+fn name_score(path: &Path, candidate: &Path) -> u8 {
+    let lower = |s: Option<&std::ffi::OsStr>| s.map(|s| s.to_string_lossy().to_lowercase());
+    if lower(path.file_name()) == lower(candidate.file_name()) {
+        2
+    } else if lower(path.extension()) == lower(candidate.extension()) {
+        1
+    } else {
+        0
+    }
+}
+
+struct Verifier<'a> {
+    info: &'a TorrentMetaV1Info<ByteString>,
+    lengths: Lengths,
+    // Offset and length of each file in the torrent.
+    files: Vec<(u64, u64)>,
+    // Where the data of each file is, if found.
+    sources: Vec<Option<PathBuf>>,
+    buf: Vec<u8>,
+}
+
+impl<'a> Verifier<'a> {
+    fn new(info: &'a TorrentMetaV1Info<ByteString>) -> anyhow::Result<Self> {
+        let mut offset = 0;
+        let mut files = Vec::new();
+        for len in info.iter_file_lengths()? {
+            files.push((offset, len));
+            offset += len;
+        }
+        Ok(Self {
+            info,
+            lengths: Lengths::from_torrent(info)?,
+            sources: vec![None; files.len()],
+            files,
+            buf: vec![0u8; 65536],
+        })
+    }
+
+    fn hash_range(
+        &mut self,
+        hash: &mut Sha1,
+        path: &Path,
+        offset: u64,
+        len: u64,
+    ) -> anyhow::Result<()> {
+        let mut f = File::open(path).with_context(|| format!("error opening {path:?}"))?;
+        f.seek(SeekFrom::Start(offset))?;
+        let mut remaining = len as usize;
+        while remaining > 0 {
+            let n = remaining.min(self.buf.len());
+            f.read_exact(&mut self.buf[..n])
+                .with_context(|| format!("error reading {path:?}"))?;
+            hash.update(&self.buf[..n]);
+            remaining -= n;
+        }
+        Ok(())
+    }
+
+    // Check the pieces of file "file_idx" against "candidate". Every piece that lies
+    // within the file is checked. Pieces that also span files which were not found yet
+    // can't be checked.
+    fn verify(&mut self, file_idx: usize, candidate: &Path) -> anyhow::Result<Verified> {
+        let (file_offset, file_len) = self.files[file_idx];
+        let piece_length = self.lengths.default_piece_length() as u64;
+        let first_piece = file_offset / piece_length;
+        let last_piece = (file_offset + file_len - 1) / piece_length;
+        let mut verified = 0;
+        let mut skipped = 0;
+        'pieces: for piece in first_piece..=last_piece {
+            let index = self
+                .lengths
+                .validate_piece_index(piece as u32)
+                .context("bug: invalid piece")?;
+            let piece_start = self.lengths.piece_offset(index);
+            let piece_end = piece_start + self.lengths.piece_length(index) as u64;
+
+            let mut parts = Vec::new();
+            for (idx, (offset, len)) in self.files.iter().copied().enumerate() {
+                let start = offset.max(piece_start);
+                let end = (offset + len).min(piece_end);
+                if start >= end {
+                    continue;
+                }
+                let source = if idx == file_idx {
+                    candidate.to_owned()
+                } else {
+                    match &self.sources[idx] {
+                        Some(s) => s.clone(),
+                        // Only pieces spanning other files can be skipped, every piece
+                        // fully inside the candidate is always checked.
+                        None => {
+                            skipped += 1;
+                            continue 'pieces;
+                        }
+                    }
+                };
+                parts.push((source, start - offset, end - start));
+            }
+
+            let mut hash = Sha1::new();
+            for (source, offset, len) in parts {
+                self.hash_range(&mut hash, &source, offset, len)?;
+            }
+            if self.info.compare_hash(piece as u32, hash.finish()) != Some(true) {
+                debug!(?candidate, piece, "piece does not match");
+                return Ok(Verified::No);
+            }
+            verified += 1;
+        }
+        Ok(match (verified, skipped) {
+            (0, 0) => Verified::No,
+            (0, _) => Verified::Unverifiable,
+            (_, 0) => Verified::Fully,
+            _ => Verified::Partly,
+        })
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Verified {
+    // A piece didn't match.
+    No,
+    // None of the pieces could be checked, as they all span files that were not found.
+    // Such files are never used, e.g. small files between two missing ones.
+    Unverifiable,
+    // All checked pieces match, but pieces spanning missing files couldn't be checked.
+    Partly,
+    Fully,
+}
+
+// Share the data blocks of "src" (copy-on-write), on filesystems that support it.
+#[cfg(target_os = "linux")]
+fn reflink(src: &Path, dst: &Path) -> std::io::Result<()> {
+    use std::os::fd::AsRawFd;
+    const FICLONE: libc::c_ulong = 0x40049409;
+    let src = File::open(src)?;
+    let dst = std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(dst)?;
+    if unsafe { libc::ioctl(dst.as_raw_fd(), FICLONE as _, src.as_raw_fd()) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn reflink(_src: &Path, _dst: &Path) -> std::io::Result<()> {
+    Err(std::io::ErrorKind::Unsupported.into())
+}
+
+// Copy under a temporary name first, so that an interrupted copy isn't taken for the file.
+fn copy(src: &Path, dst: &Path) -> anyhow::Result<()> {
+    let mut tmp = dst.as_os_str().to_owned();
+    tmp.push(".rqbit-tmp");
+    let tmp = PathBuf::from(tmp);
+    let _ = std::fs::remove_file(&tmp);
+    let result = match reflink(src, &tmp) {
+        Ok(()) => Ok(()),
+        Err(e) => {
+            debug!(?src, ?dst, error=?e, "can't reflink, copying");
+            let _ = std::fs::remove_file(&tmp);
+            std::fs::copy(src, &tmp)
+                .map(|_| ())
+                .with_context(|| format!("error copying {src:?} to {tmp:?}"))
+        }
+    }
+    .and_then(|_| {
+        std::fs::rename(&tmp, dst).with_context(|| format!("error renaming {tmp:?} to {dst:?}"))
+    });
+    if result.is_err() {
+        let _ = std::fs::remove_file(&tmp);
+    }
+    result
+}
+
+fn crosses_devices(e: &std::io::Error) -> bool {
+    #[cfg(unix)]
+    let code = Some(libc::EXDEV);
+    // ERROR_NOT_SAME_DEVICE
+    #[cfg(windows)]
+    let code = Some(17);
+    #[cfg(not(any(unix, windows)))]
+    let code: Option<i32> = None;
+    code.is_some() && e.raw_os_error() == code
+}
+
+// Put the data of "src" at "dst". Partly verified files are always copied: the download
+// fixing up their unchecked pieces must not write into the user's original files.
+fn place(src: &Path, dst: &Path, verified: Verified, copy_files: bool) -> anyhow::Result<()> {
+    if let Some(parent) = dst.parent() {
+        std::fs::create_dir_all(parent).with_context(|| format!("error creating {parent:?}"))?;
+    }
+    if copy_files || verified != Verified::Fully {
+        return copy(src, dst);
+    }
+    match std::fs::hard_link(src, dst) {
+        Ok(()) => Ok(()),
+        Err(e) if crosses_devices(&e) => {
+            debug!(?src, ?dst, "can't hard-link across filesystems, copying");
+            copy(src, dst)
+        }
+        Err(e) => Err(e).with_context(|| format!("error linking {src:?} to {dst:?}")),
+    }
+}
+
+#[derive(Default)]
+pub(crate) struct CrossSeedOptions<'a> {
+    /// Where to look for the data, recursively.
+    pub search_dirs: &'a [PathBuf],
+    /// Allow files at their place that don't match the torrent, they get overwritten.
+    pub overwrite: bool,
+    /// Copy the found files instead of hard-linking them.
+    pub copy_files: bool,
+}
+
+pub(crate) struct CrossSeedResult {
+    /// Files linked or copied from the search folders.
+    pub placed: usize,
+    /// Files at their place in "out_dir" that match the torrent, including the placed ones.
+    pub verified: usize,
+    /// Files with a candidate that could not be checked at all, as all of their pieces
+    /// span files that were not found. These get downloaded.
+    pub unverifiable: usize,
+}
+
+/// Find the data of the torrent's selected files in the search folders, and hard-link the
+/// matching files into "out_dir" at "relative_paths", or copy them if "copy_files" is set.
+///
+/// Files already at their place are checked too. If one of them does not match the torrent,
+/// or is not selected, this fails unless "overwrite" is set, so that existing data is never
+/// overwritten by accident.
+pub(crate) fn place_existing_files(
+    info: &TorrentMetaV1Info<ByteString>,
+    out_dir: &Path,
+    relative_paths: &[PathBuf],
+    file_priorities: &[FilePriority],
+    opts: &CrossSeedOptions,
+) -> anyhow::Result<CrossSeedResult> {
+    let mut verifier = Verifier::new(info)?;
+    let wanted = |idx: usize| {
+        let len = verifier.files[idx].1;
+        let selected = !file_priorities
+            .get(idx)
+            .copied()
+            .unwrap_or_default()
+            .is_skip();
+        selected && len > 0
+    };
+    let wanted = (0..relative_paths.len())
+        .filter(|idx| wanted(*idx))
+        .collect::<Vec<_>>();
+    let lengths = wanted
+        .iter()
+        .map(|idx| verifier.files[*idx].1)
+        .collect::<HashSet<_>>();
+    let index = index_files(opts.search_dirs, &lengths);
+
+    // Each pass can find more files, as pieces spanning several files can only be
+    // checked once all of these files are found. Nothing is placed until all passes
+    // are done, so that files can still become fully verified.
+    let mut verified = vec![Verified::No; relative_paths.len()];
+    let mut unverifiable = vec![false; relative_paths.len()];
+    let mut pending = wanted;
+    loop {
+        let mut next_pending = Vec::new();
+        for idx in pending.iter().copied() {
+            let target = out_dir.join(&relative_paths[idx]);
+            if target.exists() {
+                match verifier.verify(idx, &target)? {
+                    v @ (Verified::No | Verified::Unverifiable) => {
+                        unverifiable[idx] = v == Verified::Unverifiable;
+                        next_pending.push(idx)
+                    }
+                    v => {
+                        verified[idx] = v;
+                        verifier.sources[idx] = Some(target);
+                    }
+                }
+                continue;
+            }
+
+            let mut candidates = index
+                .get(&verifier.files[idx].1)
+                .map(|c| c.iter().collect::<Vec<_>>())
+                .unwrap_or_default();
+            candidates.sort_by_key(|c| std::cmp::Reverse(name_score(&target, c)));
+            for candidate in candidates {
+                match verifier.verify(idx, candidate) {
+                    Ok(Verified::No) => {}
+                    Ok(Verified::Unverifiable) => unverifiable[idx] = true,
+                    Ok(v) => {
+                        verified[idx] = v;
+                        verifier.sources[idx] = Some(candidate.clone());
+                        break;
+                    }
+                    Err(e) => debug!(?candidate, error=?e, "error checking candidate"),
+                }
+            }
+            if verifier.sources[idx].is_none() {
+                next_pending.push(idx);
+            }
+        }
+        if next_pending.len() == pending.len() {
+            break;
+        }
+        pending = next_pending;
+    }
+
+    // Files found before their neighbours could only be checked partly.
+    for (idx, v) in verified.iter_mut().enumerate() {
+        if *v == Verified::Partly {
+            let source = verifier.sources[idx].clone().context("bug: no source")?;
+            if verifier.verify(idx, &source)? == Verified::Fully {
+                *v = Verified::Fully;
+            }
+        }
+    }
+
+    if !opts.overwrite {
+        for (idx, relative_path) in relative_paths.iter().enumerate() {
+            let target = out_dir.join(relative_path);
+            if verifier.sources[idx].is_none() && target.exists() {
+                if unverifiable[idx] {
+                    bail!(
+                        "{target:?} already exists and can't be checked against the torrent without the files around it, not overwriting it"
+                    );
+                }
+                bail!(
+                    "{target:?} already exists and does not match the torrent, not overwriting it"
+                );
+            }
+        }
+    }
+
+    let mut placed = 0;
+    for (idx, relative_path) in relative_paths.iter().enumerate() {
+        let target = out_dir.join(relative_path);
+        let source = match &verifier.sources[idx] {
+            Some(s) if *s != target => s,
+            _ => continue,
+        };
+        place(source, &target, verified[idx], opts.copy_files)?;
+        info!(from=?source, to=?target, "cross-seeding existing file");
+        placed += 1;
+    }
+    Ok(CrossSeedResult {
+        placed,
+        verified: verifier.sources.iter().flatten().count(),
+        unverifiable: (0..relative_paths.len())
+            .filter(|idx| unverifiable[*idx] && verifier.sources[*idx].is_none())
+            .count(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+
+    use buffers::ByteString;
+    use librqbit_core::torrent_metainfo::{TorrentMetaV1Info, TorrentMetaV1Owned};
+
+    use crate::{create_torrent, CreateTorrentOptions, FilePriority};
+
+    use super::{place_existing_files, CrossSeedOptions};
+
+    // A torrent of "a.bin" (40000 bytes) and "b.bin" (30000 bytes) in 16384 byte pieces,
+    // so pieces 0 and 1 are within a.bin, and piece 2 spans both files.
+    async fn make_torrent(dir: &Path) -> (TorrentMetaV1Owned, Vec<PathBuf>) {
+        let src = dir.join("src");
+        std::fs::create_dir_all(&src).unwrap();
+        std::fs::write(src.join("a.bin"), vec![1u8; 40000]).unwrap();
+        std::fs::write(src.join("b.bin"), vec![2u8; 30000]).unwrap();
+        let torrent = create_torrent(
+            &src,
+            CreateTorrentOptions {
+                piece_length: Some(16384),
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap()
+        .as_info()
+        .clone();
+        let paths = torrent
+            .info
+            .iter_filenames_and_lengths()
+            .unwrap()
+            .map(|(name, _)| name.to_pathbuf().unwrap())
+            .collect();
+        (torrent, paths)
+    }
+
+    fn a_with_byte_changed(at: usize) -> Vec<u8> {
+        let mut data = vec![1u8; 40000];
+        data[at] = 0;
+        data
+    }
+
+    #[tokio::test]
+    async fn test_place_existing_files() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let (torrent, paths) = make_torrent(dir.path()).await;
+        let info: &TorrentMetaV1Info<ByteString> = &torrent.info;
+
+        // The same data under other names, plus a file of the same length that differs.
+        let other = dir.path().join("other");
+        std::fs::create_dir_all(other.join("sub")).unwrap();
+        std::fs::write(other.join("sub/renamed.bin"), vec![1u8; 40000]).unwrap();
+        std::fs::write(other.join("b.bin"), vec![3u8; 30000]).unwrap();
+        std::fs::write(other.join("x.dat"), vec![2u8; 30000]).unwrap();
+
+        let out_dir = dir.path().join("out");
+        let search_dirs = [other.clone()];
+        let opts = CrossSeedOptions {
+            search_dirs: &search_dirs,
+            ..Default::default()
+        };
+        let result = place_existing_files(info, &out_dir, &paths, &[], &opts).unwrap();
+        assert_eq!((result.placed, result.verified), (2, 2));
+        assert_eq!(
+            std::fs::read(out_dir.join("a.bin")).unwrap(),
+            vec![1u8; 40000]
+        );
+        assert_eq!(
+            std::fs::read(out_dir.join("b.bin")).unwrap(),
+            vec![2u8; 30000]
+        );
+
+        // Fully verified files are hard-linked.
+        std::fs::write(out_dir.join("b.bin"), vec![4u8; 30000]).unwrap();
+        assert_eq!(
+            std::fs::read(other.join("x.dat")).unwrap(),
+            vec![4u8; 30000]
+        );
+
+        // Existing files that don't match are not overwritten.
+        let no_search = CrossSeedOptions::default();
+        assert!(place_existing_files(info, &out_dir, &paths, &[], &no_search).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_copy_existing_files() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let (torrent, paths) = make_torrent(dir.path()).await;
+        let info: &TorrentMetaV1Info<ByteString> = &torrent.info;
+        let other = dir.path().join("other");
+        std::fs::create_dir_all(&other).unwrap();
+        std::fs::write(other.join("a.bin"), vec![1u8; 40000]).unwrap();
+        std::fs::write(other.join("b.bin"), vec![2u8; 30000]).unwrap();
+
+        let out_dir = dir.path().join("out");
+        let search_dirs = [other.clone()];
+        let opts = CrossSeedOptions {
+            search_dirs: &search_dirs,
+            copy_files: true,
+            ..Default::default()
+        };
+        let result = place_existing_files(info, &out_dir, &paths, &[], &opts).unwrap();
+        assert_eq!((result.placed, result.verified), (2, 2));
+
+        // Writing to the copies leaves the originals alone.
+        std::fs::write(out_dir.join("a.bin"), vec![0u8; 40000]).unwrap();
+        assert_eq!(
+            std::fs::read(other.join("a.bin")).unwrap(),
+            vec![1u8; 40000]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_partly_mismatching_file() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let (torrent, paths) = make_torrent(dir.path()).await;
+        let info: &TorrentMetaV1Info<ByteString> = &torrent.info;
+        let out_dir = dir.path().join("out");
+        let other = dir.path().join("other");
+        std::fs::create_dir_all(&other).unwrap();
+        let search_dirs = [other.clone()];
+        let opts = CrossSeedOptions {
+            search_dirs: &search_dirs,
+            ..Default::default()
+        };
+
+        // A difference within piece 1 is found.
+        std::fs::write(other.join("a.bin"), a_with_byte_changed(20000)).unwrap();
+        let result = place_existing_files(info, &out_dir, &paths, &[], &opts).unwrap();
+        assert_eq!((result.placed, result.verified), (0, 0));
+        assert!(!out_dir.join("a.bin").exists());
+
+        // Piece 2 can't be checked without b.bin, so a difference there isn't. The file is
+        // copied rather than linked, and the download fixing it up doesn't touch the original.
+        std::fs::write(other.join("a.bin"), a_with_byte_changed(39000)).unwrap();
+        let result = place_existing_files(info, &out_dir, &paths, &[], &opts).unwrap();
+        assert_eq!((result.placed, result.verified), (1, 1));
+        std::fs::write(out_dir.join("a.bin"), vec![1u8; 40000]).unwrap();
+        assert_eq!(
+            std::fs::read(other.join("a.bin")).unwrap(),
+            a_with_byte_changed(39000)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_unverifiable_file() {
+        // "b.bin" lies within piece 1, which also spans "a.bin" and "c.bin".
+        let dir = tempfile::TempDir::new().unwrap();
+        let src = dir.path().join("src");
+        std::fs::create_dir_all(&src).unwrap();
+        std::fs::write(src.join("a.bin"), vec![1u8; 20000]).unwrap();
+        std::fs::write(src.join("b.bin"), vec![2u8; 1000]).unwrap();
+        std::fs::write(src.join("c.bin"), vec![3u8; 20000]).unwrap();
+        let torrent = create_torrent(
+            &src,
+            CreateTorrentOptions {
+                piece_length: Some(16384),
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap()
+        .as_info()
+        .clone();
+        let paths = torrent
+            .info
+            .iter_filenames_and_lengths()
+            .unwrap()
+            .map(|(name, _)| name.to_pathbuf().unwrap())
+            .collect::<Vec<_>>();
+        let other = dir.path().join("other");
+        std::fs::create_dir_all(&other).unwrap();
+        std::fs::write(other.join("b.bin"), vec![2u8; 1000]).unwrap();
+
+        let out_dir = dir.path().join("out");
+        let search_dirs = [other.clone()];
+        let opts = CrossSeedOptions {
+            search_dirs: &search_dirs,
+            ..Default::default()
+        };
+        let result = place_existing_files(&torrent.info, &out_dir, &paths, &[], &opts).unwrap();
+        assert_eq!(
+            (result.placed, result.verified, result.unverifiable),
+            (0, 0, 1)
+        );
+
+        // With the files around it, it is checked.
+        std::fs::write(other.join("a.bin"), vec![1u8; 20000]).unwrap();
+        std::fs::write(other.join("c.bin"), vec![3u8; 20000]).unwrap();
+        let result = place_existing_files(&torrent.info, &out_dir, &paths, &[], &opts).unwrap();
+        assert_eq!(
+            (result.placed, result.verified, result.unverifiable),
+            (3, 3, 0)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_existing_target_file() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let (torrent, paths) = make_torrent(dir.path()).await;
+        let info: &TorrentMetaV1Info<ByteString> = &torrent.info;
+        let out_dir = dir.path().join("out");
+        let other = dir.path().join("other");
+        std::fs::create_dir_all(&other).unwrap();
+        std::fs::write(other.join("a.bin"), vec![1u8; 40000]).unwrap();
+
+        // A matching file at its place is used as is.
+        std::fs::create_dir_all(&out_dir).unwrap();
+        std::fs::write(out_dir.join("a.bin"), vec![1u8; 40000]).unwrap();
+        let only_a = [FilePriority::Normal, FilePriority::Skip];
+        let search_dirs = [other.clone()];
+        let opts = CrossSeedOptions {
+            search_dirs: &search_dirs,
+            ..Default::default()
+        };
+        let result = place_existing_files(info, &out_dir, &paths, &only_a, &opts).unwrap();
+        assert_eq!((result.placed, result.verified), (0, 1));
+
+        // Files that are not selected are not checked, so they are not overwritten either.
+        std::fs::write(out_dir.join("b.bin"), b"something else").unwrap();
+        assert!(place_existing_files(info, &out_dir, &paths, &only_a, &opts).is_err());
+        let overwrite = CrossSeedOptions {
+            overwrite: true,
+            ..opts
+        };
+        assert!(place_existing_files(info, &out_dir, &paths, &only_a, &overwrite).is_ok());
+        assert_eq!(
+            std::fs::read(out_dir.join("b.bin")).unwrap(),
+            b"something else"
+        );
+    }
+}
@@ -0,0 +1,337 @@
+// A session-wide cache for piece I/O.
+//
+// Received chunks are buffered per piece and written out in one go after the piece
+// passes the hash check. Pieces read for uploads are kept around, as peers usually
+// request all chunks of a piece one after another.
+//
+// Write buffers and read cache share one memory budget. Write buffers take precedence:
+// cached reads are evicted to make room for them. If there is no room even then,
+// chunks are written straight to storage.
+
+use std::{
+    collections::HashMap,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use bytes::Bytes;
+use librqbit_core::{
+    hash_id::Id20,
+    lengths::{ChunkInfo, Lengths, ValidPieceIndex},
+};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+
+pub const DEFAULT_DISK_CACHE_SIZE: usize = 32 * 1024 * 1024;
+
+type Key = (Id20, u32);
+
+fn key(info_hash: Id20, piece: ValidPieceIndex) -> Key {
+    (info_hash, piece.get())
+}
+
+pub(crate) struct WriteBuffer {
+    // Received chunks with their offsets in the piece, indexed by chunk.
+    pub chunks: Vec<Option<(usize, Vec<u8>)>>,
+}
+
+impl WriteBuffer {
+    pub fn is_complete(&self) -> bool {
+        self.chunks.iter().all(|c| c.is_some())
+    }
+
+    pub fn chunk(&self, chunk_index: usize) -> Option<&[u8]> {
+        self.chunks
+            .get(chunk_index)?
+            .as_ref()
+            .map(|(_, data)| data.as_slice())
+    }
+
+    fn len(&self) -> usize {
+        self.chunks
+            .iter()
+            .flatten()
+            .map(|(_, data)| data.len())
+            .sum()
+    }
+
+    // Contiguous received chunks joined together, to write them out with as few calls as possible.
+    pub fn into_ranges(self) -> Vec<(usize, Vec<u8>)> {
+        let mut result: Vec<(usize, Vec<u8>)> = Vec::new();
+        for (start, data) in self.chunks.into_iter().flatten() {
+            match result.last_mut() {
+                Some((last_start, last)) if *last_start + last.len() == start => {
+                    last.extend_from_slice(&data)
+                }
+                _ => result.push((start, data)),
+            }
+        }
+        result
+    }
+}
+
+struct ReadEntry {
+    data: Bytes,
+    last_used: u64,
+}
+
+#[derive(Default)]
+struct DiskCacheLocked {
+    write_buffers: HashMap<Key, WriteBuffer>,
+    read_cache: HashMap<Key, ReadEntry>,
+    write_bytes: usize,
+    read_bytes: usize,
+    tick: u64,
+}
+
+impl DiskCacheLocked {
+    // Evict least recently used reads until "needed" more bytes fit into capacity.
+    fn make_room(&mut self, capacity: usize, needed: usize) -> bool {
+        while self.write_bytes + self.read_bytes + needed > capacity {
+            let lru = self
+                .read_cache
+                .iter()
+                .min_by_key(|(_, e)| e.last_used)
+                .map(|(k, _)| *k);
+            match lru.and_then(|k| self.read_cache.remove(&k)) {
+                Some(e) => self.read_bytes -= e.data.len(),
+                None => return false,
+            }
+        }
+        true
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize)]
+pub struct DiskCacheStats {
+    pub capacity_bytes: u64,
+    pub write_buffered_bytes: u64,
+    pub read_cached_bytes: u64,
+    pub read_hits: u64,
+    pub read_misses: u64,
+    pub buffered_chunks: u64,
+    pub write_through_chunks: u64,
+    pub flushed_pieces: u64,
+}
+
+pub struct DiskCache {
+    capacity: usize,
+    locked: Mutex<DiskCacheLocked>,
+    read_hits: AtomicU64,
+    read_misses: AtomicU64,
+    buffered_chunks: AtomicU64,
+    write_through_chunks: AtomicU64,
+    flushed_pieces: AtomicU64,
+}
+
+impl DiskCache {
+    /// Create a cache with the given memory budget in bytes. 0 disables caching.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            locked: Default::default(),
+            read_hits: Default::default(),
+            read_misses: Default::default(),
+            buffered_chunks: Default::default(),
+            write_through_chunks: Default::default(),
+            flushed_pieces: Default::default(),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.capacity > 0
+    }
+
+    /// Put the chunk into the piece's write buffer. Returns false if there is no room
+    /// for it, then the caller needs to write the chunk to storage itself.
+    ///
+    /// The chunk is copied by the caller, so that this is cheap enough to be called
+    /// under the torrent state lock.
+    pub(crate) fn buffer_chunk(
+        &self,
+        info_hash: Id20,
+        lengths: &Lengths,
+        chunk_info: &ChunkInfo,
+        data: Vec<u8>,
+    ) -> bool {
+        let piece_len = lengths.piece_length(chunk_info.piece_index) as usize;
+        let chunks_per_piece = lengths.chunks_per_piece(chunk_info.piece_index) as usize;
+        let chunk_index = chunk_info.chunk_index as usize;
+        let offset_in_piece = chunk_info.offset as usize;
+        if offset_in_piece + data.len() > piece_len || chunk_index >= chunks_per_piece {
+            return false;
+        }
+        let mut g = self.locked.lock();
+        let g = &mut *g;
+        if !g.make_room(self.capacity, data.len()) {
+            self.write_through_chunks.fetch_add(1, Ordering::Relaxed);
+            return false;
+        }
+        g.write_bytes += data.len();
+        let buf = g
+            .write_buffers
+            .entry(key(info_hash, chunk_info.piece_index))
+            .or_insert_with(|| WriteBuffer {
+                chunks: vec![None; chunks_per_piece],
+            });
+        if let Some((_, prev)) = buf.chunks[chunk_index].replace((offset_in_piece, data)) {
+            g.write_bytes -= prev.len();
+        }
+        self.buffered_chunks.fetch_add(1, Ordering::Relaxed);
+        true
+    }
+
+    pub(crate) fn take_write_buffer(
+        &self,
+        info_hash: Id20,
+        piece: ValidPieceIndex,
+    ) -> Option<WriteBuffer> {
+        let mut g = self.locked.lock();
+        let buf = g.write_buffers.remove(&key(info_hash, piece))?;
+        g.write_bytes -= buf.len();
+        Some(buf)
+    }
+
+    pub(crate) fn mark_flushed(&self) {
+        self.flushed_pieces.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn get_piece(&self, info_hash: Id20, piece: ValidPieceIndex) -> Option<Bytes> {
+        let mut g = self.locked.lock();
+        g.tick += 1;
+        let tick = g.tick;
+        match g.read_cache.get_mut(&key(info_hash, piece)) {
+            Some(e) => {
+                e.last_used = tick;
+                self.read_hits.fetch_add(1, Ordering::Relaxed);
+                Some(e.data.clone())
+            }
+            None => {
+                self.read_misses.fetch_add(1, Ordering::Relaxed);
+                None
+            }
+        }
+    }
+
+    pub(crate) fn insert_piece(&self, info_hash: Id20, piece: ValidPieceIndex, data: Bytes) {
+        let mut g = self.locked.lock();
+        if !g.make_room(self.capacity, data.len()) {
+            return;
+        }
+        g.tick += 1;
+        let tick = g.tick;
+        g.read_bytes += data.len();
+        if let Some(prev) = g.read_cache.insert(
+            key(info_hash, piece),
+            ReadEntry {
+                data,
+                last_used: tick,
+            },
+        ) {
+            g.read_bytes -= prev.data.len();
+        }
+    }
+
+    /// Drop the write buffers of the torrent's pieces for which "should_drop" returns true,
+    /// e.g. when their chunks are going to be downloaded again or not at all.
+    pub(crate) fn forget_write_buffers(
+        &self,
+        info_hash: Id20,
+        mut should_drop: impl FnMut(u32) -> bool,
+    ) {
+        let mut g = self.locked.lock();
+        let g = &mut *g;
+        g.write_buffers.retain(|(ih, piece), buf| {
+            let keep = *ih != info_hash || !should_drop(*piece);
+            if !keep {
+                g.write_bytes -= buf.len();
+            }
+            keep
+        });
+    }
+
+    /// Drop everything cached for the torrent, e.g. when it's paused or deleted.
+    pub(crate) fn forget_torrent(&self, info_hash: Id20) {
+        let mut g = self.locked.lock();
+        let g = &mut *g;
+        g.write_buffers.retain(|(ih, _), buf| {
+            let keep = *ih != info_hash;
+            if !keep {
+                g.write_bytes -= buf.len();
+            }
+            keep
+        });
+        g.read_cache.retain(|(ih, _), e| {
+            let keep = *ih != info_hash;
+            if !keep {
+                g.read_bytes -= e.data.len();
+            }
+            keep
+        });
+    }
+
+    pub fn stats(&self) -> DiskCacheStats {
+        let (write_buffered_bytes, read_cached_bytes) = {
+            let g = self.locked.lock();
+            (g.write_bytes as u64, g.read_bytes as u64)
+        };
+        DiskCacheStats {
+            capacity_bytes: self.capacity as u64,
+            write_buffered_bytes,
+            read_cached_bytes,
+            read_hits: self.read_hits.load(Ordering::Relaxed),
+            read_misses: self.read_misses.load(Ordering::Relaxed),
+            buffered_chunks: self.buffered_chunks.load(Ordering::Relaxed),
+            write_through_chunks: self.write_through_chunks.load(Ordering::Relaxed),
+            flushed_pieces: self.flushed_pieces.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use librqbit_core::{hash_id::Id20, lengths::Lengths};
+
+    use super::DiskCache;
+
+    #[test]
+    fn test_disk_cache_budget() {
+        // 3 pieces of 10 bytes, 2 chunks each.
+        let lengths = Lengths::new(30, 10, Some(8)).unwrap();
+        let p = |i| lengths.validate_piece_index(i).unwrap();
+        let chunk = |i, c| lengths.iter_chunk_infos(p(i)).nth(c).unwrap();
+        let ih = Id20::new([1; 20]);
+        let cache = DiskCache::new(20);
+
+        cache.insert_piece(ih, p(0), Bytes::from_static(&[0; 10]));
+        cache.insert_piece(ih, p(1), Bytes::from_static(&[1; 10]));
+        assert!(cache.get_piece(ih, p(0)).is_some());
+
+        // The least recently used piece 1 gets evicted to make room for writes.
+        assert!(cache.buffer_chunk(ih, &lengths, &chunk(2, 1), vec![2; 2]));
+        assert!(cache.get_piece(ih, p(1)).is_none());
+        assert!(cache.get_piece(ih, p(0)).is_some());
+
+        // No room for more writes once the reads are gone, as only reads get evicted.
+        assert!(cache.buffer_chunk(ih, &lengths, &chunk(1, 0), vec![1; 8]));
+        assert!(cache.buffer_chunk(ih, &lengths, &chunk(0, 0), vec![0; 8]));
+        assert!(cache.get_piece(ih, p(0)).is_none());
+        assert!(!cache.buffer_chunk(ih, &lengths, &chunk(2, 0), vec![2; 8]));
+
+        let buf = cache.take_write_buffer(ih, p(2)).unwrap();
+        assert!(!buf.is_complete());
+        assert_eq!(buf.into_ranges(), vec![(8, vec![2; 2])]);
+
+        let stats = cache.stats();
+        assert_eq!(stats.write_buffered_bytes, 16);
+        assert_eq!(stats.read_hits, 2);
+        assert_eq!(stats.read_misses, 2);
+        assert_eq!(stats.write_through_chunks, 1);
+
+        cache.forget_write_buffers(ih, |piece| piece == 1);
+        assert_eq!(cache.stats().write_buffered_bytes, 8);
+
+        cache.forget_torrent(ih);
+        assert_eq!(cache.stats().write_buffered_bytes, 0);
+    }
+}
@@ -0,0 +1,144 @@
+// Events that happen in a session, for library users (Session::subscribe()) and the
+// HTTP API (GET /events).
+
+use std::{net::SocketAddr, sync::Arc};
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::{
+    broadcast,
+    mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+};
+use tracing::debug;
+
+use crate::{
+    hooks::{Hook, Hooks, TorrentEvent},
+    session::TorrentId,
+    torrent_state::TorrentStatsState,
+};
+
+/// How many events a slow subscriber can fall behind before it starts missing them.
+pub const SESSION_EVENTS_CAPACITY: usize = 4096;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum SessionEvent {
+    /// A torrent was added, had its metadata resolved, completed, errored, was paused or
+    /// removed. These are the events hooks run on.
+    Torrent(TorrentEvent),
+    StateChanged {
+        id: Option<TorrentId>,
+        info_hash: String,
+        state: TorrentStatsState,
+    },
+    PieceCompleted {
+        id: Option<TorrentId>,
+        info_hash: String,
+        piece: u32,
+    },
+    PeerConnected {
+        id: Option<TorrentId>,
+        info_hash: String,
+        addr: SocketAddr,
+    },
+    TrackerError {
+        id: Option<TorrentId>,
+        info_hash: String,
+        tracker: String,
+        error: String,
+    },
+}
+
+impl SessionEvent {
+    /// The name of the event, the same as its "type" when serialized.
+    pub fn name(&self) -> &'static str {
+        match self {
+            SessionEvent::Torrent(_) => "torrent",
+            SessionEvent::StateChanged { .. } => "state_changed",
+            SessionEvent::PieceCompleted { .. } => "piece_completed",
+            SessionEvent::PeerConnected { .. } => "peer_connected",
+            SessionEvent::TrackerError { .. } => "tracker_error",
+        }
+    }
+
+    pub fn torrent_id(&self) -> Option<TorrentId> {
+        match self {
+            SessionEvent::Torrent(e) => e.id,
+            SessionEvent::StateChanged { id, .. }
+            | SessionEvent::PieceCompleted { id, .. }
+            | SessionEvent::PeerConnected { id, .. }
+            | SessionEvent::TrackerError { id, .. } => *id,
+        }
+    }
+
+    pub fn info_hash(&self) -> &str {
+        match self {
+            SessionEvent::Torrent(e) => &e.info_hash,
+            SessionEvent::StateChanged { info_hash, .. }
+            | SessionEvent::PieceCompleted { info_hash, .. }
+            | SessionEvent::PeerConnected { info_hash, .. }
+            | SessionEvent::TrackerError { info_hash, .. } => info_hash,
+        }
+    }
+}
+
+/// Where the session and its torrents send events to. They are passed on to the hooks
+/// and subscribers by a task, in the order they were sent.
+pub(crate) struct SessionEvents {
+    tx: UnboundedSender<SessionEvent>,
+    subscribers: broadcast::Sender<SessionEvent>,
+    hooks: Arc<Hooks>,
+}
+
+impl SessionEvents {
+    pub fn new(hooks: Vec<Hook>) -> (Self, UnboundedReceiver<SessionEvent>) {
+        let (tx, rx) = unbounded_channel();
+        let events = Self {
+            tx,
+            subscribers: broadcast::channel(SESSION_EVENTS_CAPACITY).0,
+            hooks: Arc::new(Hooks::new(hooks)),
+        };
+        (events, rx)
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<SessionEvent> {
+        self.subscribers.subscribe()
+    }
+
+    pub fn set_hooks(&self, hooks: Vec<Hook>) {
+        self.hooks.set(hooks);
+    }
+
+    /// Send the event made by "make", which is only called if there are subscribers.
+    pub fn send(&self, make: impl FnOnce() -> SessionEvent) {
+        if self.subscribers.receiver_count() > 0 {
+            let _ = self.tx.send(make());
+        }
+    }
+
+    /// Like send(), but hooks run on torrent events too.
+    pub fn send_torrent_event(&self, make: impl FnOnce() -> TorrentEvent) {
+        if self.subscribers.receiver_count() > 0 || !self.hooks.is_empty() {
+            let _ = self.tx.send(SessionEvent::Torrent(make()));
+        }
+    }
+
+    /// The task running the hooks and sending the events to subscribers.
+    pub fn task(
+        &self,
+        mut rx: UnboundedReceiver<SessionEvent>,
+    ) -> impl std::future::Future<Output = anyhow::Result<()>> + Send + 'static {
+        let subscribers = self.subscribers.clone();
+        let hooks = self.hooks.clone();
+        async move {
+            while let Some(event) = rx.recv().await {
+                if let SessionEvent::Torrent(event) = &event {
+                    debug!(event = %event.event, info_hash = event.info_hash, id = event.id, "torrent event");
+                    hooks.fire(event);
+                }
+                // Nobody might be subscribed anymore, that's ok.
+                let _ = subscribers.send(event);
+            }
+            Ok(())
+        }
+    }
+}
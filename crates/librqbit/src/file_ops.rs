@@ -1,25 +1,27 @@
 use std::{
-    fs::File,
-    io::{Read, Seek, SeekFrom, Write},
     marker::PhantomData,
-    sync::{
-        atomic::{AtomicU64, Ordering},
-        Arc,
-    },
+    sync::atomic::{AtomicU64, Ordering},
 };
 
 use anyhow::Context;
 use buffers::ByteString;
+use bytes::Bytes;
 use librqbit_core::{
+    hash_id::Id20,
     lengths::{ChunkInfo, Lengths, ValidPieceIndex},
     torrent_metainfo::{FileIteratorName, TorrentMetaV1Info},
 };
-use parking_lot::Mutex;
 use peer_binary_protocol::Piece;
 use sha1w::ISha1;
 use tracing::{debug, trace, warn};
 
-use crate::type_aliases::{PeerHandle, BF};
+use crate::{
+    disk_cache::{DiskCache, WriteBuffer},
+    file_priority::FilePriority,
+    metrics::{LatencyHistogram, SessionMetrics},
+    storage::TorrentStorage,
+    type_aliases::{PeerHandle, BF},
+};
 
 pub(crate) struct InitialCheckResults {
     // The pieces that we need to download.
@@ -27,7 +29,7 @@ pub(crate) struct InitialCheckResults {
     // The pieces we have downloaded.
     pub have_pieces: BF,
     // How many bytes we have. This can be MORE than "total_selected_bytes",
-    // if we downloaded some pieces, and later the "only_files" was changed.
+    // if we downloaded some pieces, and later the file priorities were changed.
     pub have_bytes: u64,
     // How many bytes we need to download.
     pub needed_bytes: u64,
@@ -36,8 +38,10 @@ pub(crate) struct InitialCheckResults {
     pub total_selected_bytes: u64,
 }
 
-pub fn update_hash_from_file<Sha1: ISha1>(
-    file: &mut File,
+pub fn update_hash_from_storage<Sha1: ISha1>(
+    storage: &dyn TorrentStorage,
+    file_idx: usize,
+    offset: u64,
     hash: &mut Sha1,
     buf: &mut [u8],
     mut bytes_to_read: usize,
@@ -45,7 +49,8 @@ pub fn update_hash_from_file<Sha1: ISha1>(
     let mut read = 0;
     while bytes_to_read > 0 {
         let chunk = std::cmp::min(buf.len(), bytes_to_read);
-        file.read_exact(&mut buf[..chunk])
+        storage
+            .pread_exact(file_idx, offset + read as u64, &mut buf[..chunk])
             .with_context(|| format!("failed reading chunk of size {chunk}, read so far {read}"))?;
         bytes_to_read -= chunk;
         read += chunk;
@@ -56,28 +61,57 @@ pub fn update_hash_from_file<Sha1: ISha1>(
 
 pub(crate) struct FileOps<'a, Sha1> {
     torrent: &'a TorrentMetaV1Info<ByteString>,
-    files: &'a [Arc<Mutex<File>>],
+    storage: &'a dyn TorrentStorage,
     lengths: &'a Lengths,
+    cache: Option<(&'a DiskCache, Id20)>,
+    metrics: Option<&'a SessionMetrics>,
     phantom_data: PhantomData<Sha1>,
 }
 
 impl<'a, Sha1Impl: ISha1> FileOps<'a, Sha1Impl> {
     pub fn new(
         torrent: &'a TorrentMetaV1Info<ByteString>,
-        files: &'a [Arc<Mutex<File>>],
+        storage: &'a dyn TorrentStorage,
         lengths: &'a Lengths,
     ) -> Self {
         Self {
             torrent,
-            files,
+            storage,
             lengths,
+            cache: None,
+            metrics: None,
             phantom_data: PhantomData,
         }
     }
 
+    /// Go through the session's disk cache for reads and writes of live torrents.
+    pub fn with_cache(mut self, cache: &'a DiskCache, info_hash: Id20) -> Self {
+        if cache.is_enabled() {
+            self.cache = Some((cache, info_hash));
+        }
+        self
+    }
+
+    /// Record the latencies of storage reads and writes.
+    pub fn with_metrics(mut self, metrics: &'a SessionMetrics) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    fn timed<R>(
+        &self,
+        histogram: impl FnOnce(&SessionMetrics) -> &LatencyHistogram,
+        f: impl FnOnce() -> R,
+    ) -> R {
+        match self.metrics {
+            Some(m) => histogram(m).time(f),
+            None => f(),
+        }
+    }
+
     pub fn initial_check(
         &self,
-        only_files: Option<&[usize]>,
+        file_priorities: &[FilePriority],
         progress: &AtomicU64,
     ) -> anyhow::Result<InitialCheckResults> {
         let mut needed_pieces = BF::from_vec(vec![0u8; self.lengths.piece_bitfield_bytes()]);
@@ -90,7 +124,6 @@ impl<'a, Sha1Impl: ISha1> FileOps<'a, Sha1Impl> {
         #[derive(Debug)]
         struct CurrentFile<'a> {
             index: usize,
-            fd: &'a Arc<Mutex<File>>,
             len: u64,
             name: FileIteratorName<'a, ByteString>,
             full_file_required: bool,
@@ -105,27 +138,24 @@ impl<'a, Sha1Impl: ISha1> FileOps<'a, Sha1Impl> {
                 self.processed_bytes += bytes
             }
         }
-        let mut file_iterator = self
-            .files
-            .iter()
-            .zip(self.torrent.iter_filenames_and_lengths()?)
-            .enumerate()
-            .map(|(idx, (fd, (name, len)))| {
-                let full_file_required = if let Some(only_files) = only_files {
-                    only_files.contains(&idx)
-                } else {
-                    true
-                };
-                CurrentFile {
-                    index: idx,
-                    fd,
-                    len,
-                    name,
-                    full_file_required,
-                    processed_bytes: 0,
-                    is_broken: false,
-                }
-            });
+        let mut file_iterator =
+            self.torrent
+                .iter_filenames_and_lengths()?
+                .enumerate()
+                .map(|(idx, (name, len))| {
+                    let full_file_required = file_priorities
+                        .get(idx)
+                        .map(|p| !p.is_skip())
+                        .unwrap_or(true);
+                    CurrentFile {
+                        index: idx,
+                        len,
+                        name,
+                        full_file_required,
+                        processed_bytes: 0,
+                        is_broken: false,
+                    }
+                });
 
         let mut current_file = file_iterator
             .next()
@@ -165,12 +195,10 @@ impl<'a, Sha1Impl: ISha1> FileOps<'a, Sha1Impl> {
                     continue;
                 }
 
-                let mut fd = current_file.fd.lock();
-
-                fd.seek(SeekFrom::Start(pos))
-                    .context("bug? error seeking")?;
-                if let Err(err) = update_hash_from_file(
-                    &mut fd,
+                if let Err(err) = update_hash_from_storage(
+                    self.storage,
+                    current_file.index,
+                    pos,
                     &mut computed_hash,
                     &mut read_buffer,
                     to_read_in_file,
@@ -240,6 +268,12 @@ impl<'a, Sha1Impl: ISha1> FileOps<'a, Sha1Impl> {
         piece_index: ValidPieceIndex,
         last_received_chunk: &ChunkInfo,
     ) -> anyhow::Result<bool> {
+        if let Some((cache, info_hash)) = self.cache {
+            if let Some(buf) = cache.take_write_buffer(info_hash, piece_index) {
+                return self.check_and_flush_piece(who_sent, piece_index, buf);
+            }
+        }
+
         let mut h = Sha1Impl::new();
         let piece_length = self.lengths.piece_length(piece_index);
         let mut absolute_offset = self.lengths.piece_offset(piece_index);
@@ -256,7 +290,6 @@ impl<'a, Sha1Impl: ISha1> FileOps<'a, Sha1Impl> {
 
             let to_read_in_file =
                 std::cmp::min(file_remaining_len, piece_remaining_bytes as u64) as usize;
-            let mut file_g = self.files[file_idx].lock();
             trace!(
                 "piece={}, handle={}, file_idx={}, seeking to {}. Last received chunk: {:?}",
                 piece_index,
@@ -265,29 +298,81 @@ impl<'a, Sha1Impl: ISha1> FileOps<'a, Sha1Impl> {
                 absolute_offset,
                 &last_received_chunk
             );
-            file_g
-                .seek(SeekFrom::Start(absolute_offset))
-                .with_context(|| {
-                    format!("error seeking to {absolute_offset}, file id: {file_idx}")
-                })?;
-            update_hash_from_file(&mut file_g, &mut h, &mut buf, to_read_in_file).with_context(
-                || {
-                    format!(
-                        "error reading {to_read_in_file} bytes, file_id: {file_idx} (\"{name:?}\")"
-                    )
-                },
-            )?;
+            update_hash_from_storage(
+                self.storage,
+                file_idx,
+                absolute_offset,
+                &mut h,
+                &mut buf,
+                to_read_in_file,
+            )
+            .with_context(|| {
+                format!("error reading {to_read_in_file} bytes, file_id: {file_idx} (\"{name:?}\")")
+            })?;
 
             piece_remaining_bytes -= to_read_in_file;
 
             if piece_remaining_bytes == 0 {
-                return Ok(true);
+                break;
             }
 
             absolute_offset = 0;
         }
 
-        match self.torrent.compare_hash(piece_index.get(), h.finish()) {
+        self.compare_piece_hash(piece_index, h.finish())
+    }
+
+    // Hash the piece from its write buffer, and write it out if it's fine.
+    // Chunks that did not fit into the cache were written to storage already, these are read back.
+    fn check_and_flush_piece(
+        &self,
+        who_sent: PeerHandle,
+        piece_index: ValidPieceIndex,
+        buf: WriteBuffer,
+    ) -> anyhow::Result<bool> {
+        let mut h = Sha1Impl::new();
+        let mut read_buf = Vec::new();
+        for chunk_info in self.lengths.iter_chunk_infos(piece_index) {
+            match buf.chunk(chunk_info.chunk_index as usize) {
+                Some(data) => h.update(data),
+                _ => {
+                    read_buf.resize(chunk_info.size as usize, 0);
+                    self.read_at(
+                        who_sent,
+                        self.lengths.chunk_absolute_offset(&chunk_info),
+                        &mut read_buf,
+                    )?;
+                    h.update(&read_buf);
+                }
+            }
+        }
+
+        if !self.compare_piece_hash(piece_index, h.finish())? {
+            return Ok(false);
+        }
+
+        let piece_offset = self.lengths.piece_offset(piece_index);
+        let is_complete = buf.is_complete();
+        let mut ranges = buf.into_ranges();
+        for (start, data) in ranges.iter() {
+            self.write_at(who_sent, piece_offset + *start as u64, data)?;
+        }
+        if let Some((cache, info_hash)) = self.cache {
+            cache.mark_flushed();
+            // Freshly downloaded pieces are likely to be requested by other peers soon.
+            if let (true, Some((_, data))) = (is_complete, ranges.pop()) {
+                cache.insert_piece(info_hash, piece_index, Bytes::from(data));
+            }
+        }
+        Ok(true)
+    }
+
+    fn compare_piece_hash(
+        &self,
+        piece_index: ValidPieceIndex,
+        hash: [u8; 20],
+    ) -> anyhow::Result<bool> {
+        match self.torrent.compare_hash(piece_index.get(), hash) {
             Some(true) => {
                 trace!("piece={} hash matches", piece_index);
                 Ok(true)
@@ -306,16 +391,56 @@ impl<'a, Sha1Impl: ISha1> FileOps<'a, Sha1Impl> {
 
     pub fn read_chunk(
         &self,
-        who_sent: PeerHandle,
+        who_sent: impl std::fmt::Display,
         chunk_info: &ChunkInfo,
         result_buf: &mut [u8],
     ) -> anyhow::Result<()> {
         if result_buf.len() < chunk_info.size as usize {
             anyhow::bail!("read_chunk(): not enough capacity in the provided buffer")
         }
-        let mut absolute_offset = self.lengths.chunk_absolute_offset(chunk_info);
-        let mut buf = result_buf;
 
+        let (cache, info_hash) = match self.cache {
+            Some(c) => c,
+            None => {
+                return self.read_at(
+                    who_sent,
+                    self.lengths.chunk_absolute_offset(chunk_info),
+                    result_buf,
+                )
+            }
+        };
+
+        // Read the whole piece, as the peer will most likely ask for its other chunks next.
+        let piece = match cache.get_piece(info_hash, chunk_info.piece_index) {
+            Some(piece) => piece,
+            None => {
+                let mut piece =
+                    vec![0u8; self.lengths.piece_length(chunk_info.piece_index) as usize];
+                self.read_at(
+                    who_sent,
+                    self.lengths.piece_offset(chunk_info.piece_index),
+                    &mut piece,
+                )?;
+                let piece = Bytes::from(piece);
+                cache.insert_piece(info_hash, chunk_info.piece_index, piece.clone());
+                piece
+            }
+        };
+        let start = chunk_info.offset as usize;
+        let end = start + chunk_info.size as usize;
+        let data = piece
+            .get(start..end)
+            .with_context(|| format!("bug: chunk {chunk_info:?} out of piece bounds"))?;
+        result_buf[..data.len()].copy_from_slice(data);
+        Ok(())
+    }
+
+    fn read_at(
+        &self,
+        who_sent: impl std::fmt::Display,
+        mut absolute_offset: u64,
+        mut buf: &mut [u8],
+    ) -> anyhow::Result<()> {
         for (file_idx, file_len) in self.torrent.iter_file_lengths()?.enumerate() {
             if absolute_offset > file_len {
                 absolute_offset -= file_len;
@@ -324,25 +449,23 @@ impl<'a, Sha1Impl: ISha1> FileOps<'a, Sha1Impl> {
             let file_remaining_len = file_len - absolute_offset;
             let to_read_in_file = std::cmp::min(file_remaining_len, buf.len() as u64) as usize;
 
-            let mut file_g = self.files[file_idx].lock();
             trace!(
-                "piece={}, handle={}, file_idx={}, seeking to {}. To read chunk: {:?}",
-                chunk_info.piece_index,
+                "handle={}, file_idx={}, reading {} bytes at {}",
                 who_sent,
                 file_idx,
+                to_read_in_file,
                 absolute_offset,
-                &chunk_info
             );
-            file_g
-                .seek(SeekFrom::Start(absolute_offset))
-                .with_context(|| {
-                    format!("error seeking to {absolute_offset}, file id: {file_idx}")
-                })?;
-            file_g
-                .read_exact(&mut buf[..to_read_in_file])
-                .with_context(|| {
-                    format!("error reading {file_idx} bytes, file_id: {to_read_in_file}")
-                })?;
+            self.timed(
+                |m| &m.disk_reads,
+                || {
+                    self.storage
+                        .pread_exact(file_idx, absolute_offset, &mut buf[..to_read_in_file])
+                },
+            )
+            .with_context(|| {
+                format!("error reading {to_read_in_file} bytes, file_id: {file_idx}")
+            })?;
 
             buf = &mut buf[to_read_in_file..];
 
@@ -356,6 +479,43 @@ impl<'a, Sha1Impl: ISha1> FileOps<'a, Sha1Impl> {
         Ok(())
     }
 
+    /// Copy the chunk for buffer_chunk(), if the disk cache is on. Do this before taking
+    /// the state lock, so that the copy is not done under it.
+    pub fn copy_chunk_for_cache<ByteBuf>(&self, data: &Piece<ByteBuf>) -> Option<Vec<u8>>
+    where
+        ByteBuf: AsRef<[u8]>,
+    {
+        self.cache.map(|_| data.block.as_ref().to_vec())
+    }
+
+    /// Put the chunk copied by copy_chunk_for_cache() into the disk cache. Returns false if
+    /// it needs to be written with write_chunk() instead.
+    ///
+    /// Call this under the state lock, together with marking the chunk downloaded, so that
+    /// the chunk can't arrive after check_piece() took the piece's buffer.
+    pub fn buffer_chunk(
+        &self,
+        who_sent: PeerHandle,
+        data: Option<Vec<u8>>,
+        chunk_info: &ChunkInfo,
+    ) -> bool {
+        let (Some((cache, info_hash)), Some(data)) = (self.cache, data) else {
+            return false;
+        };
+        let len = data.len();
+        if !cache.buffer_chunk(info_hash, self.lengths, chunk_info, data) {
+            return false;
+        }
+        trace!(
+            "piece={}, chunk={:?}, handle={}, buffered {} bytes",
+            chunk_info.piece_index,
+            chunk_info,
+            who_sent,
+            len
+        );
+        true
+    }
+
     pub fn write_chunk<ByteBuf>(
         &self,
         who_sent: PeerHandle,
@@ -365,9 +525,20 @@ impl<'a, Sha1Impl: ISha1> FileOps<'a, Sha1Impl> {
     where
         ByteBuf: AsRef<[u8]>,
     {
-        let mut buf = data.block.as_ref();
-        let mut absolute_offset = self.lengths.chunk_absolute_offset(chunk_info);
+        let buf = data.block.as_ref();
+        self.write_at(
+            who_sent,
+            self.lengths.chunk_absolute_offset(chunk_info),
+            buf,
+        )
+    }
 
+    fn write_at(
+        &self,
+        who_sent: PeerHandle,
+        mut absolute_offset: u64,
+        mut buf: &[u8],
+    ) -> anyhow::Result<()> {
         for (file_idx, (name, file_len)) in self.torrent.iter_filenames_and_lengths()?.enumerate() {
             if absolute_offset > file_len {
                 absolute_offset -= file_len;
@@ -377,25 +548,23 @@ impl<'a, Sha1Impl: ISha1> FileOps<'a, Sha1Impl> {
             let remaining_len = file_len - absolute_offset;
             let to_write = std::cmp::min(buf.len(), remaining_len as usize);
 
-            let mut file_g = self.files[file_idx].lock();
             trace!(
-                "piece={}, chunk={:?}, handle={}, begin={}, file={}, writing {} bytes at {}",
-                chunk_info.piece_index,
-                chunk_info,
+                "handle={}, file={}, writing {} bytes at {}",
                 who_sent,
-                chunk_info.offset,
                 file_idx,
                 to_write,
                 absolute_offset
             );
-            file_g
-                .seek(SeekFrom::Start(absolute_offset))
-                .with_context(|| {
-                    format!("error seeking to {absolute_offset} in file {file_idx} (\"{name:?}\")")
-                })?;
-            file_g
-                .write_all(&buf[..to_write])
-                .with_context(|| format!("error writing to file {file_idx} (\"{name:?}\")"))?;
+            self.timed(
+                |m| &m.disk_writes,
+                || {
+                    self.storage
+                        .pwrite_all(file_idx, absolute_offset, &buf[..to_write])
+                },
+            )
+            .with_context(|| {
+                format!("error writing to file {file_idx} (\"{name:?}\") at {absolute_offset}")
+            })?;
             buf = &buf[to_write..];
             if buf.is_empty() {
                 break;
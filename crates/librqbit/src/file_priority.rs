@@ -0,0 +1,239 @@
+use librqbit_core::lengths::{Lengths, ValidPieceIndex};
+use serde::{Deserialize, Serialize};
+
+/// Download priority of a single file within a torrent.
+///
+/// The ordering is meaningful: a piece that spans several files gets the highest
+/// priority among them, so a "skip" file sharing a piece with a wanted file still
+/// gets that piece downloaded.
+#[derive(
+    Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum FilePriority {
+    /// Don't download the file.
+    Skip,
+    Low,
+    #[default]
+    Normal,
+    High,
+}
+
+impl std::fmt::Display for FilePriority {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            FilePriority::Skip => "skip",
+            FilePriority::Low => "low",
+            FilePriority::Normal => "normal",
+            FilePriority::High => "high",
+        })
+    }
+}
+
+impl FilePriority {
+    pub fn is_skip(&self) -> bool {
+        matches!(self, FilePriority::Skip)
+    }
+
+    /// Build per-file priorities from the legacy "only_files" list.
+    pub fn from_only_files(total_files: usize, only_files: Option<&[usize]>) -> Vec<Self> {
+        (0..total_files)
+            .map(|idx| match only_files {
+                Some(only_files) if !only_files.contains(&idx) => FilePriority::Skip,
+                _ => FilePriority::Normal,
+            })
+            .collect()
+    }
+
+    /// The reverse of from_only_files(). Returns None if no files are skipped.
+    pub fn to_only_files(priorities: &[Self]) -> Option<Vec<usize>> {
+        if priorities.iter().all(|p| !p.is_skip()) {
+            return None;
+        }
+        Some(
+            priorities
+                .iter()
+                .enumerate()
+                .filter(|(_, p)| !p.is_skip())
+                .map(|(idx, _)| idx)
+                .collect(),
+        )
+    }
+}
+
+/// Compute the priority of each piece given the per-file priorities.
+/// A piece gets the maximum priority of all the files it overlaps.
+pub(crate) fn compute_piece_priorities(
+    lengths: &Lengths,
+    file_lengths: impl IntoIterator<Item = u64>,
+    file_priorities: &[FilePriority],
+) -> Vec<FilePriority> {
+    let mut result = vec![FilePriority::Skip; lengths.total_pieces() as usize];
+    let piece_length = lengths.default_piece_length() as u64;
+    let mut offset = 0u64;
+    for (file_len, prio) in file_lengths
+        .into_iter()
+        .zip(file_priorities.iter().copied())
+    {
+        let start = offset;
+        offset += file_len;
+        if file_len == 0 {
+            continue;
+        }
+        let first_piece = (start / piece_length) as usize;
+        let last_piece = ((offset - 1) / piece_length) as usize;
+        for p in result.iter_mut().take(last_piece + 1).skip(first_piece) {
+            *p = (*p).max(prio);
+        }
+    }
+    result
+}
+
+/// Which files need to be present on disk to download all selected pieces.
+/// This includes files that are skipped but share a piece with a selected file.
+pub(crate) fn compute_files_needed_on_disk(
+    lengths: &Lengths,
+    file_lengths: impl IntoIterator<Item = u64>,
+    piece_priorities: &[FilePriority],
+) -> Vec<bool> {
+    let piece_length = lengths.default_piece_length() as u64;
+    let mut offset = 0u64;
+    file_lengths
+        .into_iter()
+        .map(|file_len| {
+            let start = offset;
+            offset += file_len;
+            if file_len == 0 {
+                return true;
+            }
+            let first_piece = (start / piece_length) as usize;
+            let last_piece = ((offset - 1) / piece_length) as usize;
+            piece_priorities
+                .iter()
+                .take(last_piece + 1)
+                .skip(first_piece)
+                .any(|p| !p.is_skip())
+        })
+        .collect()
+}
+
+/// Indices of the files all pieces of which are downloaded.
+pub(crate) fn compute_completed_files(
+    lengths: &Lengths,
+    file_lengths: impl IntoIterator<Item = u64>,
+    is_have: impl Fn(usize) -> bool,
+) -> Vec<usize> {
+    let piece_length = lengths.default_piece_length() as u64;
+    let mut offset = 0u64;
+    file_lengths
+        .into_iter()
+        .enumerate()
+        .filter_map(|(idx, file_len)| {
+            let start = offset;
+            offset += file_len;
+            if file_len == 0 {
+                return Some(idx);
+            }
+            let first_piece = (start / piece_length) as usize;
+            let last_piece = ((offset - 1) / piece_length) as usize;
+            (first_piece..=last_piece).all(&is_have).then_some(idx)
+        })
+        .collect()
+}
+
+/// The first and last pieces of each selected file, in file order.
+/// Used to prioritize them in sequential mode.
+pub(crate) fn compute_file_edge_pieces(
+    lengths: &Lengths,
+    file_lengths: impl IntoIterator<Item = u64>,
+    file_priorities: &[FilePriority],
+) -> Vec<usize> {
+    let piece_length = lengths.default_piece_length() as u64;
+    let mut offset = 0u64;
+    let mut result: Vec<usize> = Vec::new();
+    for (file_len, prio) in file_lengths
+        .into_iter()
+        .zip(file_priorities.iter().copied())
+    {
+        let start = offset;
+        offset += file_len;
+        if file_len == 0 || prio.is_skip() {
+            continue;
+        }
+        for piece in [start / piece_length, (offset - 1) / piece_length] {
+            let piece = piece as usize;
+            if !result.contains(&piece) {
+                result.push(piece);
+            }
+        }
+    }
+    result
+}
+
+pub(crate) fn piece_priority(priorities: &[FilePriority], index: ValidPieceIndex) -> FilePriority {
+    priorities
+        .get(index.get() as usize)
+        .copied()
+        .unwrap_or(FilePriority::Skip)
+}
+
+#[cfg(test)]
+mod tests {
+    use librqbit_core::lengths::Lengths;
+
+    use super::{
+        compute_completed_files, compute_file_edge_pieces, compute_files_needed_on_disk,
+        compute_piece_priorities, FilePriority as P,
+    };
+
+    #[test]
+    fn test_piece_priorities_spanning_files() {
+        // 4 pieces of 16384 bytes, files: [20000, 20000, 25536]
+        let lengths = Lengths::new(65536, 16384, None).unwrap();
+        let files = [20000u64, 20000, 25536];
+        let prios = compute_piece_priorities(&lengths, files, &[P::Skip, P::High, P::Low]);
+        assert_eq!(prios, vec![P::Skip, P::High, P::High, P::Low]);
+
+        let on_disk = compute_files_needed_on_disk(&lengths, files, &prios);
+        assert_eq!(on_disk, vec![true, true, true]);
+
+        let prios = compute_piece_priorities(&lengths, files, &[P::Skip, P::Skip, P::Normal]);
+        assert_eq!(prios, vec![P::Skip, P::Skip, P::Normal, P::Normal]);
+        let on_disk = compute_files_needed_on_disk(&lengths, files, &prios);
+        assert_eq!(on_disk, vec![false, true, true]);
+    }
+
+    #[test]
+    fn test_file_edge_pieces() {
+        let lengths = Lengths::new(65536, 16384, None).unwrap();
+        let files = [20000u64, 20000, 25536];
+        assert_eq!(
+            compute_file_edge_pieces(&lengths, files, &[P::Normal, P::Skip, P::Low]),
+            vec![0, 1, 2, 3]
+        );
+        assert_eq!(
+            compute_file_edge_pieces(&lengths, files, &[P::Skip, P::High, P::Skip]),
+            vec![1, 2]
+        );
+    }
+
+    #[test]
+    fn test_completed_files() {
+        let lengths = Lengths::new(65536, 16384, None).unwrap();
+        let files = [20000u64, 0, 20000, 25536];
+        // Piece 1 is shared by the first and the third file.
+        let have = [true, false, true, true];
+        assert_eq!(
+            compute_completed_files(&lengths, files, |p| have[p]),
+            vec![1, 3]
+        );
+    }
+
+    #[test]
+    fn test_only_files_roundtrip() {
+        let prios = P::from_only_files(3, Some(&[0, 2]));
+        assert_eq!(prios, vec![P::Normal, P::Skip, P::Normal]);
+        assert_eq!(P::to_only_files(&prios), Some(vec![0, 2]));
+        assert_eq!(P::to_only_files(&P::from_only_files(3, None)), None);
+    }
+}
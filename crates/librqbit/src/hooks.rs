@@ -0,0 +1,313 @@
+// Hooks: running commands or posting webhooks when something happens to a torrent,
+// e.g. when it finishes downloading.
+
+use std::{path::PathBuf, process::Stdio, time::Duration};
+
+use anyhow::{bail, Context};
+use buffers::ByteString;
+use librqbit_core::{hash_id::Id20, torrent_metainfo::TorrentMetaV1Info};
+use parking_lot::RwLock;
+use serde::{Deserialize, Serialize};
+use serde_with::serde_as;
+use tracing::{debug, error_span, info, warn};
+
+use crate::{session::TorrentId, spawn_utils::spawn};
+
+pub const DEFAULT_HOOK_TIMEOUT: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TorrentEventKind {
+    Added,
+    /// The metadata of a magnet link was downloaded.
+    MetadataResolved,
+    Completed,
+    Errored,
+    Paused,
+    Removed,
+}
+
+impl TorrentEventKind {
+    const ALL: [TorrentEventKind; 6] = [
+        TorrentEventKind::Added,
+        TorrentEventKind::MetadataResolved,
+        TorrentEventKind::Completed,
+        TorrentEventKind::Errored,
+        TorrentEventKind::Paused,
+        TorrentEventKind::Removed,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TorrentEventKind::Added => "added",
+            TorrentEventKind::MetadataResolved => "metadata_resolved",
+            TorrentEventKind::Completed => "completed",
+            TorrentEventKind::Errored => "errored",
+            TorrentEventKind::Paused => "paused",
+            TorrentEventKind::Removed => "removed",
+        }
+    }
+}
+
+impl std::str::FromStr for TorrentEventKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match Self::ALL.into_iter().find(|k| k.as_str() == s) {
+            Some(kind) => Ok(kind),
+            None => {
+                let names = Self::ALL.map(|k| k.as_str()).join(", ");
+                bail!("unknown event {s:?}, expected one of {names}")
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for TorrentEventKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Something that happened to a torrent. This is the JSON body posted to webhooks.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TorrentEvent {
+    pub event: TorrentEventKind,
+    /// Not known when the metadata was resolved, as the torrent is not added yet.
+    pub id: Option<TorrentId>,
+    pub info_hash: String,
+    pub name: Option<String>,
+    pub output_folder: Option<PathBuf>,
+    /// Paths of the files relative to the output folder.
+    pub files: Vec<PathBuf>,
+    pub error: Option<String>,
+}
+
+impl TorrentEvent {
+    pub(crate) fn new(
+        event: TorrentEventKind,
+        info_hash: Id20,
+        info: &TorrentMetaV1Info<ByteString>,
+    ) -> Self {
+        let files = info
+            .iter_filenames_and_lengths()
+            .map(|it| it.filter_map(|(f, _)| f.to_pathbuf().ok()).collect())
+            .unwrap_or_default();
+        Self {
+            event,
+            id: None,
+            info_hash: info_hash.as_string(),
+            name: info.name.as_ref().map(|n| n.to_string()),
+            output_folder: None,
+            files,
+            error: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum HookAction {
+    /// Run a command. The event is passed in RQBIT_* environment variables:
+    /// RQBIT_EVENT, RQBIT_TORRENT_ID, RQBIT_INFO_HASH, RQBIT_NAME, RQBIT_OUTPUT_FOLDER,
+    /// RQBIT_FILES (one per line) and RQBIT_ERROR.
+    Command {
+        program: String,
+        #[serde(default)]
+        args: Vec<String>,
+    },
+    /// POST the event as JSON to the URL.
+    Webhook { url: String },
+}
+
+#[serde_as]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Hook {
+    /// The events to fire on. All of them if empty.
+    #[serde(default)]
+    pub events: Vec<TorrentEventKind>,
+    #[serde(flatten)]
+    pub action: HookAction,
+    /// How long to let the hook run. Defaults to DEFAULT_HOOK_TIMEOUT.
+    #[serde_as(as = "Option<serde_with::DurationSeconds>")]
+    #[serde(default)]
+    pub timeout: Option<Duration>,
+}
+
+impl Hook {
+    fn fires_on(&self, event: TorrentEventKind) -> bool {
+        self.events.is_empty() || self.events.contains(&event)
+    }
+
+    async fn run(&self, client: &reqwest::Client, event: &TorrentEvent) -> anyhow::Result<()> {
+        match &self.action {
+            HookAction::Command { program, args } => {
+                let files = event
+                    .files
+                    .iter()
+                    .map(|f| f.to_string_lossy())
+                    .collect::<Vec<_>>()
+                    .join("\n");
+                let output = tokio::process::Command::new(program)
+                    .args(args)
+                    .env("RQBIT_EVENT", event.event.as_str())
+                    .env(
+                        "RQBIT_TORRENT_ID",
+                        event.id.map(|id| id.to_string()).unwrap_or_default(),
+                    )
+                    .env("RQBIT_INFO_HASH", &event.info_hash)
+                    .env("RQBIT_NAME", event.name.as_deref().unwrap_or_default())
+                    .env(
+                        "RQBIT_OUTPUT_FOLDER",
+                        event.output_folder.as_deref().unwrap_or("".as_ref()),
+                    )
+                    .env("RQBIT_FILES", files)
+                    .env("RQBIT_ERROR", event.error.as_deref().unwrap_or_default())
+                    .stdin(Stdio::null())
+                    .kill_on_drop(true)
+                    .output()
+                    .await
+                    .with_context(|| format!("error running {program:?}"))?;
+                debug!(
+                    stdout = %String::from_utf8_lossy(&output.stdout),
+                    stderr = %String::from_utf8_lossy(&output.stderr),
+                    "command output"
+                );
+                if !output.status.success() {
+                    bail!(
+                        "{program:?} exited with {}: {}",
+                        output.status,
+                        String::from_utf8_lossy(&output.stderr).trim()
+                    );
+                }
+                Ok(())
+            }
+            HookAction::Webhook { url } => {
+                let response = client
+                    .post(url)
+                    .json(event)
+                    .send()
+                    .await
+                    .with_context(|| format!("error posting to {url}"))?;
+                if !response.status().is_success() {
+                    bail!("POST {url} returned {}", response.status());
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+pub(crate) struct Hooks {
+    // Replaced when the configuration is reloaded.
+    hooks: RwLock<Vec<Hook>>,
+    client: reqwest::Client,
+}
+
+impl Hooks {
+    pub fn new(hooks: Vec<Hook>) -> Self {
+        Self {
+            hooks: RwLock::new(hooks),
+            client: reqwest::Client::new(),
+        }
+    }
+
+    pub fn set(&self, hooks: Vec<Hook>) {
+        *self.hooks.write() = hooks;
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.hooks.read().is_empty()
+    }
+
+    /// Run the hooks interested in the event in the background.
+    pub fn fire(&self, event: &TorrentEvent) {
+        for (idx, hook) in self.hooks.read().iter().enumerate() {
+            if !hook.fires_on(event.event) {
+                continue;
+            }
+            let hook = hook.clone();
+            let client = self.client.clone();
+            let event = event.clone();
+            spawn(
+                "hook",
+                error_span!("hook", hook = idx, event = %event.event, info_hash = %event.info_hash),
+                async move {
+                    let t = hook.timeout.unwrap_or(DEFAULT_HOOK_TIMEOUT);
+                    match tokio::time::timeout(t, hook.run(&client, &event)).await {
+                        Ok(Ok(())) => info!("hook finished"),
+                        Ok(Err(e)) => warn!(error=?e, "hook failed"),
+                        Err(_) => warn!(timeout=?t, "hook timed out"),
+                    }
+                    Ok(())
+                },
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{Hook, HookAction, Hooks, TorrentEvent, TorrentEventKind};
+
+    #[test]
+    fn test_hook_config() {
+        let hook: Hook = serde_json::from_str(
+            r#"{"type": "command", "program": "/bin/notify", "events": ["completed"], "timeout": 5}"#,
+        )
+        .unwrap();
+        assert!(matches!(hook.action, HookAction::Command { ref args, .. } if args.is_empty()));
+        assert!(hook.fires_on(TorrentEventKind::Completed));
+        assert!(!hook.fires_on(TorrentEventKind::Added));
+
+        let hook: Hook =
+            serde_json::from_str(r#"{"type": "webhook", "url": "http://localhost/hook"}"#).unwrap();
+        assert!(hook.fires_on(TorrentEventKind::Removed));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_run_command_hook() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let out = dir.path().join("out.txt");
+        let hooks = Hooks::new(vec![Hook {
+            events: vec![TorrentEventKind::Completed],
+            action: HookAction::Command {
+                program: "sh".to_owned(),
+                args: vec![
+                    "-c".to_owned(),
+                    r#"echo "$RQBIT_EVENT $RQBIT_TORRENT_ID $RQBIT_NAME" > "$0""#.to_owned(),
+                    out.to_str().unwrap().to_owned(),
+                ],
+            },
+            timeout: None,
+        }]);
+        let event = |kind| TorrentEvent {
+            event: kind,
+            id: Some(3),
+            info_hash: "00".repeat(20),
+            name: Some("test".to_owned()),
+            output_folder: None,
+            files: Vec::new(),
+            error: None,
+        };
+
+        hooks.fire(&event(TorrentEventKind::Added));
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        assert!(!out.exists());
+
+        hooks.fire(&event(TorrentEventKind::Completed));
+        for _ in 0..50 {
+            if let Ok(s) = std::fs::read_to_string(&out) {
+                if s.ends_with('\n') {
+                    assert_eq!(s, "completed 3 test\n");
+                    return;
+                }
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        panic!("hook didn't run");
+    }
+}
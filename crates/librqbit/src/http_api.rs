@@ -1,70 +1,133 @@
 use anyhow::Context;
 use axum::body::Bytes;
 use axum::extract::{Path, Query, State};
+use axum::response::sse::{Event, KeepAlive, Sse};
 use axum::response::IntoResponse;
 use axum::routing::{get, post};
+use axum::Extension;
 use futures::future::BoxFuture;
-use futures::{FutureExt, TryStreamExt};
+use futures::{FutureExt, StreamExt, TryStreamExt};
 use itertools::Itertools;
+use parking_lot::RwLock;
 
 use serde::{Deserialize, Serialize};
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
+use std::ops::Range;
+use std::path::PathBuf;
 use std::str::FromStr;
+use std::sync::Arc;
 use std::time::Duration;
+use tokio_stream::wrappers::errors::BroadcastStreamRecvError;
 use tracing::{debug, info};
 
 use axum::Router;
 
-use crate::api::Api;
+use crate::api::{
+    Api, PeerBanOptions, TorrentAddPeersRequest, TorrentFilePrioritiesRequest, TorrentMoveRequest,
+    TorrentRenameFilesRequest,
+};
+use crate::http_api_auth::{auth_middleware, check_folder_allowed, check_sub_folder_allowed};
+use crate::http_api_listener::{serve_tcp, serve_unix};
 use crate::peer_connection::PeerConnectionOptions;
 use crate::session::{AddTorrent, AddTorrentOptions, SUPPORTED_SCHEMES};
 use crate::torrent_state::peer::stats::snapshot::PeerStatsFilter;
 
+pub use crate::http_api_auth::{HttpApiCredentials, HttpApiRole, HttpApiUser};
+pub use crate::http_api_listener::{HttpApiTlsConfig, HttpApiUnixSocket};
+
 type ApiState = Api;
 
 use crate::api::Result;
+use crate::ApiError;
 
 /// An HTTP server for the API.
 pub struct HttpApi {
     inner: ApiState,
-    opts: HttpApiOptions,
+    opts: HttpApiOptionsHandle,
 }
 
 #[derive(Debug, Default)]
 pub struct HttpApiOptions {
     pub read_only: bool,
+    /// If set, requests must authenticate as one of these users.
+    pub users: Vec<HttpApiUser>,
+    /// If set, output and incomplete folders of added or moved torrents must be inside
+    /// one of these.
+    pub allowed_output_folders: Vec<PathBuf>,
+    /// Listen on this Unix socket instead of the TCP address.
+    pub unix_socket: Option<HttpApiUnixSocket>,
+    /// Serve HTTPS instead of plain HTTP.
+    pub tls: Option<HttpApiTlsConfig>,
+}
+
+/// Changes the users and allowed output folders of a running HttpApi, e.g. when the
+/// configuration is reloaded. The other options only apply when the server starts.
+#[derive(Clone)]
+pub struct HttpApiOptionsHandle(Arc<RwLock<Arc<HttpApiOptions>>>);
+
+impl HttpApiOptionsHandle {
+    pub fn set(&self, opts: HttpApiOptions) {
+        *self.0.write() = Arc::new(opts);
+    }
+
+    pub(crate) fn get(&self) -> Arc<HttpApiOptions> {
+        self.0.read().clone()
+    }
 }
 
 impl HttpApi {
     pub fn new(api: Api, opts: Option<HttpApiOptions>) -> Self {
         Self {
             inner: api,
-            opts: opts.unwrap_or_default(),
+            opts: HttpApiOptionsHandle(Arc::new(RwLock::new(Arc::new(opts.unwrap_or_default())))),
         }
     }
 
-    /// Run the HTTP server forever on the given address.
+    pub fn options_handle(&self) -> HttpApiOptionsHandle {
+        self.opts.clone()
+    }
+
+    /// Run the HTTP server forever on the given address, or on the Unix socket from the
+    /// options if there's one.
     /// If read_only is passed, no state-modifying methods will be exposed.
     #[inline(never)]
     pub fn make_http_api_and_run(self, addr: SocketAddr) -> BoxFuture<'static, anyhow::Result<()>> {
         let state = self.inner;
+        let opts_handle = self.opts;
+        let opts = opts_handle.get();
 
         async fn api_root() -> impl IntoResponse {
             axum::Json(serde_json::json!({
                 "apis": {
                     "GET /": "list all available APIs",
+                    "GET /connections/stats": "Peer connection counts and limits",
                     "GET /dht/stats": "DHT stats",
                     "GET /dht/table": "DHT routing table",
+                    "GET /disk_cache/stats": "Disk cache usage, hits and misses",
+                    "GET /events": "Stream session events (Server-Sent Events)",
+                    "GET /ip_filter": "IP filter files, ranges and blocked peer counts",
+                    "GET /metrics": "Prometheus metrics",
+                    "GET /peer_bans": "Banned peers",
                     "GET /torrents": "List torrents (default torrent is 0)",
                     "GET /torrents/{index}": "Torrent details",
                     "GET /torrents/{index}/haves": "The bitfield of have pieces",
                     "GET /torrents/{index}/stats/v1": "Torrent stats",
                     "GET /torrents/{index}/peer_stats": "Per peer stats",
+                    "GET /torrents/{index}/stream/{file_idx}": "Stream a file, supports HTTP Range requests",
                     "POST /torrents/{index}/pause": "Pause torrent",
                     "POST /torrents/{index}/start": "Resume torrent",
                     "POST /torrents/{index}/forget": "Forget about the torrent, keep the files",
                     "POST /torrents/{index}/delete": "Forget about the torrent, remove the files",
+                    "POST /torrents/{index}/files": "Change file priorities (skip, low, normal, high)",
+                    "POST /torrents/{index}/sequential?enabled=true": "Switch sequential download mode",
+                    "POST /torrents/{index}/move": "Move the torrent's files to {\"output_folder\": \"...\"}",
+                    "POST /torrents/{index}/rename": "Rename files, {\"files\": {\"<file_idx>\": \"new/path\"}}, null to revert",
+                    "POST /torrents/{index}/peers": "Connect to peers, {\"peers\": [\"1.2.3.4:6881\"]}",
+                    "POST /torrents/{index}/peers/{addr}/disconnect": "Disconnect the peer",
+                    "POST /torrents/{index}/peers/{addr}/ban?duration_secs=3600": "Ban the peer's IP, forever without duration_secs",
                     "POST /torrents": "Add a torrent here. magnet: or http:// or a local file.",
+                    "POST /ip_filter/reload": "Read the IP filter files again",
+                    "POST /peer_bans/{ip}/remove": "Unban the peer",
                     "POST /rust_log": "Set RUST_LOG to this post launch (for debugging)",
                     "GET /web/": "Web UI",
                 },
@@ -81,15 +144,54 @@ impl HttpApi {
             state.api_dht_table().map(axum::Json)
         }
 
+        async fn disk_cache_stats(State(state): State<ApiState>) -> impl IntoResponse {
+            axum::Json(state.api_disk_cache_stats())
+        }
+
+        async fn connection_stats(State(state): State<ApiState>) -> impl IntoResponse {
+            axum::Json(state.api_connection_stats())
+        }
+
+        async fn ip_filter_stats(State(state): State<ApiState>) -> impl IntoResponse {
+            axum::Json(state.api_ip_filter_stats())
+        }
+
+        async fn ip_filter_reload(State(state): State<ApiState>) -> Result<impl IntoResponse> {
+            state.api_ip_filter_reload().map(axum::Json)
+        }
+
+        async fn peer_bans(State(state): State<ApiState>) -> impl IntoResponse {
+            axum::Json(state.api_peer_bans())
+        }
+
+        async fn peer_unban(
+            State(state): State<ApiState>,
+            Path(ip): Path<IpAddr>,
+        ) -> Result<impl IntoResponse> {
+            state.api_unban_peer(ip).map(axum::Json)
+        }
+
         async fn torrents_list(State(state): State<ApiState>) -> impl IntoResponse {
             axum::Json(state.api_torrent_list())
         }
 
         async fn torrents_post(
             State(state): State<ApiState>,
+            Extension(opts): Extension<HttpApiOptionsHandle>,
             Query(params): Query<TorrentAddQueryParams>,
             data: Bytes,
         ) -> Result<impl IntoResponse> {
+            let opts = opts.get();
+            let allowed = &opts.allowed_output_folders;
+            for folder in [&params.output_folder, &params.incomplete_folder]
+                .into_iter()
+                .flatten()
+            {
+                check_folder_allowed(allowed, folder)?;
+            }
+            if let Some(sub_folder) = &params.sub_folder {
+                check_sub_folder_allowed(allowed, sub_folder)?;
+            }
             let is_url = params.is_url;
             let opts = params.into_add_torrent_options();
             let data = data.to_vec();
@@ -153,6 +255,96 @@ impl HttpApi {
             state.api_peer_stats(idx, filter).map(axum::Json)
         }
 
+        async fn torrent_add_peers(
+            State(state): State<ApiState>,
+            Path(idx): Path<usize>,
+            axum::Json(req): axum::Json<TorrentAddPeersRequest>,
+        ) -> Result<impl IntoResponse> {
+            state.api_add_peers(idx, req).map(axum::Json)
+        }
+
+        async fn torrent_disconnect_peer(
+            State(state): State<ApiState>,
+            Path((idx, addr)): Path<(usize, SocketAddr)>,
+        ) -> Result<impl IntoResponse> {
+            state.api_disconnect_peer(idx, addr).map(axum::Json)
+        }
+
+        async fn torrent_ban_peer(
+            State(state): State<ApiState>,
+            Path((idx, addr)): Path<(usize, SocketAddr)>,
+            Query(opts): Query<PeerBanOptions>,
+        ) -> Result<impl IntoResponse> {
+            state.api_ban_peer(idx, addr, opts).map(axum::Json)
+        }
+
+        async fn torrent_stream_file(
+            State(state): State<ApiState>,
+            Path((idx, file_id)): Path<(usize, usize)>,
+            headers: http::HeaderMap,
+        ) -> Result<impl IntoResponse> {
+            let file_length = state
+                .api_torrent_details(idx)?
+                .files
+                .get(file_id)
+                .map(|f| f.length)
+                .ok_or(ApiError::new_from_text(
+                    http::StatusCode::NOT_FOUND,
+                    "file not found",
+                ))?;
+            let range = match headers.get(http::header::RANGE) {
+                Some(h) => match parse_range_header(h.to_str().unwrap_or_default(), file_length) {
+                    Ok(range) => range,
+                    Err(e) => return Ok(range_not_satisfiable(e, file_length)),
+                },
+                None => None,
+            };
+            let is_partial = range.is_some();
+            let resp = state.api_stream(idx, file_id, range).await?;
+
+            let mut response_headers = http::HeaderMap::new();
+            response_headers.insert(
+                http::header::ACCEPT_RANGES,
+                http::HeaderValue::from_static("bytes"),
+            );
+            response_headers.insert(
+                http::header::CONTENT_TYPE,
+                http::HeaderValue::from_static(guess_content_type(&resp.filename)),
+            );
+            response_headers.insert(
+                http::header::CONTENT_LENGTH,
+                http::HeaderValue::from(resp.range.end - resp.range.start),
+            );
+            let status = if is_partial {
+                let content_range = format!(
+                    "bytes {}-{}/{}",
+                    resp.range.start,
+                    resp.range.end.saturating_sub(1),
+                    resp.file_length
+                );
+                response_headers.insert(
+                    http::header::CONTENT_RANGE,
+                    http::HeaderValue::from_str(&content_range)
+                        .context("bug: invalid content-range")?,
+                );
+                http::StatusCode::PARTIAL_CONTENT
+            } else {
+                http::StatusCode::OK
+            };
+            let body = axum::body::Body::from_stream(resp.stream);
+            Ok((status, response_headers, body).into_response())
+        }
+
+        async fn torrent_set_sequential(
+            State(state): State<ApiState>,
+            Path(idx): Path<usize>,
+            Query(params): Query<SequentialQueryParams>,
+        ) -> Result<impl IntoResponse> {
+            state
+                .api_torrent_set_sequential(idx, params.enabled)
+                .map(axum::Json)
+        }
+
         async fn torrent_action_pause(
             State(state): State<ApiState>,
             Path(idx): Path<usize>,
@@ -181,6 +373,37 @@ impl HttpApi {
             state.api_torrent_action_delete(idx).map(axum::Json)
         }
 
+        async fn torrent_set_file_priorities(
+            State(state): State<ApiState>,
+            Path(idx): Path<usize>,
+            axum::Json(req): axum::Json<TorrentFilePrioritiesRequest>,
+        ) -> Result<impl IntoResponse> {
+            state
+                .api_torrent_set_file_priorities(idx, req)
+                .map(axum::Json)
+        }
+
+        async fn torrent_move(
+            State(state): State<ApiState>,
+            Extension(opts): Extension<HttpApiOptionsHandle>,
+            Path(idx): Path<usize>,
+            axum::Json(req): axum::Json<TorrentMoveRequest>,
+        ) -> Result<impl IntoResponse> {
+            check_folder_allowed(&opts.get().allowed_output_folders, &req.output_folder)?;
+            state.api_torrent_move(idx, req).await.map(axum::Json)
+        }
+
+        async fn torrent_rename_files(
+            State(state): State<ApiState>,
+            Path(idx): Path<usize>,
+            axum::Json(req): axum::Json<TorrentRenameFilesRequest>,
+        ) -> Result<impl IntoResponse> {
+            state
+                .api_torrent_rename_files(idx, req)
+                .await
+                .map(axum::Json)
+        }
+
         async fn set_rust_log(
             State(state): State<ApiState>,
             new_value: String,
@@ -196,26 +419,70 @@ impl HttpApi {
             Ok(axum::body::Body::from_stream(s))
         }
 
+        async fn metrics(State(state): State<ApiState>) -> impl IntoResponse {
+            (
+                [(
+                    http::header::CONTENT_TYPE,
+                    "text/plain; version=0.0.4; charset=utf-8",
+                )],
+                state.api_metrics(),
+            )
+        }
+
+        async fn events(State(state): State<ApiState>) -> impl IntoResponse {
+            let s = state.api_events_stream().filter_map(|event| async move {
+                match event {
+                    Ok(event) => Some(Event::default().event(event.name()).json_data(&event)),
+                    Err(BroadcastStreamRecvError::Lagged(n)) => {
+                        debug!(skipped = n, "events subscriber is lagging");
+                        Some(Ok(Event::default()
+                            .event("lagged")
+                            .data(format!("{{\"skipped\":{n}}}"))))
+                    }
+                }
+            });
+            Sse::new(s).keep_alive(KeepAlive::default())
+        }
+
         let mut app = Router::new()
             .route("/", get(api_root))
             .route("/stream_logs", get(stream_logs))
+            .route("/events", get(events))
+            .route("/metrics", get(metrics))
             .route("/rust_log", post(set_rust_log))
+            .route("/connections/stats", get(connection_stats))
             .route("/dht/stats", get(dht_stats))
             .route("/dht/table", get(dht_table))
+            .route("/disk_cache/stats", get(disk_cache_stats))
+            .route("/ip_filter", get(ip_filter_stats))
+            .route("/peer_bans", get(peer_bans))
             .route("/torrents", get(torrents_list))
             .route("/torrents/:id", get(torrent_details))
             .route("/torrents/:id/haves", get(torrent_haves))
             .route("/torrents/:id/stats", get(torrent_stats_v0))
             .route("/torrents/:id/stats/v1", get(torrent_stats_v1))
-            .route("/torrents/:id/peer_stats", get(peer_stats));
+            .route("/torrents/:id/peer_stats", get(peer_stats))
+            .route("/torrents/:id/stream/:file_id", get(torrent_stream_file));
 
-        if !self.opts.read_only {
+        if !opts.read_only {
             app = app
                 .route("/torrents", post(torrents_post))
+                .route("/ip_filter/reload", post(ip_filter_reload))
+                .route("/peer_bans/:ip/remove", post(peer_unban))
                 .route("/torrents/:id/pause", post(torrent_action_pause))
                 .route("/torrents/:id/start", post(torrent_action_start))
                 .route("/torrents/:id/forget", post(torrent_action_forget))
-                .route("/torrents/:id/delete", post(torrent_action_delete));
+                .route("/torrents/:id/delete", post(torrent_action_delete))
+                .route("/torrents/:id/files", post(torrent_set_file_priorities))
+                .route("/torrents/:id/sequential", post(torrent_set_sequential))
+                .route("/torrents/:id/move", post(torrent_move))
+                .route("/torrents/:id/rename", post(torrent_rename_files))
+                .route("/torrents/:id/peers", post(torrent_add_peers))
+                .route(
+                    "/torrents/:id/peers/:addr/disconnect",
+                    post(torrent_disconnect_peer),
+                )
+                .route("/torrents/:id/peers/:addr/ban", post(torrent_ban_peer));
         }
 
         #[cfg(feature = "webui")]
@@ -281,28 +548,111 @@ impl HttpApi {
                 .allow_headers(AllowHeaders::any())
         };
 
+        // Layers added later run first, so CORS preflight requests are answered before auth.
         let app = app
+            .layer(axum::middleware::from_fn_with_state(
+                opts_handle.clone(),
+                auth_middleware,
+            ))
+            .layer(Extension(opts_handle))
             .layer(cors_layer)
             .layer(tower_http::trace::TraceLayer::new_for_http())
-            .with_state(state)
-            .into_make_service();
-
-        info!(%addr, "starting HTTP server");
-
-        use tokio::net::TcpListener;
+            .with_state(state);
 
         async move {
-            let listener = TcpListener::bind(&addr)
+            if let Some(socket) = &opts.unix_socket {
+                return serve_unix(socket, opts.tls.as_ref(), app).await;
+            }
+            if let Some(tls) = &opts.tls {
+                return serve_tcp(addr, tls, app).await;
+            }
+            info!(%addr, "starting HTTP server");
+            let listener = tokio::net::TcpListener::bind(&addr)
                 .await
                 .with_context(|| format!("error binding to {addr}"))?;
-            axum::serve(listener, app).await?;
+            axum::serve(
+                listener,
+                app.into_make_service_with_connect_info::<SocketAddr>(),
+            )
+            .await?;
             Ok(())
         }
         .boxed()
     }
 }
 
-pub(crate) struct OnlyFiles(Vec<usize>);
+#[derive(Deserialize)]
+struct SequentialQueryParams {
+    enabled: bool,
+}
+
+// Parse a "Range: bytes=..." header. Only single ranges are supported, for anything else
+// the whole file is served, which is allowed by the RFC.
+fn parse_range_header(value: &str, file_length: u64) -> Result<Option<Range<u64>>> {
+    let not_satisfiable = || {
+        ApiError::new_from_text(
+            http::StatusCode::RANGE_NOT_SATISFIABLE,
+            "requested range is not satisfiable",
+        )
+    };
+    let spec = match value.trim().strip_prefix("bytes=") {
+        Some(spec) if !spec.contains(',') => spec,
+        _ => return Ok(None),
+    };
+    let (start, end) = match spec.split_once('-') {
+        Some(v) => v,
+        None => return Ok(None),
+    };
+    let (start, end) = (start.trim(), end.trim());
+    let range = match (start.parse::<u64>(), end.parse::<u64>()) {
+        (Ok(start), Ok(end)) if start <= end => start..(end + 1).min(file_length),
+        (Ok(start), Err(_)) if end.is_empty() => start..file_length,
+        (Err(_), Ok(suffix)) if start.is_empty() && suffix > 0 => {
+            file_length.saturating_sub(suffix)..file_length
+        }
+        _ => return Ok(None),
+    };
+    if range.start >= file_length {
+        return Err(not_satisfiable());
+    }
+    Ok(Some(range))
+}
+
+// The response to a range parse_range_header() rejected. It has to tell the length of
+// the file (RFC 9110, 14.4), so that the client can ask for a range that exists.
+fn range_not_satisfiable(e: ApiError, file_length: u64) -> axum::response::Response {
+    let mut response = e.into_response();
+    if let Ok(value) = http::HeaderValue::try_from(format!("bytes */{file_length}")) {
+        response
+            .headers_mut()
+            .insert(http::header::CONTENT_RANGE, value);
+    }
+    response
+}
+
+fn guess_content_type(filename: &str) -> &'static str {
+    let ext = filename
+        .rsplit_once('.')
+        .map(|(_, ext)| ext.to_ascii_lowercase())
+        .unwrap_or_default();
+    match ext.as_str() {
+        "mp4" | "m4v" => "video/mp4",
+        "mkv" => "video/x-matroska",
+        "webm" => "video/webm",
+        "avi" => "video/x-msvideo",
+        "mov" => "video/quicktime",
+        "ts" => "video/mp2t",
+        "mp3" => "audio/mpeg",
+        "flac" => "audio/flac",
+        "ogg" => "audio/ogg",
+        "m4a" => "audio/mp4",
+        "wav" => "audio/wav",
+        "srt" | "txt" => "text/plain",
+        _ => "application/octet-stream",
+    }
+}
+
+pub(crate) struct OnlyFiles(pub Vec<usize>);
 pub(crate) struct InitialPeers(pub Vec<SocketAddr>);
 
 #[derive(Serialize, Deserialize, Default)]
@@ -310,6 +660,9 @@ pub(crate) struct TorrentAddQueryParams {
     pub overwrite: Option<bool>,
     pub output_folder: Option<String>,
     pub sub_folder: Option<String>,
+    pub incomplete_folder: Option<String>,
+    pub part_suffix: Option<bool>,
+    pub cross_seed: Option<bool>,
     pub only_files_regex: Option<String>,
     pub only_files: Option<OnlyFiles>,
     pub peer_connect_timeout: Option<u64>,
@@ -318,6 +671,7 @@ pub(crate) struct TorrentAddQueryParams {
     // Will force interpreting the content as a URL.
     pub is_url: Option<bool>,
     pub list_only: Option<bool>,
+    pub sequential: Option<bool>,
 }
 
 impl Serialize for OnlyFiles {
@@ -395,7 +749,11 @@ impl TorrentAddQueryParams {
             only_files: self.only_files.map(|o| o.0),
             output_folder: self.output_folder,
             sub_folder: self.sub_folder,
+            incomplete_folder: self.incomplete_folder,
+            part_suffix: self.part_suffix,
+            cross_seed: self.cross_seed.unwrap_or(false),
             list_only: self.list_only.unwrap_or(false),
+            sequential: self.sequential.unwrap_or(false),
             initial_peers: self.initial_peers.map(|i| i.0),
             peer_opts: Some(PeerConnectionOptions {
                 connect_timeout: self.peer_connect_timeout.map(Duration::from_secs),
@@ -406,3 +764,32 @@ impl TorrentAddQueryParams {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_range_header, range_not_satisfiable};
+
+    #[test]
+    fn test_parse_range_header() {
+        let r = |s| parse_range_header(s, 1000).map_err(|e| e.status());
+        assert_eq!(r("bytes=0-99"), Ok(Some(0..100)));
+        assert_eq!(r("bytes=900-"), Ok(Some(900..1000)));
+        assert_eq!(r("bytes=-100"), Ok(Some(900..1000)));
+        assert_eq!(r("bytes=990-2000"), Ok(Some(990..1000)));
+        assert_eq!(r("bytes=-5000"), Ok(Some(0..1000)));
+        assert_eq!(r("bytes=0-1,5-10"), Ok(None));
+        assert_eq!(r("items=0-1"), Ok(None));
+        assert_eq!(
+            r("bytes=1000-"),
+            Err(http::StatusCode::RANGE_NOT_SATISFIABLE)
+        );
+
+        let response =
+            range_not_satisfiable(parse_range_header("bytes=1000-", 1000).unwrap_err(), 1000);
+        assert_eq!(response.status(), http::StatusCode::RANGE_NOT_SATISFIABLE);
+        assert_eq!(
+            response.headers().get(http::header::CONTENT_RANGE).unwrap(),
+            "bytes */1000"
+        );
+    }
+}
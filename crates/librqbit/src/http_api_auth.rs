@@ -0,0 +1,337 @@
+// Authentication and authorization for the HTTP API.
+
+use std::{
+    net::SocketAddr,
+    path::{Component, Path, PathBuf},
+};
+
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use base64::Engine;
+use http::{header, Method, StatusCode};
+use tracing::{debug, warn};
+
+use crate::{
+    api::Result,
+    http_api::{HttpApiOptions, HttpApiOptionsHandle},
+    ApiError,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum HttpApiRole {
+    /// Only GET routes.
+    ReadOnly,
+    #[default]
+    ReadWrite,
+}
+
+#[derive(Clone)]
+pub enum HttpApiCredentials {
+    /// HTTP Basic auth.
+    Basic { username: String, password: String },
+    /// "Authorization: Bearer <token>".
+    Bearer { token: String },
+}
+
+impl std::fmt::Debug for HttpApiCredentials {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Basic { username, .. } => f
+                .debug_struct("Basic")
+                .field("username", username)
+                .finish_non_exhaustive(),
+            Self::Bearer { .. } => f.debug_struct("Bearer").finish_non_exhaustive(),
+        }
+    }
+}
+
+impl HttpApiCredentials {
+    /// Parse "username:password" as Basic credentials.
+    pub fn parse_basic(s: &str) -> anyhow::Result<Self> {
+        match s.split_once(':') {
+            Some((username, password)) if !username.is_empty() && !password.is_empty() => {
+                Ok(Self::Basic {
+                    username: username.to_owned(),
+                    password: password.to_owned(),
+                })
+            }
+            _ => anyhow::bail!("expected \"username:password\""),
+        }
+    }
+
+    /// The value of the "Authorization" header to send with these credentials.
+    pub(crate) fn authorization_header(&self) -> String {
+        match self {
+            Self::Basic { username, password } => format!(
+                "Basic {}",
+                base64::engine::general_purpose::STANDARD.encode(format!("{username}:{password}"))
+            ),
+            Self::Bearer { token } => format!("Bearer {token}"),
+        }
+    }
+
+    pub fn parse_bearer(token: &str) -> anyhow::Result<Self> {
+        if token.is_empty() {
+            anyhow::bail!("the token is empty");
+        }
+        Ok(Self::Bearer {
+            token: token.to_owned(),
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct HttpApiUser {
+    pub credentials: HttpApiCredentials,
+    pub role: HttpApiRole,
+}
+
+// Compare secrets without returning early on the first mismatch.
+fn secure_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+fn authenticate(users: &[HttpApiUser], authorization: &str) -> Option<HttpApiRole> {
+    let (scheme, value) = authorization.trim().split_once(' ')?;
+    let value = value.trim();
+    if scheme.eq_ignore_ascii_case("basic") {
+        let decoded = base64::engine::general_purpose::STANDARD
+            .decode(value)
+            .ok()?;
+        let decoded = String::from_utf8(decoded).ok()?;
+        let (u, p) = decoded.split_once(':')?;
+        users
+            .iter()
+            .find(|user| match &user.credentials {
+                HttpApiCredentials::Basic { username, password } => {
+                    secure_eq(username.as_bytes(), u.as_bytes())
+                        & secure_eq(password.as_bytes(), p.as_bytes())
+                }
+                _ => false,
+            })
+            .map(|user| user.role)
+    } else if scheme.eq_ignore_ascii_case("bearer") {
+        users
+            .iter()
+            .find(|user| match &user.credentials {
+                HttpApiCredentials::Bearer { token } => {
+                    secure_eq(token.as_bytes(), value.as_bytes())
+                }
+                _ => false,
+            })
+            .map(|user| user.role)
+    } else {
+        None
+    }
+}
+
+fn unauthorized(opts: &HttpApiOptions) -> Response {
+    let basic = opts
+        .users
+        .iter()
+        .any(|u| matches!(u.credentials, HttpApiCredentials::Basic { .. }));
+    let challenge = if basic {
+        "Basic realm=\"rqbit\""
+    } else {
+        "Bearer realm=\"rqbit\""
+    };
+    (
+        StatusCode::UNAUTHORIZED,
+        [(header::WWW_AUTHENTICATE, challenge)],
+        "authentication required",
+    )
+        .into_response()
+}
+
+/// Reject requests without valid credentials, and writes by read-only users.
+/// Does nothing if no users are configured.
+pub(crate) async fn auth_middleware(
+    State(opts): State<HttpApiOptionsHandle>,
+    remote: Option<ConnectInfo<SocketAddr>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let opts = opts.get();
+    if opts.users.is_empty() {
+        return next.run(request).await;
+    }
+    // None for Unix socket connections.
+    let remote = remote.map(|ConnectInfo(addr)| addr);
+    let method = request.method().clone();
+    let path = request.uri().path().to_owned();
+
+    let authorization = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok());
+    let role = match authorization.and_then(|a| authenticate(&opts.users, a)) {
+        Some(role) => role,
+        None => {
+            warn!(
+                ?remote,
+                %method,
+                path,
+                credentials_sent = authorization.is_some(),
+                "HTTP API authentication failed"
+            );
+            return unauthorized(&opts);
+        }
+    };
+
+    let is_read = matches!(method, Method::GET | Method::HEAD | Method::OPTIONS);
+    if role == HttpApiRole::ReadOnly && !is_read {
+        warn!(?remote, %method, path, "read-only HTTP API user tried to modify state");
+        return (StatusCode::FORBIDDEN, "read-only access").into_response();
+    }
+    debug!(?remote, ?role, "authenticated");
+    next.run(request).await
+}
+
+// Resolve "." and ".." without touching the filesystem.
+fn normalize(path: &Path) -> PathBuf {
+    let mut result = PathBuf::new();
+    for c in path.components() {
+        match c {
+            Component::ParentDir => {
+                result.pop();
+            }
+            Component::CurDir => {}
+            c => result.push(c),
+        }
+    }
+    result
+}
+
+// Where "path" really is: the deepest part of it that exists with symlinks resolved, and
+// the rest, which can't have any yet, resolved lexically.
+fn resolve(path: &Path) -> PathBuf {
+    let components = path.components().collect::<Vec<_>>();
+    for existing in (1..=components.len()).rev() {
+        let prefix = components[..existing].iter().collect::<PathBuf>();
+        if let Ok(mut resolved) = prefix.canonicalize() {
+            resolved.extend(&components[existing..]);
+            return normalize(&resolved);
+        }
+    }
+    normalize(path)
+}
+
+/// Check that "folder" is inside one of the "allowed" folders. Anything goes if the
+/// allow-list is empty. Symlinks are followed, so that they can't lead out of the allowed
+/// folders.
+pub(crate) fn check_folder_allowed(allowed: &[PathBuf], folder: &str) -> Result<()> {
+    if allowed.is_empty() {
+        return Ok(());
+    }
+    let path = Path::new(folder);
+    let resolved = resolve(path);
+    if path.is_absolute() && allowed.iter().any(|a| resolved.starts_with(resolve(a))) {
+        return Ok(());
+    }
+    warn!(
+        folder,
+        "HTTP API request for a folder outside of the allowed ones"
+    );
+    Err(ApiError::new_from_text(
+        StatusCode::FORBIDDEN,
+        "the folder is not in the list of allowed folders",
+    ))
+}
+
+/// Sub-folders are joined to the session's output folder, so they must not leave it.
+pub(crate) fn check_sub_folder_allowed(allowed: &[PathBuf], sub_folder: &str) -> Result<()> {
+    if allowed.is_empty() {
+        return Ok(());
+    }
+    if Path::new(sub_folder)
+        .components()
+        .all(|c| matches!(c, Component::Normal(_) | Component::CurDir))
+    {
+        return Ok(());
+    }
+    warn!(
+        sub_folder,
+        "HTTP API request for a sub-folder outside of the output folder"
+    );
+    Err(ApiError::new_from_text(
+        StatusCode::FORBIDDEN,
+        "the sub-folder must be inside the output folder",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use base64::Engine;
+
+    use super::{
+        authenticate, check_folder_allowed, check_sub_folder_allowed, HttpApiCredentials,
+        HttpApiRole, HttpApiUser,
+    };
+
+    #[test]
+    fn test_authenticate() {
+        let users = vec![
+            HttpApiUser {
+                credentials: HttpApiCredentials::parse_basic("admin:secret").unwrap(),
+                role: HttpApiRole::ReadWrite,
+            },
+            HttpApiUser {
+                credentials: HttpApiCredentials::parse_bearer("ro-token").unwrap(),
+                role: HttpApiRole::ReadOnly,
+            },
+        ];
+        let basic = |s: &str| {
+            format!(
+                "Basic {}",
+                base64::engine::general_purpose::STANDARD.encode(s)
+            )
+        };
+        assert_eq!(
+            authenticate(&users, &basic("admin:secret")),
+            Some(HttpApiRole::ReadWrite)
+        );
+        assert_eq!(authenticate(&users, &basic("admin:wrong")), None);
+        assert_eq!(
+            authenticate(&users, "Bearer ro-token"),
+            Some(HttpApiRole::ReadOnly)
+        );
+        assert_eq!(authenticate(&users, "Bearer secret"), None);
+        assert_eq!(authenticate(&users, "garbage"), None);
+    }
+
+    #[test]
+    fn test_folder_allow_list() {
+        let allowed = vec![PathBuf::from("/data/torrents")];
+        assert!(check_folder_allowed(&[], "/etc").is_ok());
+        assert!(check_folder_allowed(&allowed, "/data/torrents/movies").is_ok());
+        assert!(check_folder_allowed(&allowed, "/data/torrents/../../etc").is_err());
+        assert!(check_folder_allowed(&allowed, "/data/torrents-other").is_err());
+        assert!(check_folder_allowed(&allowed, "data/torrents").is_err());
+        assert!(check_sub_folder_allowed(&allowed, "movies/new").is_ok());
+        assert!(check_sub_folder_allowed(&allowed, "../etc").is_err());
+        assert!(check_sub_folder_allowed(&allowed, "/etc").is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_folder_allow_list_symlinks() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let allowed_dir = dir.path().join("allowed");
+        let outside = dir.path().join("outside");
+        std::fs::create_dir_all(allowed_dir.join("real")).unwrap();
+        std::fs::create_dir_all(&outside).unwrap();
+        std::os::unix::fs::symlink(&outside, allowed_dir.join("link")).unwrap();
+        let allowed = vec![allowed_dir.clone()];
+        let check = |p: PathBuf| check_folder_allowed(&allowed, p.to_str().unwrap());
+
+        assert!(check(allowed_dir.join("real/new/folder")).is_ok());
+        assert!(check(allowed_dir.join("link")).is_err());
+        assert!(check(allowed_dir.join("link/new")).is_err());
+        assert!(check(allowed_dir.join("link/../outside")).is_err());
+    }
+}
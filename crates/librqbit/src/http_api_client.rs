@@ -1,35 +1,84 @@
+use std::{
+    net::{IpAddr, SocketAddr},
+    ops::Range,
+    path::PathBuf,
+    time::Duration,
+};
+
 use anyhow::Context;
-use futures::{future::BoxFuture, FutureExt};
-use serde::Deserialize;
+use bytes::{Bytes, BytesMut};
+use futures::{future::BoxFuture, stream::BoxStream, FutureExt, StreamExt, TryStreamExt};
+use http::{header, HeaderMap, HeaderValue, Method, StatusCode};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 
 use crate::{
-    api::ApiAddTorrentResponse,
-    http_api::TorrentAddQueryParams,
-    session::{AddTorrent, AddTorrentOptions},
+    api::{
+        ApiAddTorrentResponse, EmptyJsonResponse, LiveStats, PeerStatsFilter, PeerStatsSnapshot,
+        TorrentAddPeersRequest, TorrentAddPeersResponse, TorrentDetailsResponse,
+        TorrentFilePrioritiesRequest, TorrentListResponse, TorrentMoveRequest,
+        TorrentRenameFilesRequest, TorrentStats,
+    },
+    dht::DhtStats,
+    http_api::{HttpApiCredentials, InitialPeers, OnlyFiles, TorrentAddQueryParams},
+    session::{AddTorrent, AddTorrentOptions, TorrentId},
+    ConnectionStats, DiskCacheStats, IpFilterStats, PeerBan, SessionEvent,
 };
 
+type BodyStream = BoxStream<'static, anyhow::Result<Bytes>>;
+
+#[derive(Clone)]
+enum Transport {
+    Http(reqwest::Client),
+    /// "unix:///path/to/socket" URLs.
+    Unix(PathBuf),
+}
+
+/// A client for the HTTP API, with a method for each route.
 #[derive(Clone)]
 pub struct HttpApiClient {
-    client: reqwest::Client,
+    transport: Transport,
     base_url: reqwest::Url,
+    credentials: Option<HttpApiCredentials>,
+}
+
+struct Response {
+    url: String,
+    status: StatusCode,
+    body: BodyStream,
 }
 
-async fn check_response(r: reqwest::Response) -> anyhow::Result<reqwest::Response> {
-    if r.status().is_success() {
+impl Response {
+    async fn bytes(self) -> anyhow::Result<Bytes> {
+        let url = self.url;
+        let status = self.status;
+        let body = self
+            .body
+            .try_fold(BytesMut::new(), |mut acc, chunk| async move {
+                acc.extend_from_slice(&chunk);
+                Ok(acc)
+            })
+            .await
+            .with_context(|| {
+                format!("cannot read response body for request to {url} ({status})")
+            })?;
+        Ok(body.freeze())
+    }
+}
+
+async fn check_response(r: Response) -> anyhow::Result<Response> {
+    if r.status.is_success() {
         return Ok(r);
     }
-    let status = r.status();
-    let url = r.url().clone();
-    let body = r
-        .text()
-        .await
-        .with_context(|| format!("cannot read response body for request to {url} ({status})"))?;
+    let url = r.url.clone();
+    let status = r.status;
+    let body = r.bytes().await?;
 
     #[derive(Deserialize)]
     struct HumanReadableError<'a> {
         human_readable: Option<&'a str>,
     }
 
+    let body = String::from_utf8_lossy(&body);
     let human_readable_internal_error = serde_json::from_str::<HumanReadableError<'_>>(&body)
         .ok()
         .and_then(|e| e.human_readable);
@@ -43,12 +92,11 @@ struct ApiRoot {
     server: String,
 }
 
-async fn json_response<T: serde::de::DeserializeOwned + std::any::Any>(
-    response: reqwest::Response,
+async fn json_response<T: DeserializeOwned + std::any::Any>(
+    response: Response,
 ) -> anyhow::Result<T> {
-    let url = response.url().clone();
-    let response = check_response(response).await?;
-    let body = response.bytes().await?;
+    let url = response.url.clone();
+    let body = check_response(response).await?.bytes().await?;
     let response: T = serde_json::from_slice(&body).with_context(|| {
         format!(
             "error deserializing response from {:?} as {:?}",
@@ -59,24 +107,208 @@ async fn json_response<T: serde::de::DeserializeOwned + std::any::Any>(
     Ok(response)
 }
 
+async fn text_response(response: Response) -> anyhow::Result<String> {
+    let body = check_response(response).await?.bytes().await?;
+    String::from_utf8(body.into()).context("response is not UTF-8")
+}
+
+#[cfg(unix)]
+async fn send_unix(
+    socket: &std::path::Path,
+    request: http::Request<Bytes>,
+) -> anyhow::Result<(StatusCode, BodyStream)> {
+    use http_body_util::{BodyStream, Full};
+    use hyper_util::rt::TokioIo;
+
+    let stream = tokio::net::UnixStream::connect(socket)
+        .await
+        .with_context(|| format!("error connecting to {socket:?}"))?;
+    let (mut sender, conn) = hyper::client::conn::http1::handshake(TokioIo::new(stream)).await?;
+    crate::spawn_utils::spawn(
+        "http_api_client_connection",
+        tracing::debug_span!("http_api_client_connection"),
+        async move {
+            conn.await?;
+            Ok(())
+        },
+    );
+    let response = sender.send_request(request.map(Full::new)).await?;
+    let status = response.status();
+    let body = BodyStream::new(response.into_body())
+        .try_filter_map(|frame| async move { Ok(frame.into_data().ok()) })
+        .map_err(anyhow::Error::from)
+        .boxed();
+    Ok((status, body))
+}
+
+#[cfg(not(unix))]
+async fn send_unix(
+    _socket: &std::path::Path,
+    _request: http::Request<Bytes>,
+) -> anyhow::Result<(StatusCode, BodyStream)> {
+    anyhow::bail!("Unix sockets are not supported on this platform")
+}
+
+// Take the first complete server-sent event off "buf", decoded. The bytes are only
+// decoded once the whole event is there, so that characters split across network chunks
+// stay intact.
+fn take_sse_block(buf: &mut Vec<u8>) -> Option<String> {
+    let end = buf.windows(2).position(|w| w == b"\n\n")?;
+    let block = String::from_utf8_lossy(&buf[..end]).into_owned();
+    buf.drain(..end + 2);
+    Some(block)
+}
+
+// One server-sent event: its name and data.
+fn parse_sse_event(block: &str) -> Option<(&str, String)> {
+    let mut event = "message";
+    let mut data: Option<String> = None;
+    for line in block.lines() {
+        // Lines starting with ":" are comments, e.g. keep-alives.
+        let (field, value) = match line.split_once(':') {
+            Some(("", _)) => continue,
+            Some((field, value)) => (field, value.strip_prefix(' ').unwrap_or(value)),
+            None => (line, ""),
+        };
+        match field {
+            "event" => event = value,
+            "data" => match &mut data {
+                Some(data) => {
+                    data.push('\n');
+                    data.push_str(value);
+                }
+                None => data = Some(value.to_owned()),
+            },
+            _ => {}
+        }
+    }
+    data.map(|data| (event, data))
+}
+
 impl HttpApiClient {
+    /// Accepts "http://", "https://" and "unix:///path/to/socket" URLs.
     #[inline(never)]
     pub fn new(url: &str) -> anyhow::Result<Self> {
+        let mut base_url = reqwest::Url::parse(url)?;
+        let transport = match base_url.scheme() {
+            "http" | "https" => Transport::Http(reqwest::ClientBuilder::new().build()?),
+            "unix" => Transport::Unix(PathBuf::from(base_url.path())),
+            scheme => anyhow::bail!("unsupported HTTP API URL scheme {scheme:?}"),
+        };
+        // So that joining relative paths doesn't drop the last segment.
+        if let Transport::Http(_) = transport {
+            if !base_url.path().ends_with('/') {
+                base_url.set_path(&format!("{}/", base_url.path()));
+            }
+        }
         Ok(Self {
-            base_url: reqwest::Url::parse(url)?,
-            client: reqwest::ClientBuilder::new().build()?,
+            transport,
+            base_url,
+            credentials: None,
         })
     }
 
+    /// Authenticate requests with these credentials.
+    pub fn with_credentials(mut self, credentials: Option<HttpApiCredentials>) -> Self {
+        self.credentials = credentials;
+        self
+    }
+
     pub fn base_url(&self) -> &reqwest::Url {
         &self.base_url
     }
 
+    // Send a request to "path_and_query", relative to the base URL.
+    async fn send(
+        &self,
+        method: Method,
+        path_and_query: &str,
+        body: Option<Bytes>,
+    ) -> anyhow::Result<Response> {
+        self.send_with_headers(method, path_and_query, body, HeaderMap::new())
+            .await
+    }
+
+    async fn send_with_headers(
+        &self,
+        method: Method,
+        path_and_query: &str,
+        body: Option<Bytes>,
+        mut headers: HeaderMap,
+    ) -> anyhow::Result<Response> {
+        if let Some(credentials) = &self.credentials {
+            headers.insert(
+                header::AUTHORIZATION,
+                HeaderValue::from_str(&credentials.authorization_header())
+                    .context("invalid credentials")?,
+            );
+        }
+        match &self.transport {
+            Transport::Http(client) => {
+                let url = self.base_url.join(path_and_query)?;
+                let mut request = client.request(method, url.clone()).headers(headers);
+                if let Some(body) = body {
+                    request = request.body(body);
+                }
+                let response = request.send().await?;
+                Ok(Response {
+                    url: url.to_string(),
+                    status: response.status(),
+                    body: response.bytes_stream().map_err(anyhow::Error::from).boxed(),
+                })
+            }
+            Transport::Unix(socket) => {
+                let mut request = http::Request::builder()
+                    .method(method)
+                    .uri(format!("/{path_and_query}"))
+                    .header(header::HOST, "localhost");
+                if let Some(h) = request.headers_mut() {
+                    h.extend(headers);
+                }
+                let request = request.body(body.unwrap_or_default())?;
+                let (status, body) = send_unix(socket, request).await?;
+                Ok(Response {
+                    url: format!("{}/{path_and_query}", self.base_url),
+                    status,
+                    body,
+                })
+            }
+        }
+    }
+
+    async fn get_json<T: DeserializeOwned + std::any::Any>(
+        &self,
+        path_and_query: &str,
+    ) -> anyhow::Result<T> {
+        json_response(self.send(Method::GET, path_and_query, None).await?).await
+    }
+
+    async fn post_json<T: DeserializeOwned + std::any::Any>(
+        &self,
+        path_and_query: &str,
+        body: Option<&impl Serialize>,
+    ) -> anyhow::Result<T> {
+        let body = body.map(serde_json::to_vec).transpose()?.map(Bytes::from);
+        json_response(self.send(Method::POST, path_and_query, body).await?).await
+    }
+
+    async fn post_empty(&self, path_and_query: &str) -> anyhow::Result<()> {
+        self.post_json::<EmptyJsonResponse>(path_and_query, None::<&()>)
+            .await?;
+        Ok(())
+    }
+
+    async fn stream(&self, path_and_query: &str, headers: HeaderMap) -> anyhow::Result<BodyStream> {
+        let response = self
+            .send_with_headers(Method::GET, path_and_query, None, headers)
+            .await?;
+        Ok(check_response(response).await?.body)
+    }
+
     #[inline(never)]
     pub fn validate_rqbit_server(&self) -> BoxFuture<'_, anyhow::Result<()>> {
         async move {
-            let response = self.client.get(self.base_url.clone()).send().await?;
-            let root: ApiRoot = json_response(response).await?;
+            let root: ApiRoot = self.get_json("").await?;
             if root.server == "rqbit" {
                 return Ok(());
             }
@@ -95,24 +327,289 @@ impl HttpApiClient {
             let params = TorrentAddQueryParams {
                 overwrite: Some(opts.overwrite),
                 only_files_regex: opts.only_files_regex,
-                only_files: None,
+                only_files: opts.only_files.map(OnlyFiles),
                 output_folder: opts.output_folder,
                 sub_folder: opts.sub_folder,
+                incomplete_folder: opts.incomplete_folder,
+                part_suffix: opts.part_suffix,
+                cross_seed: opts.cross_seed.then_some(true),
+                initial_peers: opts.initial_peers.map(InitialPeers),
                 list_only: Some(opts.list_only),
+                sequential: opts.sequential.then_some(true),
                 ..Default::default()
             };
             let qs = serde_urlencoded::to_string(&params).unwrap();
-            let url = format!("{}torrents?{}", &self.base_url, qs);
-            let response = check_response(
-                self.client
-                    .post(&url)
-                    .body(torrent.into_bytes())
-                    .send()
-                    .await?,
-            )
-            .await?;
+            let response = self
+                .send(
+                    Method::POST,
+                    &format!("torrents?{qs}"),
+                    Some(torrent.into_bytes().into()),
+                )
+                .await?;
             json_response(response).await
         }
         .boxed()
     }
+
+    pub async fn list_torrents(&self) -> anyhow::Result<TorrentListResponse> {
+        self.get_json("torrents").await
+    }
+
+    pub async fn torrent_details(&self, id: TorrentId) -> anyhow::Result<TorrentDetailsResponse> {
+        self.get_json(&format!("torrents/{id}")).await
+    }
+
+    /// A debug dump of the pieces the torrent has.
+    pub async fn torrent_haves(&self, id: TorrentId) -> anyhow::Result<String> {
+        text_response(
+            self.send(Method::GET, &format!("torrents/{id}/haves"), None)
+                .await?,
+        )
+        .await
+    }
+
+    /// GET /torrents/{id}/stats, only works for live torrents.
+    pub async fn torrent_live_stats(&self, id: TorrentId) -> anyhow::Result<LiveStats> {
+        self.get_json(&format!("torrents/{id}/stats")).await
+    }
+
+    pub async fn torrent_stats(&self, id: TorrentId) -> anyhow::Result<TorrentStats> {
+        self.get_json(&format!("torrents/{id}/stats/v1")).await
+    }
+
+    pub async fn peer_stats(
+        &self,
+        id: TorrentId,
+        filter: PeerStatsFilter,
+    ) -> anyhow::Result<PeerStatsSnapshot> {
+        let qs = serde_urlencoded::to_string(&filter)?;
+        self.get_json(&format!("torrents/{id}/peer_stats?{qs}"))
+            .await
+    }
+
+    /// Stream a file of the torrent, or a byte range of it.
+    pub async fn stream_file(
+        &self,
+        id: TorrentId,
+        file_id: usize,
+        range: Option<Range<u64>>,
+    ) -> anyhow::Result<BodyStream> {
+        let mut headers = HeaderMap::new();
+        if let Some(range) = range.filter(|r| !r.is_empty()) {
+            headers.insert(
+                header::RANGE,
+                HeaderValue::from_str(&format!("bytes={}-{}", range.start, range.end - 1))?,
+            );
+        }
+        self.stream(&format!("torrents/{id}/stream/{file_id}"), headers)
+            .await
+    }
+
+    pub async fn pause(&self, id: TorrentId) -> anyhow::Result<()> {
+        self.post_empty(&format!("torrents/{id}/pause")).await
+    }
+
+    pub async fn start(&self, id: TorrentId) -> anyhow::Result<()> {
+        self.post_empty(&format!("torrents/{id}/start")).await
+    }
+
+    /// Remove the torrent from the session, keeping the files.
+    pub async fn forget(&self, id: TorrentId) -> anyhow::Result<()> {
+        self.post_empty(&format!("torrents/{id}/forget")).await
+    }
+
+    /// Remove the torrent from the session and delete its files.
+    pub async fn delete(&self, id: TorrentId) -> anyhow::Result<()> {
+        self.post_empty(&format!("torrents/{id}/delete")).await
+    }
+
+    pub async fn set_file_priorities(
+        &self,
+        id: TorrentId,
+        req: &TorrentFilePrioritiesRequest,
+    ) -> anyhow::Result<TorrentDetailsResponse> {
+        self.post_json(&format!("torrents/{id}/files"), Some(req))
+            .await
+    }
+
+    pub async fn set_sequential(&self, id: TorrentId, enabled: bool) -> anyhow::Result<()> {
+        self.post_empty(&format!("torrents/{id}/sequential?enabled={enabled}"))
+            .await
+    }
+
+    pub async fn move_torrent(
+        &self,
+        id: TorrentId,
+        req: &TorrentMoveRequest,
+    ) -> anyhow::Result<()> {
+        self.post_json::<EmptyJsonResponse>(&format!("torrents/{id}/move"), Some(req))
+            .await?;
+        Ok(())
+    }
+
+    pub async fn rename_files(
+        &self,
+        id: TorrentId,
+        req: &TorrentRenameFilesRequest,
+    ) -> anyhow::Result<TorrentDetailsResponse> {
+        self.post_json(&format!("torrents/{id}/rename"), Some(req))
+            .await
+    }
+
+    pub async fn add_peers(
+        &self,
+        id: TorrentId,
+        peers: Vec<SocketAddr>,
+    ) -> anyhow::Result<TorrentAddPeersResponse> {
+        let req = TorrentAddPeersRequest { peers };
+        self.post_json(&format!("torrents/{id}/peers"), Some(&req))
+            .await
+    }
+
+    pub async fn disconnect_peer(&self, id: TorrentId, addr: SocketAddr) -> anyhow::Result<()> {
+        let addr = urlencoding::encode(&addr.to_string()).into_owned();
+        self.post_empty(&format!("torrents/{id}/peers/{addr}/disconnect"))
+            .await
+    }
+
+    /// Ban the peer's IP for the duration, or forever.
+    pub async fn ban_peer(
+        &self,
+        id: TorrentId,
+        addr: SocketAddr,
+        duration: Option<Duration>,
+    ) -> anyhow::Result<()> {
+        let addr = urlencoding::encode(&addr.to_string()).into_owned();
+        let query = match duration {
+            Some(d) => format!("?duration_secs={}", d.as_secs()),
+            None => String::new(),
+        };
+        self.post_empty(&format!("torrents/{id}/peers/{addr}/ban{query}"))
+            .await
+    }
+
+    pub async fn dht_stats(&self) -> anyhow::Result<DhtStats> {
+        self.get_json("dht/stats").await
+    }
+
+    /// The DHT routing table, its format is not stable.
+    pub async fn dht_table(&self) -> anyhow::Result<serde_json::Value> {
+        self.get_json("dht/table").await
+    }
+
+    pub async fn disk_cache_stats(&self) -> anyhow::Result<DiskCacheStats> {
+        self.get_json("disk_cache/stats").await
+    }
+
+    pub async fn connection_stats(&self) -> anyhow::Result<ConnectionStats> {
+        self.get_json("connections/stats").await
+    }
+
+    pub async fn ip_filter_stats(&self) -> anyhow::Result<IpFilterStats> {
+        self.get_json("ip_filter").await
+    }
+
+    /// Make the server read its IP filter files again.
+    pub async fn reload_ip_filter(&self) -> anyhow::Result<IpFilterStats> {
+        self.post_json("ip_filter/reload", None::<&()>).await
+    }
+
+    pub async fn peer_bans(&self) -> anyhow::Result<Vec<PeerBan>> {
+        self.get_json("peer_bans").await
+    }
+
+    pub async fn unban_peer(&self, ip: IpAddr) -> anyhow::Result<()> {
+        self.post_empty(&format!("peer_bans/{ip}/remove")).await
+    }
+
+    /// Prometheus metrics, in the text format.
+    pub async fn metrics(&self) -> anyhow::Result<String> {
+        text_response(self.send(Method::GET, "metrics", None).await?).await
+    }
+
+    /// Change the server's log filter, same syntax as RUST_LOG.
+    pub async fn set_rust_log(&self, value: &str) -> anyhow::Result<()> {
+        let response = self
+            .send(
+                Method::POST,
+                "rust_log",
+                Some(Bytes::copy_from_slice(value.as_bytes())),
+            )
+            .await?;
+        json_response::<EmptyJsonResponse>(response).await?;
+        Ok(())
+    }
+
+    /// The server's log lines as they are written.
+    pub async fn stream_logs(&self) -> anyhow::Result<BodyStream> {
+        self.stream("stream_logs", HeaderMap::new()).await
+    }
+
+    /// Session events as they happen. An error is yielded if the server dropped events
+    /// because we were reading too slowly, the stream goes on after it.
+    pub async fn events(&self) -> anyhow::Result<BoxStream<'static, anyhow::Result<SessionEvent>>> {
+        let mut body = self.stream("events", HeaderMap::new()).await?;
+        let s = async_stream::stream! {
+            let mut buf = Vec::new();
+            while let Some(chunk) = body.next().await {
+                let chunk = match chunk {
+                    Ok(chunk) => chunk,
+                    Err(e) => {
+                        yield Err(e);
+                        return;
+                    }
+                };
+                // "\r\n" line endings become "\n". A "\r" can't be part of the JSON data,
+                // that escapes it.
+                buf.extend(chunk.iter().copied().filter(|b| *b != b'\r'));
+                while let Some(block) = take_sse_block(&mut buf) {
+                    match parse_sse_event(&block) {
+                        Some(("lagged", data)) => {
+                            yield Err(anyhow::anyhow!("the server dropped events: {data}"));
+                        }
+                        Some((_, data)) => {
+                            yield serde_json::from_str::<SessionEvent>(&data)
+                                .with_context(|| format!("error deserializing event {data:?}"));
+                        }
+                        None => {}
+                    }
+                }
+            }
+        };
+        Ok(s.boxed())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_sse_event, take_sse_block};
+
+    #[test]
+    fn test_parse_sse_event() {
+        assert_eq!(
+            parse_sse_event("event: torrent\ndata: {\"a\":1}"),
+            Some(("torrent", "{\"a\":1}".to_owned()))
+        );
+        assert_eq!(
+            parse_sse_event("data: line1\ndata:line2"),
+            Some(("message", "line1\nline2".to_owned()))
+        );
+        assert_eq!(parse_sse_event(":keep-alive"), None);
+    }
+
+    #[test]
+    fn test_take_sse_block() {
+        // "é" split across two chunks.
+        let event = "data: \"caf\u{e9}\"\n\n".as_bytes();
+        let split = event.len() - 4;
+        let mut buf = event[..split].to_vec();
+        assert_eq!(take_sse_block(&mut buf), None);
+        buf.extend_from_slice(&event[split..]);
+        buf.extend_from_slice(b"data: 1");
+        assert_eq!(
+            take_sse_block(&mut buf).as_deref(),
+            Some("data: \"caf\u{e9}\"")
+        );
+        assert_eq!(buf, b"data: 1");
+    }
 }
@@ -0,0 +1,238 @@
+// Serving the HTTP API over TLS and Unix domain sockets. Plain TCP goes through
+// axum::serve, these need their own accept loop.
+
+use std::{
+    io::BufReader,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use anyhow::Context;
+use axum::{extract::ConnectInfo, Router};
+use hyper::body::Incoming;
+use hyper_util::{
+    rt::{TokioExecutor, TokioIo},
+    server::conn::auto,
+};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_rustls::{
+    rustls::{
+        pki_types::{CertificateDer, PrivateKeyDer},
+        ServerConfig,
+    },
+    TlsAcceptor,
+};
+use tower::ServiceExt;
+use tracing::{debug, debug_span, info, warn};
+
+use crate::spawn_utils::spawn;
+
+const ACCEPT_ERROR_BACKOFF: Duration = Duration::from_millis(100);
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Serve HTTPS with a certificate and private key in PEM files.
+#[derive(Debug, Clone)]
+pub struct HttpApiTlsConfig {
+    /// The certificate chain, leaf certificate first.
+    pub cert_file: PathBuf,
+    pub key_file: PathBuf,
+}
+
+/// Listen on a Unix domain socket instead of TCP.
+#[derive(Debug, Clone)]
+pub struct HttpApiUnixSocket {
+    pub path: PathBuf,
+    /// Permissions of the socket file, e.g. 0o660 to only let the group in. The
+    /// process umask applies if not set.
+    pub mode: Option<u32>,
+}
+
+fn load_tls_config(config: &HttpApiTlsConfig) -> anyhow::Result<TlsAcceptor> {
+    let open = |path: &Path| {
+        std::fs::File::open(path)
+            .map(BufReader::new)
+            .with_context(|| format!("error opening {path:?}"))
+    };
+    let certs = rustls_pemfile::certs(&mut open(&config.cert_file)?)
+        .collect::<Result<Vec<CertificateDer<'static>>, _>>()
+        .with_context(|| format!("error reading certificates from {:?}", config.cert_file))?;
+    if certs.is_empty() {
+        anyhow::bail!("no certificates found in {:?}", config.cert_file);
+    }
+    let key: PrivateKeyDer<'static> = rustls_pemfile::private_key(&mut open(&config.key_file)?)
+        .with_context(|| format!("error reading private key from {:?}", config.key_file))?
+        .with_context(|| format!("no private key found in {:?}", config.key_file))?;
+
+    let mut server_config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .context("invalid certificate or private key")?;
+    server_config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    Ok(TlsAcceptor::from(Arc::new(server_config)))
+}
+
+async fn serve_connection<S>(stream: S, app: Router, remote: Option<SocketAddr>)
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let service = hyper::service::service_fn(move |mut request: hyper::Request<Incoming>| {
+        // Unix socket peers have no address, the auth middleware copes with that.
+        if let Some(remote) = remote {
+            request.extensions_mut().insert(ConnectInfo(remote));
+        }
+        app.clone().oneshot(request)
+    });
+    if let Err(e) = auto::Builder::new(TokioExecutor::new())
+        .serve_connection_with_upgrades(TokioIo::new(stream), service)
+        .await
+    {
+        debug!("error serving HTTP API connection: {e:#}");
+    }
+}
+
+async fn handle<S>(stream: S, tls: Option<TlsAcceptor>, app: Router, remote: Option<SocketAddr>)
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    match tls {
+        Some(acceptor) => {
+            match tokio::time::timeout(TLS_HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                Ok(Ok(stream)) => serve_connection(stream, app, remote).await,
+                Ok(Err(e)) => debug!("TLS handshake failed: {e:#}"),
+                Err(_) => debug!("TLS handshake timed out"),
+            }
+        }
+        None => serve_connection(stream, app, remote).await,
+    }
+}
+
+pub(crate) async fn serve_tcp(
+    addr: SocketAddr,
+    tls: &HttpApiTlsConfig,
+    app: Router,
+) -> anyhow::Result<()> {
+    let acceptor = load_tls_config(tls)?;
+    let listener = tokio::net::TcpListener::bind(&addr)
+        .await
+        .with_context(|| format!("error binding to {addr}"))?;
+    info!(%addr, "starting HTTPS server");
+    loop {
+        let (stream, remote) = match listener.accept().await {
+            Ok(s) => s,
+            Err(e) => {
+                warn!("error accepting HTTP API connection: {e:#}");
+                // E.g. out of file descriptors, don't spin.
+                tokio::time::sleep(ACCEPT_ERROR_BACKOFF).await;
+                continue;
+            }
+        };
+        let (acceptor, app) = (acceptor.clone(), app.clone());
+        spawn(
+            "http_api_connection",
+            debug_span!("http_api_connection", %remote),
+            async move {
+                handle(stream, Some(acceptor), app, Some(remote)).await;
+                Ok(())
+            },
+        );
+    }
+}
+
+#[cfg(unix)]
+pub(crate) async fn serve_unix(
+    socket: &HttpApiUnixSocket,
+    tls: Option<&HttpApiTlsConfig>,
+    app: Router,
+) -> anyhow::Result<()> {
+    use std::os::unix::fs::FileTypeExt;
+
+    let acceptor = tls.map(load_tls_config).transpose()?;
+    let path = &socket.path;
+
+    // A socket left over from a previous run would make bind() fail. Only remove it if
+    // nobody is listening on it anymore.
+    if let Ok(meta) = std::fs::symlink_metadata(path) {
+        if !meta.file_type().is_socket() {
+            anyhow::bail!("{path:?} exists and is not a socket");
+        }
+        if tokio::net::UnixStream::connect(path).await.is_ok() {
+            anyhow::bail!("{path:?} is in use by another process");
+        }
+        std::fs::remove_file(path).with_context(|| format!("error removing stale {path:?}"))?;
+    }
+
+    let listener = match socket.mode {
+        Some(mode) => bind_unix_with_mode(path, mode)?,
+        None => tokio::net::UnixListener::bind(path)
+            .with_context(|| format!("error binding to {path:?}"))?,
+    };
+    info!(
+        ?path,
+        tls = acceptor.is_some(),
+        "starting HTTP server on Unix socket"
+    );
+    loop {
+        let (stream, _) = match listener.accept().await {
+            Ok(s) => s,
+            Err(e) => {
+                warn!("error accepting HTTP API connection: {e:#}");
+                // E.g. out of file descriptors, don't spin.
+                tokio::time::sleep(ACCEPT_ERROR_BACKOFF).await;
+                continue;
+            }
+        };
+        let (acceptor, app) = (acceptor.clone(), app.clone());
+        spawn(
+            "http_api_connection",
+            debug_span!("http_api_connection"),
+            async move {
+                handle(stream, acceptor, app, None).await;
+                Ok(())
+            },
+        );
+    }
+}
+
+// The socket is connectable as soon as it's bound, so it's bound in a folder only we can
+// enter, and moved into place once it has its permissions.
+#[cfg(unix)]
+fn bind_unix_with_mode(path: &Path, mode: u32) -> anyhow::Result<tokio::net::UnixListener> {
+    use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+
+    let file_name = path
+        .file_name()
+        .with_context(|| format!("{path:?} is not a file path"))?;
+    let mut dir_name = std::ffi::OsString::from(".");
+    dir_name.push(file_name);
+    dir_name.push(format!(".{}.tmp", std::process::id()));
+    let dir = path.with_file_name(dir_name);
+    std::fs::DirBuilder::new()
+        .mode(0o700)
+        .create(&dir)
+        .with_context(|| format!("error creating {dir:?}"))?;
+
+    let tmp = dir.join("socket");
+    let result = tokio::net::UnixListener::bind(&tmp)
+        .with_context(|| format!("error binding to {tmp:?}"))
+        .and_then(|listener| {
+            std::fs::set_permissions(&tmp, std::fs::Permissions::from_mode(mode))
+                .with_context(|| format!("error setting permissions {mode:o} on {tmp:?}"))?;
+            std::fs::rename(&tmp, path)
+                .with_context(|| format!("error moving {tmp:?} to {path:?}"))?;
+            Ok(listener)
+        });
+    let _ = std::fs::remove_file(&tmp);
+    let _ = std::fs::remove_dir(&dir);
+    result
+}
+
+#[cfg(not(unix))]
+pub(crate) async fn serve_unix(
+    _socket: &HttpApiUnixSocket,
+    _tls: Option<&HttpApiTlsConfig>,
+    _app: Router,
+) -> anyhow::Result<()> {
+    anyhow::bail!("Unix sockets are not supported on this platform")
+}
@@ -0,0 +1,484 @@
+// Blocking peers by IP address, with lists in the formats other clients use:
+//
+//   eMule ipfilter.dat:  001.002.003.000 - 001.002.003.255 , 000 , Some organization
+//   PeerGuardian .p2p:   Some organization:1.2.3.0-1.2.3.255
+//   CIDR or single IPs:  1.2.3.0/24, 2001:db8::/32, 1.2.3.4
+//
+// Files may be gzipped. All ranges are merged into sorted, non-overlapping lists, so a
+// lookup is a binary search.
+
+use std::{
+    collections::HashMap,
+    io::{BufRead, BufReader, Read},
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Context};
+use parking_lot::RwLock;
+use serde::{Deserialize, Serialize};
+use tracing::{info, trace, warn};
+
+/// Peers that sent data for this many pieces that failed the hash check get banned.
+pub const DEFAULT_PEER_BAN_THRESHOLD: u32 = 3;
+
+// eMule lists allow ranges with an access level above this.
+const EMULE_MAX_BLOCKED_LEVEL: u32 = 127;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum IpRange {
+    V4(u32, u32),
+    V6(u128, u128),
+}
+
+fn parse_ip(s: &str) -> anyhow::Result<IpAddr> {
+    let s = s.trim();
+    if let Ok(ip) = s.parse() {
+        return Ok(ip);
+    }
+    // eMule lists pad the octets with zeros, e.g. 001.002.003.004, which std rejects.
+    let octets = s
+        .split('.')
+        .map(|o| o.parse::<u8>())
+        .collect::<Result<Vec<_>, _>>()
+        .ok()
+        .and_then(|o| <[u8; 4]>::try_from(o).ok())
+        .with_context(|| format!("invalid IP address {s:?}"))?;
+    Ok(IpAddr::V4(Ipv4Addr::from(octets)))
+}
+
+fn range(start: IpAddr, end: IpAddr) -> anyhow::Result<IpRange> {
+    let r = match (start.to_canonical(), end.to_canonical()) {
+        (IpAddr::V4(s), IpAddr::V4(e)) => IpRange::V4(s.into(), e.into()),
+        (IpAddr::V6(s), IpAddr::V6(e)) => IpRange::V6(s.into(), e.into()),
+        _ => bail!("{start} and {end} are not the same IP version"),
+    };
+    match r {
+        IpRange::V4(s, e) if s > e => bail!("{start} is after {end}"),
+        IpRange::V6(s, e) if s > e => bail!("{start} is after {end}"),
+        r => Ok(r),
+    }
+}
+
+fn parse_cidr(s: &str) -> anyhow::Result<IpRange> {
+    let (ip, prefix) = s.split_once('/').context("expected a CIDR")?;
+    let ip = parse_ip(ip)?;
+    let prefix: u32 = prefix.trim().parse().context("invalid prefix length")?;
+    match ip {
+        IpAddr::V4(ip) if prefix <= 32 => {
+            let mask = u32::MAX.checked_shl(32 - prefix).unwrap_or(0);
+            let start = u32::from(ip) & mask;
+            Ok(IpRange::V4(start, start | !mask))
+        }
+        IpAddr::V6(ip) if prefix <= 128 => {
+            let mask = u128::MAX.checked_shl(128 - prefix).unwrap_or(0);
+            let start = u128::from(ip) & mask;
+            Ok(IpRange::V6(start, start | !mask))
+        }
+        _ => bail!("invalid prefix length {prefix} for {ip}"),
+    }
+}
+
+// "a-b", "a - b", "a/n" or "a".
+fn parse_range(s: &str) -> anyhow::Result<IpRange> {
+    let s = s.trim();
+    if s.contains('/') {
+        return parse_cidr(s);
+    }
+    if let Some((start, end)) = s.split_once('-') {
+        return range(parse_ip(start)?, parse_ip(end)?);
+    }
+    let ip = parse_ip(s)?;
+    range(ip, ip)
+}
+
+// Ok(None) for comments and ranges that are allowed.
+fn parse_line(line: &str) -> anyhow::Result<Option<IpRange>> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') || line.starts_with("//") {
+        return Ok(None);
+    }
+    // eMule: "range , level , description". PeerGuardian descriptions may contain commas
+    // too, so only lines starting with a range are taken for eMule ones.
+    if let Some((range, rest)) = line.split_once(',') {
+        if let Ok(range) = parse_range(range) {
+            let level = rest.split(',').next().unwrap_or_default().trim();
+            let level: u32 = level
+                .parse()
+                .with_context(|| format!("invalid access level {level:?}"))?;
+            if level > EMULE_MAX_BLOCKED_LEVEL {
+                return Ok(None);
+            }
+            return Ok(Some(range));
+        }
+    }
+    match parse_range(line) {
+        Ok(r) => Ok(Some(r)),
+        // PeerGuardian: "description:range". The description may contain colons too.
+        Err(e) => match line.rsplit_once(':') {
+            Some((_, range)) if range.contains('-') => parse_range(range).map(Some),
+            _ => Err(e),
+        },
+    }
+}
+
+// Sort and merge overlapping or adjacent ranges.
+fn merge<T: Ord + Copy>(mut ranges: Vec<(T, T)>, next: impl Fn(T) -> Option<T>) -> Vec<(T, T)> {
+    ranges.sort_unstable();
+    let mut merged: Vec<(T, T)> = Vec::with_capacity(ranges.len());
+    for (start, end) in ranges {
+        match merged.last_mut() {
+            Some(last) if next(last.1).map_or(true, |n| start <= n) => {
+                last.1 = last.1.max(end);
+            }
+            _ => merged.push((start, end)),
+        }
+    }
+    merged
+}
+
+fn contains<T: Ord + Copy>(ranges: &[(T, T)], value: T) -> bool {
+    // The first range that ends at or after the value is the only candidate.
+    let idx = ranges.partition_point(|(_, end)| *end < value);
+    ranges.get(idx).is_some_and(|(start, _)| *start <= value)
+}
+
+/// A set of blocked IP ranges.
+#[derive(Debug, Default, Clone)]
+pub struct IpFilter {
+    v4: Vec<(u32, u32)>,
+    v6: Vec<(u128, u128)>,
+}
+
+impl IpFilter {
+    /// Parse a list in eMule .dat, PeerGuardian .p2p or CIDR format, plain or gzipped.
+    /// Lines that can't be parsed are skipped with a warning.
+    pub fn parse(reader: impl Read, name: &str) -> anyhow::Result<Self> {
+        let mut reader = BufReader::new(reader);
+        let gzipped = reader.fill_buf()?.starts_with(&[0x1f, 0x8b]);
+        let reader: Box<dyn BufRead> = if gzipped {
+            Box::new(BufReader::new(flate2::bufread::MultiGzDecoder::new(reader)))
+        } else {
+            Box::new(reader)
+        };
+
+        let (mut v4, mut v6) = (Vec::new(), Vec::new());
+        let mut invalid = 0;
+        for (idx, line) in reader.split(b'\n').enumerate() {
+            let line = line.with_context(|| format!("error reading {name}"))?;
+            // Descriptions aren't always UTF-8.
+            match parse_line(&String::from_utf8_lossy(&line)) {
+                Ok(Some(IpRange::V4(s, e))) => v4.push((s, e)),
+                Ok(Some(IpRange::V6(s, e))) => v6.push((s, e)),
+                Ok(None) => {}
+                Err(e) => {
+                    invalid += 1;
+                    if invalid <= 10 {
+                        warn!("{name}:{}: {e:#}", idx + 1);
+                    }
+                }
+            }
+        }
+        if invalid > 0 {
+            warn!("skipped {invalid} invalid lines in {name}");
+        }
+        Ok(Self {
+            v4: merge(v4, |v| v.checked_add(1)),
+            v6: merge(v6, |v| v.checked_add(1)),
+        })
+    }
+
+    /// Load and merge the lists in the files.
+    pub fn from_files(files: &[PathBuf]) -> anyhow::Result<Self> {
+        let mut filter = Self::default();
+        for path in files {
+            let file =
+                std::fs::File::open(path).with_context(|| format!("error opening {path:?}"))?;
+            filter.extend(Self::parse(file, &path.display().to_string())?);
+        }
+        Ok(filter)
+    }
+
+    fn extend(&mut self, other: Self) {
+        let v4 = std::mem::take(&mut self.v4).into_iter().chain(other.v4);
+        self.v4 = merge(v4.collect(), |v| v.checked_add(1));
+        let v6 = std::mem::take(&mut self.v6).into_iter().chain(other.v6);
+        self.v6 = merge(v6.collect(), |v| v.checked_add(1));
+    }
+
+    pub fn is_blocked(&self, ip: IpAddr) -> bool {
+        match ip.to_canonical() {
+            IpAddr::V4(ip) => contains(&self.v4, u32::from(ip)),
+            IpAddr::V6(ip) => contains(&self.v6, u128::from(ip)),
+        }
+    }
+
+    /// The number of ranges, after merging overlapping ones.
+    pub fn len(&self) -> usize {
+        self.v4.len() + self.v6.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Where a blocked address came from.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum IpFilterSource {
+    Incoming,
+    /// Connecting to a peer we heard of elsewhere, e.g. passed in initial_peers.
+    Outgoing,
+    Tracker,
+    Dht,
+}
+
+/// The IP filter of a session and how many peers it blocked.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct IpFilterStats {
+    pub files: Vec<PathBuf>,
+    /// Blocked ranges, after merging overlapping ones.
+    pub ranges: usize,
+    pub blocked_incoming: u64,
+    pub blocked_outgoing: u64,
+    pub blocked_tracker: u64,
+    pub blocked_dht: u64,
+    pub banned_peers: usize,
+}
+
+/// A peer that is not allowed to connect, e.g. because it sent corrupt data.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PeerBan {
+    pub ip: IpAddr,
+    pub reason: String,
+    /// Seconds until the ban is lifted, none if it's permanent.
+    #[serde(default)]
+    pub expires_in_secs: Option<u64>,
+}
+
+struct BanEntry {
+    reason: String,
+    until: Option<Instant>,
+}
+
+impl BanEntry {
+    fn is_active(&self, now: Instant) -> bool {
+        self.until.map_or(true, |until| until > now)
+    }
+}
+
+/// The session's filter, shared with its torrents: the lists from the files, and the banned
+/// peers. Reloading swaps the whole list, bans stay.
+#[derive(Default)]
+pub(crate) struct SessionIpFilter {
+    files: RwLock<Vec<PathBuf>>,
+    filter: RwLock<Arc<IpFilter>>,
+    bans: RwLock<HashMap<IpAddr, BanEntry>>,
+    // 0 disables banning.
+    ban_threshold: u32,
+    blocked_incoming: AtomicU64,
+    blocked_outgoing: AtomicU64,
+    blocked_tracker: AtomicU64,
+    blocked_dht: AtomicU64,
+}
+
+impl SessionIpFilter {
+    pub fn new(ban_threshold: u32) -> Self {
+        Self {
+            ban_threshold,
+            ..Default::default()
+        }
+    }
+
+    /// Read the files and start using them. The current filter is kept on errors.
+    pub fn load(&self, files: Vec<PathBuf>) -> anyhow::Result<()> {
+        let filter = IpFilter::from_files(&files)?;
+        if !files.is_empty() {
+            info!(?files, ranges = filter.len(), "loaded IP filter");
+        }
+        *self.filter.write() = Arc::new(filter);
+        *self.files.write() = files;
+        Ok(())
+    }
+
+    pub fn reload(&self) -> anyhow::Result<()> {
+        let files = self.files.read().clone();
+        self.load(files)
+    }
+
+    /// Check the address, and count it if it's blocked.
+    pub fn is_blocked(&self, addr: SocketAddr, source: IpFilterSource) -> bool {
+        let ip = addr.ip().to_canonical();
+        if !self.is_banned(ip) && !self.filter.read().is_blocked(ip) {
+            return false;
+        }
+        trace!(%addr, ?source, "blocked by the IP filter");
+        let counter = match source {
+            IpFilterSource::Incoming => &self.blocked_incoming,
+            IpFilterSource::Outgoing => &self.blocked_outgoing,
+            IpFilterSource::Tracker => &self.blocked_tracker,
+            IpFilterSource::Dht => &self.blocked_dht,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        true
+    }
+
+    pub fn stats(&self) -> IpFilterStats {
+        IpFilterStats {
+            files: self.files.read().clone(),
+            ranges: self.filter.read().len(),
+            blocked_incoming: self.blocked_incoming.load(Ordering::Relaxed),
+            blocked_outgoing: self.blocked_outgoing.load(Ordering::Relaxed),
+            blocked_tracker: self.blocked_tracker.load(Ordering::Relaxed),
+            blocked_dht: self.blocked_dht.load(Ordering::Relaxed),
+            banned_peers: self.bans().len(),
+        }
+    }
+
+    /// Ban the peer if it sent corrupt data `hash_failures` times. Returns true if it's banned.
+    pub fn ban_for_hash_failures(&self, addr: SocketAddr, hash_failures: u32) -> bool {
+        if self.ban_threshold == 0 || hash_failures < self.ban_threshold {
+            return false;
+        }
+        warn!(%addr, hash_failures, "banning peer for sending corrupt data");
+        self.ban(
+            addr.ip(),
+            format!("sent corrupt data for {hash_failures} pieces"),
+            None,
+        );
+        true
+    }
+
+    /// Ban the IP for the duration, or forever. Replaces an existing ban.
+    pub fn ban(&self, ip: IpAddr, reason: String, duration: Option<Duration>) {
+        let now = Instant::now();
+        let mut bans = self.bans.write();
+        bans.retain(|_, b| b.is_active(now));
+        bans.insert(
+            ip.to_canonical(),
+            BanEntry {
+                reason,
+                until: duration.and_then(|d| now.checked_add(d)),
+            },
+        );
+    }
+
+    fn is_banned(&self, ip: IpAddr) -> bool {
+        self.bans
+            .read()
+            .get(&ip)
+            .is_some_and(|b| b.is_active(Instant::now()))
+    }
+
+    pub fn bans(&self) -> Vec<PeerBan> {
+        let now = Instant::now();
+        let mut bans = self
+            .bans
+            .read()
+            .iter()
+            .filter(|(_, b)| b.is_active(now))
+            .map(|(ip, b)| PeerBan {
+                ip: *ip,
+                reason: b.reason.clone(),
+                expires_in_secs: b
+                    .until
+                    .map(|until| until.duration_since(now).as_secs_f64().ceil() as u64),
+            })
+            .collect::<Vec<_>>();
+        bans.sort_by_key(|b| b.ip);
+        bans
+    }
+
+    /// Returns false if the IP wasn't banned.
+    pub fn unban(&self, ip: IpAddr) -> bool {
+        self.bans
+            .write()
+            .remove(&ip.to_canonical())
+            .is_some_and(|b| b.is_active(Instant::now()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::Write,
+        net::{IpAddr, SocketAddr},
+        time::Duration,
+    };
+
+    use super::{IpFilter, IpFilterSource, SessionIpFilter, DEFAULT_PEER_BAN_THRESHOLD};
+
+    fn blocked(filter: &IpFilter, ip: &str) -> bool {
+        filter.is_blocked(ip.parse::<IpAddr>().unwrap())
+    }
+
+    #[test]
+    fn test_formats() {
+        let list = "\
+# comment
+001.002.003.000 - 001.002.003.255 , 000 , Blocked org
+005.000.000.000 - 005.255.255.255 , 200 , Allowed org
+Some: org:10.0.0.0-10.0.0.9
+Some Corp, Inc:11.0.0.0-11.0.0.255
+10.0.0.10-10.0.0.20
+192.168.0.0/16
+8.8.8.8
+2001:db8::/32
+not an ip
+";
+        let filter = IpFilter::parse(list.as_bytes(), "test").unwrap();
+        assert!(blocked(&filter, "1.2.3.4"));
+        assert!(!blocked(&filter, "1.2.4.0"));
+        assert!(!blocked(&filter, "5.1.1.1"));
+        assert!(blocked(&filter, "10.0.0.0"));
+        assert!(blocked(&filter, "10.0.0.20"));
+        assert!(!blocked(&filter, "10.0.0.21"));
+        assert!(blocked(&filter, "11.0.0.128"));
+        assert!(blocked(&filter, "192.168.255.255"));
+        assert!(blocked(&filter, "8.8.8.8"));
+        assert!(!blocked(&filter, "8.8.8.9"));
+        assert!(blocked(&filter, "2001:db8:1::1"));
+        assert!(!blocked(&filter, "2001:db9::1"));
+        // IPv4-mapped IPv6 addresses are checked as IPv4.
+        assert!(blocked(&filter, "::ffff:8.8.8.8"));
+        // 10.0.0.0-9 and 10.0.0.10-20 are merged.
+        assert_eq!(filter.len(), 6);
+    }
+
+    #[test]
+    fn test_gzip() {
+        let mut gz = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        gz.write_all(b"1.0.0.0/8\n").unwrap();
+        let filter = IpFilter::parse(&gz.finish().unwrap()[..], "test.gz").unwrap();
+        assert!(blocked(&filter, "1.255.0.1"));
+        assert!(!blocked(&filter, "2.0.0.1"));
+    }
+
+    #[test]
+    fn test_bans() {
+        let filter = SessionIpFilter::new(DEFAULT_PEER_BAN_THRESHOLD);
+        let addr: SocketAddr = "1.2.3.4:6881".parse().unwrap();
+        let banned = || filter.is_blocked(addr, IpFilterSource::Outgoing);
+
+        assert!(!filter.ban_for_hash_failures(addr, DEFAULT_PEER_BAN_THRESHOLD - 1));
+        assert!(filter.ban_for_hash_failures(addr, DEFAULT_PEER_BAN_THRESHOLD));
+        assert!(banned());
+        assert_eq!(filter.bans()[0].expires_in_secs, None);
+
+        // Replacing it with a ban that is over already.
+        filter.ban(addr.ip(), "test".to_owned(), Some(Duration::ZERO));
+        assert!(!banned());
+        assert!(filter.bans().is_empty());
+        assert!(!filter.unban(addr.ip()));
+
+        filter.ban(addr.ip(), "test".to_owned(), Some(Duration::from_secs(60)));
+        assert_eq!(filter.bans()[0].expires_in_secs, Some(60));
+        assert!(filter.unban(addr.ip()));
+        assert!(!banned());
+    }
+}
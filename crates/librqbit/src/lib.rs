@@ -25,31 +25,55 @@
 pub mod api;
 mod api_error;
 mod chunk_tracker;
+mod connection_limits;
 mod create_torrent_file;
+mod cross_seed;
 mod dht_utils;
+mod disk_cache;
+mod events;
 mod file_ops;
+mod file_priority;
+mod hooks;
 pub mod http_api;
+mod http_api_auth;
 pub mod http_api_client;
+mod http_api_listener;
+mod ip_filter;
+mod metrics;
+mod network_binding;
 mod peer_connection;
 mod peer_info_reader;
+mod proxy;
 mod read_buf;
 mod session;
 mod spawn_utils;
+pub mod storage;
 mod torrent_state;
 pub mod tracing_subscriber_config_utils;
 mod type_aliases;
 
 pub use api::Api;
 pub use api_error::ApiError;
+pub use connection_limits::{ConnectionLimits, ConnectionStats};
 pub use create_torrent_file::{create_torrent, CreateTorrentOptions};
 pub use dht;
+pub use disk_cache::{DiskCacheStats, DEFAULT_DISK_CACHE_SIZE};
+pub use events::{SessionEvent, SESSION_EVENTS_CAPACITY};
+pub use file_priority::FilePriority;
+pub use hooks::{Hook, HookAction, TorrentEvent, TorrentEventKind, DEFAULT_HOOK_TIMEOUT};
+pub use ip_filter::{IpFilter, IpFilterStats, PeerBan, DEFAULT_PEER_BAN_THRESHOLD};
+pub use network_binding::NetworkBinding;
 pub use peer_connection::PeerConnectionOptions;
+pub use proxy::ProxyConfig;
 pub use session::{
     AddTorrent, AddTorrentOptions, AddTorrentResponse, ListOnlyResponse, Session, SessionOptions,
     SUPPORTED_SCHEMES,
 };
 pub use spawn_utils::spawn as librqbit_spawn;
-pub use torrent_state::{ManagedTorrent, ManagedTorrentState, TorrentStats, TorrentStatsState};
+pub use torrent_state::{
+    FileStream, ManagedTorrent, ManagedTorrentInfo, ManagedTorrentState, TorrentStats,
+    TorrentStatsState,
+};
 
 pub use buffers::*;
 pub use clone_to_owned::CloneToOwned;
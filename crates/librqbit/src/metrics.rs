@@ -0,0 +1,446 @@
+// Prometheus metrics, for GET /metrics.
+//
+// Most of the values are read from the torrents' stats when scraped. Only the session-wide
+// counters below are updated as things happen, with relaxed atomic increments.
+
+use std::{
+    fmt::Write,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+
+use crate::{session::Session, torrent_state::TorrentStatsState};
+
+// Upper bounds of the latency histogram buckets, in seconds.
+const LATENCY_BUCKETS: [f64; 10] = [
+    0.0001, 0.00025, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.05, 0.25, 1.0,
+];
+
+#[derive(Default)]
+pub(crate) struct LatencyHistogram {
+    buckets: [AtomicU64; LATENCY_BUCKETS.len()],
+    count: AtomicU64,
+    sum_micros: AtomicU64,
+}
+
+impl LatencyHistogram {
+    pub fn observe(&self, elapsed: Duration) {
+        let secs = elapsed.as_secs_f64();
+        if let Some(idx) = LATENCY_BUCKETS.iter().position(|b| secs <= *b) {
+            self.buckets[idx].fetch_add(1, Ordering::Relaxed);
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_micros
+            .fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    pub fn time<R>(&self, f: impl FnOnce() -> R) -> R {
+        let start = Instant::now();
+        let r = f();
+        self.observe(start.elapsed());
+        r
+    }
+
+    fn write(&self, out: &mut String, name: &str, help: &str) {
+        header(out, name, help, "histogram");
+        // Buckets are stored individually, Prometheus wants them cumulative.
+        let mut cumulative = 0;
+        for (bound, bucket) in LATENCY_BUCKETS.iter().zip(self.buckets.iter()) {
+            cumulative += bucket.load(Ordering::Relaxed);
+            let _ = writeln!(out, "{name}_bucket{{le=\"{bound}\"}} {cumulative}");
+        }
+        let count = self.count.load(Ordering::Relaxed);
+        let _ = writeln!(out, "{name}_bucket{{le=\"+Inf\"}} {count}");
+        let sum = self.sum_micros.load(Ordering::Relaxed) as f64 / 1_000_000f64;
+        let _ = writeln!(out, "{name}_sum {sum}");
+        let _ = writeln!(out, "{name}_count {count}");
+    }
+}
+
+/// Session-wide counters that outlive the torrents they were counted for.
+#[derive(Default)]
+pub(crate) struct SessionMetrics {
+    pub fetched_bytes: AtomicU64,
+    pub uploaded_bytes: AtomicU64,
+    pub hash_failures: AtomicU64,
+    pub tracker_announces: AtomicU64,
+    pub tracker_announce_failures: AtomicU64,
+    pub disk_reads: LatencyHistogram,
+    pub disk_writes: LatencyHistogram,
+}
+
+impl SessionMetrics {
+    pub fn inc(counter: &AtomicU64, by: u64) {
+        counter.fetch_add(by, Ordering::Relaxed);
+    }
+}
+
+fn header(out: &mut String, name: &str, help: &str, kind: &str) {
+    let _ = writeln!(out, "# HELP {name} {help}");
+    let _ = writeln!(out, "# TYPE {name} {kind}");
+}
+
+fn metric(out: &mut String, name: &str, help: &str, kind: &str, value: impl std::fmt::Display) {
+    header(out, name, help, kind);
+    let _ = writeln!(out, "{name} {value}");
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+struct TorrentRow {
+    labels: String,
+    progress: f64,
+    download_speed: f64,
+    upload_speed: f64,
+    uploaded_bytes: u64,
+    fetched_bytes: u64,
+    hash_failures: u64,
+    peers: [(&'static str, usize); 4],
+    state: TorrentStatsState,
+}
+
+/// Render the session's metrics in the Prometheus text format.
+pub(crate) fn render(session: &Session) -> String {
+    let mut out = String::new();
+    let m = session.metrics();
+    let load = |c: &AtomicU64| c.load(Ordering::Relaxed);
+
+    let rows = session.with_torrents(|torrents| {
+        torrents
+            .map(|(id, t)| {
+                let stats = t.stats();
+                let name = t.info().info.name.as_ref().map(|n| n.to_string());
+                let labels = format!(
+                    "id=\"{id}\",info_hash=\"{}\",name=\"{}\"",
+                    t.info_hash().as_string(),
+                    escape_label(name.as_deref().unwrap_or_default())
+                );
+                let live = stats.live.as_ref();
+                let snapshot = live.map(|l| &l.snapshot);
+                let peers = snapshot.map(|s| &s.peer_stats);
+                TorrentRow {
+                    labels,
+                    progress: if stats.total_bytes == 0 {
+                        1f64
+                    } else {
+                        stats.progress_bytes as f64 / stats.total_bytes as f64
+                    },
+                    download_speed: live.map(|l| l.download_speed.mbps).unwrap_or_default()
+                        * 1024f64
+                        * 1024f64,
+                    upload_speed: live.map(|l| l.upload_speed.mbps).unwrap_or_default()
+                        * 1024f64
+                        * 1024f64,
+                    uploaded_bytes: stats.uploaded_bytes,
+                    fetched_bytes: snapshot.map(|s| s.fetched_bytes).unwrap_or_default(),
+                    hash_failures: snapshot.map(|s| s.hash_failed_pieces).unwrap_or_default(),
+                    peers: [
+                        ("live", peers.map(|p| p.live).unwrap_or_default()),
+                        (
+                            "connecting",
+                            peers.map(|p| p.connecting).unwrap_or_default(),
+                        ),
+                        ("queued", peers.map(|p| p.queued).unwrap_or_default()),
+                        ("dead", peers.map(|p| p.dead).unwrap_or_default()),
+                    ],
+                    state: stats.state,
+                }
+            })
+            .collect::<Vec<_>>()
+    });
+
+    metric(
+        &mut out,
+        "rqbit_torrents",
+        "Torrents in the session",
+        "gauge",
+        rows.len(),
+    );
+    metric(
+        &mut out,
+        "rqbit_downloaded_bytes_total",
+        "Bytes received from peers",
+        "counter",
+        load(&m.fetched_bytes),
+    );
+    metric(
+        &mut out,
+        "rqbit_uploaded_bytes_total",
+        "Bytes sent to peers",
+        "counter",
+        load(&m.uploaded_bytes),
+    );
+    metric(
+        &mut out,
+        "rqbit_peers_connected",
+        "Live peer connections",
+        "gauge",
+        rows.iter().map(|r| r.peers[0].1).sum::<usize>(),
+    );
+    metric(
+        &mut out,
+        "rqbit_hash_failures_total",
+        "Downloaded pieces that did not match their hash",
+        "counter",
+        load(&m.hash_failures),
+    );
+
+    header(
+        &mut out,
+        "rqbit_tracker_announces_total",
+        "Tracker announces by result",
+        "counter",
+    );
+    let _ = writeln!(
+        out,
+        "rqbit_tracker_announces_total{{result=\"success\"}} {}",
+        load(&m.tracker_announces)
+    );
+    let _ = writeln!(
+        out,
+        "rqbit_tracker_announces_total{{result=\"failure\"}} {}",
+        load(&m.tracker_announce_failures)
+    );
+
+    if let Some(dht) = session.get_dht() {
+        let stats = dht.stats();
+        metric(
+            &mut out,
+            "rqbit_dht_nodes",
+            "Nodes in the DHT routing table",
+            "gauge",
+            stats.routing_table_size,
+        );
+        metric(
+            &mut out,
+            "rqbit_dht_outstanding_requests",
+            "DHT requests waiting for a response",
+            "gauge",
+            stats.outstanding_requests,
+        );
+    }
+
+    let connections = session.connection_stats();
+    metric(
+        &mut out,
+        "rqbit_connections",
+        "Peer connections of all torrents, including ones being set up",
+        "gauge",
+        connections.connections,
+    );
+    metric(
+        &mut out,
+        "rqbit_connections_half_open",
+        "Outgoing peer connects that haven't finished yet",
+        "gauge",
+        connections.half_open,
+    );
+    header(
+        &mut out,
+        "rqbit_connection_limit",
+        "Limits on peer connections",
+        "gauge",
+    );
+    let limits = &connections.limits;
+    for (kind, value) in [
+        ("session", limits.max_connections),
+        ("torrent", limits.max_connections_per_torrent),
+        ("half_open", limits.max_half_open),
+    ] {
+        let _ = writeln!(out, "rqbit_connection_limit{{kind=\"{kind}\"}} {value}");
+    }
+    metric(
+        &mut out,
+        "rqbit_incoming_connections_rejected_total",
+        "Incoming connections closed because of connection limits",
+        "counter",
+        connections.rejected_incoming,
+    );
+    metric(
+        &mut out,
+        "rqbit_replaced_peers_total",
+        "Idle peers disconnected to make room for others",
+        "counter",
+        connections.replaced_peers,
+    );
+
+    let ip_filter = session.ip_filter_stats();
+    metric(
+        &mut out,
+        "rqbit_ip_filter_ranges",
+        "IP ranges blocked by the IP filter",
+        "gauge",
+        ip_filter.ranges,
+    );
+    metric(
+        &mut out,
+        "rqbit_peer_bans",
+        "Peers banned for sending corrupt data",
+        "gauge",
+        ip_filter.banned_peers,
+    );
+    header(
+        &mut out,
+        "rqbit_ip_filter_blocked_total",
+        "Peer addresses blocked by the IP filter, by where they came from",
+        "counter",
+    );
+    for (source, count) in [
+        ("incoming", ip_filter.blocked_incoming),
+        ("outgoing", ip_filter.blocked_outgoing),
+        ("tracker", ip_filter.blocked_tracker),
+        ("dht", ip_filter.blocked_dht),
+    ] {
+        let _ = writeln!(
+            out,
+            "rqbit_ip_filter_blocked_total{{source=\"{source}\"}} {count}"
+        );
+    }
+
+    let cache = session.disk_cache_stats();
+    metric(
+        &mut out,
+        "rqbit_disk_cache_read_hits_total",
+        "Piece reads served from the disk cache",
+        "counter",
+        cache.read_hits,
+    );
+    metric(
+        &mut out,
+        "rqbit_disk_cache_read_misses_total",
+        "Piece reads that went to disk",
+        "counter",
+        cache.read_misses,
+    );
+    m.disk_reads.write(
+        &mut out,
+        "rqbit_disk_read_duration_seconds",
+        "Latency of reads from torrent storage",
+    );
+    m.disk_writes.write(
+        &mut out,
+        "rqbit_disk_write_duration_seconds",
+        "Latency of writes to torrent storage",
+    );
+
+    let per_torrent = |out: &mut String,
+                       name: &str,
+                       help: &str,
+                       kind: &str,
+                       value: &dyn Fn(&TorrentRow) -> String| {
+        header(out, name, help, kind);
+        for row in rows.iter() {
+            let _ = writeln!(out, "{name}{{{}}} {}", row.labels, value(row));
+        }
+    };
+    per_torrent(
+        &mut out,
+        "rqbit_torrent_progress_ratio",
+        "Share of the selected data that was downloaded, 0 to 1",
+        "gauge",
+        &|r| r.progress.to_string(),
+    );
+    per_torrent(
+        &mut out,
+        "rqbit_torrent_download_speed_bytes",
+        "Download speed in bytes per second",
+        "gauge",
+        &|r| r.download_speed.to_string(),
+    );
+    per_torrent(
+        &mut out,
+        "rqbit_torrent_upload_speed_bytes",
+        "Upload speed in bytes per second",
+        "gauge",
+        &|r| r.upload_speed.to_string(),
+    );
+    per_torrent(
+        &mut out,
+        "rqbit_torrent_downloaded_bytes_total",
+        "Bytes received from peers since the torrent went live",
+        "counter",
+        &|r| r.fetched_bytes.to_string(),
+    );
+    per_torrent(
+        &mut out,
+        "rqbit_torrent_uploaded_bytes_total",
+        "Bytes sent to peers",
+        "counter",
+        &|r| r.uploaded_bytes.to_string(),
+    );
+    per_torrent(
+        &mut out,
+        "rqbit_torrent_hash_failures_total",
+        "Pieces that did not match their hash since the torrent went live",
+        "counter",
+        &|r| r.hash_failures.to_string(),
+    );
+
+    header(
+        &mut out,
+        "rqbit_torrent_peers",
+        "Peers of the torrent by state",
+        "gauge",
+    );
+    for row in rows.iter() {
+        for (state, count) in row.peers {
+            let _ = writeln!(
+                out,
+                "rqbit_torrent_peers{{{},state=\"{state}\"}} {count}",
+                row.labels
+            );
+        }
+    }
+
+    header(
+        &mut out,
+        "rqbit_torrent_state",
+        "1 for the state the torrent is in",
+        "gauge",
+    );
+    for row in rows.iter() {
+        for state in [
+            TorrentStatsState::Initializing,
+            TorrentStatsState::Live,
+            TorrentStatsState::Paused,
+            TorrentStatsState::Error,
+        ] {
+            let value = (state == row.state) as u8;
+            let _ = writeln!(
+                out,
+                "rqbit_torrent_state{{{},state=\"{state}\"}} {value}",
+                row.labels
+            );
+        }
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{escape_label, LatencyHistogram};
+
+    #[test]
+    fn test_latency_histogram() {
+        let h = LatencyHistogram::default();
+        h.observe(Duration::from_micros(50));
+        h.observe(Duration::from_millis(3));
+        h.observe(Duration::from_secs(2));
+        let mut out = String::new();
+        h.write(&mut out, "latency", "help");
+        assert!(out.contains("latency_bucket{le=\"0.0001\"} 1\n"));
+        assert!(out.contains("latency_bucket{le=\"0.005\"} 2\n"));
+        assert!(out.contains("latency_bucket{le=\"1\"} 2\n"));
+        assert!(out.contains("latency_bucket{le=\"+Inf\"} 3\n"));
+        assert!(out.contains("latency_count 3\n"));
+        assert_eq!(escape_label("a \"b\"\\"), "a \\\"b\\\"\\\\");
+    }
+}
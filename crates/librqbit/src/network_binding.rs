@@ -0,0 +1,143 @@
+// Binding peer traffic to a network interface or a source IP, e.g. to keep it on a VPN.
+//
+// The listener, DHT and tracker sockets are bound once, when the session starts. Outgoing
+// peer connections look the interface up again on every connect, so that they fail instead
+// of going out through another interface once the bound one goes away.
+
+use std::{
+    fmt::Display,
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+};
+
+use anyhow::{bail, Context};
+use network_interface::{Addr, NetworkInterface, NetworkInterfaceConfig};
+use serde_with::{DeserializeFromStr, SerializeDisplay};
+use tokio::net::{TcpListener, TcpSocket, TcpStream};
+
+/// A network interface name (e.g. "wg0"), or an IP address of this host.
+#[derive(Debug, Clone, PartialEq, Eq, SerializeDisplay, DeserializeFromStr)]
+pub enum NetworkBinding {
+    Interface(String),
+    Ip(IpAddr),
+}
+
+impl FromStr for NetworkBinding {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if s.is_empty() {
+            bail!("expected an interface name or an IP address");
+        }
+        Ok(match s.parse() {
+            Ok(ip) => NetworkBinding::Ip(ip),
+            Err(_) => NetworkBinding::Interface(s.to_owned()),
+        })
+    }
+}
+
+impl Display for NetworkBinding {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            NetworkBinding::Interface(name) => f.write_str(name),
+            NetworkBinding::Ip(ip) => write!(f, "{ip}"),
+        }
+    }
+}
+
+impl NetworkBinding {
+    /// The address to bind to. For interfaces, this is their first IPv4 address if they
+    /// have one, otherwise the first IPv6 one.
+    pub fn resolve(&self) -> anyhow::Result<IpAddr> {
+        self.resolve_for(None)
+    }
+
+    fn resolve_for(&self, ipv6: Option<bool>) -> anyhow::Result<IpAddr> {
+        let name = match self {
+            NetworkBinding::Ip(ip) => {
+                if ipv6.is_some_and(|v6| v6 != ip.is_ipv6()) {
+                    bail!("can't reach this address family from {ip}");
+                }
+                return Ok(*ip);
+            }
+            NetworkBinding::Interface(name) => name,
+        };
+        let interfaces = NetworkInterface::show().context("error listing network interfaces")?;
+        let addrs = interfaces
+            .iter()
+            .filter(|i| &i.name == name)
+            .flat_map(|i| i.addr.iter())
+            .map(|addr| match addr {
+                Addr::V4(v4) => IpAddr::V4(v4.ip),
+                Addr::V6(v6) => IpAddr::V6(v6.ip),
+            })
+            .collect::<Vec<_>>();
+        if addrs.is_empty() {
+            bail!("network interface {name:?} not found or has no addresses");
+        }
+        let found = match ipv6 {
+            Some(v6) => addrs.iter().find(|ip| ip.is_ipv6() == v6),
+            None => addrs
+                .iter()
+                .find(|ip| ip.is_ipv4())
+                .or_else(|| addrs.first()),
+        };
+        match found {
+            Some(ip) => Ok(*ip),
+            None => bail!("network interface {name:?} has no address of this family"),
+        }
+    }
+
+    fn socket(&self, ip: IpAddr) -> anyhow::Result<TcpSocket> {
+        let socket = if ip.is_ipv4() {
+            TcpSocket::new_v4()
+        } else {
+            TcpSocket::new_v6()
+        }
+        .context("error creating socket")?;
+        #[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+        if let NetworkBinding::Interface(name) = self {
+            socket
+                .bind_device(Some(name.as_bytes()))
+                .with_context(|| format!("error binding socket to interface {name:?}"))?;
+        }
+        Ok(socket)
+    }
+
+    pub(crate) fn listen(&self, ip: IpAddr, port: u16) -> anyhow::Result<TcpListener> {
+        let socket = self.socket(ip)?;
+        socket.set_reuseaddr(true)?;
+        socket.bind(SocketAddr::new(ip, port))?;
+        Ok(socket.listen(1024)?)
+    }
+
+    pub(crate) async fn connect(&self, addr: SocketAddr) -> anyhow::Result<TcpStream> {
+        let ip = self.resolve_for(Some(addr.is_ipv6()))?;
+        let socket = self.socket(ip)?;
+        socket
+            .bind(SocketAddr::new(ip, 0))
+            .with_context(|| format!("error binding to {ip}"))?;
+        Ok(socket.connect(addr).await?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::NetworkBinding;
+
+    #[test]
+    fn test_parse() {
+        let b = |s: &str| s.parse::<NetworkBinding>().unwrap();
+        assert_eq!(
+            b("10.8.0.2"),
+            NetworkBinding::Ip("10.8.0.2".parse().unwrap())
+        );
+        assert_eq!(b("fe80::1"), NetworkBinding::Ip("fe80::1".parse().unwrap()));
+        assert_eq!(b("wg0"), NetworkBinding::Interface("wg0".to_owned()));
+        assert!("".parse::<NetworkBinding>().is_err());
+        assert!(NetworkBinding::Interface("no-such-interface".to_owned())
+            .resolve()
+            .is_err());
+    }
+}
@@ -6,7 +6,9 @@ use std::{
 use anyhow::{bail, Context};
 use buffers::{ByteBuf, ByteString};
 use clone_to_owned::CloneToOwned;
-use librqbit_core::{hash_id::Id20, lengths::ChunkInfo, peer_id::try_decode_peer_id};
+use librqbit_core::{
+    hash_id::Id20, lengths::ChunkInfo, peer_id::try_decode_peer_id, socks5::Socks5Proxy,
+};
 use parking_lot::RwLock;
 use peer_binary_protocol::{
     extended::{handshake::ExtendedHandshake, ExtendedMessage},
@@ -17,7 +19,7 @@ use serde_with::serde_as;
 use tokio::time::timeout;
 use tracing::trace;
 
-use crate::{read_buf::ReadBuf, spawn_utils::BlockingSpawner};
+use crate::{network_binding::NetworkBinding, read_buf::ReadBuf, spawn_utils::BlockingSpawner};
 
 pub trait PeerConnectionHandler {
     fn on_connected(&self, _connection_time: Duration) {}
@@ -53,12 +55,54 @@ pub struct PeerConnectionOptions {
     pub keep_alive_interval: Option<Duration>,
 }
 
+/// How outgoing connections are made: through a proxy, from a bound interface or directly.
+/// With both, the connection to the proxy is made from the bound interface.
+#[derive(Debug, Default, Clone)]
+pub(crate) struct PeerConnector {
+    pub bind: Option<NetworkBinding>,
+    pub proxy: Option<Socks5Proxy>,
+}
+
+impl PeerConnector {
+    pub async fn connect(&self, addr: SocketAddr) -> anyhow::Result<tokio::net::TcpStream> {
+        match (&self.proxy, &self.bind) {
+            (Some(proxy), Some(bind)) => {
+                let stream = Self::connect_to_proxy(proxy, bind).await?;
+                proxy.connect_over(stream, addr).await
+            }
+            (Some(proxy), None) => proxy.connect(addr).await,
+            (None, Some(bind)) => bind.connect(addr).await,
+            (None, None) => Ok(tokio::net::TcpStream::connect(addr).await?),
+        }
+    }
+
+    async fn connect_to_proxy(
+        proxy: &Socks5Proxy,
+        bind: &NetworkBinding,
+    ) -> anyhow::Result<tokio::net::TcpStream> {
+        let mut last_error = None;
+        for proxy_addr in tokio::net::lookup_host(&proxy.addr)
+            .await
+            .with_context(|| format!("error resolving SOCKS5 proxy {}", proxy.addr))?
+        {
+            match bind.connect(proxy_addr).await {
+                Ok(stream) => return Ok(stream),
+                Err(e) => last_error = Some(e),
+            }
+        }
+        Err(last_error
+            .unwrap_or_else(|| anyhow::anyhow!("SOCKS5 proxy {} has no addresses", proxy.addr))
+            .context(format!("error connecting to SOCKS5 proxy {}", proxy.addr)))
+    }
+}
+
 pub(crate) struct PeerConnection<H> {
     handler: H,
     addr: SocketAddr,
     info_hash: Id20,
     peer_id: Id20,
     options: PeerConnectionOptions,
+    connector: PeerConnector,
     spawner: BlockingSpawner,
 }
 
@@ -82,6 +126,7 @@ impl<H: PeerConnectionHandler> PeerConnection<H> {
         peer_id: Id20,
         handler: H,
         options: Option<PeerConnectionOptions>,
+        connector: PeerConnector,
         spawner: BlockingSpawner,
     ) -> Self {
         PeerConnection {
@@ -91,6 +136,7 @@ impl<H: PeerConnectionHandler> PeerConnection<H> {
             peer_id,
             spawner,
             options: options.unwrap_or_default(),
+            connector,
         }
     }
 
@@ -162,7 +208,7 @@ impl<H: PeerConnectionHandler> PeerConnection<H> {
             .unwrap_or_else(|| Duration::from_secs(10));
 
         let now = Instant::now();
-        let mut conn = with_timeout(connect_timeout, tokio::net::TcpStream::connect(self.addr))
+        let mut conn = with_timeout(connect_timeout, self.connector.connect(self.addr))
             .await
             .context("error connecting")?;
         self.handler.on_connected(now.elapsed());
@@ -19,7 +19,7 @@ use tracing::trace;
 
 use crate::{
     peer_connection::{
-        PeerConnection, PeerConnectionHandler, PeerConnectionOptions, WriterRequest,
+        PeerConnection, PeerConnectionHandler, PeerConnectionOptions, PeerConnector, WriterRequest,
     },
     spawn_utils::BlockingSpawner,
 };
@@ -29,6 +29,7 @@ pub(crate) async fn read_metainfo_from_peer(
     peer_id: Id20,
     info_hash: Id20,
     peer_connection_options: Option<PeerConnectionOptions>,
+    connector: PeerConnector,
     spawner: BlockingSpawner,
 ) -> anyhow::Result<TorrentMetaV1Info<ByteString>> {
     let (result_tx, result_rx) =
@@ -47,6 +48,7 @@ pub(crate) async fn read_metainfo_from_peer(
         peer_id,
         handler,
         peer_connection_options,
+        connector,
         spawner,
     );
 
@@ -250,10 +252,15 @@ mod tests {
         let addr = SocketAddr::from_str("127.0.0.1:27311").unwrap();
         let peer_id = generate_peer_id();
         let info_hash = Id20::from_str("9905f844e5d8787ecd5e08fb46b2eb0a42c131d7").unwrap();
-        dbg!(
-            read_metainfo_from_peer(addr, peer_id, info_hash, None, BlockingSpawner::new(true))
-                .await
-                .unwrap()
-        );
+        dbg!(read_metainfo_from_peer(
+            addr,
+            peer_id,
+            info_hash,
+            None,
+            Default::default(),
+            BlockingSpawner::new(true)
+        )
+        .await
+        .unwrap());
     }
 }
@@ -0,0 +1,88 @@
+// Sending peer, tracker and DHT traffic through a proxy.
+//
+// Peers and DHT need a SOCKS5 proxy (CONNECT and UDP ASSOCIATE). Trackers and torrent URL
+// downloads go through reqwest, which takes HTTP proxies too, but UDP trackers are skipped
+// with those.
+
+use anyhow::{bail, Context};
+use librqbit_core::socks5::Socks5Proxy;
+use serde::{Deserialize, Serialize};
+use url::Url;
+
+fn default_true() -> bool {
+    true
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ProxyConfig {
+    /// "socks5://[user:password@]host:port", or "http://[user:password@]host:port" if peers
+    /// and DHT don't go through the proxy. "socks5h" and "https" work too.
+    pub url: String,
+    /// Connect to peers through the proxy. Incoming connections are still accepted.
+    #[serde(default = "default_true")]
+    pub peers: bool,
+    /// Announce to trackers through the proxy.
+    #[serde(default = "default_true")]
+    pub trackers: bool,
+    /// Send DHT traffic through the proxy's UDP relay.
+    #[serde(default = "default_true")]
+    pub dht: bool,
+}
+
+impl ProxyConfig {
+    /// Send everything through the proxy at url.
+    pub fn new(url: impl Into<String>) -> Self {
+        Self {
+            url: url.into(),
+            peers: true,
+            trackers: true,
+            dht: true,
+        }
+    }
+}
+
+pub(crate) struct SessionProxy {
+    config: ProxyConfig,
+    url: Url,
+    socks5: Option<Socks5Proxy>,
+}
+
+impl SessionProxy {
+    pub fn new(config: ProxyConfig) -> anyhow::Result<Self> {
+        let url = Url::parse(&config.url).context("error parsing proxy URL")?;
+        let socks5 = match url.scheme() {
+            "socks5" | "socks5h" => Some(Socks5Proxy::from_url(&url)?),
+            "http" | "https" => None,
+            other => bail!("unsupported proxy scheme {other:?}"),
+        };
+        if socks5.is_none() && (config.peers || config.dht) {
+            bail!(
+                "peers and DHT can only go through a SOCKS5 proxy, turn them off for HTTP proxies"
+            );
+        }
+        // Check that reqwest takes it, before trackers find out.
+        reqwest::Proxy::all(url.as_str()).context("invalid proxy URL")?;
+        Ok(Self {
+            config,
+            url,
+            socks5,
+        })
+    }
+
+    pub fn peers(&self) -> Option<&Socks5Proxy> {
+        self.socks5.as_ref().filter(|_| self.config.peers)
+    }
+
+    pub fn dht(&self) -> Option<&Socks5Proxy> {
+        self.socks5.as_ref().filter(|_| self.config.dht)
+    }
+
+    pub fn trackers(&self) -> Option<&Url> {
+        Some(&self.url).filter(|_| self.config.trackers)
+    }
+
+    /// For downloading torrents from URLs.
+    pub fn reqwest_proxy(&self) -> anyhow::Result<reqwest::Proxy> {
+        Ok(reqwest::Proxy::all(self.url.as_str())?)
+    }
+}
@@ -1,21 +1,38 @@
 use std::{
     borrow::Cow,
-    collections::{HashMap, HashSet},
+    collections::{BTreeMap, HashMap, HashSet},
     io::{BufReader, BufWriter, Read},
-    net::SocketAddr,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
     path::PathBuf,
     str::FromStr,
-    sync::Arc,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
     time::Duration,
 };
 
 use crate::{
+    connection_limits::{ConnectionLimiter, ConnectionLimits, ConnectionStats},
+    cross_seed::{place_existing_files, CrossSeedOptions},
     dht_utils::{read_metainfo_from_peer_receiver, ReadMetainfoResult},
-    peer_connection::PeerConnectionOptions,
+    disk_cache::{DiskCache, DiskCacheStats, DEFAULT_DISK_CACHE_SIZE},
+    events::{SessionEvent, SessionEvents},
+    file_priority::FilePriority,
+    hooks::{Hook, TorrentEvent, TorrentEventKind},
+    ip_filter::{
+        IpFilterSource, IpFilterStats, PeerBan, SessionIpFilter, DEFAULT_PEER_BAN_THRESHOLD,
+    },
+    metrics::SessionMetrics,
+    network_binding::NetworkBinding,
+    peer_connection::{PeerConnectionOptions, PeerConnector},
+    proxy::{ProxyConfig, SessionProxy},
     read_buf::ReadBuf,
     spawn_utils::BlockingSpawner,
+    storage::{BoxStorageFactory, PreallocationMode},
     torrent_state::{
-        ManagedTorrentBuilder, ManagedTorrentHandle, ManagedTorrentState, TorrentStateLive,
+        peer::PeerSource, ManagedTorrentBuilder, ManagedTorrentHandle, ManagedTorrentState,
+        TorrentStateLive,
     },
     type_aliases::PeerStream,
 };
@@ -43,11 +60,14 @@ use parking_lot::RwLock;
 use peer_binary_protocol::Handshake;
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use serde_with::serde_as;
-use tokio::net::{TcpListener, TcpStream};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::{broadcast, OwnedSemaphorePermit},
+};
 use tokio_stream::StreamExt;
 use tokio_util::sync::{CancellationToken, DropGuard};
 use tracing::{debug, error, error_span, info, trace, warn, Instrument};
-use tracker_comms::TrackerComms;
+use tracker_comms::{TrackerComms, TrackerConnectOptions};
 
 pub const SUPPORTED_SCHEMES: [&str; 3] = ["http:", "https:", "magnet:"];
 
@@ -68,24 +88,19 @@ pub struct SessionDatabase {
 }
 
 impl SessionDatabase {
-    fn add_torrent(
-        &mut self,
-        torrent: ManagedTorrentHandle,
-        preferred_id: Option<TorrentId>,
-    ) -> TorrentId {
+    // The id for a new torrent, the torrent is inserted once built with it.
+    fn allocate_id(&mut self, preferred_id: Option<TorrentId>) -> TorrentId {
         match preferred_id {
             Some(id) if self.torrents.contains_key(&id) => {
                 warn!("id {id} already present in DB, ignoring \"preferred_id\" parameter");
             }
             Some(id) => {
-                self.torrents.insert(id, torrent);
                 self.next_id = id.max(self.next_id).wrapping_add(1);
                 return id;
             }
             _ => {}
         }
         let idx = self.next_id;
-        self.torrents.insert(idx, torrent);
         self.next_id += 1;
         idx
     }
@@ -107,10 +122,16 @@ impl SessionDatabase {
                                 .collect(),
                             info_hash: torrent.info_hash().as_string(),
                             info: torrent.info().info.clone(),
-                            only_files: torrent.only_files.clone(),
+                            only_files: torrent.only_files(),
+                            file_priorities: Some(torrent.file_priorities()),
+                            sequential: torrent.is_sequential(),
+                            preallocation: torrent.preallocation(),
                             is_paused: torrent
                                 .with_state(|s| matches!(s, ManagedTorrentState::Paused(_))),
-                            output_folder: torrent.info().out_dir.clone(),
+                            output_folder: torrent.info().out_dir(),
+                            file_path_overrides: torrent.info().file_path_overrides(),
+                            incomplete_folder: torrent.incomplete_folder().map(|p| p.to_owned()),
+                            part_suffix: torrent.part_suffix(),
                         },
                     )
                 })
@@ -130,7 +151,19 @@ struct SerializedTorrent {
     trackers: HashSet<String>,
     output_folder: PathBuf,
     only_files: Option<Vec<usize>>,
+    #[serde(default)]
+    file_priorities: Option<Vec<FilePriority>>,
+    #[serde(default)]
+    sequential: bool,
+    #[serde(default)]
+    preallocation: PreallocationMode,
     is_paused: bool,
+    #[serde(default)]
+    file_path_overrides: BTreeMap<usize, PathBuf>,
+    #[serde(default)]
+    incomplete_folder: Option<PathBuf>,
+    #[serde(default)]
+    part_suffix: bool,
 }
 
 fn serialize_torrent<S>(t: &TorrentMetaV1Info<ByteString>, serializer: S) -> Result<S::Ok, S::Error>
@@ -168,10 +201,28 @@ pub struct Session {
     peer_id: Id20,
     dht: Option<Dht>,
     persistence_filename: PathBuf,
+    // Set once the stored torrents are restored. Dumping before that would lose the ones
+    // not added yet.
+    persistence_ready: AtomicBool,
     peer_opts: PeerConnectionOptions,
     spawner: BlockingSpawner,
+    disk_cache: Arc<DiskCache>,
     db: RwLock<SessionDatabase>,
     output_folder: PathBuf,
+    incomplete_folder: Option<PathBuf>,
+    part_suffix: bool,
+    cross_seed_folders: Vec<PathBuf>,
+    cross_seed_copy: bool,
+    events: Arc<SessionEvents>,
+    ip_filter: Arc<SessionIpFilter>,
+    connection_limiter: Arc<ConnectionLimiter>,
+    network_binding: Option<NetworkBinding>,
+    // The address of network_binding when the session started.
+    bind_ip: Option<IpAddr>,
+    proxy: Option<SessionProxy>,
+    // For downloading torrents from URLs.
+    reqwest_client: reqwest::Client,
+    metrics: Arc<SessionMetrics>,
 
     tcp_listen_port: Option<u16>,
 
@@ -181,8 +232,13 @@ pub struct Session {
     _cancellation_token_drop_guard: DropGuard,
 }
 
-async fn torrent_from_url(url: &str) -> anyhow::Result<TorrentMetaV1Owned> {
-    let response = reqwest::get(url)
+async fn torrent_from_url(
+    client: &reqwest::Client,
+    url: &str,
+) -> anyhow::Result<TorrentMetaV1Owned> {
+    let response = client
+        .get(url)
+        .send()
         .await
         .context("error downloading torrent metadata")?;
     if !response.status().is_success() {
@@ -215,6 +271,32 @@ fn compute_only_files_regex<ByteBuf: AsRef<[u8]>>(
     Ok(only_files)
 }
 
+fn compute_file_priorities(
+    info: &TorrentMetaV1Info<ByteString>,
+    file_priorities: Option<Vec<FilePriority>>,
+    only_files: Option<Vec<usize>>,
+    only_files_regex: Option<String>,
+    list_only: bool,
+) -> anyhow::Result<Vec<FilePriority>> {
+    let total_files = info.iter_file_lengths()?.count();
+    match file_priorities {
+        Some(_) if only_files.is_some() || only_files_regex.is_some() => {
+            bail!("file_priorities is mutually exclusive with only_files and only_files_regex")
+        }
+        Some(p) if p.len() != total_files => {
+            bail!("expected {} file priorities, got {}", total_files, p.len())
+        }
+        Some(p) => Ok(p),
+        None => {
+            let only_files = compute_only_files(info, only_files, only_files_regex, list_only)?;
+            Ok(FilePriority::from_only_files(
+                total_files,
+                only_files.as_deref(),
+            ))
+        }
+    }
+}
+
 fn compute_only_files(
     info: &TorrentMetaV1Info<ByteString>,
     only_files: Option<Vec<usize>>,
@@ -274,9 +356,24 @@ pub struct AddTorrentOptions {
     /// An explicit list of file IDs to download.
     /// To see the file indices, run with "list_only".
     pub only_files: Option<Vec<usize>>,
+    /// Priorities for each file in the torrent, in order. Files with "skip" priority
+    /// are not downloaded. Mutually exclusive with "only_files" and "only_files_regex".
+    pub file_priorities: Option<Vec<FilePriority>>,
+    /// Download pieces in order, first and last pieces of each file first.
+    /// Useful for streaming media.
+    pub sequential: bool,
+    /// How to allocate the files before downloading.
+    pub preallocation: PreallocationMode,
+    /// Store files under different paths than the ones in the torrent, relative to the
+    /// output folder. File index to its path.
+    pub file_path_overrides: BTreeMap<usize, PathBuf>,
     /// Allow writing on top of existing files, including when resuming a torrent.
     /// You probably want to set it, however for safety it's not default.
     pub overwrite: bool,
+    /// Look for the torrent's data in the session's cross-seed folders, e.g. the same
+    /// content downloaded from elsewhere under different names. Files that match the
+    /// piece hashes are hard-linked into the output folder instead of being downloaded.
+    pub cross_seed: bool,
     /// Only list the files in the torrent without starting it.
     pub list_only: bool,
     /// The output folder for the torrent. If not set, the session's default one will be used.
@@ -284,6 +381,12 @@ pub struct AddTorrentOptions {
     /// Sub-folder within session's default output folder. Will error if "output_folder" if also set.
     /// By default, multi-torrent files are downloaded to a sub-folder.
     pub sub_folder: Option<String>,
+    /// Download into this folder, and move the files to the output folder once finished.
+    /// If not set, the session's one will be used, if any.
+    pub incomplete_folder: Option<String>,
+    /// Add ".part" to the names of files until the torrent finishes.
+    /// If not set, the session's default will be used.
+    pub part_suffix: Option<bool>,
     /// Peer connection options, timeouts etc. If not set, session's defaults will be used.
     pub peer_opts: Option<PeerConnectionOptions>,
 
@@ -299,6 +402,13 @@ pub struct AddTorrentOptions {
     /// This is used to restore the session from serialized state.
     #[serde(skip)]
     pub preferred_id: Option<usize>,
+
+    /// Where to store the torrent's data. By default, files in the output folder.
+    ///
+    /// This is not persisted, torrents restored from the session's state
+    /// use the filesystem.
+    #[serde(skip)]
+    pub storage_factory: Option<BoxStorageFactory>,
 }
 
 pub struct ListOnlyResponse {
@@ -406,13 +516,55 @@ pub struct SessionOptions {
 
     pub listen_port_range: Option<std::ops::Range<u16>>,
     pub enable_upnp_port_forwarding: bool,
+
+    /// Memory budget in bytes for caching piece reads and writes, shared by all torrents.
+    /// Defaults to DEFAULT_DISK_CACHE_SIZE. Set to 0 to disable the cache.
+    pub disk_cache_size: Option<usize>,
+
+    /// Download into this folder, and move the files to the output folder once finished.
+    /// Torrents get the same sub-folders here as in the output folder.
+    pub incomplete_folder: Option<PathBuf>,
+    /// Add ".part" to the names of files until their torrent finishes.
+    pub part_suffix: bool,
+
+    /// Folders to look for existing data in, when adding torrents with "cross_seed".
+    pub cross_seed_folders: Vec<PathBuf>,
+    /// Copy cross-seeded files instead of hard-linking them, so that the torrent's files
+    /// and the originals don't share their data.
+    pub cross_seed_copy: bool,
+
+    /// Commands to run or webhooks to call when torrents are added, finish etc.
+    pub hooks: Vec<Hook>,
+
+    /// Block peers in these lists (eMule .dat, PeerGuardian .p2p or CIDR, optionally gzipped).
+    pub ip_filter_files: Vec<PathBuf>,
+    /// Ban peers that sent data for this many pieces that failed the hash check, 0 to never
+    /// ban. Defaults to DEFAULT_PEER_BAN_THRESHOLD.
+    pub peer_ban_threshold: Option<u32>,
+
+    /// Limits on the number of peer connections.
+    pub connection_limits: ConnectionLimits,
+
+    /// Send and receive peer, DHT and tracker traffic only through this interface or IP.
+    /// Listens on its address instead of 0.0.0.0.
+    pub network_binding: Option<NetworkBinding>,
+    /// Send peer, tracker and DHT traffic, and torrent URL downloads, through this proxy.
+    /// The proxy is reached through network_binding, if set.
+    pub proxy: Option<ProxyConfig>,
 }
 
 async fn create_tcp_listener(
     port_range: std::ops::Range<u16>,
+    bind: Option<(&NetworkBinding, IpAddr)>,
 ) -> anyhow::Result<(TcpListener, u16)> {
     for port in port_range.clone() {
-        match TcpListener::bind(("0.0.0.0", port)).await {
+        let listener = match bind {
+            Some((bind, ip)) => bind.listen(ip, port),
+            None => TcpListener::bind(("0.0.0.0", port))
+                .await
+                .map_err(Into::into),
+        };
+        match listener {
             Ok(l) => return Ok((l, port)),
             Err(e) => {
                 debug!("error listening on port {port}: {e:#}")
@@ -427,6 +579,8 @@ pub(crate) struct CheckedIncomingConnection {
     pub stream: tokio::net::TcpStream,
     pub read_buf: ReadBuf,
     pub handshake: Handshake<ByteString>,
+    // The session-wide connection slot.
+    pub permit: OwnedSemaphorePermit,
 }
 
 impl Session {
@@ -441,6 +595,14 @@ impl Session {
         Ok(dir.data_dir().join("session.json"))
     }
 
+    pub(crate) fn metrics(&self) -> &SessionMetrics {
+        &self.metrics
+    }
+
+    pub fn disk_cache_stats(&self) -> DiskCacheStats {
+        self.disk_cache.stats()
+    }
+
     pub fn cancellation_token(&self) -> &CancellationToken {
         &self.cancellation_token
     }
@@ -455,11 +617,39 @@ impl Session {
             let peer_id = opts.peer_id.unwrap_or_else(generate_peer_id);
             let token = CancellationToken::new();
 
+            let bind_ip = opts
+                .network_binding
+                .as_ref()
+                .map(|b| b.resolve())
+                .transpose()
+                .context("error resolving network binding")?;
+            if let (Some(binding), Some(ip)) = (&opts.network_binding, bind_ip) {
+                info!("binding peer, DHT and tracker traffic to {binding} ({ip})");
+            }
+            let proxy = opts
+                .proxy
+                .map(SessionProxy::new)
+                .transpose()
+                .context("error setting up proxy")?;
+            let mut reqwest_client = reqwest::Client::builder().local_address(bind_ip);
+            if let Some(proxy) = &proxy {
+                reqwest_client = reqwest_client.proxy(proxy.reqwest_proxy()?);
+            }
+            let reqwest_client = reqwest_client
+                .build()
+                .context("error building HTTP client")?;
+            let dht_proxy = proxy.as_ref().and_then(|p| p.dht()).cloned();
+
             let (tcp_listener, tcp_listen_port) = if let Some(port_range) = opts.listen_port_range {
-                let (l, p) = create_tcp_listener(port_range)
-                    .await
-                    .context("error listening on TCP")?;
-                info!("Listening on 0.0.0.0:{p} for incoming peer connections");
+                let (l, p) =
+                    create_tcp_listener(port_range, opts.network_binding.as_ref().zip(bind_ip))
+                        .await
+                        .context("error listening on TCP")?;
+                let listen_ip = bind_ip.unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED));
+                info!(
+                    "Listening on {} for incoming peer connections",
+                    SocketAddr::new(listen_ip, p)
+                );
                 (Some(l), Some(p))
             } else {
                 (None, None)
@@ -470,13 +660,17 @@ impl Session {
             } else {
                 let dht = if opts.disable_dht_persistence {
                     DhtBuilder::with_config(DhtConfig {
+                        listen_addr: bind_ip.map(|ip| SocketAddr::new(ip, 0)),
+                        socks5_proxy: dht_proxy,
                         cancellation_token: Some(token.child_token()),
                         ..Default::default()
                     })
                     .await
                     .context("error initializing DHT")?
                 } else {
-                    let pdht_config = opts.dht_config.take().unwrap_or_default();
+                    let mut pdht_config = opts.dht_config.take().unwrap_or_default();
+                    pdht_config.listen_ip = pdht_config.listen_ip.or(bind_ip);
+                    pdht_config.socks5_proxy = pdht_config.socks5_proxy.or(dht_proxy);
                     PersistentDht::create(Some(pdht_config), Some(token.clone()))
                         .await
                         .context("error initializing persistent DHT")?
@@ -490,20 +684,49 @@ impl Session {
                 None => Self::default_persistence_filename()?,
             };
             let spawner = BlockingSpawner::default();
+            let disk_cache = Arc::new(DiskCache::new(
+                opts.disk_cache_size.unwrap_or(DEFAULT_DISK_CACHE_SIZE),
+            ));
+
+            let ip_filter = Arc::new(SessionIpFilter::new(
+                opts.peer_ban_threshold
+                    .unwrap_or(DEFAULT_PEER_BAN_THRESHOLD),
+            ));
+            ip_filter
+                .load(opts.ip_filter_files)
+                .context("error loading IP filter")?;
+
+            let (events, events_rx) = SessionEvents::new(opts.hooks);
 
             let session = Arc::new(Self {
                 persistence_filename,
+                persistence_ready: AtomicBool::new(false),
                 peer_id,
                 dht,
                 peer_opts,
                 spawner,
+                disk_cache,
                 output_folder,
+                incomplete_folder: opts.incomplete_folder,
+                part_suffix: opts.part_suffix,
+                cross_seed_folders: opts.cross_seed_folders,
+                cross_seed_copy: opts.cross_seed_copy,
+                events: Arc::new(events),
+                ip_filter,
+                connection_limiter: Arc::new(ConnectionLimiter::new(opts.connection_limits)),
+                network_binding: opts.network_binding,
+                bind_ip,
+                proxy,
+                reqwest_client,
+                metrics: Default::default(),
                 db: RwLock::new(Default::default()),
                 _cancellation_token_drop_guard: token.clone().drop_guard(),
                 cancellation_token: token,
                 tcp_listen_port,
             });
 
+            session.spawn(error_span!("events"), session.events.task(events_rx));
+
             if let Some(tcp_listener) = tcp_listener {
                 session.spawn(
                     error_span!("tcp_listen", port = tcp_listen_port),
@@ -539,11 +762,70 @@ impl Session {
         .boxed()
     }
 
+    /// Replace the hooks passed in SessionOptions, e.g. when the configuration is reloaded.
+    /// Hooks that are already running are not stopped.
+    pub fn set_hooks(&self, hooks: Vec<Hook>) {
+        self.events.set_hooks(hooks);
+    }
+
+    /// Read the IP filter files again, or replace them if `files` is given. The old filter
+    /// stays in use if the new one can't be loaded.
+    pub fn reload_ip_filter(&self, files: Option<Vec<PathBuf>>) -> anyhow::Result<IpFilterStats> {
+        match files {
+            Some(files) => self.ip_filter.load(files)?,
+            None => self.ip_filter.reload()?,
+        }
+        Ok(self.ip_filter.stats())
+    }
+
+    pub fn ip_filter_stats(&self) -> IpFilterStats {
+        self.ip_filter.stats()
+    }
+
+    pub fn connection_stats(&self) -> ConnectionStats {
+        self.connection_limiter.stats()
+    }
+
+    /// Peers banned for sending corrupt data.
+    pub fn peer_bans(&self) -> Vec<PeerBan> {
+        self.ip_filter.bans()
+    }
+
+    /// Ban the IP for the duration, or forever, and disconnect it from all torrents.
+    pub fn ban_peer(&self, ip: IpAddr, reason: String, duration: Option<Duration>) {
+        info!(%ip, ?duration, reason, "banning peer");
+        self.ip_filter.ban(ip, reason, duration);
+        let torrents = self
+            .db
+            .read()
+            .torrents
+            .values()
+            .cloned()
+            .collect::<Vec<_>>();
+        for live in torrents.iter().filter_map(|t| t.live()) {
+            live.disconnect_ip(ip);
+        }
+    }
+
+    /// Let the peer connect again. Returns false if it wasn't banned.
+    pub fn unban_peer(&self, ip: IpAddr) -> bool {
+        self.ip_filter.unban(ip)
+    }
+
+    /// Subscribe to the events of all torrents in the session: added, state changes, pieces
+    /// completed, peers connected, tracker errors etc.
+    ///
+    /// Subscribers that fall more than SESSION_EVENTS_CAPACITY events behind miss some.
+    pub fn subscribe(&self) -> broadcast::Receiver<SessionEvent> {
+        self.events.subscribe()
+    }
+
     async fn task_persistence(self: Arc<Self>) -> anyhow::Result<()> {
         // Populate initial from the state filename
         if let Err(e) = self.populate_from_stored().await {
             error!("could not populate session from stored file: {:?}", e);
         }
+        self.persistence_ready.store(true, Ordering::Release);
 
         let session = Arc::downgrade(&self);
         drop(self);
@@ -566,7 +848,12 @@ impl Session {
         &self,
         addr: SocketAddr,
         mut stream: TcpStream,
+        permit: OwnedSemaphorePermit,
     ) -> anyhow::Result<(Arc<TorrentStateLive>, CheckedIncomingConnection)> {
+        if self.ip_filter.is_blocked(addr, IpFilterSource::Incoming) {
+            bail!("{addr} is blocked by the IP filter");
+        }
+
         let rwtimeout = self
             .peer_opts
             .read_write_timeout
@@ -604,6 +891,7 @@ impl Session {
                     stream,
                     handshake,
                     read_buf,
+                    permit,
                 },
             ));
         }
@@ -623,8 +911,13 @@ impl Session {
                     match r {
                         Ok((stream, addr)) => {
                             trace!("accepted connection from {addr}");
+                            let Some(permit) = self.connection_limiter.try_acquire() else {
+                                debug!(%addr, "too many connections, closing incoming connection");
+                                self.connection_limiter.on_rejected_incoming();
+                                continue;
+                            };
                             futs.push(
-                                self.check_incoming_connection(addr, stream)
+                                self.check_incoming_connection(addr, stream, permit)
                                     .map_err(|e| {
                                         debug!("error checking incoming connection: {e:#}");
                                         e
@@ -691,7 +984,7 @@ impl Session {
             .cloned()
             .collect::<Vec<_>>();
         for torrent in torrents {
-            if let Err(e) = torrent.pause() {
+            if let Err(e) = torrent.pause_internal() {
                 debug!("error pausing torrent: {e:#}");
             }
         }
@@ -739,7 +1032,7 @@ impl Session {
                 let session = self.clone();
                 async move {
                     session
-                        .add_torrent(
+                        .add_torrent_impl(
                             AddTorrent::TorrentInfo(Box::new(info)),
                             Some(AddTorrentOptions {
                                 paused: storrent.is_paused,
@@ -750,11 +1043,26 @@ impl Session {
                                         .context("broken path")?
                                         .to_owned(),
                                 ),
-                                only_files: storrent.only_files,
+                                only_files: match storrent.file_priorities {
+                                    Some(_) => None,
+                                    None => storrent.only_files,
+                                },
+                                file_priorities: storrent.file_priorities,
+                                sequential: storrent.sequential,
+                                preallocation: storrent.preallocation,
+                                file_path_overrides: storrent.file_path_overrides,
+                                incomplete_folder: storrent
+                                    .incomplete_folder
+                                    .map(|p| {
+                                        p.to_str().context("broken path").map(|s| s.to_owned())
+                                    })
+                                    .transpose()?,
+                                part_suffix: Some(storrent.part_suffix),
                                 overwrite: true,
                                 preferred_id: Some(id),
                                 ..Default::default()
                             }),
+                            true,
                         )
                         .await
                         .map_err(|e| {
@@ -780,7 +1088,12 @@ impl Session {
         );
         let serialized = self.db.read().serialize();
         serde_json::to_writer(&mut tmp, &serialized).context("error serializing")?;
-        drop(tmp);
+        // Dropping would ignore errors, e.g. when the disk is full, and the rename below would
+        // replace the good file with a truncated one.
+        tmp.into_inner()
+            .map_err(|e| e.into_error())
+            .and_then(|f| f.sync_all())
+            .with_context(|| format!("error writing {:?}", tmp_filename))?;
 
         std::fs::rename(&tmp_filename, &self.persistence_filename)
             .context("error renaming persistence file")?;
@@ -802,6 +1115,17 @@ impl Session {
         self: &'a Arc<Self>,
         add: AddTorrent<'a>,
         opts: Option<AddTorrentOptions>,
+    ) -> BoxFuture<'a, anyhow::Result<AddTorrentResponse>> {
+        self.add_torrent_impl(add, opts, false)
+    }
+
+    // "restored" is set for torrents from the session file, these were added before so
+    // there's no "added" event for them.
+    fn add_torrent_impl<'a>(
+        self: &'a Arc<Self>,
+        add: AddTorrent<'a>,
+        opts: Option<AddTorrentOptions>,
+        restored: bool,
     ) -> BoxFuture<'a, anyhow::Result<AddTorrentResponse>> {
         async move {
             // Magnet links are different in that we first need to discover the metadata.
@@ -841,9 +1165,15 @@ impl Session {
                     let (info, peer_rx, initial_peers) = match read_metainfo_from_peer_receiver(
                         self.peer_id,
                         info_hash,
-                        opts.initial_peers.clone().unwrap_or_default(),
+                        opts.initial_peers
+                            .iter()
+                            .flatten()
+                            .copied()
+                            .filter(|a| !self.ip_filter.is_blocked(*a, IpFilterSource::Outgoing))
+                            .collect(),
                         peer_rx,
                         Some(self.merge_peer_opts(opts.peer_opts)),
+                        &self.peer_connector(),
                     )
                     .await
                     {
@@ -853,6 +1183,9 @@ impl Session {
                         }
                     };
                     debug!(?info, "received result from DHT");
+                    self.events.send_torrent_event(|| {
+                        TorrentEvent::new(TorrentEventKind::MetadataResolved, info_hash, &info)
+                    });
                     (
                         info_hash,
                         info,
@@ -866,7 +1199,7 @@ impl Session {
                         AddTorrent::Url(url)
                             if url.starts_with("http://") || url.starts_with("https://") =>
                         {
-                            torrent_from_url(&url).await?
+                            torrent_from_url(&self.reqwest_client, &url).await?
                         }
                         AddTorrent::Url(url) => {
                             bail!(
@@ -912,6 +1245,7 @@ impl Session {
                             .clone()
                             .unwrap_or_default()
                             .into_iter()
+                            .map(|a| (a, PeerSource::InitialPeers))
                             .collect(),
                     )
                 }
@@ -924,6 +1258,7 @@ impl Session {
                 peer_rx,
                 initial_peers.into_iter().collect(),
                 opts,
+                restored,
             )
             .await
         }
@@ -957,57 +1292,141 @@ impl Session {
         Ok::<_, anyhow::Error>(Some(PathBuf::from(longest)))
     }
 
+    #[allow(clippy::too_many_arguments)]
     async fn main_torrent_info(
         &self,
         info_hash: Id20,
         info: TorrentMetaV1Info<ByteString>,
         trackers: Vec<String>,
         peer_rx: Option<PeerStream>,
-        initial_peers: Vec<SocketAddr>,
+        initial_peers: Vec<(SocketAddr, PeerSource)>,
         opts: AddTorrentOptions,
+        restored: bool,
     ) -> anyhow::Result<AddTorrentResponse> {
         debug!("Torrent info: {:#?}", &info);
 
-        let only_files = compute_only_files(
+        let file_priorities = compute_file_priorities(
             &info,
+            opts.file_priorities,
             opts.only_files,
             opts.only_files_regex,
             opts.list_only,
         )?;
 
-        let output_folder = match (opts.output_folder, opts.sub_folder) {
-            (None, None) => self.output_folder.join(
-                self.get_default_subfolder_for_torrent(&info)?
-                    .unwrap_or_default(),
-            ),
-            (Some(o), None) => PathBuf::from(o),
-            (Some(_), Some(_)) => bail!("you can't provide both output_folder and sub_folder"),
-            (None, Some(s)) => self.output_folder.join(s),
+        if opts.output_folder.is_some() && opts.sub_folder.is_some() {
+            bail!("you can't provide both output_folder and sub_folder")
+        }
+        let sub_folder = match opts.sub_folder {
+            Some(s) => PathBuf::from(s),
+            None => self
+                .get_default_subfolder_for_torrent(&info)?
+                .unwrap_or_default(),
+        };
+        let output_folder = match opts.output_folder {
+            Some(o) => PathBuf::from(o),
+            None => self.output_folder.join(&sub_folder),
+        };
+        let incomplete_folder = match opts.incomplete_folder {
+            Some(f) => Some(PathBuf::from(f)),
+            None => self.incomplete_folder.as_ref().map(|f| f.join(&sub_folder)),
         };
 
         if opts.list_only {
             return Ok(AddTorrentResponse::ListOnly(ListOnlyResponse {
                 info_hash,
                 info,
-                only_files,
+                only_files: FilePriority::to_only_files(&file_priorities),
                 output_folder,
-                seen_peers: initial_peers,
+                seen_peers: initial_peers.into_iter().map(|(a, _)| a).collect(),
             }));
         }
 
+        let overwrite = if opts.cross_seed {
+            // The files of a torrent that is already managed would fail the check, or get
+            // replaced under it.
+            if let Some((id, handle)) = self
+                .db
+                .read()
+                .torrents
+                .iter()
+                .find(|(_, t)| t.info_hash() == info_hash)
+            {
+                return Ok(AddTorrentResponse::AlreadyManaged(*id, handle.clone()));
+            }
+            if self.cross_seed_folders.is_empty() {
+                bail!("cross_seed is set, but no cross-seed folders are configured");
+            }
+            let relative_paths =
+                crate::torrent_state::relative_file_paths(&info, &opts.file_path_overrides)?;
+            let result = tokio::task::spawn_blocking({
+                let info = info.clone();
+                let output_folder = output_folder.clone();
+                let file_priorities = file_priorities.clone();
+                let search_dirs = self.cross_seed_folders.clone();
+                let copy_files = self.cross_seed_copy;
+                move || {
+                    place_existing_files(
+                        &info,
+                        &output_folder,
+                        &relative_paths,
+                        &file_priorities,
+                        &CrossSeedOptions {
+                            search_dirs: &search_dirs,
+                            overwrite: opts.overwrite,
+                            copy_files,
+                        },
+                    )
+                }
+            })
+            .await
+            .context("cross-seeding task panicked")?
+            .context("error looking for existing data")?;
+            info!(
+                placed = result.placed,
+                verified = result.verified,
+                unverifiable = result.unverifiable,
+                "placed existing files for cross-seeding"
+            );
+            // Unless overwriting was allowed anyway, all files that exist now were checked
+            // to match the torrent, so they need opening rather than creating.
+            opts.overwrite || result.verified > 0
+        } else {
+            opts.overwrite
+        };
+
         let mut builder = ManagedTorrentBuilder::new(info, info_hash, output_folder.clone());
         builder
-            .overwrite(opts.overwrite)
+            .overwrite(overwrite)
             .spawner(self.spawner)
+            .disk_cache(self.disk_cache.clone())
             .trackers(trackers)
-            .peer_id(self.peer_id);
-
-        if let Some(only_files) = only_files {
-            builder.only_files(only_files);
+            .peer_id(self.peer_id)
+            .events(self.events.clone())
+            .metrics(self.metrics.clone())
+            .ip_filter(self.ip_filter.clone())
+            .connection_limiter(self.connection_limiter.clone());
+        let peer_connector = self.peer_connector();
+        if let Some(bind) = peer_connector.bind {
+            builder.network_binding(bind);
         }
+        if let Some(proxy) = peer_connector.proxy {
+            builder.peer_proxy(proxy);
+        }
+
+        builder
+            .file_priorities(file_priorities)
+            .file_path_overrides(opts.file_path_overrides)
+            .sequential(opts.sequential)
+            .preallocation(opts.preallocation)
+            .incomplete_folder(incomplete_folder)
+            .shared_incomplete_folder(self.incomplete_folder.clone())
+            .part_suffix(opts.part_suffix.unwrap_or(self.part_suffix));
         if let Some(interval) = opts.force_tracker_interval {
             builder.force_tracker_interval(interval);
         }
+        if let Some(storage_factory) = opts.storage_factory {
+            builder.storage_factory(storage_factory);
+        }
 
         let peer_opts = self.merge_peer_opts(opts.peer_opts);
 
@@ -1025,13 +1444,19 @@ impl Session {
             {
                 return Ok(AddTorrentResponse::AlreadyManaged(*id, handle.clone()));
             }
-            let next_id = g.torrents.len();
-            let managed_torrent =
-                builder.build(error_span!(parent: None, "torrent", id = next_id))?;
-            let id = g.add_torrent(managed_torrent.clone(), opts.preferred_id);
+            let id = g.allocate_id(opts.preferred_id);
+            builder.id(id);
+            let managed_torrent = builder.build(error_span!(parent: None, "torrent", id = id))?;
+            g.torrents.insert(id, managed_torrent.clone());
             (managed_torrent, id)
         };
 
+        // Torrents restored from the session file were added before.
+        if !restored {
+            self.events
+                .send_torrent_event(|| managed_torrent.info.event(TorrentEventKind::Added));
+        }
+
         // Merge "initial_peers" and "peer_rx" into one stream.
         let peer_rx = merge_two_optional_streams(
             if !initial_peers.is_empty() {
@@ -1059,12 +1484,20 @@ impl Session {
     }
 
     pub fn delete(&self, id: TorrentId, delete_files: bool) -> anyhow::Result<()> {
-        let removed = self
-            .db
-            .write()
-            .torrents
-            .remove(&id)
-            .with_context(|| format!("torrent with id {} did not exist", id))?;
+        let removed = {
+            let mut g = self.db.write();
+            let handle = g
+                .torrents
+                .get(&id)
+                .with_context(|| format!("torrent with id {} did not exist", id))?;
+            if handle.is_moving() {
+                bail!("torrent data is being moved, can't remove the torrent");
+            }
+            g.torrents.remove(&id).unwrap()
+        };
+
+        self.events
+            .send_torrent_event(|| removed.info.event(TorrentEventKind::Removed));
 
         let paused = removed
             .with_state_mut(|s| {
@@ -1083,10 +1516,9 @@ impl Session {
                 warn!(error=?e, "error deleting torrent cleanly");
             }
             (Ok(Some(paused)), true) => {
-                drop(paused.files);
-                for file in paused.filenames {
-                    if let Err(e) = std::fs::remove_file(&file) {
-                        warn!(?file, error=?e, "could not delete file");
+                for file_id in 0..paused.info.info.iter_file_lengths()?.count() {
+                    if let Err(e) = paused.storage.remove_file(file_id) {
+                        warn!(file_id, error=?e, "could not delete file");
                     }
                 }
             }
@@ -1108,7 +1540,12 @@ impl Session {
             .dht
             .as_ref()
             .map(|dht| dht.get_peers(info_hash, announce_port))
-            .transpose()?;
+            .transpose()?
+            .map(|rx| {
+                let ip_filter = self.ip_filter.clone();
+                rx.filter(move |a| !ip_filter.is_blocked(*a, IpFilterSource::Dht))
+                    .map(|a| (a, PeerSource::Dht))
+            });
 
         let peer_rx_stats = PeerRxTorrentInfo {
             info_hash,
@@ -1121,11 +1558,96 @@ impl Session {
             Box::new(peer_rx_stats),
             force_tracker_interval,
             announce_port,
-        );
+            TrackerConnectOptions {
+                bind_ip: self.bind_ip,
+                proxy: self.proxy.as_ref().and_then(|p| p.trackers()).cloned(),
+            },
+        )
+        .map(|rx| {
+            let ip_filter = self.ip_filter.clone();
+            rx.filter(move |a| !ip_filter.is_blocked(*a, IpFilterSource::Tracker))
+                .map(|a| (a, PeerSource::Tracker))
+        });
 
         Ok(merge_two_optional_streams(dht_rx, peer_rx))
     }
 
+    /// Move the torrent's data to a new output folder.
+    ///
+    /// Live torrents are paused while moving, and resumed afterwards, even if moving failed.
+    /// Progress is reported in the torrent's stats.
+    pub async fn move_torrent(
+        self: &Arc<Self>,
+        handle: &ManagedTorrentHandle,
+        new_output_folder: PathBuf,
+    ) -> anyhow::Result<()> {
+        if new_output_folder == handle.info().out_dir() {
+            return Ok(());
+        }
+        let overrides = handle.info().file_path_overrides();
+        self.relocate(handle, new_output_folder, overrides).await
+    }
+
+    /// Rename files of the torrent, file index to the new path relative to the output
+    /// folder. None reverts the file to its path from the torrent.
+    ///
+    /// Files already on disk are renamed, pausing the torrent meanwhile like move_torrent().
+    pub async fn rename_files(
+        self: &Arc<Self>,
+        handle: &ManagedTorrentHandle,
+        renames: BTreeMap<usize, Option<PathBuf>>,
+    ) -> anyhow::Result<()> {
+        let mut overrides = handle.info().file_path_overrides();
+        for (idx, path) in renames {
+            match path {
+                Some(path) => overrides.insert(idx, path),
+                None => overrides.remove(&idx),
+            };
+        }
+        if overrides == handle.info().file_path_overrides() {
+            return Ok(());
+        }
+        let out_dir = handle.info().out_dir();
+        self.relocate(handle, out_dir, overrides).await
+    }
+
+    async fn relocate(
+        self: &Arc<Self>,
+        handle: &ManagedTorrentHandle,
+        new_output_folder: PathBuf,
+        file_path_overrides: BTreeMap<usize, PathBuf>,
+    ) -> anyhow::Result<()> {
+        // Check the paths before pausing.
+        crate::torrent_state::relative_file_paths(&handle.info().info, &file_path_overrides)?;
+        let was_live = handle.with_state(|s| matches!(s, ManagedTorrentState::Live(_)));
+        if was_live {
+            handle.pause_internal()?;
+        }
+
+        let h = handle.clone();
+        let result =
+            tokio::task::spawn_blocking(move || h.relocate(new_output_folder, file_path_overrides))
+                .await
+                .context("moving task panicked")
+                .and_then(|r| r);
+
+        // Don't wait for the periodic dump, as the old paths are gone now.
+        if result.is_ok() && self.persistence_ready.load(Ordering::Acquire) {
+            if let Err(e) = self.dump_to_disk() {
+                error!("error dumping session to disk: {:?}", e);
+            }
+        }
+
+        if was_live {
+            if let Err(e) = self.unpause(handle) {
+                warn!(error=?e, "error resuming torrent after moving its data");
+                // The move error is more useful to the caller, if there was one.
+                return result.and(Err(e));
+            }
+        }
+        result
+    }
+
     pub fn unpause(self: &Arc<Self>, handle: &ManagedTorrentHandle) -> anyhow::Result<()> {
         let peer_rx = self.make_peer_rx(
             handle.info_hash(),
@@ -1137,6 +1659,13 @@ impl Session {
         Ok(())
     }
 
+    fn peer_connector(&self) -> PeerConnector {
+        PeerConnector {
+            bind: self.network_binding.clone(),
+            proxy: self.proxy.as_ref().and_then(|p| p.peers()).cloned(),
+        }
+    }
+
     pub fn tcp_listen_port(&self) -> Option<u16> {
         self.tcp_listen_port
     }
@@ -1149,6 +1678,27 @@ struct PeerRxTorrentInfo {
 }
 
 impl tracker_comms::TorrentStatsProvider for PeerRxTorrentInfo {
+    fn on_tracker_announce(&self, _tracker: &str) {
+        SessionMetrics::inc(&self.session.metrics.tracker_announces, 1);
+    }
+
+    fn on_tracker_error(&self, tracker: &str, error: &anyhow::Error) {
+        SessionMetrics::inc(&self.session.metrics.tracker_announce_failures, 1);
+        self.session.events.send(|| SessionEvent::TrackerError {
+            id: self.session.with_torrents(|torrents| {
+                for (id, mt) in torrents {
+                    if mt.info_hash() == self.info_hash {
+                        return Some(id);
+                    }
+                }
+                None
+            }),
+            info_hash: self.info_hash.as_string(),
+            tracker: tracker.to_owned(),
+            error: format!("{error:#}"),
+        });
+    }
+
     fn get(&self) -> tracker_comms::TrackerCommsStats {
         let mt = self.session.with_torrents(|torrents| {
             for (_, mt) in torrents {
@@ -0,0 +1,409 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Context};
+use parking_lot::Mutex;
+use tracing::{debug, info};
+
+use crate::torrent_state::ManagedTorrentInfo;
+
+use super::{relocate::move_files, RelocateProgress, StorageFactory, TorrentStorage};
+
+/// The default storage, files on disk in the torrent's output folder.
+#[derive(Default, Clone, Copy)]
+pub struct FilesystemStorageFactory;
+
+impl StorageFactory for FilesystemStorageFactory {
+    fn create_storage(&self, info: &ManagedTorrentInfo) -> anyhow::Result<Box<dyn TorrentStorage>> {
+        Ok(Box::new(FilesystemStorage::new(info)?))
+    }
+}
+
+/// Appended to the names of unfinished files, if enabled.
+pub const PART_SUFFIX: &str = ".part";
+
+struct StorageFile {
+    // None if the file was not opened, e.g. because it was not selected for download.
+    file: Option<File>,
+    // Where the file is now. Either its final path, or the incomplete one until the
+    // torrent finishes.
+    path: PathBuf,
+}
+
+pub struct FilesystemStorage {
+    files: Vec<Mutex<StorageFile>>,
+    out_dir: PathBuf,
+    relative_paths: Vec<PathBuf>,
+    incomplete_folder: Option<PathBuf>,
+    shared_incomplete_folder: Option<PathBuf>,
+    part_suffix: bool,
+    overwrite: bool,
+}
+
+fn incomplete_path(
+    out_dir: &Path,
+    incomplete_folder: Option<&Path>,
+    relative_path: &Path,
+    part_suffix: bool,
+) -> PathBuf {
+    let path = incomplete_folder.unwrap_or(out_dir).join(relative_path);
+    if !part_suffix {
+        return path;
+    }
+    let mut path = path.into_os_string();
+    path.push(PART_SUFFIX);
+    path.into()
+}
+
+#[cfg(target_os = "linux")]
+fn fallocate(file: &File, length: u64) -> std::io::Result<()> {
+    use std::os::fd::AsRawFd;
+    if length == 0 {
+        return Ok(());
+    }
+    let ret = unsafe { libc::fallocate(file.as_raw_fd(), 0, 0, length as libc::off_t) };
+    if ret == 0 {
+        return Ok(());
+    }
+    let err = std::io::Error::last_os_error();
+    match err.raw_os_error() {
+        // The filesystem can't do it, the file was extended with set_len() already.
+        Some(libc::EOPNOTSUPP) => {
+            debug!("fallocate not supported, falling back to sparse file");
+            Ok(())
+        }
+        _ => Err(err),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn fallocate(_file: &File, _length: u64) -> std::io::Result<()> {
+    Ok(())
+}
+
+#[cfg(unix)]
+fn free_space(path: &Path) -> anyhow::Result<Option<u64>> {
+    use std::os::unix::ffi::OsStrExt;
+    // The folder might not be created yet.
+    let path = path
+        .ancestors()
+        .find(|p| p.exists())
+        .with_context(|| format!("none of the parents of {path:?} exist"))?;
+    let cpath = std::ffi::CString::new(path.as_os_str().as_bytes())
+        .with_context(|| format!("invalid path {path:?}"))?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(cpath.as_ptr(), &mut stat) } != 0 {
+        return Err(std::io::Error::last_os_error())
+            .with_context(|| format!("error getting free space of {path:?}"));
+    }
+    #[allow(clippy::unnecessary_cast)]
+    Ok(Some(stat.f_bavail as u64 * stat.f_frsize as u64))
+}
+
+#[cfg(not(unix))]
+fn free_space(_path: &Path) -> anyhow::Result<Option<u64>> {
+    Ok(None)
+}
+
+fn open_or_create_file(full_path: &Path, overwrite: bool) -> anyhow::Result<File> {
+    std::fs::create_dir_all(full_path.parent().unwrap())?;
+    let file = if overwrite {
+        OpenOptions::new()
+            .create(true)
+            .read(true)
+            .write(true)
+            .open(full_path)
+            .with_context(|| format!("error opening {full_path:?} in read/write mode"))?
+    } else {
+        // TODO: create_new does not seem to work with read(true), so calling this twice.
+        OpenOptions::new()
+            .create_new(true)
+            .write(true)
+            .open(full_path)
+            .with_context(|| format!("error creating {:?}", &full_path))?;
+        OpenOptions::new().read(true).write(true).open(full_path)?
+    };
+    Ok(file)
+}
+
+impl FilesystemStorage {
+    pub fn new(info: &ManagedTorrentInfo) -> anyhow::Result<Self> {
+        let mut storage = Self {
+            files: Vec::new(),
+            out_dir: info.out_dir(),
+            relative_paths: info.relative_file_paths()?,
+            incomplete_folder: info.options.incomplete_folder.clone(),
+            shared_incomplete_folder: info.options.shared_incomplete_folder.clone(),
+            part_suffix: info.options.part_suffix,
+            overwrite: info.options.overwrite,
+        };
+        // Files that are already in their final place were finished before.
+        storage.files = (0..storage.relative_paths.len())
+            .map(|idx| {
+                let final_path = storage.final_path(idx);
+                let path = if final_path.exists() {
+                    final_path
+                } else {
+                    storage.incomplete_path(idx)
+                };
+                Mutex::new(StorageFile { file: None, path })
+            })
+            .collect();
+        Ok(storage)
+    }
+
+    fn final_path(&self, file_id: usize) -> PathBuf {
+        self.out_dir.join(&self.relative_paths[file_id])
+    }
+
+    fn incomplete_path(&self, file_id: usize) -> PathBuf {
+        incomplete_path(
+            &self.out_dir,
+            self.incomplete_folder.as_deref(),
+            &self.relative_paths[file_id],
+            self.part_suffix,
+        )
+    }
+
+    fn incomplete_root(&self) -> &Path {
+        self.incomplete_folder.as_deref().unwrap_or(&self.out_dir)
+    }
+
+    // Folders emptied by finishing files are removed up to here. The torrent's own
+    // incomplete folder goes too if it's in the session-wide one, which other torrents
+    // still use.
+    fn prune_root(&self) -> &Path {
+        self.shared_incomplete_folder
+            .as_deref()
+            .unwrap_or_else(|| self.incomplete_root())
+    }
+
+    fn slot(&self, file_id: usize) -> anyhow::Result<&Mutex<StorageFile>> {
+        self.files
+            .get(file_id)
+            .with_context(|| format!("invalid file id {file_id}"))
+    }
+
+    fn with_file<R>(
+        &self,
+        file_id: usize,
+        f: impl FnOnce(&mut File) -> std::io::Result<R>,
+    ) -> anyhow::Result<R> {
+        let mut g = self.slot(file_id)?.lock();
+        let g = &mut *g;
+        let file = g
+            .file
+            .as_mut()
+            .with_context(|| format!("file {file_id} is not opened"))?;
+        f(file).with_context(|| format!("error accessing {:?}", g.path))
+    }
+}
+
+fn reopen(path: &Path, read_only: bool) -> anyhow::Result<File> {
+    OpenOptions::new()
+        .read(true)
+        .write(!read_only)
+        .open(path)
+        .with_context(|| format!("error opening {path:?} (read_only={read_only})"))
+}
+
+impl TorrentStorage for FilesystemStorage {
+    fn open_or_create(&self, file_id: usize) -> anyhow::Result<()> {
+        let mut g = self.slot(file_id)?.lock();
+        if g.file.is_none() {
+            g.file = Some(open_or_create_file(&g.path, self.overwrite)?);
+            debug!("opened {:?}", g.path);
+        }
+        Ok(())
+    }
+
+    fn exists(&self, file_id: usize) -> bool {
+        match self.files.get(file_id) {
+            Some(f) => {
+                let g = f.lock();
+                g.file.is_some() || g.path.exists()
+            }
+            None => false,
+        }
+    }
+
+    fn pread_exact(&self, file_id: usize, offset: u64, buf: &mut [u8]) -> anyhow::Result<()> {
+        self.with_file(file_id, |f| {
+            f.seek(SeekFrom::Start(offset))?;
+            f.read_exact(buf)
+        })
+    }
+
+    fn pwrite_all(&self, file_id: usize, offset: u64, buf: &[u8]) -> anyhow::Result<()> {
+        self.with_file(file_id, |f| {
+            f.seek(SeekFrom::Start(offset))?;
+            f.write_all(buf)
+        })
+    }
+
+    fn ensure_file_length(&self, file_id: usize, length: u64) -> anyhow::Result<()> {
+        self.with_file(file_id, |f| f.set_len(length))
+    }
+
+    fn preallocate(&self, file_id: usize, length: u64) -> anyhow::Result<()> {
+        self.with_file(file_id, |f| {
+            f.set_len(length)?;
+            fallocate(f, length)
+        })
+    }
+
+    fn free_space(&self) -> anyhow::Result<Option<u64>> {
+        free_space(self.incomplete_root())
+    }
+
+    fn remove_file(&self, file_id: usize) -> anyhow::Result<()> {
+        let mut g = self.slot(file_id)?.lock();
+        // Close the file first.
+        g.file.take();
+        match std::fs::remove_file(&g.path) {
+            Ok(()) => Ok(()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(e).with_context(|| format!("error removing {:?}", g.path)),
+        }
+    }
+
+    fn take(&self) -> anyhow::Result<Box<dyn TorrentStorage>> {
+        Ok(Box::new(Self {
+            files: self
+                .files
+                .iter()
+                .map(|f| {
+                    let mut g = f.lock();
+                    Mutex::new(StorageFile {
+                        file: g.file.take(),
+                        path: g.path.clone(),
+                    })
+                })
+                .collect(),
+            out_dir: self.out_dir.clone(),
+            relative_paths: self.relative_paths.clone(),
+            incomplete_folder: self.incomplete_folder.clone(),
+            shared_incomplete_folder: self.shared_incomplete_folder.clone(),
+            part_suffix: self.part_suffix,
+            overwrite: self.overwrite,
+        }))
+    }
+
+    fn relocate(
+        &self,
+        new_out_dir: &Path,
+        relative_paths: &[PathBuf],
+        progress: &RelocateProgress,
+    ) -> anyhow::Result<Box<dyn TorrentStorage>> {
+        if relative_paths.len() != self.files.len() {
+            bail!(
+                "expected {} paths, got {}",
+                self.files.len(),
+                relative_paths.len()
+            );
+        }
+
+        // Close the files while moving, remembering which ones to open again.
+        // Unfinished files stay unfinished, in the incomplete folder if there is one.
+        let mut old_paths = Vec::with_capacity(self.files.len());
+        let mut new_paths = Vec::with_capacity(self.files.len());
+        let mut was_open = Vec::with_capacity(self.files.len());
+        for (idx, (f, relative_path)) in self.files.iter().zip(relative_paths).enumerate() {
+            let mut g = f.lock();
+            was_open.push(g.file.take().is_some());
+            new_paths.push(if g.path == self.final_path(idx) {
+                new_out_dir.join(relative_path)
+            } else {
+                incomplete_path(
+                    new_out_dir,
+                    self.incomplete_folder.as_deref(),
+                    relative_path,
+                    self.part_suffix,
+                )
+            });
+            old_paths.push(g.path.clone());
+        }
+        let reopen_all = |paths: &[PathBuf]| {
+            paths
+                .iter()
+                .zip(was_open.iter())
+                .map(|(path, was_open)| {
+                    let file = if *was_open {
+                        Some(reopen(path, false)?)
+                    } else {
+                        None
+                    };
+                    Ok(Mutex::new(StorageFile {
+                        file,
+                        path: path.clone(),
+                    }))
+                })
+                .collect::<anyhow::Result<Vec<_>>>()
+        };
+
+        if let Err(e) = move_files(&old_paths, &new_paths, &self.out_dir, progress) {
+            for (file, reopened) in self.files.iter().zip(reopen_all(&old_paths)?) {
+                *file.lock() = reopened.into_inner();
+            }
+            return Err(e);
+        }
+
+        Ok(Box::new(Self {
+            files: reopen_all(&new_paths)?,
+            out_dir: new_out_dir.to_owned(),
+            relative_paths: relative_paths.to_owned(),
+            incomplete_folder: self.incomplete_folder.clone(),
+            shared_incomplete_folder: self.shared_incomplete_folder.clone(),
+            part_suffix: self.part_suffix,
+            overwrite: self.overwrite,
+        }))
+    }
+
+    fn finalize_files(&self, file_ids: &[usize]) -> anyhow::Result<()> {
+        for file_id in file_ids.iter().copied() {
+            let final_path = self.final_path(file_id);
+            let mut g = self.slot(file_id)?.lock();
+            if g.path == final_path {
+                continue;
+            }
+            let was_open = g.file.take().is_some();
+            let result = move_files(
+                std::slice::from_ref(&g.path),
+                std::slice::from_ref(&final_path),
+                self.prune_root(),
+                &RelocateProgress::default(),
+            );
+            if result.is_ok() {
+                debug!(from=?g.path, to=?final_path, "moved finished file into place");
+                g.path = final_path;
+            }
+            if was_open {
+                g.file = Some(reopen(&g.path, false)?);
+            }
+            result?;
+        }
+        Ok(())
+    }
+
+    fn set_read_only(&self, read_only: bool) -> anyhow::Result<()> {
+        for file in self.files.iter() {
+            let mut g = file.lock();
+            // Files that were never opened stay that way.
+            if g.file.is_none() {
+                continue;
+            }
+            // this should close the original file
+            // putting in a block just in case to guarantee drop.
+            {
+                g.file = None;
+            }
+            g.file = Some(reopen(&g.path, read_only)?);
+            debug!("reopened {:?}, read_only={}", g.path, read_only);
+        }
+        info!("reopened all torrent files, read_only={}", read_only);
+        Ok(())
+    }
+}
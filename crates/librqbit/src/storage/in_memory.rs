@@ -0,0 +1,148 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context};
+use parking_lot::RwLock;
+
+use crate::torrent_state::ManagedTorrentInfo;
+
+use super::{RelocateProgress, StorageFactory, TorrentStorage};
+
+/// Keeps torrents in memory. Useful for tests and for ephemeral torrents
+/// that are going to be consumed by streaming.
+#[derive(Default, Clone, Copy)]
+pub struct InMemoryStorageFactory;
+
+impl StorageFactory for InMemoryStorageFactory {
+    fn create_storage(&self, info: &ManagedTorrentInfo) -> anyhow::Result<Box<dyn TorrentStorage>> {
+        Ok(Box::new(InMemoryStorage::new(
+            info.info.iter_file_lengths()?.count(),
+        )))
+    }
+}
+
+pub struct InMemoryStorage {
+    // None if the file was not created.
+    files: Vec<RwLock<Option<Vec<u8>>>>,
+}
+
+impl InMemoryStorage {
+    pub fn new(num_files: usize) -> Self {
+        Self {
+            files: (0..num_files).map(|_| RwLock::new(None)).collect(),
+        }
+    }
+
+    fn file(&self, file_id: usize) -> anyhow::Result<&RwLock<Option<Vec<u8>>>> {
+        self.files
+            .get(file_id)
+            .with_context(|| format!("invalid file id {file_id}"))
+    }
+}
+
+impl TorrentStorage for InMemoryStorage {
+    fn open_or_create(&self, file_id: usize) -> anyhow::Result<()> {
+        self.file(file_id)?.write().get_or_insert_with(Vec::new);
+        Ok(())
+    }
+
+    fn exists(&self, file_id: usize) -> bool {
+        self.files
+            .get(file_id)
+            .map(|f| f.read().is_some())
+            .unwrap_or(false)
+    }
+
+    fn pread_exact(&self, file_id: usize, offset: u64, buf: &mut [u8]) -> anyhow::Result<()> {
+        let g = self.file(file_id)?.read();
+        let data = g
+            .as_ref()
+            .with_context(|| format!("file {file_id} does not exist"))?;
+        let start = offset as usize;
+        let src = match data.get(start..start + buf.len()) {
+            Some(src) => src,
+            None => bail!(
+                "file {file_id}: can't read {} bytes at {offset}, file length is {}",
+                buf.len(),
+                data.len()
+            ),
+        };
+        buf.copy_from_slice(src);
+        Ok(())
+    }
+
+    fn pwrite_all(&self, file_id: usize, offset: u64, buf: &[u8]) -> anyhow::Result<()> {
+        let mut g = self.file(file_id)?.write();
+        let data = g
+            .as_mut()
+            .with_context(|| format!("file {file_id} does not exist"))?;
+        let start = offset as usize;
+        let end = start + buf.len();
+        if data.len() < end {
+            data.resize(end, 0);
+        }
+        data[start..end].copy_from_slice(buf);
+        Ok(())
+    }
+
+    fn ensure_file_length(&self, file_id: usize, length: u64) -> anyhow::Result<()> {
+        self.file(file_id)?
+            .write()
+            .as_mut()
+            .with_context(|| format!("file {file_id} does not exist"))?
+            .resize(length as usize, 0);
+        Ok(())
+    }
+
+    fn remove_file(&self, file_id: usize) -> anyhow::Result<()> {
+        self.file(file_id)?.write().take();
+        Ok(())
+    }
+
+    fn relocate(
+        &self,
+        _new_out_dir: &Path,
+        _relative_paths: &[PathBuf],
+        _progress: &RelocateProgress,
+    ) -> anyhow::Result<Box<dyn TorrentStorage>> {
+        // Nothing is stored on disk.
+        self.take()
+    }
+
+    fn take(&self) -> anyhow::Result<Box<dyn TorrentStorage>> {
+        Ok(Box::new(Self {
+            files: self
+                .files
+                .iter()
+                .map(|f| RwLock::new(f.write().take()))
+                .collect(),
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::storage::TorrentStorage;
+
+    use super::InMemoryStorage;
+
+    #[test]
+    fn test_in_memory_storage() {
+        let s = InMemoryStorage::new(2);
+        assert!(!s.exists(0));
+        assert!(s.pwrite_all(0, 0, b"hello").is_err());
+
+        s.open_or_create(0).unwrap();
+        s.ensure_file_length(0, 4).unwrap();
+        s.pwrite_all(0, 2, b"llo").unwrap();
+        let mut buf = [0u8; 5];
+        s.pread_exact(0, 0, &mut buf).unwrap();
+        assert_eq!(&buf, b"\0\0llo");
+        assert!(s.pread_exact(0, 1, &mut buf).is_err());
+
+        let taken = s.take().unwrap();
+        assert!(!s.exists(0));
+        assert!(taken.exists(0));
+        taken.remove_file(0).unwrap();
+        assert!(!taken.exists(0));
+    }
+}
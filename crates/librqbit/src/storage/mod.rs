@@ -0,0 +1,151 @@
+// Where torrent data is stored.
+//
+// By default torrents are stored as regular files on disk, but this can be swapped for
+// anything else by passing a custom StorageFactory when adding a torrent.
+
+mod filesystem;
+mod in_memory;
+mod relocate;
+
+use std::{
+    path::{Path, PathBuf},
+    sync::{atomic::AtomicU64, Arc},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::torrent_state::ManagedTorrentInfo;
+
+pub use filesystem::{FilesystemStorage, FilesystemStorageFactory, PART_SUFFIX};
+pub use in_memory::{InMemoryStorage, InMemoryStorageFactory};
+
+/// Storage of the files of one torrent. Files are addressed by their index in the torrent.
+pub trait TorrentStorage: Send + Sync {
+    /// Make the file ready for reading and writing, creating it if needed.
+    /// Does nothing if the file is already opened.
+    fn open_or_create(&self, file_id: usize) -> anyhow::Result<()>;
+
+    /// Check if the file is present in the storage, e.g. left over from a previous run.
+    fn exists(&self, file_id: usize) -> bool;
+
+    /// Read exactly buf.len() bytes starting at offset.
+    fn pread_exact(&self, file_id: usize, offset: u64, buf: &mut [u8]) -> anyhow::Result<()>;
+
+    /// Write the whole buf starting at offset.
+    fn pwrite_all(&self, file_id: usize, offset: u64, buf: &[u8]) -> anyhow::Result<()>;
+
+    /// Truncate or extend the file to the given length.
+    fn ensure_file_length(&self, file_id: usize, length: u64) -> anyhow::Result<()>;
+
+    /// Like ensure_file_length, but also reserve the space for the file upfront, so that
+    /// it's not fragmented, and running out of space is found before downloading.
+    fn preallocate(&self, file_id: usize, length: u64) -> anyhow::Result<()> {
+        self.ensure_file_length(file_id, length)
+    }
+
+    /// How many bytes can still be stored, if known.
+    fn free_space(&self) -> anyhow::Result<Option<u64>> {
+        Ok(None)
+    }
+
+    /// Remove the file. Removing a file that is not there is not an error.
+    fn remove_file(&self, file_id: usize) -> anyhow::Result<()>;
+
+    /// Move everything to a new storage, leaving this one unusable.
+    ///
+    /// This is called when the torrent is paused, so that nothing left over from the
+    /// live torrent can touch the data anymore.
+    fn take(&self) -> anyhow::Result<Box<dyn TorrentStorage>>;
+
+    /// Move the data to a new output folder, with "relative_paths" being the new paths
+    /// of the files in it, and return the storage for the new location.
+    ///
+    /// Like take(), this is called on paused torrents. If it fails, the data must be
+    /// left where it was, and this storage must stay usable.
+    fn relocate(
+        &self,
+        new_out_dir: &Path,
+        relative_paths: &[PathBuf],
+        progress: &RelocateProgress,
+    ) -> anyhow::Result<Box<dyn TorrentStorage>>;
+
+    /// Called when the torrent finished downloading, with the files that are complete.
+    /// Storages that keep unfinished files apart move these to their final place, so that
+    /// whoever reads the output folder never sees incomplete files.
+    fn finalize_files(&self, file_ids: &[usize]) -> anyhow::Result<()> {
+        let _ = file_ids;
+        Ok(())
+    }
+
+    /// Called when the torrent finished downloading (read_only = true), or when it
+    /// needs to download again (read_only = false).
+    fn set_read_only(&self, read_only: bool) -> anyhow::Result<()> {
+        let _ = read_only;
+        Ok(())
+    }
+}
+
+/// Creates storage for torrents when they are initialized.
+pub trait StorageFactory: Send + Sync {
+    fn create_storage(&self, info: &ManagedTorrentInfo) -> anyhow::Result<Box<dyn TorrentStorage>>;
+}
+
+impl<F> StorageFactory for F
+where
+    F: Fn(&ManagedTorrentInfo) -> anyhow::Result<Box<dyn TorrentStorage>> + Send + Sync,
+{
+    fn create_storage(&self, info: &ManagedTorrentInfo) -> anyhow::Result<Box<dyn TorrentStorage>> {
+        self(info)
+    }
+}
+
+pub type BoxStorageFactory = Arc<dyn StorageFactory>;
+
+/// Progress of TorrentStorage::relocate().
+#[derive(Default, Debug)]
+pub struct RelocateProgress {
+    pub total_bytes: AtomicU64,
+    pub moved_bytes: AtomicU64,
+}
+
+/// Error of TorrentStorage::relocate() when it would overwrite a file at the destination.
+#[derive(Debug)]
+pub struct DestinationExists(pub PathBuf);
+
+impl std::fmt::Display for DestinationExists {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?} already exists, not overwriting it", self.0)
+    }
+}
+
+impl std::error::Error for DestinationExists {}
+
+/// How to allocate the files of a torrent before downloading.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PreallocationMode {
+    /// Files grow as pieces are written.
+    None,
+    /// Files are set to their full length, but the space is not reserved (sparse files).
+    #[default]
+    Sparse,
+    /// The space for the files is reserved on disk (fallocate on Linux).
+    Full,
+}
+
+/// Check if the error was caused by the disk being full.
+pub(crate) fn is_out_of_space(e: &anyhow::Error) -> bool {
+    e.chain()
+        .filter_map(|e| e.downcast_ref::<std::io::Error>())
+        .any(|e| {
+            #[cfg(unix)]
+            {
+                e.raw_os_error() == Some(libc::ENOSPC)
+            }
+            #[cfg(not(unix))]
+            {
+                let _ = e;
+                false
+            }
+        })
+}
@@ -0,0 +1,261 @@
+// Moving files to a new location, for FilesystemStorage::relocate().
+//
+// Files are renamed if possible, and copied otherwise (e.g. to another filesystem). Copies
+// are made under a temporary name and verified, so that a file never shows up incomplete
+// at its destination, and only then the sources are deleted. If anything fails,
+// everything that was done is rolled back, so the data stays where it was.
+
+use std::{
+    fs::{File, OpenOptions},
+    io::{Read, Write},
+    path::{Path, PathBuf},
+    sync::atomic::Ordering,
+};
+
+use anyhow::{bail, Context};
+use tracing::{debug, info, warn};
+
+use super::{DestinationExists, RelocateProgress};
+
+const COPY_BUF_LEN: usize = 1024 * 1024;
+
+enum Moved<'a> {
+    Renamed(&'a Path, &'a Path),
+    Copied(&'a Path, &'a Path),
+}
+
+fn is_cross_device(e: &std::io::Error) -> bool {
+    #[cfg(unix)]
+    {
+        e.raw_os_error() == Some(libc::EXDEV)
+    }
+    #[cfg(windows)]
+    {
+        // ERROR_NOT_SAME_DEVICE
+        e.raw_os_error() == Some(17)
+    }
+    #[cfg(not(any(unix, windows)))]
+    {
+        let _ = e;
+        false
+    }
+}
+
+fn read_full(f: &mut File, buf: &mut [u8]) -> std::io::Result<usize> {
+    let mut read = 0;
+    while read < buf.len() {
+        match f.read(&mut buf[read..])? {
+            0 => break,
+            n => read += n,
+        }
+    }
+    Ok(read)
+}
+
+fn copy_and_verify(src: &Path, dst: &Path, progress: &RelocateProgress) -> anyhow::Result<()> {
+    let mut buf = vec![0u8; COPY_BUF_LEN];
+    {
+        let mut from = File::open(src).with_context(|| format!("error opening {src:?}"))?;
+        let mut to = OpenOptions::new()
+            .create_new(true)
+            .write(true)
+            .open(dst)
+            .with_context(|| format!("error creating {dst:?}"))?;
+        loop {
+            let n =
+                read_full(&mut from, &mut buf).with_context(|| format!("error reading {src:?}"))?;
+            if n == 0 {
+                break;
+            }
+            to.write_all(&buf[..n])
+                .with_context(|| format!("error writing {dst:?}"))?;
+            progress.moved_bytes.fetch_add(n as u64, Ordering::Relaxed);
+        }
+        to.sync_all()
+            .with_context(|| format!("error writing {dst:?}"))?;
+    }
+
+    let mut from = File::open(src).with_context(|| format!("error opening {src:?}"))?;
+    let mut to = File::open(dst).with_context(|| format!("error opening {dst:?}"))?;
+    let mut verify_buf = vec![0u8; COPY_BUF_LEN];
+    loop {
+        let n = read_full(&mut from, &mut buf).with_context(|| format!("error reading {src:?}"))?;
+        let m = read_full(&mut to, &mut verify_buf)
+            .with_context(|| format!("error reading {dst:?}"))?;
+        if n != m || buf[..n] != verify_buf[..m] {
+            bail!("{dst:?} is not the same as {src:?} after copying");
+        }
+        if n == 0 {
+            return Ok(());
+        }
+    }
+}
+
+fn move_one<'a>(
+    src: &'a Path,
+    dst: &'a Path,
+    progress: &RelocateProgress,
+) -> anyhow::Result<Moved<'a>> {
+    if let Some(parent) = dst.parent() {
+        std::fs::create_dir_all(parent).with_context(|| format!("error creating {parent:?}"))?;
+    }
+    match std::fs::rename(src, dst) {
+        Ok(()) => {
+            let len = std::fs::metadata(dst).map(|m| m.len()).unwrap_or(0);
+            progress.moved_bytes.fetch_add(len, Ordering::Relaxed);
+            debug!(?src, ?dst, "renamed");
+            Ok(Moved::Renamed(src, dst))
+        }
+        Err(e) if is_cross_device(&e) => {
+            let mut tmp = dst.as_os_str().to_owned();
+            tmp.push(".rqbit-tmp");
+            let tmp = PathBuf::from(tmp);
+            let result = copy_and_verify(src, &tmp, progress).and_then(|_| {
+                std::fs::rename(&tmp, dst)
+                    .with_context(|| format!("error renaming {tmp:?} to {dst:?}"))
+            });
+            if let Err(e) = result {
+                let _ = std::fs::remove_file(&tmp);
+                return Err(e);
+            }
+            debug!(?src, ?dst, "copied");
+            Ok(Moved::Copied(src, dst))
+        }
+        Err(e) => Err(e).with_context(|| format!("error moving {src:?} to {dst:?}")),
+    }
+}
+
+fn rollback(moved: Vec<Moved<'_>>) {
+    for m in moved.into_iter().rev() {
+        match m {
+            Moved::Renamed(src, dst) => {
+                if let Err(e) = std::fs::rename(dst, src) {
+                    warn!(?src, ?dst, error=?e, "error moving file back");
+                }
+            }
+            Moved::Copied(_, dst) => {
+                if let Err(e) = std::fs::remove_file(dst) {
+                    warn!(?dst, error=?e, "error removing copied file");
+                }
+            }
+        }
+    }
+}
+
+// Remove the directories left empty after moving, up to "root". "root" itself stays, it
+// may be the user's download folder.
+fn remove_empty_dirs(file: &Path, root: &Path) {
+    let mut dir = file.parent();
+    while let Some(d) = dir {
+        if d == root || !d.starts_with(root) || std::fs::remove_dir(d).is_err() {
+            break;
+        }
+        dir = d.parent();
+    }
+}
+
+/// Move files from "src" to "dst" paths, pairwise. Missing source files, and the ones
+/// that stay where they are, are skipped.
+pub(super) fn move_files(
+    src: &[PathBuf],
+    dst: &[PathBuf],
+    src_root: &Path,
+    progress: &RelocateProgress,
+) -> anyhow::Result<()> {
+    let pairs = src
+        .iter()
+        .zip(dst.iter())
+        .filter(|(s, d)| s != d && s.exists())
+        .collect::<Vec<_>>();
+    let mut total = 0;
+    for (s, d) in pairs.iter() {
+        if d.exists() {
+            return Err(DestinationExists(d.to_path_buf()).into());
+        }
+        total += std::fs::metadata(s)
+            .with_context(|| format!("error reading metadata of {s:?}"))?
+            .len();
+    }
+    progress.total_bytes.store(total, Ordering::Relaxed);
+    progress.moved_bytes.store(0, Ordering::Relaxed);
+
+    let mut moved = Vec::with_capacity(pairs.len());
+    for (s, d) in pairs {
+        match move_one(s, d, progress) {
+            Ok(m) => moved.push(m),
+            Err(e) => {
+                rollback(moved);
+                return Err(e);
+            }
+        }
+    }
+
+    for m in moved.iter() {
+        let src = match m {
+            Moved::Copied(src, _) => {
+                if let Err(e) = std::fs::remove_file(src) {
+                    warn!(?src, error=?e, "error removing the source of a copied file");
+                }
+                src
+            }
+            Moved::Renamed(src, _) => src,
+        };
+        remove_empty_dirs(src, src_root);
+    }
+    info!(files = moved.len(), "moved torrent files");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::{copy_and_verify, move_files};
+    use crate::storage::{DestinationExists, RelocateProgress};
+
+    #[test]
+    fn test_move_files() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let src_root = dir.path().join("src");
+        let dst_root = dir.path().join("dst");
+        let src = vec![
+            src_root.join("a"),
+            src_root.join("sub/b"),
+            src_root.join("c"),
+        ];
+        let dst = src
+            .iter()
+            .map(|p| dst_root.join(p.strip_prefix(&src_root).unwrap()))
+            .collect::<Vec<PathBuf>>();
+        std::fs::create_dir_all(src_root.join("sub")).unwrap();
+        std::fs::write(&src[0], b"hello").unwrap();
+        std::fs::write(&src[1], b"world!").unwrap();
+
+        // Refuses to overwrite, and leaves the source alone.
+        std::fs::create_dir_all(dst[1].parent().unwrap()).unwrap();
+        std::fs::write(&dst[1], b"other").unwrap();
+        let progress = RelocateProgress::default();
+        let err = move_files(&src, &dst, &src_root, &progress).unwrap_err();
+        assert!(err.downcast_ref::<DestinationExists>().is_some());
+        assert!(src[0].exists() && src[1].exists());
+        std::fs::remove_file(&dst[1]).unwrap();
+
+        move_files(&src, &dst, &src_root, &progress).unwrap();
+        assert_eq!(std::fs::read(&dst[0]).unwrap(), b"hello");
+        assert_eq!(std::fs::read(&dst[1]).unwrap(), b"world!");
+        assert!(!dst[2].exists());
+        assert!(!src_root.join("sub").exists());
+        assert!(src_root.exists());
+        assert_eq!(progress.moved_bytes.into_inner(), 11);
+
+        // Renaming within the same folder leaves the other files alone.
+        let renamed = vec![dst[0].clone(), dst_root.join("renamed/b"), dst[2].clone()];
+        move_files(&dst, &renamed, &dst_root, &RelocateProgress::default()).unwrap();
+        assert_eq!(std::fs::read(&renamed[1]).unwrap(), b"world!");
+        assert!(dst[0].exists() && !dst[1].exists());
+
+        let copied = dir.path().join("copied");
+        copy_and_verify(&dst[0], &copied, &RelocateProgress::default()).unwrap();
+        assert_eq!(std::fs::read(&copied).unwrap(), b"hello");
+    }
+}
@@ -1,5 +1,6 @@
 use std::{
     borrow::Cow,
+    io::SeekFrom,
     net::{Ipv4Addr, SocketAddr},
     time::Duration,
 };
@@ -8,6 +9,7 @@ use anyhow::bail;
 use futures::{stream::FuturesUnordered, StreamExt};
 use rand::Rng;
 use tokio::{
+    io::{AsyncReadExt, AsyncSeekExt},
     spawn,
     time::{interval, timeout},
 };
@@ -16,7 +18,7 @@ use tracing::{error_span, info, Instrument};
 use crate::{
     create_torrent,
     tests::test_util::{create_default_random_dir_with_torrents, TestPeerMetadata},
-    AddTorrentOptions, AddTorrentResponse, Session, SessionOptions,
+    AddTorrentOptions, AddTorrentResponse, Session, SessionEvent, SessionOptions, TorrentEventKind,
 };
 
 #[tokio::test(flavor = "multi_thread", worker_threads = 64)]
@@ -72,6 +74,17 @@ async fn test_e2e() {
                         peer_opts: None,
                         listen_port_range: Some(15100..17000),
                         enable_upnp_port_forwarding: false,
+                        disk_cache_size: None,
+                        incomplete_folder: None,
+                        part_suffix: false,
+                        cross_seed_folders: Vec::new(),
+                        cross_seed_copy: false,
+                        hooks: Vec::new(),
+                        ip_filter_files: Vec::new(),
+                        peer_ban_threshold: None,
+                        connection_limits: Default::default(),
+                        network_binding: None,
+                        proxy: None,
                     },
                 )
                 .await
@@ -138,6 +151,7 @@ async fn test_e2e() {
     // 3. Start a client with the initial peers, and download the file.
     for _ in 0..client_iters {
         let outdir = tempfile::TempDir::with_prefix("rqbit_e2e_client").unwrap();
+        let incomplete_dir = tempfile::TempDir::with_prefix("rqbit_e2e_client_incomplete").unwrap();
         let session = Session::new_with_opts(
             outdir.path().to_owned(),
             SessionOptions {
@@ -148,6 +162,10 @@ async fn test_e2e() {
                 persistence_filename: None,
                 listen_port_range: None,
                 enable_upnp_port_forwarding: false,
+                disk_cache_size: None,
+                // Files are downloaded here, and moved to outdir once finished.
+                incomplete_folder: Some(incomplete_dir.path().to_owned()),
+                part_suffix: true,
                 ..Default::default()
             },
         )
@@ -156,6 +174,8 @@ async fn test_e2e() {
 
         info!("started client session");
 
+        let mut events = session.subscribe();
+
         let (id, handle) = {
             let r = session
                 .add_torrent(
@@ -178,6 +198,32 @@ async fn test_e2e() {
 
         info!("added handle");
 
+        // Read a part of a file while it's being downloaded, to compare later with
+        // what got written to disk.
+        let (_, file_length) = handle
+            .info()
+            .info
+            .iter_filenames_and_lengths()
+            .unwrap()
+            .nth(1)
+            .unwrap();
+        let reader = spawn({
+            let handle = handle.clone();
+            async move {
+                let mut file = loop {
+                    match handle.open_file(1) {
+                        Ok(file) => break file,
+                        Err(_) => tokio::time::sleep(Duration::from_millis(10)).await,
+                    }
+                };
+                assert_eq!(file.len(), file_length);
+                file.seek(SeekFrom::Start(1000)).await.unwrap();
+                let mut buf = vec![0u8; file_length as usize - 2000];
+                file.read_exact(&mut buf).await.unwrap();
+                buf
+            }
+        });
+
         {
             let stats_printer = {
                 let handle = handle.clone();
@@ -223,6 +269,76 @@ async fn test_e2e() {
         }
 
         info!("handle is completed");
+
+        {
+            let (filename, _) = handle
+                .info()
+                .info
+                .iter_filenames_and_lengths()
+                .unwrap()
+                .nth(1)
+                .unwrap();
+            let expected =
+                std::fs::read(handle.info().out_dir().join(filename.to_pathbuf().unwrap()))
+                    .unwrap();
+            let streamed = timeout(Duration::from_secs(10), reader)
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(streamed, &expected[1000..expected.len() - 1000]);
+            let left_over = std::fs::read_dir(incomplete_dir.path())
+                .unwrap()
+                .map(|e| e.unwrap().path())
+                .collect::<Vec<_>>();
+            assert!(
+                left_over.is_empty(),
+                "finished files should be moved out of the incomplete folder: {left_over:?}"
+            );
+        }
+        {
+            // The events are delivered asynchronously, so they may still be arriving.
+            let mut completed = false;
+            let mut pieces = 0;
+            let mut added = false;
+            while !completed {
+                let event = timeout(Duration::from_secs(10), events.recv())
+                    .await
+                    .unwrap()
+                    .unwrap();
+                assert_eq!(event.torrent_id(), Some(id));
+                assert!(
+                    added
+                        || matches!(&event, SessionEvent::Torrent(e) if e.event == TorrentEventKind::Added),
+                    "the first event should be \"added\", got {event:?}"
+                );
+                match event {
+                    SessionEvent::Torrent(e) if e.event == TorrentEventKind::Added => added = true,
+                    SessionEvent::Torrent(e) if e.event == TorrentEventKind::Completed => {
+                        completed = true
+                    }
+                    SessionEvent::PieceCompleted { .. } => pieces += 1,
+                    _ => {}
+                }
+            }
+            assert!(added);
+            assert_eq!(pieces, handle.info().lengths.total_pieces());
+        }
+
+        {
+            let metrics = crate::metrics::render(&session);
+            let downloaded = metrics
+                .lines()
+                .find_map(|l| l.strip_prefix("rqbit_downloaded_bytes_total "))
+                .unwrap()
+                .parse::<u64>()
+                .unwrap();
+            assert!(downloaded >= handle.info().lengths.total_length());
+            assert!(metrics.contains(&format!(
+                "rqbit_torrent_progress_ratio{{id=\"{id}\",info_hash=\"{}\"",
+                handle.info_hash().as_string()
+            )));
+        }
+
         session.delete(id, false).unwrap();
 
         info!("deleted handle");
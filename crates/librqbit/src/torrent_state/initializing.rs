@@ -1,36 +1,112 @@
 use std::{
-    fs::{File, OpenOptions},
     sync::{atomic::AtomicU64, Arc},
     time::Instant,
 };
 
-use anyhow::Context;
-
-use parking_lot::Mutex;
-
+use anyhow::bail;
 use sha1w::Sha1;
 use size_format::SizeFormatterBinary as SF;
 use tracing::{debug, info, warn};
 
-use crate::{chunk_tracker::ChunkTracker, file_ops::FileOps};
+use crate::{
+    chunk_tracker::ChunkTracker,
+    file_ops::FileOps,
+    file_priority::{
+        compute_completed_files, compute_files_needed_on_disk, compute_piece_priorities,
+        FilePriority,
+    },
+    storage::{is_out_of_space, PreallocationMode, TorrentStorage},
+};
 
 use super::{paused::TorrentStatePaused, ManagedTorrentInfo};
 
-fn ensure_file_length(file: &File, length: u64) -> anyhow::Result<()> {
-    Ok(file.set_len(length)?)
+// Set the length of a selected file according to the torrent's preallocation mode.
+// Only running out of space is an error, as nothing can be downloaded then anyway.
+fn allocate_file(
+    meta: &ManagedTorrentInfo,
+    storage: &dyn TorrentStorage,
+    idx: usize,
+    length: u64,
+) -> anyhow::Result<()> {
+    let now = Instant::now();
+    let res = match meta.options.preallocation {
+        PreallocationMode::None => return Ok(()),
+        PreallocationMode::Sparse => storage.ensure_file_length(idx, length),
+        PreallocationMode::Full => storage.preallocate(idx, length),
+    };
+    match res {
+        Ok(()) => {
+            debug!(
+                "Allocated file {} with {} in {:?}",
+                idx,
+                SF::new(length),
+                now.elapsed()
+            );
+            Ok(())
+        }
+        Err(err) if is_out_of_space(&err) => Err(err.context(format!(
+            "no space left on device to allocate {} for file {} in {:?}",
+            SF::new(length),
+            idx,
+            meta.out_dir()
+        ))),
+        Err(err) => {
+            warn!(
+                "Error setting length for file {} to {}: {:#?}",
+                idx, length, err
+            );
+            Ok(())
+        }
+    }
+}
+
+/// Create the files that are needed on disk to download the pieces selected by
+/// file_priorities. Files that are already opened are left alone.
+///
+/// Returns the computed priority of each piece.
+pub(crate) fn prepare_files_for_priorities(
+    meta: &ManagedTorrentInfo,
+    storage: &dyn TorrentStorage,
+    file_priorities: &[FilePriority],
+) -> anyhow::Result<Vec<FilePriority>> {
+    let piece_priorities = compute_piece_priorities(
+        &meta.lengths,
+        meta.info.iter_file_lengths()?,
+        file_priorities,
+    );
+    let needed_on_disk = compute_files_needed_on_disk(
+        &meta.lengths,
+        meta.info.iter_file_lengths()?,
+        &piece_priorities,
+    );
+    for (idx, (length, needed)) in meta
+        .info
+        .iter_file_lengths()?
+        .zip(needed_on_disk)
+        .enumerate()
+    {
+        if !needed {
+            continue;
+        }
+        storage.open_or_create(idx)?;
+        if !file_priorities[idx].is_skip() {
+            allocate_file(meta, storage, idx, length)?;
+        }
+    }
+    Ok(piece_priorities)
 }
 
 pub struct TorrentStateInitializing {
     pub(crate) meta: Arc<ManagedTorrentInfo>,
-    pub(crate) only_files: Option<Vec<usize>>,
+    pub(crate) file_priorities: Vec<FilePriority>,
     pub(crate) checked_bytes: AtomicU64,
 }
 
 impl TorrentStateInitializing {
-    pub fn new(meta: Arc<ManagedTorrentInfo>, only_files: Option<Vec<usize>>) -> Self {
+    pub fn new(meta: Arc<ManagedTorrentInfo>, file_priorities: Vec<FilePriority>) -> Self {
         Self {
             meta,
-            only_files,
+            file_priorities,
             checked_bytes: AtomicU64::new(0),
         }
     }
@@ -41,48 +117,32 @@ impl TorrentStateInitializing {
     }
 
     pub async fn check(&self) -> anyhow::Result<TorrentStatePaused> {
-        let (files, filenames) = {
-            let mut files =
-                Vec::<Arc<Mutex<File>>>::with_capacity(self.meta.info.iter_file_lengths()?.count());
-            let mut filenames = Vec::new();
-            for (path_bits, _) in self.meta.info.iter_filenames_and_lengths()? {
-                let mut full_path = self.meta.out_dir.clone();
-                let relative_path = path_bits
-                    .to_pathbuf()
-                    .context("error converting file to path")?;
-                full_path.push(relative_path);
-
-                std::fs::create_dir_all(full_path.parent().unwrap())?;
-                let file = if self.meta.options.overwrite {
-                    OpenOptions::new()
-                        .create(true)
-                        .read(true)
-                        .write(true)
-                        .open(&full_path)
-                        .with_context(|| {
-                            format!("error opening {full_path:?} in read/write mode")
-                        })?
-                } else {
-                    // TODO: create_new does not seem to work with read(true), so calling this twice.
-                    OpenOptions::new()
-                        .create_new(true)
-                        .write(true)
-                        .open(&full_path)
-                        .with_context(|| format!("error creating {:?}", &full_path))?;
-                    OpenOptions::new().read(true).write(true).open(&full_path)?
-                };
-                filenames.push(full_path);
-                files.push(Arc::new(Mutex::new(file)))
+        let piece_priorities = compute_piece_priorities(
+            &self.meta.lengths,
+            self.meta.info.iter_file_lengths()?,
+            &self.file_priorities,
+        );
+        let needed_on_disk = compute_files_needed_on_disk(
+            &self.meta.lengths,
+            self.meta.info.iter_file_lengths()?,
+            &piece_priorities,
+        );
+
+        let storage = self.meta.storage_factory.create_storage(&self.meta)?;
+        for (idx, needed) in needed_on_disk.iter().copied().enumerate() {
+            // Files that are not selected are not created, but if they are already
+            // there, we still check what pieces they have.
+            if needed || (self.meta.options.overwrite && storage.exists(idx)) {
+                storage.open_or_create(idx)?;
             }
-            (files, filenames)
-        };
+        }
 
         debug!("computed lengths: {:?}", &self.meta.lengths);
 
         info!("Doing initial checksum validation, this might take a while...");
         let initial_check_results = self.meta.spawner.spawn_block_in_place(|| {
-            FileOps::<Sha1>::new(&self.meta.info, &files, &self.meta.lengths)
-                .initial_check(self.only_files.as_deref(), &self.checked_bytes)
+            FileOps::<Sha1>::new(&self.meta.info, &*storage, &self.meta.lengths)
+                .initial_check(&self.file_priorities, &self.checked_bytes)
         })?;
 
         info!(
@@ -92,48 +152,66 @@ impl TorrentStateInitializing {
             SF::new(initial_check_results.total_selected_bytes)
         );
 
+        // Full preallocation finds this out exactly when reserving the space below, and
+        // already preallocated files would count twice here.
+        if self.meta.options.preallocation != PreallocationMode::Full {
+            if let Some(free) = storage.free_space()? {
+                if initial_check_results.needed_bytes > free {
+                    bail!(
+                        "not enough disk space in {:?}: {} more needed, {} available",
+                        self.meta.out_dir(),
+                        SF::new(initial_check_results.needed_bytes),
+                        SF::new(free)
+                    );
+                }
+            }
+        }
+
         self.meta.spawner.spawn_block_in_place(|| {
-            for (idx, (file, (name, length))) in files
-                .iter()
-                .zip(self.meta.info.iter_filenames_and_lengths().unwrap())
-                .enumerate()
-            {
+            for (idx, length) in self.meta.info.iter_file_lengths()?.enumerate() {
                 if self
-                    .only_files
-                    .as_ref()
-                    .map(|v| !v.contains(&idx))
+                    .file_priorities
+                    .get(idx)
+                    .map(|p| p.is_skip())
                     .unwrap_or(false)
                 {
                     continue;
                 }
-                let now = Instant::now();
-                if let Err(err) = ensure_file_length(&file.lock(), length) {
-                    warn!(
-                        "Error setting length for file {:?} to {}: {:#?}",
-                        name, length, err
-                    );
-                } else {
-                    debug!(
-                        "Set length for file {:?} to {} in {:?}",
-                        name,
-                        SF::new(length),
-                        now.elapsed()
-                    );
+                if !storage.exists(idx) {
+                    continue;
                 }
+                allocate_file(&self.meta, &*storage, idx, length)?;
             }
-        });
+            Ok::<_, anyhow::Error>(())
+        })?;
+
+        // Finished before, but the files were not moved into place, e.g. due to a crash.
+        if initial_check_results.needed_bytes == 0 {
+            let completed = compute_completed_files(
+                &self.meta.lengths,
+                self.meta.info.iter_file_lengths()?,
+                |piece| initial_check_results.have_pieces[piece],
+            );
+            if let Err(e) = self
+                .meta
+                .spawner
+                .spawn_block_in_place(|| storage.finalize_files(&completed))
+            {
+                warn!(error=?e, "error moving finished files into the output folder");
+            }
+        }
 
         let chunk_tracker = ChunkTracker::new(
             initial_check_results.needed_pieces,
             initial_check_results.have_pieces,
             self.meta.lengths,
             initial_check_results.total_selected_bytes,
+            piece_priorities,
         );
 
         let paused = TorrentStatePaused {
             info: self.meta.clone(),
-            files,
-            filenames,
+            storage,
             chunk_tracker,
             have_bytes: initial_check_results.have_bytes,
             needed_bytes: initial_check_results.needed_bytes,
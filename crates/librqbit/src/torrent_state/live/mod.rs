@@ -41,16 +41,17 @@
 
 pub mod peer;
 pub mod peers;
+mod piece_senders;
 pub mod stats;
+mod streaming;
 
 use std::{
-    collections::HashMap,
-    fs::File,
-    net::SocketAddr,
-    path::PathBuf,
+    collections::{hash_map::RandomState, HashMap},
+    hash::BuildHasher,
+    net::{IpAddr, SocketAddr},
     sync::{
-        atomic::{AtomicU64, Ordering},
-        Arc,
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Weak,
     },
     time::{Duration, Instant},
 };
@@ -68,7 +69,7 @@ use librqbit_core::{
     speed_estimator::SpeedEstimator,
     torrent_metainfo::TorrentMetaV1Info,
 };
-use parking_lot::{Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use parking_lot::{RwLock, RwLockReadGuard, RwLockWriteGuard};
 use peer_binary_protocol::{
     extended::handshake::ExtendedHandshake, Handshake, Message, MessageOwned, Piece, Request,
 };
@@ -85,11 +86,17 @@ use tracing::{debug, error, error_span, info, trace, warn};
 
 use crate::{
     chunk_tracker::{ChunkMarkingResult, ChunkTracker},
+    events::SessionEvent,
     file_ops::FileOps,
+    file_priority::{compute_completed_files, FilePriority},
+    hooks::TorrentEventKind,
+    ip_filter::IpFilterSource,
+    metrics::SessionMetrics,
     peer_connection::{
         PeerConnection, PeerConnectionHandler, PeerConnectionOptions, WriterRequest,
     },
     session::CheckedIncomingConnection,
+    storage::{is_out_of_space, TorrentStorage},
     torrent_state::{peer::Peer, utils::atomic_inc},
     type_aliases::{PeerHandle, BF},
 };
@@ -100,35 +107,41 @@ use self::{
             atomic::PeerCountersAtomic as AtomicPeerCounters,
             snapshot::{PeerStatsFilter, PeerStatsSnapshot},
         },
-        InflightRequest, PeerRx, PeerState, PeerTx,
+        InflightRequest, PeerRx, PeerSource, PeerState, PeerTx,
     },
     peers::PeerStates,
+    piece_senders::PieceSenders,
     stats::{atomic::AtomicStats, snapshot::StatsSnapshot},
+    streaming::TorrentStreams,
 };
 
+pub use self::streaming::FileStream;
+
 use super::{
+    initializing::prepare_files_for_priorities,
     paused::TorrentStatePaused,
     utils::{timeit, TimedExistence},
     ManagedTorrentInfo,
 };
 
+// How often to look for idle peers to replace, and how much they need to send in between
+// to be kept.
+const IDLE_PEER_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+const IDLE_PEER_MIN_BYTES_PER_SEC: u64 = 1024;
+const MAX_REPLACED_PEERS_PER_CHECK: usize = 5;
+
+// Connection slots held by an outgoing peer for as long as it's connected.
+struct OutgoingPermits {
+    torrent: OwnedSemaphorePermit,
+    session: OwnedSemaphorePermit,
+    half_open: OwnedSemaphorePermit,
+}
+
 struct InflightPiece {
     peer: PeerHandle,
     started: Instant,
 }
 
-fn dummy_file() -> anyhow::Result<std::fs::File> {
-    #[cfg(target_os = "windows")]
-    const DEVNULL: &str = "NUL";
-    #[cfg(not(target_os = "windows"))]
-    const DEVNULL: &str = "/dev/null";
-
-    std::fs::OpenOptions::new()
-        .read(true)
-        .open(DEVNULL)
-        .with_context(|| format!("error opening {}", DEVNULL))
-}
-
 fn make_piece_bitfield(lengths: &Lengths) -> BF {
     BF::from_vec(vec![0; lengths.piece_bitfield_bytes()])
 }
@@ -142,6 +155,9 @@ pub(crate) struct TorrentStateLocked {
     // inflight_pieces stores this information.
     inflight_pieces: HashMap<ValidPieceIndex, InflightPiece>,
 
+    // Who sent which chunks, to find the peers sending corrupt data.
+    piece_senders: PieceSenders,
+
     // If this is None, then it was already used
     fatal_errors_tx: Option<tokio::sync::oneshot::Sender<anyhow::Error>>,
 }
@@ -171,23 +187,42 @@ pub struct TorrentStateLive {
     meta: Arc<ManagedTorrentInfo>,
     locked: RwLock<TorrentStateLocked>,
 
-    files: Vec<Arc<Mutex<File>>>,
-    filenames: Vec<PathBuf>,
+    storage: Box<dyn TorrentStorage>,
 
-    initially_needed_bytes: u64,
-    total_selected_bytes: u64,
+    // These change when file priorities are changed.
+    initially_needed_bytes: AtomicU64,
+    total_selected_bytes: AtomicU64,
+    // Pieces deselected while in-flight still get downloaded, but they don't count
+    // towards finishing.
+    deselected_downloaded_bytes: AtomicU64,
 
     stats: AtomicStats,
     lengths: Lengths,
 
     // Limits how many active (occupying network resources) peers there are at a moment in time.
     peer_semaphore: Arc<Semaphore>,
+    // Set while the peer adder waits for this torrent's, the session's or a half-open
+    // connection slot.
+    waiting_for_connection_slot: AtomicBool,
 
     // The queue for peer manager to connect to them.
     peer_queue_tx: UnboundedSender<SocketAddr>,
 
     finished_notify: Notify,
 
+    // Notified when a finished torrent needs to download again, as new files were selected.
+    unfinished_notify: Notify,
+
+    // Notified every time a piece is downloaded and verified.
+    have_notify: Notify,
+
+    // Readers streaming files of this torrent.
+    streams: TorrentStreams,
+
+    // Fingerprints the received chunks for PieceSenders. Keyed randomly, so that peers can't
+    // make their data look like someone else's.
+    chunk_hasher: RandomState,
+
     down_speed_estimator: SpeedEstimator,
     up_speed_estimator: SpeedEstimator,
     cancellation_token: CancellationToken,
@@ -215,20 +250,33 @@ impl TorrentStateLive {
             locked: RwLock::new(TorrentStateLocked {
                 chunks: Some(paused.chunk_tracker),
                 inflight_pieces: Default::default(),
+                piece_senders: Default::default(),
                 fatal_errors_tx: Some(fatal_errors_tx),
             }),
-            files: paused.files,
-            filenames: paused.filenames,
+            storage: paused.storage,
             stats: AtomicStats {
                 have_bytes: AtomicU64::new(have_bytes),
                 ..Default::default()
             },
-            initially_needed_bytes: needed_bytes,
+            initially_needed_bytes: AtomicU64::new(needed_bytes),
             lengths,
-            total_selected_bytes,
-            peer_semaphore: Arc::new(Semaphore::new(128)),
+            total_selected_bytes: AtomicU64::new(total_selected_bytes),
+            deselected_downloaded_bytes: Default::default(),
+            peer_semaphore: Arc::new(Semaphore::new(
+                paused
+                    .info
+                    .connection_limiter
+                    .limits()
+                    .max_connections_per_torrent
+                    .min(Semaphore::MAX_PERMITS),
+            )),
+            waiting_for_connection_slot: Default::default(),
             peer_queue_tx,
             finished_notify: Notify::new(),
+            unfinished_notify: Notify::new(),
+            have_notify: Notify::new(),
+            streams: Default::default(),
+            chunk_hasher: RandomState::new(),
             down_speed_estimator,
             up_speed_estimator,
             cancellation_token,
@@ -251,7 +299,7 @@ impl TorrentStateLive {
                         // fetched can be too high in theory, so for safety make sure that it doesn't wrap around u64.
                         let remaining = needed
                             .wrapping_sub(fetched)
-                            .min(needed - stats.downloaded_and_checked_bytes);
+                            .min(state.get_left_to_download_bytes());
                         state
                             .down_speed_estimator
                             .add_snapshot(fetched, Some(remaining), now);
@@ -268,6 +316,13 @@ impl TorrentStateLive {
             error_span!(parent: state.meta.span.clone(), "peer_adder"),
             state.clone().task_peer_adder(peer_queue_rx),
         );
+
+        if state.meta.connection_limiter.limits().replace_idle_peers {
+            state.spawn(
+                error_span!(parent: state.meta.span.clone(), "idle_peer_replacer"),
+                Self::task_replace_idle_peers(Arc::downgrade(&state)),
+            );
+        }
         state
     }
 
@@ -296,7 +351,8 @@ impl TorrentStateLive {
         let permit = match self.peer_semaphore.clone().try_acquire_owned() {
             Ok(permit) => permit,
             Err(_) => {
-                warn!("limit of live peers reached, dropping incoming peer");
+                debug!("limit of live peers reached, dropping incoming peer");
+                self.meta.connection_limiter.on_rejected_incoming();
                 self.peers.with_peer(checked_peer.addr, |p| {
                     atomic_inc(&p.stats.counters.incoming_connections);
                 });
@@ -317,7 +373,7 @@ impl TorrentStateLive {
                 peer.stats.counters.clone()
             }
             Entry::Vacant(vac) => {
-                atomic_inc(&self.peers.stats.seen);
+                self.peers.stats.on_seen(PeerSource::Incoming);
                 let peer = Peer::new_live_for_incoming_connection(
                     Id20::new(checked_peer.handshake.peer_id),
                     tx.clone(),
@@ -329,6 +385,7 @@ impl TorrentStateLive {
             }
         };
         atomic_inc(&counters.incoming_connections);
+        self.emit_peer_connected(checked_peer.addr);
 
         self.spawn(
             error_span!(
@@ -355,7 +412,10 @@ impl TorrentStateLive {
             addr: checked_peer.addr,
             on_bitfield_notify: Default::default(),
             unchoke_notify: Default::default(),
-            locked: RwLock::new(PeerHandlerLocked { i_am_choked: true }),
+            locked: RwLock::new(PeerHandlerLocked {
+                i_am_choked: true,
+                half_open_permit: None,
+            }),
             requests_sem: Semaphore::new(0),
             state: self.clone(),
             tx,
@@ -372,6 +432,7 @@ impl TorrentStateLive {
             self.meta.peer_id,
             &handler,
             Some(options),
+            Default::default(),
             self.meta.spawner,
         );
         let requester = handler.task_peer_chunk_requester();
@@ -397,15 +458,21 @@ impl TorrentStateLive {
             }
         };
         drop(permit);
+        drop(checked_peer.permit);
         Ok(())
     }
 
     async fn task_manage_outgoing_peer(
         self: Arc<Self>,
         addr: SocketAddr,
-        permit: OwnedSemaphorePermit,
+        permits: OutgoingPermits,
     ) -> anyhow::Result<()> {
         let state = self;
+        let OutgoingPermits {
+            torrent: torrent_permit,
+            session: session_permit,
+            half_open,
+        } = permits;
         let (rx, tx) = state.peers.mark_peer_connecting(addr)?;
         let counters = state
             .peers
@@ -416,7 +483,10 @@ impl TorrentStateLive {
             addr,
             on_bitfield_notify: Default::default(),
             unchoke_notify: Default::default(),
-            locked: RwLock::new(PeerHandlerLocked { i_am_choked: true }),
+            locked: RwLock::new(PeerHandlerLocked {
+                i_am_choked: true,
+                half_open_permit: Some(half_open),
+            }),
             requests_sem: Semaphore::new(0),
             state: state.clone(),
             tx,
@@ -433,6 +503,7 @@ impl TorrentStateLive {
             state.meta.peer_id,
             &handler,
             Some(options),
+            state.meta.options.peer_connector.clone(),
             state.meta.spawner,
         );
         let requester = handler.task_peer_chunk_requester();
@@ -456,10 +527,42 @@ impl TorrentStateLive {
                 handler.on_peer_died(Some(e))?;
             }
         }
-        drop(permit);
+        drop((torrent_permit, session_permit));
         Ok::<_, anyhow::Error>(())
     }
 
+    fn try_acquire_outgoing_permits(&self) -> Option<OutgoingPermits> {
+        Some(OutgoingPermits {
+            half_open: self.meta.connection_limiter.try_acquire_half_open()?,
+            torrent: self.peer_semaphore.clone().try_acquire_owned().ok()?,
+            session: self.meta.connection_limiter.try_acquire()?,
+        })
+    }
+
+    async fn acquire_outgoing_permits(&self) -> anyhow::Result<OutgoingPermits> {
+        if let Some(permits) = self.try_acquire_outgoing_permits() {
+            return Ok(permits);
+        }
+        let limiter = &self.meta.connection_limiter;
+        // Wait for a half-open slot first, so that connection slots are not held idle
+        // while other connection attempts finish.
+        let half_open = limiter.acquire_half_open().await?;
+        // Replacing idle peers only frees connection slots, so flag just this wait.
+        self.waiting_for_connection_slot
+            .store(true, Ordering::Relaxed);
+        let permits = async {
+            Ok::<_, anyhow::Error>(OutgoingPermits {
+                torrent: self.peer_semaphore.clone().acquire_owned().await?,
+                session: limiter.acquire().await?,
+                half_open,
+            })
+        }
+        .await;
+        self.waiting_for_connection_slot
+            .store(false, Ordering::Relaxed);
+        permits
+    }
+
     async fn task_peer_adder(
         self: Arc<Self>,
         mut peer_queue_rx: UnboundedReceiver<SocketAddr>,
@@ -467,20 +570,82 @@ impl TorrentStateLive {
         let state = self;
         loop {
             let addr = peer_queue_rx.recv().await.context("torrent closed")?;
+            // The peer could have been banned, or the IP filter reloaded, since it was queued.
+            if state
+                .meta
+                .ip_filter
+                .is_blocked(addr, IpFilterSource::Outgoing)
+            {
+                state.peers.drop_peer(addr);
+                continue;
+            }
             if state.is_finished() {
                 debug!("ignoring peer {} as we are finished", addr);
                 state.peers.mark_peer_not_needed(addr);
                 continue;
             }
 
-            let permit = state.peer_semaphore.clone().acquire_owned().await?;
+            let permits = state.acquire_outgoing_permits().await?;
             state.spawn(
                 error_span!(parent: state.meta.span.clone(), "manage_peer", peer = addr.to_string()),
-                state.clone().task_manage_outgoing_peer(addr, permit),
+                state.clone().task_manage_outgoing_peer(addr, permits),
             );
         }
     }
 
+    // Disconnect peers that send (almost) nothing while others wait for a connection slot.
+    async fn task_replace_idle_peers(state: Weak<Self>) -> anyhow::Result<()> {
+        let mut previous = HashMap::new();
+        loop {
+            tokio::time::sleep(IDLE_PEER_CHECK_INTERVAL).await;
+            let state = match state.upgrade() {
+                Some(state) => state,
+                None => return Ok(()),
+            };
+            let fetched: HashMap<PeerHandle, u64> = state
+                .peers
+                .states
+                .iter()
+                .filter(|e| e.value().state.get_live().is_some())
+                .map(|e| {
+                    let fetched = e
+                        .value()
+                        .stats
+                        .counters
+                        .fetched_bytes
+                        .load(Ordering::Relaxed);
+                    (*e.key(), fetched)
+                })
+                .collect();
+            let previous = std::mem::replace(&mut previous, fetched.clone());
+
+            if !state.waiting_for_connection_slot.load(Ordering::Relaxed) || state.is_finished() {
+                continue;
+            }
+            let queued = state.peers.stats.queued.load(Ordering::Relaxed) as usize;
+            let min_bytes = IDLE_PEER_MIN_BYTES_PER_SEC * IDLE_PEER_CHECK_INTERVAL.as_secs();
+            // Only peers that were connected for the whole interval.
+            let idle = fetched
+                .iter()
+                .filter_map(|(addr, fetched)| {
+                    let sent = fetched.saturating_sub(*previous.get(addr)?);
+                    (sent < min_bytes).then_some((*addr, sent))
+                })
+                .sorted_by_key(|(_, sent)| *sent)
+                .take(queued.min(MAX_REPLACED_PEERS_PER_CHECK));
+            for (addr, sent) in idle {
+                debug!(peer = %addr, sent, "disconnecting idle peer to make room for others");
+                let disconnected = state
+                    .peers
+                    .with_live(addr, |live| live.tx.send(WriterRequest::Disconnect).is_ok())
+                    .unwrap_or_default();
+                if disconnected {
+                    state.meta.connection_limiter.on_replaced_peer();
+                }
+            }
+        }
+    }
+
     pub fn meta(&self) -> &ManagedTorrentInfo {
         &self.meta
     }
@@ -495,10 +660,12 @@ impl TorrentStateLive {
         self.meta.peer_id
     }
     pub(crate) fn file_ops(&self) -> FileOps<'_, Sha1> {
-        FileOps::new(&self.meta.info, &self.files, &self.lengths)
+        FileOps::new(&self.meta.info, &*self.storage, &self.lengths)
+            .with_cache(&self.meta.disk_cache, self.meta.info_hash)
+            .with_metrics(&self.meta.metrics)
     }
     pub fn initially_needed(&self) -> u64 {
-        self.initially_needed_bytes
+        self.initially_needed_bytes.load(Ordering::Acquire)
     }
 
     pub(crate) fn lock_read(
@@ -519,10 +686,20 @@ impl TorrentStateLive {
             p.state
                 .connecting_to_live(Id20::new(h.peer_id), &self.peers.stats);
         });
+        self.emit_peer_connected(handle);
+    }
+
+    fn emit_peer_connected(&self, addr: PeerHandle) {
+        self.meta
+            .send_event(|id, info_hash| SessionEvent::PeerConnected {
+                id,
+                info_hash,
+                addr,
+            });
     }
 
     pub fn get_total_selected_bytes(&self) -> u64 {
-        self.total_selected_bytes
+        self.total_selected_bytes.load(Ordering::Relaxed)
     }
 
     pub fn get_uploaded_bytes(&self) -> u64 {
@@ -542,8 +719,15 @@ impl TorrentStateLive {
         self.get_left_to_download_bytes() == 0
     }
 
+    // Downloaded bytes of the pieces that count towards finishing.
+    fn get_selected_downloaded_bytes(&self) -> u64 {
+        self.get_downloaded_bytes()
+            .saturating_sub(self.deselected_downloaded_bytes.load(Ordering::Acquire))
+    }
+
     pub fn get_left_to_download_bytes(&self) -> u64 {
-        self.initially_needed_bytes - self.get_downloaded_bytes()
+        self.initially_needed()
+            .saturating_sub(self.get_selected_downloaded_bytes())
     }
 
     fn maybe_transmit_haves(&self, index: ValidPieceIndex) {
@@ -603,8 +787,44 @@ impl TorrentStateLive {
         );
     }
 
-    pub(crate) fn add_peer_if_not_seen(&self, addr: SocketAddr) -> anyhow::Result<bool> {
-        match self.peers.add_if_not_seen(addr) {
+    /// Disconnect the peer. Returns false if it isn't connected.
+    pub fn disconnect_peer(&self, addr: SocketAddr) -> bool {
+        self.peers
+            .with_live(addr, |live| {
+                let _ = live.tx.send(WriterRequest::Disconnect);
+            })
+            .is_some()
+    }
+
+    /// Disconnect all peers with this IP, e.g. after it was banned.
+    pub(crate) fn disconnect_ip(&self, ip: IpAddr) {
+        let ip = ip.to_canonical();
+        let addrs = self
+            .peers
+            .states
+            .iter()
+            .map(|e| *e.key())
+            .filter(|addr| addr.ip().to_canonical() == ip)
+            .collect::<Vec<_>>();
+        for addr in addrs {
+            self.disconnect_peer(addr);
+        }
+    }
+
+    pub(crate) fn add_peer_if_not_seen(
+        &self,
+        addr: SocketAddr,
+        source: PeerSource,
+    ) -> anyhow::Result<bool> {
+        // Peers from trackers and DHT were filtered already, this catches the rest.
+        if self
+            .meta
+            .ip_filter
+            .is_blocked(addr, IpFilterSource::Outgoing)
+        {
+            return Ok(false);
+        }
+        match self.peers.add_if_not_seen(addr, source) {
             Some(handle) => handle,
             None => return Ok(false),
         };
@@ -622,7 +842,14 @@ impl TorrentStateLive {
             fetched_bytes: self.stats.fetched_bytes.load(Relaxed),
             uploaded_bytes: self.stats.uploaded_bytes.load(Relaxed),
             total_piece_download_ms: self.stats.total_piece_download_ms.load(Relaxed),
-            peer_stats: self.peers.stats(),
+            hash_failed_pieces: self.stats.hash_failed_pieces.load(Relaxed),
+            peer_stats: {
+                let mut s = self.peers.stats();
+                if self.waiting_for_connection_slot.load(Ordering::Relaxed) {
+                    s.queued_by_limit = s.queued;
+                }
+                s
+            },
         }
     }
 
@@ -645,23 +872,33 @@ impl TorrentStateLive {
         self.finished_notify.notified().await;
     }
 
+    /// Wait until the piece is downloaded and verified.
+    /// Errors out if the torrent gets paused.
+    pub(crate) async fn wait_for_piece(&self, index: ValidPieceIndex) -> anyhow::Result<()> {
+        loop {
+            let notified = self.have_notify.notified();
+            tokio::pin!(notified);
+            // Register before checking, so that we don't miss a notification.
+            notified.as_mut().enable();
+            if self
+                .lock_read("wait_for_piece")
+                .get_chunks()?
+                .is_piece_have(index)
+            {
+                return Ok(());
+            }
+            notified.await;
+        }
+    }
+
     pub fn pause(&self) -> anyhow::Result<TorrentStatePaused> {
         self.cancellation_token.cancel();
 
         let mut g = self.locked.write();
 
-        let files = self
-            .files
-            .iter()
-            .map(|f| {
-                let mut f = f.lock();
-                let dummy = dummy_file()?;
-                let f = std::mem::replace(&mut *f, dummy);
-                Ok::<_, anyhow::Error>(Arc::new(Mutex::new(f)))
-            })
-            .try_collect()?;
-
-        let filenames = self.filenames.clone();
+        let storage = self.storage.take()?;
+        // In-flight pieces are marked broken below, so their buffered chunks are not needed.
+        self.meta.disk_cache.forget_torrent(self.meta.info_hash);
 
         let mut chunk_tracker = g
             .chunks
@@ -670,21 +907,185 @@ impl TorrentStateLive {
         for piece_id in g.inflight_pieces.keys().copied() {
             chunk_tracker.mark_piece_broken_if_not_have(piece_id);
         }
+        g.piece_senders.clear();
         let have_bytes = chunk_tracker.calc_have_bytes();
         let needed_bytes = chunk_tracker.calc_needed_bytes();
 
+        // Wake up the readers waiting for pieces, they will see that the torrent was paused.
+        self.have_notify.notify_waiters();
+
         // g.chunks;
         Ok(TorrentStatePaused {
             info: self.meta.clone(),
-            files,
-            filenames,
+            storage,
             chunk_tracker,
             have_bytes,
             needed_bytes,
         })
     }
 
+    pub(crate) fn set_file_priorities(
+        &self,
+        file_priorities: &[FilePriority],
+    ) -> anyhow::Result<()> {
+        let was_finished = self.is_finished();
+
+        // Create the missing files first, so that newly selected pieces have somewhere to go.
+        let piece_priorities =
+            prepare_files_for_priorities(&self.meta, &*self.storage, file_priorities)?;
+
+        {
+            let mut g = self.lock_write("set_file_priorities");
+            let g = &mut **g;
+            let inflight_pieces = &g.inflight_pieces;
+            let chunks = g
+                .chunks
+                .as_mut()
+                .context("chunk tracker empty, torrent was paused")?;
+            let remaining = chunks.update_piece_priorities(piece_priorities, |piece| {
+                inflight_pieces.contains_key(&piece)
+            });
+            // Deselected pieces are not downloaded further, unless they are in-flight.
+            self.meta
+                .disk_cache
+                .forget_write_buffers(self.meta.info_hash, |piece| {
+                    self.lengths
+                        .validate_piece_index(piece)
+                        .is_some_and(|piece| {
+                            !chunks.is_piece_selected(piece)
+                                && !inflight_pieces.contains_key(&piece)
+                        })
+                });
+            self.total_selected_bytes
+                .store(chunks.get_total_selected_bytes(), Ordering::Relaxed);
+            // Downloaded bytes are only updated under this lock, so this stays consistent.
+            self.initially_needed_bytes.store(
+                self.get_selected_downloaded_bytes() + remaining,
+                Ordering::Release,
+            );
+        }
+
+        match (was_finished, self.is_finished()) {
+            (false, true) => {
+                info!("torrent finished downloading, as the remaining files were deselected");
+                self.on_finished()?;
+            }
+            (true, false) => {
+                info!("new files were selected, resuming download");
+                self.reopen_files(false)?;
+                self.unfinished_notify.notify_waiters();
+                self.requeue_not_needed_peers();
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    pub(crate) fn set_sequential_piece_ids(
+        &self,
+        piece_ids: Option<Vec<usize>>,
+    ) -> anyhow::Result<()> {
+        self.lock_write("set_sequential_piece_ids")
+            .get_chunks_mut()?
+            .set_sequential_piece_ids(piece_ids);
+        Ok(())
+    }
+
+    fn on_finished(&self) -> anyhow::Result<()> {
+        self.disconnect_all_peers_that_have_full_torrent();
+        // The files are seeded from wherever they are if this fails.
+        if let Err(e) = self.finalize_files() {
+            warn!(error=?e, "error moving finished files into the output folder");
+        }
+        self.reopen_files(true)?;
+        // Notify last, so that waiters find the files in their final place.
+        self.finished_notify.notify_waiters();
+        self.meta.emit(TorrentEventKind::Completed, None);
+        Ok(())
+    }
+
+    // Move finished files out of the incomplete folder, or drop their .part suffix.
+    fn finalize_files(&self) -> anyhow::Result<()> {
+        let completed = {
+            let g = self.lock_read("finalize_files");
+            let have = g.get_chunks()?.get_have_pieces();
+            compute_completed_files(
+                &self.lengths,
+                self.meta.info.iter_file_lengths()?,
+                |piece| have[piece],
+            )
+        };
+        // Not under the state lock, as moving to another device copies the files. Storage
+        // locks each file while it's moved, and completed files are only read from.
+        self.storage.finalize_files(&completed)
+    }
+
+    fn disconnect_all_peers_that_have_full_torrent(&self) {
+        for mut pe in self.peers.states.iter_mut() {
+            if let PeerState::Live(l) = pe.value().state.get() {
+                if l.has_full_torrent(self.lengths.total_pieces() as usize) {
+                    let prev = pe.value_mut().state.set_not_needed(&self.peers.stats);
+                    let _ = prev
+                        .take_live_no_counters()
+                        .unwrap()
+                        .tx
+                        .send(WriterRequest::Disconnect);
+                }
+            }
+        }
+    }
+
+    // When we need to download again after being finished, give the peers we stopped talking to
+    // another chance.
+    fn requeue_not_needed_peers(&self) {
+        for mut pe in self.peers.states.iter_mut() {
+            if let PeerState::NotNeeded = pe.value().state.get() {
+                pe.value_mut()
+                    .state
+                    .set(PeerState::Queued, &self.peers.stats);
+                let _ = self.peer_queue_tx.send(*pe.key());
+            }
+        }
+    }
+
+    fn reopen_files(&self, read_only: bool) -> anyhow::Result<()> {
+        // Lock exclusive just in case to ensure in-flight operations finish.??
+        let _guard = self.lock_write("reopen_files");
+
+        self.storage.set_read_only(read_only)
+    }
+
+    // Count a piece that failed the hash check against the peer that sent it, and ban the peer
+    // if it does that too often.
+    fn on_corrupt_data(&self, addr: PeerHandle) {
+        let hash_failures = match self.peers.with_peer(addr, |p| {
+            p.stats
+                .counters
+                .hash_failures
+                .fetch_add(1, Ordering::Relaxed)
+                + 1
+        }) {
+            Some(n) => n,
+            None => return,
+        };
+        debug!(peer = %addr, hash_failures, "peer sent corrupt data");
+        if self
+            .meta
+            .ip_filter
+            .ban_for_hash_failures(addr, hash_failures)
+        {
+            self.peers.with_live(addr, |live| {
+                let _ = live.tx.send(WriterRequest::Disconnect);
+            });
+        }
+    }
+
     fn on_fatal_error(&self, e: anyhow::Error) -> anyhow::Result<()> {
+        let e = if is_out_of_space(&e) {
+            e.context(format!("no space left on device in {:?}", self.meta.out_dir()))
+        } else {
+            e
+        };
         let mut g = self.lock_write("fatal_error");
         let tx = g
             .fatal_errors_tx
@@ -700,6 +1101,8 @@ impl TorrentStateLive {
 
 struct PeerHandlerLocked {
     pub i_am_choked: bool,
+    // Released once the TCP connection is established.
+    pub half_open_permit: Option<OwnedSemaphorePermit>,
 }
 
 // All peer state that would never be used by other actors should pe put here.
@@ -731,6 +1134,7 @@ struct PeerHandler {
 
 impl<'a> PeerConnectionHandler for &'a PeerHandler {
     fn on_connected(&self, connection_time: Duration) {
+        self.locked.write().half_open_permit = None;
         self.counters
             .outgoing_connections
             .fetch_add(1, Ordering::Relaxed);
@@ -788,13 +1192,24 @@ impl<'a> PeerConnectionHandler for &'a PeerHandler {
             .stats
             .uploaded_bytes
             .fetch_add(bytes as u64, Ordering::Relaxed);
+        SessionMetrics::inc(&self.state.meta.metrics.uploaded_bytes, bytes as u64);
     }
 
     fn read_chunk(&self, chunk: &ChunkInfo, buf: &mut [u8]) -> anyhow::Result<()> {
         self.state.file_ops().read_chunk(self.addr, chunk, buf)
     }
 
-    fn on_extended_handshake(&self, _: &ExtendedHandshake<ByteBuf>) -> anyhow::Result<()> {
+    fn on_extended_handshake(&self, h: &ExtendedHandshake<ByteBuf>) -> anyhow::Result<()> {
+        let mut extensions =
+            h.m.keys()
+                .map(|k| String::from_utf8_lossy(k.as_ref()).into_owned())
+                .collect::<Vec<_>>();
+        extensions.sort();
+        self.state
+            .peers
+            .with_live_mut(self.addr, "on_extended_handshake", |live| {
+                live.extensions = extensions;
+            });
         Ok(())
     }
 
@@ -906,6 +1321,9 @@ impl PeerHandler {
     }
 
     fn reserve_next_needed_piece(&self) -> anyhow::Result<Option<ValidPieceIndex>> {
+        // Pieces that are right ahead of the streaming readers go first.
+        let stream_pieces = self.state.streams.window_pieces(&self.state.lengths);
+
         // TODO: locking one inside the other in different order results in deadlocks.
         self.state
             .peers
@@ -919,7 +1337,12 @@ impl PeerHandler {
                 let n = {
                     let mut n_opt = None;
                     let bf = &live.bitfield;
-                    for n in g.get_chunks()?.iter_needed_pieces() {
+                    let chunks = g.get_chunks()?;
+                    let stream_pieces = stream_pieces
+                        .iter()
+                        .filter(|p| chunks.is_piece_needed(**p))
+                        .map(|p| p.get() as usize);
+                    for n in stream_pieces.chain(chunks.iter_needed_pieces()) {
                         if bf.get(n).map(|v| *v) == Some(true) {
                             n_opt = Some(n);
                             break;
@@ -977,7 +1400,14 @@ impl PeerHandler {
             );
             piece_req.peer = self.addr;
             piece_req.started = Instant::now();
-            return Some(*idx);
+            let idx = *idx;
+            // All chunks get requested again, so drop the ones received from the slow peer.
+            g.get_chunks_mut().ok()?.reset_piece_chunks(idx);
+            self.state
+                .meta
+                .disk_cache
+                .forget_write_buffers(self.state.meta.info_hash, |piece| piece == idx.get());
+            return Some(idx);
         }
         None
     }
@@ -1120,10 +1550,14 @@ impl PeerHandler {
             self.wait_for_unchoke().await;
 
             if self.state.is_finished() {
-                debug!("nothing left to download, looping forever until manage_peer quits");
-                loop {
-                    tokio::time::sleep(Duration::from_secs(86400)).await;
-                }
+                debug!("nothing left to download, waiting until manage_peer quits or more pieces are needed");
+                self.wait_for_any_notify(&self.state.unfinished_notify, || {
+                    !self.state.is_finished()
+                })
+                .await;
+                self.tx
+                    .send(WriterRequest::Message(MessageOwned::Interested))?;
+                continue;
             }
 
             // Try steal a pice from a very slow peer first. Otherwise we might wait too long
@@ -1198,27 +1632,6 @@ impl PeerHandler {
         self.state.peers.mark_peer_interested(self.addr, true);
     }
 
-    fn reopen_read_only(&self) -> anyhow::Result<()> {
-        // Lock exclusive just in case to ensure in-flight operations finish.??
-        let _guard = self.state.lock_write("reopen_read_only");
-
-        for (file, filename) in self.state.files.iter().zip(self.state.filenames.iter()) {
-            let mut g = file.lock();
-            // this should close the original file
-            // putting in a block just in case to guarantee drop.
-            {
-                *g = dummy_file()?;
-            }
-            *g = std::fs::OpenOptions::new()
-                .read(true)
-                .open(filename)
-                .with_context(|| format!("error re-opening {:?} readonly", filename))?;
-            debug!("reopened {:?} read-only", filename);
-        }
-        info!("reopened all torrent files in read-only mode");
-        Ok(())
-    }
-
     fn on_i_am_unchoked(&self) {
         trace!("we are unchoked");
         self.locked.write().i_am_choked = false;
@@ -1245,12 +1658,17 @@ impl PeerHandler {
             .fetched_bytes
             .fetch_add(piece.block.len() as u64, Ordering::Relaxed);
         self.counters.fetched_chunks.fetch_add(1, Ordering::Relaxed);
+        let fingerprint = self.state.chunk_hasher.hash_one(piece.block.as_ref());
 
         // Global chunk/byte counters.
         self.state
             .stats
             .fetched_bytes
             .fetch_add(piece.block.len() as u64, Ordering::Relaxed);
+        SessionMetrics::inc(
+            &self.state.meta.metrics.fetched_bytes,
+            piece.block.len() as u64,
+        );
 
         self.state
             .peers
@@ -1269,7 +1687,8 @@ impl PeerHandler {
             })
             .context("peer not found")??;
 
-        let full_piece_download_time = {
+        let chunk_copy = self.state.file_ops().copy_chunk_for_cache(&piece);
+        let (full_piece_download_time, buffered) = {
             let mut g = self.state.lock_write("mark_chunk_downloaded");
 
             match g.inflight_pieces.get(&chunk_info.piece_index) {
@@ -1290,7 +1709,14 @@ impl PeerHandler {
                 }
             };
 
-            match g.get_chunks_mut()?.mark_chunk_downloaded(&piece) {
+            let marked = g.get_chunks_mut()?.mark_chunk_downloaded(&piece);
+            if let Some(ChunkMarkingResult::Completed | ChunkMarkingResult::NotCompleted) = marked {
+                let chunks_in_piece =
+                    self.state.lengths.chunks_per_piece(chunk_info.piece_index) as usize;
+                g.piece_senders
+                    .record(&chunk_info, chunks_in_piece, self.addr, fingerprint);
+            }
+            let full_piece_download_time = match marked {
                 Some(ChunkMarkingResult::Completed) => {
                     trace!("piece={} done, will write and checksum", piece.index,);
                     // This will prevent others from stealing it.
@@ -1312,7 +1738,14 @@ impl PeerHandler {
                         piece
                     );
                 }
-            }
+            };
+            // Buffered under the lock, so that the piece's buffer is complete by the time
+            // check_piece() takes it. The chunk was copied before taking the lock.
+            let buffered = self
+                .state
+                .file_ops()
+                .buffer_chunk(self.addr, chunk_copy, &chunk_info);
+            (full_piece_download_time, buffered)
         };
 
         // By this time we reach here, no other peer can for this piece. All others, even if they steal pieces would
@@ -1328,13 +1761,12 @@ impl PeerHandler {
                 // should we really do? If we unmark it, it will get requested forever...
                 //
                 // So let's just unwrap and abort.
-                match self
-                    .state
-                    .file_ops()
-                    .write_chunk(self.addr, &piece, &chunk_info)
-                {
-                    Ok(()) => {}
-                    Err(e) => {
+                if !buffered {
+                    if let Err(e) =
+                        self.state
+                            .file_ops()
+                            .write_chunk(self.addr, &piece, &chunk_info)
+                    {
                         error!("FATAL: error writing chunk to disk: {:?}", e);
                         return self.state.on_fatal_error(e);
                     }
@@ -1345,34 +1777,61 @@ impl PeerHandler {
                     None => return Ok(()),
                 };
 
-                match self
+                // With the disk cache on, this is also where the piece gets written to disk.
+                let is_valid = match self
                     .state
                     .file_ops()
                     .check_piece(self.addr, chunk_info.piece_index, &chunk_info)
-                    .with_context(|| format!("error checking piece={index}"))?
+                    .with_context(|| format!("error checking piece={index}"))
                 {
+                    Ok(v) => v,
+                    Err(e) => {
+                        error!("FATAL: {:?}", e);
+                        return self.state.on_fatal_error(e);
+                    }
+                };
+
+                match is_valid {
                     true => {
-                        {
+                        let piece_len =
+                            self.state.lengths.piece_length(chunk_info.piece_index) as u64;
+                        let culprits = {
                             let mut g = self.state.lock_write("mark_piece_downloaded");
-                            g.get_chunks_mut()?
-                                .mark_piece_downloaded(chunk_info.piece_index);
+                            let chunks = g.get_chunks_mut()?;
+                            chunks.mark_piece_downloaded(chunk_info.piece_index);
+
+                            // If the piece was deselected while in-flight, it was not accounted
+                            // as needed, so don't let it count towards finishing.
+                            if !chunks.is_piece_selected(chunk_info.piece_index) {
+                                self.state
+                                    .deselected_downloaded_bytes
+                                    .fetch_add(piece_len, Ordering::Release);
+                            }
+
+                            // Global piece counters.
+                            // These are updated under the lock, so that changing file priorities
+                            // sees them consistent with the chunk tracker.
+                            self.state
+                                .stats
+                                .downloaded_and_checked_bytes
+                                // This counter is used to compute "is_finished", so using
+                                // stronger ordering.
+                                .fetch_add(piece_len, Ordering::Release);
+                            self.state
+                                .stats
+                                .downloaded_and_checked_pieces
+                                // This counter is used to compute "is_finished", so using
+                                // stronger ordering.
+                                .fetch_add(1, Ordering::Release);
+
+                            g.piece_senders.on_piece_passed(chunk_info.piece_index)
+                        };
+                        self.state.have_notify.notify_waiters();
+                        // Peers that sent different data for this piece before, when it failed.
+                        for peer in culprits {
+                            self.state.on_corrupt_data(peer);
                         }
 
-                        // Global piece counters.
-                        let piece_len =
-                            self.state.lengths.piece_length(chunk_info.piece_index) as u64;
-                        self.state
-                            .stats
-                            .downloaded_and_checked_bytes
-                            // This counter is used to compute "is_finished", so using
-                            // stronger ordering.
-                            .fetch_add(piece_len, Ordering::Release);
-                        self.state
-                            .stats
-                            .downloaded_and_checked_pieces
-                            // This counter is used to compute "is_finished", so using
-                            // stronger ordering.
-                            .fetch_add(1, Ordering::Release);
                         self.state
                             .stats
                             .have_bytes
@@ -1389,11 +1848,17 @@ impl PeerHandler {
 
                         debug!("piece={} successfully downloaded and verified", index);
 
+                        self.state
+                            .meta
+                            .send_event(|id, info_hash| SessionEvent::PieceCompleted {
+                                id,
+                                info_hash,
+                                piece: index,
+                            });
+
                         if self.state.is_finished() {
                             info!("torrent finished downloading");
-                            self.state.finished_notify.notify_waiters();
-                            self.disconnect_all_peers_that_have_full_torrent();
-                            self.reopen_read_only()?;
+                            self.state.on_finished()?;
                         }
 
                         self.state.maybe_transmit_haves(chunk_info.piece_index);
@@ -1401,9 +1866,25 @@ impl PeerHandler {
                     false => {
                         warn!("checksum for piece={} did not validate", index,);
                         self.state
-                            .lock_write("mark_piece_broken")
-                            .get_chunks_mut()?
-                            .mark_piece_broken_if_not_have(chunk_info.piece_index);
+                            .stats
+                            .hash_failed_pieces
+                            .fetch_add(1, Ordering::Relaxed);
+                        SessionMetrics::inc(&self.state.meta.metrics.hash_failures, 1);
+                        let culprit = {
+                            let mut g = self.state.lock_write("mark_piece_broken");
+                            g.get_chunks_mut()?
+                                .mark_piece_broken_if_not_have(chunk_info.piece_index);
+                            self.state
+                                .meta
+                                .disk_cache
+                                .forget_write_buffers(self.state.meta.info_hash, |piece| {
+                                    piece == chunk_info.piece_index.get()
+                                });
+                            g.piece_senders.on_piece_failed(chunk_info.piece_index)
+                        };
+                        if let Some(peer) = culprit {
+                            self.state.on_corrupt_data(peer);
+                        }
                     }
                 };
                 Ok::<_, anyhow::Error>(())
@@ -1411,19 +1892,4 @@ impl PeerHandler {
             .with_context(|| format!("error processing received chunk {chunk_info:?}"))?;
         Ok(())
     }
-
-    fn disconnect_all_peers_that_have_full_torrent(&self) {
-        for mut pe in self.state.peers.states.iter_mut() {
-            if let PeerState::Live(l) = pe.value().state.get() {
-                if l.has_full_torrent(self.state.lengths.total_pieces() as usize) {
-                    let prev = pe.value_mut().state.set_not_needed(&self.state.peers.stats);
-                    let _ = prev
-                        .take_live_no_counters()
-                        .unwrap()
-                        .tx
-                        .send(WriterRequest::Disconnect);
-                }
-            }
-        }
-    }
 }
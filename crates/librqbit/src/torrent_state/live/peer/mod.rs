@@ -4,6 +4,7 @@ use std::collections::HashSet;
 
 use librqbit_core::hash_id::Id20;
 use librqbit_core::lengths::{ChunkInfo, ValidPieceIndex};
+use serde::{Deserialize, Serialize};
 
 use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
 
@@ -30,23 +31,79 @@ impl From<&ChunkInfo> for InflightRequest {
 pub(crate) type PeerRx = UnboundedReceiver<WriterRequest>;
 pub(crate) type PeerTx = UnboundedSender<WriterRequest>;
 
-#[derive(Debug, Default)]
+/// Where we first heard of a peer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PeerSource {
+    Tracker,
+    Dht,
+    /// The peer connected to us.
+    Incoming,
+    /// Passed in AddTorrentOptions::initial_peers.
+    InitialPeers,
+    /// Added through the API.
+    Manual,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PeerDirection {
+    Incoming,
+    Outgoing,
+}
+
+impl std::fmt::Display for PeerSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            PeerSource::Tracker => "tracker",
+            PeerSource::Dht => "dht",
+            PeerSource::Incoming => "incoming",
+            PeerSource::InitialPeers => "initial_peers",
+            PeerSource::Manual => "manual",
+        })
+    }
+}
+
+impl std::fmt::Display for PeerDirection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            PeerDirection::Incoming => "in",
+            PeerDirection::Outgoing => "out",
+        })
+    }
+}
+
+#[derive(Debug)]
 pub(crate) struct Peer {
     pub state: PeerStateNoMut,
     pub stats: stats::atomic::PeerStats,
+    pub source: PeerSource,
 }
 
 impl Peer {
+    pub fn new(source: PeerSource) -> Self {
+        Self {
+            state: Default::default(),
+            stats: Default::default(),
+            source,
+        }
+    }
+
     pub fn new_live_for_incoming_connection(
         peer_id: Id20,
         tx: PeerTx,
         counters: &AggregatePeerStatsAtomic,
     ) -> Self {
-        let state = PeerStateNoMut(PeerState::Live(LivePeerState::new(peer_id, tx)));
+        let state = PeerStateNoMut(PeerState::Live(LivePeerState::new(
+            peer_id,
+            tx,
+            PeerDirection::Incoming,
+        )));
         counters.inc(&state.0);
         Self {
             state,
             stats: Default::default(),
+            source: PeerSource::Incoming,
         }
     }
 }
@@ -147,7 +204,10 @@ impl PeerStateNoMut {
         }
         match self.take(counters) {
             PeerState::Queued | PeerState::Dead | PeerState::NotNeeded => {
-                self.set(PeerState::Live(LivePeerState::new(peer_id, tx)), counters);
+                self.set(
+                    PeerState::Live(LivePeerState::new(peer_id, tx, PeerDirection::Incoming)),
+                    counters,
+                );
             }
             PeerState::Connecting(..) | PeerState::Live(..) => unreachable!(),
         }
@@ -164,7 +224,10 @@ impl PeerStateNoMut {
                 PeerState::Connecting(tx) => tx,
                 _ => unreachable!(),
             };
-            self.set(PeerState::Live(LivePeerState::new(peer_id, tx)), counters);
+            self.set(
+                PeerState::Live(LivePeerState::new(peer_id, tx, PeerDirection::Outgoing)),
+                counters,
+            );
             self.get_live_mut()
         } else {
             None
@@ -178,8 +241,12 @@ impl PeerStateNoMut {
 
 #[derive(Debug)]
 pub(crate) struct LivePeerState {
-    #[allow(dead_code)]
-    peer_id: Id20,
+    pub peer_id: Id20,
+
+    pub direction: PeerDirection,
+
+    // Extensions from the peer's extended handshake, e.g. "ut_metadata".
+    pub extensions: Vec<String>,
 
     pub peer_interested: bool,
 
@@ -194,9 +261,11 @@ pub(crate) struct LivePeerState {
 }
 
 impl LivePeerState {
-    pub fn new(peer_id: Id20, tx: PeerTx) -> Self {
+    pub fn new(peer_id: Id20, tx: PeerTx, direction: PeerDirection) -> Self {
         LivePeerState {
             peer_id,
+            direction,
+            extensions: Vec::new(),
             peer_interested: false,
             bitfield: BF::new(),
             inflight_requests: Default::default(),
@@ -16,6 +16,8 @@ pub(crate) struct PeerCountersAtomic {
     pub outgoing_connection_attempts: AtomicU32,
     pub outgoing_connections: AtomicU32,
     pub errors: AtomicU32,
+    // Pieces that failed the hash check because of data from this peer.
+    pub hash_failures: AtomicU32,
     pub fetched_chunks: AtomicU32,
     pub downloaded_and_checked_pieces: AtomicU32,
     pub downloaded_and_checked_bytes: AtomicU64,
@@ -1,8 +1,9 @@
-use std::{collections::HashMap, sync::atomic::Ordering};
+use std::{borrow::Cow, collections::HashMap, sync::atomic::Ordering};
 
+use librqbit_core::peer_id::try_decode_peer_id;
 use serde::{Deserialize, Serialize};
 
-use crate::torrent_state::live::peer::{Peer, PeerState};
+use crate::torrent_state::live::peer::{Peer, PeerDirection, PeerSource, PeerState};
 
 #[derive(Serialize, Deserialize)]
 pub struct PeerCounters {
@@ -12,6 +13,7 @@ pub struct PeerCounters {
     pub connection_attempts: u32,
     pub connections: u32,
     pub errors: u32,
+    pub hash_failures: u32,
     pub fetched_chunks: u32,
     pub downloaded_and_checked_pieces: u32,
     pub total_piece_download_ms: u64,
@@ -20,7 +22,17 @@ pub struct PeerCounters {
 #[derive(Serialize, Deserialize)]
 pub struct PeerStats {
     pub counters: PeerCounters,
-    pub state: &'static str,
+    pub state: Cow<'static, str>,
+    pub source: PeerSource,
+    // The fields below are only set for live peers.
+    /// The client, decoded from the peer id, e.g. "Transmission 3.0.0.0".
+    #[serde(default)]
+    pub client: Option<String>,
+    #[serde(default)]
+    pub direction: Option<PeerDirection>,
+    /// Extensions from the peer's extended handshake.
+    #[serde(default)]
+    pub extensions: Vec<String>,
 }
 
 impl From<&super::atomic::PeerCountersAtomic> for PeerCounters {
@@ -34,6 +46,7 @@ impl From<&super::atomic::PeerCountersAtomic> for PeerCounters {
                 .load(Ordering::Relaxed),
             connections: counters.outgoing_connections.load(Ordering::Relaxed),
             errors: counters.errors.load(Ordering::Relaxed),
+            hash_failures: counters.hash_failures.load(Ordering::Relaxed),
             fetched_chunks: counters.fetched_chunks.load(Ordering::Relaxed),
             downloaded_and_checked_pieces: counters
                 .downloaded_and_checked_pieces
@@ -45,19 +58,26 @@ impl From<&super::atomic::PeerCountersAtomic> for PeerCounters {
 
 impl From<&Peer> for PeerStats {
     fn from(peer: &Peer) -> Self {
+        let live = peer.state.get_live();
         Self {
             counters: peer.stats.counters.as_ref().into(),
-            state: peer.state.get().name(),
+            state: Cow::Borrowed(peer.state.get().name()),
+            source: peer.source,
+            client: live
+                .and_then(|l| try_decode_peer_id(l.peer_id))
+                .map(|id| id.to_string()),
+            direction: live.map(|l| l.direction),
+            extensions: live.map(|l| l.extensions.clone()).unwrap_or_default(),
         }
     }
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, Deserialize)]
 pub struct PeerStatsSnapshot {
     pub peers: HashMap<String, PeerStats>,
 }
 
-#[derive(Clone, Copy, Default, Deserialize)]
+#[derive(Clone, Copy, Default, Serialize, Deserialize)]
 pub enum PeerStatsFilterState {
     All,
     #[default]
@@ -70,7 +90,7 @@ impl PeerStatsFilterState {
     }
 }
 
-#[derive(Default, Deserialize)]
+#[derive(Default, Serialize, Deserialize)]
 pub struct PeerStatsFilter {
     pub state: PeerStatsFilterState,
 }
@@ -11,7 +11,7 @@ use crate::{
 
 use self::stats::{atomic::AggregatePeerStatsAtomic, snapshot::AggregatePeerStats};
 
-use super::peer::{LivePeerState, Peer, PeerRx, PeerState, PeerTx};
+use super::peer::{LivePeerState, Peer, PeerRx, PeerSource, PeerState, PeerTx};
 
 pub mod stats;
 
@@ -26,14 +26,14 @@ impl PeerStates {
         AggregatePeerStats::from(&self.stats)
     }
 
-    pub fn add_if_not_seen(&self, addr: SocketAddr) -> Option<PeerHandle> {
+    pub fn add_if_not_seen(&self, addr: SocketAddr, source: PeerSource) -> Option<PeerHandle> {
         use dashmap::mapref::entry::Entry;
         match self.states.entry(addr) {
             Entry::Occupied(_) => None,
             Entry::Vacant(vac) => {
-                vac.insert(Default::default());
+                vac.insert(Peer::new(source));
                 atomic_inc(&self.stats.queued);
-                atomic_inc(&self.stats.seen);
+                self.stats.on_seen(source);
                 Some(addr)
             }
         }
@@ -3,7 +3,7 @@ use std::sync::atomic::AtomicU32;
 use serde::Serialize;
 
 use crate::torrent_state::{
-    live::peer::PeerState,
+    live::peer::{PeerSource, PeerState},
     utils::{atomic_dec, atomic_inc},
 };
 
@@ -15,6 +15,28 @@ pub(crate) struct AggregatePeerStatsAtomic {
     pub seen: AtomicU32,
     pub dead: AtomicU32,
     pub not_needed: AtomicU32,
+    pub sources: PeerSourceCountersAtomic,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub(crate) struct PeerSourceCountersAtomic {
+    pub tracker: AtomicU32,
+    pub dht: AtomicU32,
+    pub incoming: AtomicU32,
+    pub initial_peers: AtomicU32,
+    pub manual: AtomicU32,
+}
+
+impl PeerSourceCountersAtomic {
+    pub fn counter(&self, source: PeerSource) -> &AtomicU32 {
+        match source {
+            PeerSource::Tracker => &self.tracker,
+            PeerSource::Dht => &self.dht,
+            PeerSource::Incoming => &self.incoming,
+            PeerSource::InitialPeers => &self.initial_peers,
+            PeerSource::Manual => &self.manual,
+        }
+    }
 }
 
 impl AggregatePeerStatsAtomic {
@@ -28,6 +50,11 @@ impl AggregatePeerStatsAtomic {
         }
     }
 
+    pub fn on_seen(&self, source: PeerSource) {
+        atomic_inc(&self.seen);
+        atomic_inc(self.sources.counter(source));
+    }
+
     pub fn inc(&self, state: &PeerState) {
         atomic_inc(self.counter(state));
     }
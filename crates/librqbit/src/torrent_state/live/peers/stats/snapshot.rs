@@ -1,10 +1,10 @@
 use std::sync::atomic::Ordering;
 
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 
-use super::atomic::AggregatePeerStatsAtomic;
+use super::atomic::{AggregatePeerStatsAtomic, PeerSourceCountersAtomic};
 
-#[derive(Debug, Default, Serialize, PartialEq, Eq)]
+#[derive(Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
 pub struct AggregatePeerStats {
     pub queued: usize,
     pub connecting: usize,
@@ -12,6 +12,34 @@ pub struct AggregatePeerStats {
     pub seen: usize,
     pub dead: usize,
     pub not_needed: usize,
+    /// Queued peers waiting because a connection limit was reached.
+    #[serde(default)]
+    pub queued_by_limit: usize,
+    /// Peers seen, by where we first heard of them.
+    #[serde(default)]
+    pub sources: PeerSourceCounts,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct PeerSourceCounts {
+    pub tracker: usize,
+    pub dht: usize,
+    pub incoming: usize,
+    pub initial_peers: usize,
+    pub manual: usize,
+}
+
+impl<'a> From<&'a PeerSourceCountersAtomic> for PeerSourceCounts {
+    fn from(s: &'a PeerSourceCountersAtomic) -> Self {
+        let ordering = Ordering::Relaxed;
+        Self {
+            tracker: s.tracker.load(ordering) as usize,
+            dht: s.dht.load(ordering) as usize,
+            incoming: s.incoming.load(ordering) as usize,
+            initial_peers: s.initial_peers.load(ordering) as usize,
+            manual: s.manual.load(ordering) as usize,
+        }
+    }
 }
 
 impl<'a> From<&'a AggregatePeerStatsAtomic> for AggregatePeerStats {
@@ -24,6 +52,8 @@ impl<'a> From<&'a AggregatePeerStatsAtomic> for AggregatePeerStats {
             seen: s.seen.load(ordering) as usize,
             dead: s.dead.load(ordering) as usize,
             not_needed: s.not_needed.load(ordering) as usize,
+            queued_by_limit: 0,
+            sources: (&s.sources).into(),
         }
     }
 }
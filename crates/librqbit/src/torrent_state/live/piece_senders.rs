@@ -0,0 +1,138 @@
+// Who sent the chunks of the pieces being downloaded, to find out who sent corrupt data
+// when a piece fails its hash check.
+//
+// If all chunks of a bad piece came from one peer, that peer is to blame. Otherwise the
+// senders of the chunks are kept along with fingerprints of the data, and once the piece
+// is downloaded correctly, the peers whose chunks differ from the good ones are to blame.
+
+use std::collections::HashMap;
+
+use itertools::Itertools;
+use librqbit_core::lengths::{ChunkInfo, ValidPieceIndex};
+
+use crate::type_aliases::PeerHandle;
+
+#[derive(Clone, Copy)]
+struct ChunkSender {
+    peer: PeerHandle,
+    fingerprint: u64,
+}
+
+type Senders = Vec<Option<ChunkSender>>;
+
+#[derive(Default)]
+pub(crate) struct PieceSenders {
+    downloading: HashMap<ValidPieceIndex, Senders>,
+    // Everyone who sent each chunk of the piece in all failed attempts.
+    failed: HashMap<ValidPieceIndex, Vec<Vec<ChunkSender>>>,
+}
+
+impl PieceSenders {
+    pub fn record(
+        &mut self,
+        chunk: &ChunkInfo,
+        chunks_in_piece: usize,
+        peer: PeerHandle,
+        fingerprint: u64,
+    ) {
+        let senders = self
+            .downloading
+            .entry(chunk.piece_index)
+            .or_insert_with(|| vec![None; chunks_in_piece]);
+        if let Some(s) = senders.get_mut(chunk.chunk_index as usize) {
+            *s = Some(ChunkSender { peer, fingerprint });
+        }
+    }
+
+    /// The piece failed its hash check. Returns the peer to blame if it's clear who it is.
+    pub fn on_piece_failed(&mut self, piece: ValidPieceIndex) -> Option<PeerHandle> {
+        let senders = self.downloading.remove(&piece)?;
+        match senders
+            .iter()
+            .flatten()
+            .map(|s| s.peer)
+            .unique()
+            .exactly_one()
+        {
+            Ok(peer) => Some(peer),
+            Err(_) => {
+                let failed = self
+                    .failed
+                    .entry(piece)
+                    .or_insert_with(|| vec![Vec::new(); senders.len()]);
+                for (all, sender) in failed.iter_mut().zip(senders) {
+                    all.extend(sender);
+                }
+                None
+            }
+        }
+    }
+
+    /// The piece passed its hash check. Returns the peers that sent different data for it
+    /// before, when it failed.
+    pub fn on_piece_passed(&mut self, piece: ValidPieceIndex) -> Vec<PeerHandle> {
+        let good = self.downloading.remove(&piece);
+        let (Some(good), Some(bad)) = (good, self.failed.remove(&piece)) else {
+            return Vec::new();
+        };
+        good.iter()
+            .zip(bad.iter())
+            .filter_map(|(good, bad)| Some((good.as_ref()?, bad)))
+            .flat_map(|(good, bad)| {
+                bad.iter()
+                    .filter(|bad| bad.fingerprint != good.fingerprint)
+                    .map(|bad| bad.peer)
+            })
+            .unique()
+            .collect()
+    }
+
+    /// Forget everything, e.g. when the torrent is paused.
+    pub fn clear(&mut self) {
+        self.downloading.clear();
+        self.failed.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use librqbit_core::lengths::Lengths;
+
+    use super::PieceSenders;
+
+    #[test]
+    fn test_find_culprit() {
+        let lengths = Lengths::new(32768, 32768, None).unwrap();
+        let piece = lengths.validate_piece_index(0).unwrap();
+        let chunks = lengths.iter_chunk_infos(piece).collect::<Vec<_>>();
+        let (good_peer, bad_peer) = ("1.1.1.1:1".parse().unwrap(), "2.2.2.2:2".parse().unwrap());
+        let mut s = PieceSenders::default();
+        let (good, bad) = (1, 2);
+
+        // One sender, it's to blame.
+        s.record(&chunks[0], 2, bad_peer, bad);
+        s.record(&chunks[1], 2, bad_peer, good);
+        assert_eq!(s.on_piece_failed(piece), Some(bad_peer));
+
+        // Two senders, blame the one whose data differs from the good piece.
+        s.record(&chunks[0], 2, bad_peer, bad);
+        s.record(&chunks[1], 2, good_peer, good);
+        assert_eq!(s.on_piece_failed(piece), None);
+        s.record(&chunks[0], 2, good_peer, good);
+        s.record(&chunks[1], 2, good_peer, good);
+        assert_eq!(s.on_piece_passed(piece), vec![bad_peer]);
+
+        // Senders of all failed attempts are remembered until the piece passes.
+        let other_bad_peer = "3.3.3.3:3".parse().unwrap();
+        s.record(&chunks[0], 2, bad_peer, bad);
+        s.record(&chunks[1], 2, good_peer, good);
+        assert_eq!(s.on_piece_failed(piece), None);
+        s.record(&chunks[0], 2, good_peer, good);
+        s.record(&chunks[1], 2, other_bad_peer, bad);
+        assert_eq!(s.on_piece_failed(piece), None);
+        s.record(&chunks[0], 2, good_peer, good);
+        s.record(&chunks[1], 2, good_peer, good);
+        assert_eq!(s.on_piece_passed(piece), vec![bad_peer, other_bad_peer]);
+        assert_eq!(s.on_piece_passed(piece), vec![]);
+    }
+}
@@ -1,10 +1,10 @@
 use std::time::Duration;
 
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 
 use crate::torrent_state::live::peers::stats::snapshot::AggregatePeerStats;
 
-#[derive(Debug, Serialize, Default)]
+#[derive(Debug, Serialize, Deserialize, Default)]
 pub struct StatsSnapshot {
     pub downloaded_and_checked_bytes: u64,
 
@@ -13,6 +13,8 @@ pub struct StatsSnapshot {
 
     pub downloaded_and_checked_pieces: u64,
     pub total_piece_download_ms: u64,
+    /// Downloaded pieces that did not match their hash.
+    pub hash_failed_pieces: u64,
     pub peer_stats: AggregatePeerStats,
 }
 
@@ -0,0 +1,273 @@
+// Reading files of a live torrent while they are being downloaded.
+//
+// Each reader registers its position here. Requesters download the pieces right ahead of
+// every reader first, so that the window of prioritized pieces follows playback.
+
+use std::{
+    io::SeekFrom,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    task::{Context as TaskContext, Poll},
+};
+
+use anyhow::Context;
+use bytes::Bytes;
+use dashmap::DashMap;
+use futures::{future::BoxFuture, FutureExt};
+use librqbit_core::lengths::{Lengths, ValidPieceIndex};
+use tokio::io::{AsyncRead, AsyncSeek, ReadBuf};
+use tracing::trace;
+
+use super::TorrentStateLive;
+
+// How many bytes ahead of the reader's position to prioritize.
+const STREAM_WINDOW_BYTES: u64 = 32 * 1024 * 1024;
+
+type StreamId = usize;
+
+struct StreamState {
+    // The piece the reader is currently waiting for or reading.
+    current_piece: ValidPieceIndex,
+    // The last piece the reader is interested in.
+    last_piece: ValidPieceIndex,
+}
+
+#[derive(Default)]
+pub(crate) struct TorrentStreams {
+    next_id: AtomicUsize,
+    streams: DashMap<StreamId, StreamState>,
+}
+
+impl TorrentStreams {
+    fn register(&self, current_piece: ValidPieceIndex, last_piece: ValidPieceIndex) -> StreamId {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.streams.insert(
+            id,
+            StreamState {
+                current_piece,
+                last_piece,
+            },
+        );
+        id
+    }
+
+    fn update(&self, id: StreamId, current_piece: ValidPieceIndex) {
+        if let Some(mut s) = self.streams.get_mut(&id) {
+            s.current_piece = current_piece;
+        }
+    }
+
+    fn remove(&self, id: StreamId) {
+        self.streams.remove(&id);
+    }
+
+    /// The pieces right ahead of each reader, in the order they should be downloaded.
+    pub(crate) fn window_pieces(&self, lengths: &Lengths) -> Vec<ValidPieceIndex> {
+        if self.streams.is_empty() {
+            return Vec::new();
+        }
+        let window = (STREAM_WINDOW_BYTES / lengths.default_piece_length() as u64).max(1) as u32;
+        let mut result = Vec::new();
+        for s in self.streams.iter() {
+            let start = s.current_piece.get();
+            let end = s.last_piece.get().min(start.saturating_add(window - 1));
+            result.extend((start..=end).filter_map(|p| lengths.validate_piece_index(p)));
+        }
+        result
+    }
+}
+
+fn piece_at(lengths: &Lengths, offset: u64) -> anyhow::Result<ValidPieceIndex> {
+    lengths
+        .validate_piece_index((offset / lengths.default_piece_length() as u64) as u32)
+        .with_context(|| format!("bug: no piece at offset {offset}"))
+}
+
+/// A reader for one file of a live torrent, implementing AsyncRead and AsyncSeek.
+///
+/// Reads wait until the pieces they need are downloaded and verified. While the reader
+/// is alive, the pieces right ahead of its position are downloaded before anything else.
+pub struct FileStream {
+    live: Arc<TorrentStateLive>,
+    id: StreamId,
+    file_id: usize,
+    // Absolute offset of the file in the torrent.
+    file_offset: u64,
+    file_len: u64,
+    // Position within the file.
+    position: u64,
+    // Data read at "position", but not yet consumed.
+    buffered: Bytes,
+    pending: Option<BoxFuture<'static, anyhow::Result<Bytes>>>,
+}
+
+impl FileStream {
+    pub(crate) fn new(live: Arc<TorrentStateLive>, file_id: usize) -> anyhow::Result<Self> {
+        let (file_offset, file_len) = live.file_offset_and_length(file_id)?;
+        // Empty files at the end of the torrent would point past the last piece.
+        let last_byte = live.lengths.total_length().saturating_sub(1);
+        let first_piece = piece_at(&live.lengths, file_offset.min(last_byte))?;
+        let last_piece = piece_at(
+            &live.lengths,
+            (file_offset + file_len).saturating_sub(1).min(last_byte),
+        )?;
+        let id = live.streams.register(first_piece, last_piece);
+        Ok(Self {
+            live,
+            id,
+            file_id,
+            file_offset,
+            file_len,
+            position: 0,
+            buffered: Bytes::new(),
+            pending: None,
+        })
+    }
+
+    pub fn file_id(&self) -> usize {
+        self.file_id
+    }
+
+    pub fn len(&self) -> u64 {
+        self.file_len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.file_len == 0
+    }
+
+    pub fn position(&self) -> u64 {
+        self.position
+    }
+
+    fn start_read(&mut self) -> anyhow::Result<BoxFuture<'static, anyhow::Result<Bytes>>> {
+        let offset = self.file_offset + self.position;
+        let end = self.file_offset + self.file_len;
+        let piece = piece_at(&self.live.lengths, offset)?;
+        self.live.streams.update(self.id, piece);
+        let live = self.live.clone();
+        Ok(async move {
+            live.wait_for_piece(piece).await?;
+            live.read_at(offset, end)
+        }
+        .boxed())
+    }
+}
+
+impl Drop for FileStream {
+    fn drop(&mut self) {
+        self.live.streams.remove(self.id);
+    }
+}
+
+impl AsyncRead for FileStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        if self.buffered.is_empty() {
+            if self.position >= self.file_len || buf.remaining() == 0 {
+                return Poll::Ready(Ok(()));
+            }
+            if self.pending.is_none() {
+                let fut = self.start_read().map_err(std::io::Error::other)?;
+                self.pending = Some(fut);
+            }
+            let fut = self.pending.as_mut().unwrap();
+            let data = match fut.poll_unpin(cx) {
+                Poll::Ready(r) => r,
+                Poll::Pending => return Poll::Pending,
+            };
+            self.pending = None;
+            self.buffered = data.map_err(std::io::Error::other)?;
+            trace!(
+                file_id = self.file_id,
+                position = self.position,
+                len = self.buffered.len(),
+                "read"
+            );
+        }
+
+        let len = std::cmp::min(buf.remaining(), self.buffered.len());
+        let data = self.buffered.split_to(len);
+        buf.put_slice(&data);
+        self.position += len as u64;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncSeek for FileStream {
+    fn start_seek(mut self: Pin<&mut Self>, position: SeekFrom) -> std::io::Result<()> {
+        let new_position = match position {
+            SeekFrom::Start(pos) => Some(pos),
+            SeekFrom::End(offset) => self.file_len.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+        }
+        .ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "invalid seek to a negative or overflowing position",
+            )
+        })?;
+        if new_position != self.position {
+            self.position = new_position;
+            self.buffered = Bytes::new();
+            self.pending = None;
+        }
+        Ok(())
+    }
+
+    fn poll_complete(
+        self: Pin<&mut Self>,
+        _cx: &mut TaskContext<'_>,
+    ) -> Poll<std::io::Result<u64>> {
+        Poll::Ready(Ok(self.position))
+    }
+}
+
+impl TorrentStateLive {
+    /// The absolute offset of the file in the torrent, and its length.
+    pub(crate) fn file_offset_and_length(&self, file_id: usize) -> anyhow::Result<(u64, u64)> {
+        self.meta
+            .info
+            .iter_file_lengths()?
+            .scan(0u64, |offset, len| {
+                let file_offset = *offset;
+                *offset += len;
+                Some((file_offset, len))
+            })
+            .nth(file_id)
+            .with_context(|| format!("invalid file id {file_id}"))
+    }
+
+    // Read from the chunk at the given absolute offset, but not past "end".
+    fn read_at(&self, offset: u64, end: u64) -> anyhow::Result<Bytes> {
+        let lengths = &self.lengths;
+        let piece_index = piece_at(lengths, offset)?;
+        let offset_in_piece = (offset - lengths.piece_offset(piece_index)) as u32;
+        let chunk_index = offset_in_piece / lengths.default_chunk_length();
+        let chunk_size = lengths
+            .chunk_size(piece_index, chunk_index)
+            .context("invalid chunk")?;
+        let chunk_info = lengths
+            .chunk_info_from_received_data(
+                piece_index,
+                chunk_index * lengths.default_chunk_length(),
+                chunk_size,
+            )
+            .context("invalid chunk")?;
+
+        let mut buf = vec![0u8; chunk_size as usize];
+        self.meta
+            .spawner
+            .spawn_block_in_place(|| self.file_ops().read_chunk("stream", &chunk_info, &mut buf))?;
+
+        let skip = (offset - lengths.chunk_absolute_offset(&chunk_info)) as usize;
+        let take = std::cmp::min(buf.len() - skip, (end - offset) as usize);
+        Ok(Bytes::from(buf).slice(skip..skip + take))
+    }
+}
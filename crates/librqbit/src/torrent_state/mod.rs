@@ -4,9 +4,12 @@ pub mod paused;
 pub mod stats;
 pub mod utils;
 
+use std::collections::BTreeMap;
 use std::collections::HashSet;
+use std::path::Component;
 use std::path::Path;
 use std::path::PathBuf;
+use std::sync::atomic::AtomicBool;
 use std::sync::atomic::Ordering;
 use std::sync::Arc;
 use std::time::Duration;
@@ -19,22 +22,44 @@ use futures::FutureExt;
 use librqbit_core::hash_id::Id20;
 use librqbit_core::lengths::Lengths;
 use librqbit_core::peer_id::generate_peer_id;
+use librqbit_core::socks5::Socks5Proxy;
 
 use librqbit_core::spawn_utils::spawn_with_cancel;
 use librqbit_core::torrent_metainfo::TorrentMetaV1Info;
 pub use live::*;
+use parking_lot::Mutex;
 use parking_lot::RwLock;
+use parking_lot::RwLockWriteGuard;
 
 use tokio::time::timeout;
 use tokio_stream::StreamExt;
 use tokio_util::sync::CancellationToken;
 use tracing::debug;
 use tracing::error_span;
+use tracing::info;
 use tracing::warn;
 
 use crate::chunk_tracker::ChunkTracker;
+use crate::connection_limits::ConnectionLimiter;
+use crate::disk_cache::DiskCache;
+use crate::events::SessionEvent;
+use crate::events::SessionEvents;
+use crate::file_priority::compute_file_edge_pieces;
+use crate::file_priority::FilePriority;
+use crate::hooks::TorrentEvent;
+use crate::hooks::TorrentEventKind;
+use crate::ip_filter::SessionIpFilter;
+use crate::metrics::SessionMetrics;
+use crate::network_binding::NetworkBinding;
+use crate::peer_connection::PeerConnector;
+use crate::session::TorrentId;
 use crate::spawn_utils::BlockingSpawner;
+use crate::storage::BoxStorageFactory;
+use crate::storage::FilesystemStorageFactory;
+use crate::storage::PreallocationMode;
+use crate::storage::RelocateProgress;
 use crate::torrent_state::stats::LiveStats;
+use crate::torrent_state::stats::MoveStats;
 use crate::type_aliases::PeerStream;
 
 use initializing::TorrentStateInitializing;
@@ -42,6 +67,7 @@ use initializing::TorrentStateInitializing;
 use self::paused::TorrentStatePaused;
 pub use self::stats::{TorrentStats, TorrentStatsState};
 
+#[allow(clippy::large_enum_variant)]
 pub enum ManagedTorrentState {
     Initializing(Arc<TorrentStateInitializing>),
     Paused(TorrentStatePaused),
@@ -75,23 +101,137 @@ pub(crate) struct ManagedTorrentOptions {
     pub peer_connect_timeout: Option<Duration>,
     pub peer_read_write_timeout: Option<Duration>,
     pub overwrite: bool,
+    pub preallocation: PreallocationMode,
+    pub incomplete_folder: Option<PathBuf>,
+    // The session-wide incomplete folder that incomplete_folder is in, if it is.
+    pub shared_incomplete_folder: Option<PathBuf>,
+    pub part_suffix: bool,
+    pub peer_connector: PeerConnector,
 }
 
 pub struct ManagedTorrentInfo {
+    /// The id in the session, None if the torrent isn't managed by a session.
+    pub id: Option<TorrentId>,
     pub info: TorrentMetaV1Info<ByteString>,
     pub info_hash: Id20,
+    #[deprecated(note = "this is the folder the torrent was added with, use out_dir() instead")]
     pub out_dir: PathBuf,
+    // Changes when the torrent's data is moved.
+    current_out_dir: RwLock<PathBuf>,
+    // File index to its path relative to out_dir, if renamed.
+    file_path_overrides: RwLock<BTreeMap<usize, PathBuf>>,
     pub(crate) spawner: BlockingSpawner,
     pub trackers: HashSet<String>,
     pub peer_id: Id20,
     pub lengths: Lengths,
     pub span: tracing::Span,
     pub(crate) options: ManagedTorrentOptions,
+    pub(crate) storage_factory: BoxStorageFactory,
+    pub(crate) disk_cache: Arc<DiskCache>,
+    pub(crate) events: Option<Arc<SessionEvents>>,
+    pub(crate) metrics: Arc<SessionMetrics>,
+    pub(crate) ip_filter: Arc<SessionIpFilter>,
+    pub(crate) connection_limiter: Arc<ConnectionLimiter>,
+}
+
+impl ManagedTorrentInfo {
+    /// Where the torrent's data is, this changes when it's moved.
+    pub fn out_dir(&self) -> PathBuf {
+        self.current_out_dir.read().clone()
+    }
+
+    /// Files that were renamed, file index to the path relative to out_dir().
+    pub fn file_path_overrides(&self) -> BTreeMap<usize, PathBuf> {
+        self.file_path_overrides.read().clone()
+    }
+
+    /// Paths of all files relative to out_dir(), with the renames applied.
+    pub fn relative_file_paths(&self) -> anyhow::Result<Vec<PathBuf>> {
+        relative_file_paths(&self.info, &self.file_path_overrides.read())
+    }
+
+    pub(crate) fn event(&self, kind: TorrentEventKind) -> TorrentEvent {
+        let mut event = TorrentEvent::new(kind, self.info_hash, &self.info);
+        event.id = self.id;
+        event.output_folder = Some(self.out_dir());
+        if let Ok(files) = self.relative_file_paths() {
+            event.files = files;
+        }
+        event
+    }
+
+    /// Notify the session (and its hooks) about something that happened to the torrent.
+    pub(crate) fn emit(&self, kind: TorrentEventKind, error: Option<&anyhow::Error>) {
+        if let Some(events) = self.events.as_ref() {
+            events.send_torrent_event(|| {
+                let mut event = self.event(kind);
+                event.error = error.map(|e| format!("{e:#}"));
+                event
+            });
+        }
+    }
+
+    /// Send an event to the session's subscribers. "make" gets the torrent id and info
+    /// hash, and is only called if there's someone to send to.
+    pub(crate) fn send_event(
+        &self,
+        make: impl FnOnce(Option<TorrentId>, String) -> SessionEvent,
+    ) {
+        if let Some(events) = self.events.as_ref() {
+            events.send(|| make(self.id, self.info_hash.as_string()));
+        }
+    }
+
+    pub(crate) fn emit_state(&self, state: TorrentStatsState) {
+        self.send_event(|id, info_hash| SessionEvent::StateChanged {
+            id,
+            info_hash,
+            state,
+        });
+    }
+}
+
+// Paths of the torrent's files relative to the output folder, the ones from the
+// metainfo replaced by "overrides". Errors if an override is not a plain relative path,
+// or if two files would end up at the same path.
+pub(crate) fn relative_file_paths(
+    info: &TorrentMetaV1Info<ByteString>,
+    overrides: &BTreeMap<usize, PathBuf>,
+) -> anyhow::Result<Vec<PathBuf>> {
+    let mut paths = info
+        .iter_filenames_and_lengths()?
+        .enumerate()
+        .map(|(idx, (name, _))| {
+            name.to_pathbuf()
+                .with_context(|| format!("error converting file {idx} to path"))
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    for (idx, path) in overrides.iter() {
+        let slot = paths
+            .get_mut(*idx)
+            .with_context(|| format!("invalid file id {idx}"))?;
+        if path.as_os_str().is_empty()
+            || !path.components().all(|c| matches!(c, Component::Normal(_)))
+        {
+            bail!("invalid path {path:?} for file {idx}, expected a relative path without \"..\"");
+        }
+        slot.clone_from(path);
+    }
+    let mut seen = HashSet::new();
+    for path in paths.iter() {
+        if !seen.insert(path) {
+            bail!("more than one file would be stored at {path:?}");
+        }
+    }
+    Ok(paths)
 }
 
 pub struct ManagedTorrent {
     pub info: Arc<ManagedTorrentInfo>,
-    pub(crate) only_files: Option<Vec<usize>>,
+    // Set while the torrent's data is being moved.
+    moving: Mutex<Option<(PathBuf, Arc<RelocateProgress>)>>,
+    file_priorities: RwLock<Vec<FilePriority>>,
+    sequential: AtomicBool,
     locked: RwLock<ManagedTorrentLocked>,
 }
 
@@ -109,7 +249,174 @@ impl ManagedTorrent {
     }
 
     pub fn only_files(&self) -> Option<Vec<usize>> {
-        self.only_files.clone()
+        FilePriority::to_only_files(&self.file_priorities.read())
+    }
+
+    pub fn file_priorities(&self) -> Vec<FilePriority> {
+        self.file_priorities.read().clone()
+    }
+
+    /// Change the priorities of the torrent's files.
+    ///
+    /// Works both on paused and live torrents. Newly selected files are created on disk,
+    /// and deselected ones stop being downloaded (pieces already in-flight are let to finish).
+    pub fn set_file_priorities(&self, file_priorities: Vec<FilePriority>) -> anyhow::Result<()> {
+        let total_files = self.file_priorities.read().len();
+        if file_priorities.len() != total_files {
+            bail!(
+                "expected {} file priorities, got {}",
+                total_files,
+                file_priorities.len()
+            );
+        }
+
+        let mut g = self.lock_unless_moving("change file priorities")?;
+        match &mut g.state {
+            ManagedTorrentState::Initializing(_) => {
+                bail!("torrent is initializing, can't change file priorities")
+            }
+            ManagedTorrentState::Paused(p) => {
+                p.set_file_priorities(&file_priorities)?;
+                p.chunk_tracker
+                    .set_sequential_piece_ids(self.sequential_piece_ids(&file_priorities)?);
+            }
+            ManagedTorrentState::Live(l) => {
+                l.set_file_priorities(&file_priorities)?;
+                l.set_sequential_piece_ids(self.sequential_piece_ids(&file_priorities)?)?;
+            }
+            // Will be used when the torrent is restarted.
+            ManagedTorrentState::Error(_) => {}
+            ManagedTorrentState::None => bail!("bug: torrent is in empty state"),
+        }
+        *self.file_priorities.write() = file_priorities;
+        Ok(())
+    }
+
+    /// Open a file of the torrent for reading while it's being downloaded.
+    ///
+    /// The torrent has to be live and the file selected for download. Reads wait until
+    /// the pieces they need are downloaded, and these pieces are downloaded first.
+    pub fn open_file(&self, file_id: usize) -> anyhow::Result<FileStream> {
+        let priority = self
+            .file_priorities
+            .read()
+            .get(file_id)
+            .copied()
+            .with_context(|| format!("invalid file id {file_id}"))?;
+        if priority.is_skip() {
+            bail!("file {file_id} is not selected for download");
+        }
+        let live = self.live().context("torrent is not live")?;
+        FileStream::new(live, file_id)
+    }
+
+    pub fn preallocation(&self) -> PreallocationMode {
+        self.info.options.preallocation
+    }
+
+    pub fn incomplete_folder(&self) -> Option<&Path> {
+        self.info.options.incomplete_folder.as_deref()
+    }
+
+    pub fn part_suffix(&self) -> bool {
+        self.info.options.part_suffix
+    }
+
+    pub fn is_sequential(&self) -> bool {
+        self.sequential.load(Ordering::Relaxed)
+    }
+
+    /// Switch sequential download mode.
+    ///
+    /// In sequential mode pieces are downloaded in order, but the first and last pieces of
+    /// each selected file go first, as media players usually need them to start playback.
+    pub fn set_sequential(&self, sequential: bool) -> anyhow::Result<()> {
+        let mut g = self.locked.write();
+        self.sequential.store(sequential, Ordering::Relaxed);
+        let piece_ids = self.sequential_piece_ids(&self.file_priorities.read())?;
+        match &mut g.state {
+            ManagedTorrentState::Paused(p) => p.chunk_tracker.set_sequential_piece_ids(piece_ids),
+            ManagedTorrentState::Live(l) => l.set_sequential_piece_ids(piece_ids)?,
+            // Will be applied once the torrent is initialized.
+            ManagedTorrentState::Initializing(_) | ManagedTorrentState::Error(_) => {}
+            ManagedTorrentState::None => bail!("bug: torrent is in empty state"),
+        }
+        Ok(())
+    }
+
+    fn sequential_piece_ids(
+        &self,
+        file_priorities: &[FilePriority],
+    ) -> anyhow::Result<Option<Vec<usize>>> {
+        if !self.is_sequential() {
+            return Ok(None);
+        }
+        Ok(Some(compute_file_edge_pieces(
+            &self.info.lengths,
+            self.info.info.iter_file_lengths()?,
+            file_priorities,
+        )))
+    }
+
+    // Lock the state for changes that can't happen while the data is being moved.
+    fn lock_unless_moving(
+        &self,
+        action: &str,
+    ) -> anyhow::Result<RwLockWriteGuard<'_, ManagedTorrentLocked>> {
+        let moving = self.moving.lock();
+        if moving.is_some() {
+            bail!("torrent data is being moved, can't {action}");
+        }
+        Ok(self.locked.write())
+    }
+
+    pub fn is_moving(&self) -> bool {
+        self.moving.lock().is_some()
+    }
+
+    /// Move the torrent's data to a new output folder, and/or rename its files.
+    /// The torrent must be paused.
+    ///
+    /// This blocks until all files are moved, the progress is reported in stats().
+    /// If moving fails, the data is left where it was.
+    pub(crate) fn relocate(
+        &self,
+        new_out_dir: PathBuf,
+        file_path_overrides: BTreeMap<usize, PathBuf>,
+    ) -> anyhow::Result<()> {
+        let new_paths = relative_file_paths(&self.info.info, &file_path_overrides)?;
+        let progress = Arc::new(RelocateProgress::default());
+        let storage = {
+            let mut moving = self.moving.lock();
+            if moving.is_some() {
+                bail!("torrent data is already being moved");
+            }
+            let storage = match &self.locked.read().state {
+                ManagedTorrentState::Paused(p) => p.storage.take()?,
+                _ => bail!("torrent must be paused to move its data"),
+            };
+            *moving = Some((new_out_dir.clone(), progress.clone()));
+            storage
+        };
+
+        info!(from=?self.info.out_dir(), to=?new_out_dir, "moving torrent data");
+        let result = storage.relocate(&new_out_dir, &new_paths, &progress);
+        if result.is_ok() {
+            *self.info.current_out_dir.write() = new_out_dir;
+            *self.info.file_path_overrides.write() = file_path_overrides;
+        }
+
+        let mut moving = self.moving.lock();
+        let (storage, result) = match result {
+            Ok(new_storage) => (new_storage, Ok(())),
+            Err(e) => (storage, Err(e)),
+        };
+        match &mut self.locked.write().state {
+            ManagedTorrentState::Paused(p) => p.storage = storage,
+            _ => warn!("bug: torrent state changed while moving its data"),
+        }
+        *moving = None;
+        result
     }
 
     pub fn with_state<R>(&self, f: impl FnOnce(&ManagedTorrentState) -> R) -> R {
@@ -165,6 +472,8 @@ impl ManagedTorrent {
             _ => {}
         };
 
+        self.info.emit(TorrentEventKind::Errored, Some(&error));
+        self.info.emit_state(TorrentStatsState::Error);
         g.state = ManagedTorrentState::Error(error)
     }
 
@@ -174,7 +483,7 @@ impl ManagedTorrent {
         start_paused: bool,
         live_cancellation_token: CancellationToken,
     ) -> anyhow::Result<()> {
-        let mut g = self.locked.write();
+        let mut g = self.lock_unless_moving("start")?;
 
         let spawn_fatal_errors_receiver =
             |state: &Arc<Self>,
@@ -220,12 +529,13 @@ impl ManagedTorrent {
 
                         loop {
                             match timeout(Duration::from_secs(5), peer_rx.next()).await {
-                                Ok(Some(peer)) => {
+                                Ok(Some((peer, source))) => {
                                     let live = match live.upgrade() {
                                         Some(live) => live,
                                         None => return Ok(()),
                                     };
-                                    live.add_peer_if_not_seen(peer).context("torrent closed")?;
+                                    live.add_peer_if_not_seen(peer, source)
+                                        .context("torrent closed")?;
                                 }
                                 Ok(None) => return Ok(()),
                                 // If timeout, check if the torrent is live.
@@ -253,7 +563,7 @@ impl ManagedTorrent {
                     token.clone(),
                     async move {
                         match init.check().await {
-                            Ok(paused) => {
+                            Ok(mut paused) => {
                                 let mut g = t.locked.write();
                                 if let ManagedTorrentState::Initializing(_) = &g.state {
                                 } else {
@@ -261,8 +571,13 @@ impl ManagedTorrent {
                                     return Ok(());
                                 }
 
+                                paused.chunk_tracker.set_sequential_piece_ids(
+                                    t.sequential_piece_ids(&t.file_priorities())?,
+                                );
+
                                 if start_paused {
                                     g.state = ManagedTorrentState::Paused(paused);
+                                    t.info.emit_state(TorrentStatsState::Paused);
                                     return Ok(());
                                 }
 
@@ -270,6 +585,7 @@ impl ManagedTorrent {
                                 let live =
                                     TorrentStateLive::new(paused, tx, live_cancellation_token);
                                 g.state = ManagedTorrentState::Live(live.clone());
+                                t.info.emit_state(TorrentStatsState::Live);
 
                                 spawn_fatal_errors_receiver(&t, rx, token);
                                 spawn_peer_adder(&live, peer_rx);
@@ -278,6 +594,8 @@ impl ManagedTorrent {
                             }
                             Err(err) => {
                                 let result = anyhow::anyhow!("{:?}", err);
+                                t.info.emit(TorrentEventKind::Errored, Some(&err));
+                                t.info.emit_state(TorrentStatsState::Error);
                                 t.locked.write().state = ManagedTorrentState::Error(err);
                                 Err(result)
                             }
@@ -291,6 +609,7 @@ impl ManagedTorrent {
                 let (tx, rx) = tokio::sync::oneshot::channel();
                 let live = TorrentStateLive::new(paused, tx, live_cancellation_token.clone());
                 g.state = ManagedTorrentState::Live(live.clone());
+                self.info.emit_state(TorrentStatsState::Live);
                 spawn_fatal_errors_receiver(self, rx, live_cancellation_token);
                 spawn_peer_adder(&live, peer_rx);
                 Ok(())
@@ -298,9 +617,10 @@ impl ManagedTorrent {
             ManagedTorrentState::Error(_) => {
                 let initializing = Arc::new(TorrentStateInitializing::new(
                     self.info.clone(),
-                    self.only_files.clone(),
+                    self.file_priorities(),
                 ));
                 g.state = ManagedTorrentState::Initializing(initializing.clone());
+                self.info.emit_state(TorrentStatsState::Initializing);
                 drop(g);
 
                 // Recurse.
@@ -312,11 +632,25 @@ impl ManagedTorrent {
 
     /// Pause the torrent if it's live.
     pub fn pause(&self) -> anyhow::Result<()> {
+        self.pause_impl(true)
+    }
+
+    /// Pause the torrent without firing "paused" hooks, e.g. while moving its files or
+    /// stopping the session. The user didn't ask for it.
+    pub(crate) fn pause_internal(&self) -> anyhow::Result<()> {
+        self.pause_impl(false)
+    }
+
+    fn pause_impl(&self, emit: bool) -> anyhow::Result<()> {
         let mut g = self.locked.write();
         match &g.state {
             ManagedTorrentState::Live(live) => {
                 let paused = live.pause()?;
                 g.state = ManagedTorrentState::Paused(paused);
+                if emit {
+                    self.info.emit(TorrentEventKind::Paused, None);
+                }
+                self.info.emit_state(TorrentStatsState::Paused);
                 Ok(())
             }
             ManagedTorrentState::Initializing(_) => {
@@ -343,6 +677,11 @@ impl ManagedTorrent {
             uploaded_bytes: 0,
             finished: false,
             live: None,
+            moving: self.moving.lock().as_ref().map(|(dest, p)| MoveStats {
+                destination: dest.clone(),
+                total_bytes: p.total_bytes.load(Ordering::Relaxed),
+                moved_bytes: p.moved_bytes.load(Ordering::Relaxed),
+            }),
         };
 
         self.with_state(|s| {
@@ -354,7 +693,7 @@ impl ManagedTorrent {
                 ManagedTorrentState::Paused(p) => {
                     resp.state = S::Paused;
                     resp.total_bytes = p.chunk_tracker.get_total_selected_bytes();
-                    resp.progress_bytes = resp.total_bytes - p.needed_bytes;
+                    resp.progress_bytes = resp.total_bytes.saturating_sub(p.needed_bytes);
                     resp.finished = resp.progress_bytes == resp.total_bytes;
                 }
                 ManagedTorrentState::Live(l) => {
@@ -362,7 +701,7 @@ impl ManagedTorrent {
                     let live_stats = LiveStats::from(l.as_ref());
                     let total = l.get_total_selected_bytes();
                     let remaining = l.get_left_to_download_bytes();
-                    let progress = total - remaining;
+                    let progress = total.saturating_sub(remaining);
 
                     resp.progress_bytes = progress;
                     resp.total_bytes = total;
@@ -416,11 +755,25 @@ pub struct ManagedTorrentBuilder {
     force_tracker_interval: Option<Duration>,
     peer_connect_timeout: Option<Duration>,
     peer_read_write_timeout: Option<Duration>,
-    only_files: Option<Vec<usize>>,
+    file_priorities: Option<Vec<FilePriority>>,
+    file_path_overrides: BTreeMap<usize, PathBuf>,
+    sequential: bool,
     trackers: Vec<String>,
     peer_id: Option<Id20>,
     overwrite: bool,
+    preallocation: PreallocationMode,
+    incomplete_folder: Option<PathBuf>,
+    shared_incomplete_folder: Option<PathBuf>,
+    part_suffix: bool,
     spawner: Option<BlockingSpawner>,
+    storage_factory: Option<BoxStorageFactory>,
+    disk_cache: Option<Arc<DiskCache>>,
+    id: Option<TorrentId>,
+    events: Option<Arc<SessionEvents>>,
+    metrics: Option<Arc<SessionMetrics>>,
+    ip_filter: Option<Arc<SessionIpFilter>>,
+    connection_limiter: Option<Arc<ConnectionLimiter>>,
+    peer_connector: PeerConnector,
 }
 
 impl ManagedTorrentBuilder {
@@ -437,15 +790,45 @@ impl ManagedTorrentBuilder {
             force_tracker_interval: None,
             peer_connect_timeout: None,
             peer_read_write_timeout: None,
-            only_files: None,
+            file_priorities: None,
+            file_path_overrides: Default::default(),
+            sequential: false,
             trackers: Default::default(),
             peer_id: None,
             overwrite: false,
+            preallocation: Default::default(),
+            incomplete_folder: None,
+            shared_incomplete_folder: None,
+            part_suffix: false,
+            storage_factory: None,
+            disk_cache: None,
+            id: None,
+            events: None,
+            metrics: None,
+            ip_filter: None,
+            connection_limiter: None,
+            peer_connector: Default::default(),
         }
     }
 
-    pub fn only_files(&mut self, only_files: Vec<usize>) -> &mut Self {
-        self.only_files = Some(only_files);
+    /// Set per-file priorities. By default all files are downloaded with normal priority.
+    pub fn file_priorities(&mut self, file_priorities: Vec<FilePriority>) -> &mut Self {
+        self.file_priorities = Some(file_priorities);
+        self
+    }
+
+    /// Store some files under different paths than the ones in the metainfo.
+    /// The paths are relative to the output folder.
+    pub fn file_path_overrides(
+        &mut self,
+        file_path_overrides: BTreeMap<usize, PathBuf>,
+    ) -> &mut Self {
+        self.file_path_overrides = file_path_overrides;
+        self
+    }
+
+    pub fn sequential(&mut self, sequential: bool) -> &mut Self {
+        self.sequential = sequential;
         self
     }
 
@@ -459,6 +842,36 @@ impl ManagedTorrentBuilder {
         self
     }
 
+    /// Where to store the torrent's data. By default, files in the output folder.
+    pub fn storage_factory(&mut self, storage_factory: BoxStorageFactory) -> &mut Self {
+        self.storage_factory = Some(storage_factory);
+        self
+    }
+
+    pub fn preallocation(&mut self, preallocation: PreallocationMode) -> &mut Self {
+        self.preallocation = preallocation;
+        self
+    }
+
+    /// Download into this folder, and move the files to the output folder once finished.
+    pub fn incomplete_folder(&mut self, incomplete_folder: Option<PathBuf>) -> &mut Self {
+        self.incomplete_folder = incomplete_folder;
+        self
+    }
+
+    // The session-wide incomplete folder, that incomplete_folder is a sub-folder of for
+    // torrents with several files. It is left alone when the torrent finishes.
+    pub(crate) fn shared_incomplete_folder(&mut self, folder: Option<PathBuf>) -> &mut Self {
+        self.shared_incomplete_folder = folder;
+        self
+    }
+
+    /// Add ".part" to the names of files until the torrent finishes.
+    pub fn part_suffix(&mut self, part_suffix: bool) -> &mut Self {
+        self.part_suffix = part_suffix;
+        self
+    }
+
     pub fn force_tracker_interval(&mut self, force_tracker_interval: Duration) -> &mut Self {
         self.force_tracker_interval = Some(force_tracker_interval);
         self
@@ -469,6 +882,51 @@ impl ManagedTorrentBuilder {
         self
     }
 
+    pub(crate) fn disk_cache(&mut self, disk_cache: Arc<DiskCache>) -> &mut Self {
+        self.disk_cache = Some(disk_cache);
+        self
+    }
+
+    pub(crate) fn id(&mut self, id: TorrentId) -> &mut Self {
+        self.id = Some(id);
+        self
+    }
+
+    pub(crate) fn events(&mut self, events: Arc<SessionEvents>) -> &mut Self {
+        self.events = Some(events);
+        self
+    }
+
+    pub(crate) fn metrics(&mut self, metrics: Arc<SessionMetrics>) -> &mut Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    pub(crate) fn ip_filter(&mut self, ip_filter: Arc<SessionIpFilter>) -> &mut Self {
+        self.ip_filter = Some(ip_filter);
+        self
+    }
+
+    pub(crate) fn connection_limiter(
+        &mut self,
+        connection_limiter: Arc<ConnectionLimiter>,
+    ) -> &mut Self {
+        self.connection_limiter = Some(connection_limiter);
+        self
+    }
+
+    /// Make outgoing peer connections from this interface or IP.
+    pub fn network_binding(&mut self, network_binding: NetworkBinding) -> &mut Self {
+        self.peer_connector.bind = Some(network_binding);
+        self
+    }
+
+    /// Make outgoing peer connections through this proxy.
+    pub fn peer_proxy(&mut self, proxy: Socks5Proxy) -> &mut Self {
+        self.peer_connector.proxy = Some(proxy);
+        self
+    }
+
     pub fn peer_id(&mut self, peer_id: Id20) -> &mut Self {
         self.peer_id = Some(peer_id);
         self
@@ -486,11 +944,24 @@ impl ManagedTorrentBuilder {
 
     pub(crate) fn build(self, span: tracing::Span) -> anyhow::Result<ManagedTorrentHandle> {
         let lengths = Lengths::from_torrent(&self.info)?;
+        let total_files = self.info.iter_file_lengths()?.count();
+        let file_priorities = match self.file_priorities {
+            Some(p) if p.len() != total_files => {
+                bail!("expected {} file priorities, got {}", total_files, p.len())
+            }
+            Some(p) => p,
+            None => FilePriority::from_only_files(total_files, None),
+        };
+        relative_file_paths(&self.info, &self.file_path_overrides)?;
+        #[allow(deprecated)]
         let info = Arc::new(ManagedTorrentInfo {
             span,
+            id: self.id,
             info: self.info,
             info_hash: self.info_hash,
-            out_dir: self.output_folder,
+            out_dir: self.output_folder.clone(),
+            current_out_dir: RwLock::new(self.output_folder),
+            file_path_overrides: RwLock::new(self.file_path_overrides),
             trackers: self.trackers.into_iter().collect(),
             spawner: self.spawner.unwrap_or_default(),
             peer_id: self.peer_id.unwrap_or_else(generate_peer_id),
@@ -500,14 +971,35 @@ impl ManagedTorrentBuilder {
                 peer_connect_timeout: self.peer_connect_timeout,
                 peer_read_write_timeout: self.peer_read_write_timeout,
                 overwrite: self.overwrite,
+                preallocation: self.preallocation,
+                shared_incomplete_folder: self.shared_incomplete_folder.filter(|shared| {
+                    self.incomplete_folder
+                        .as_ref()
+                        .is_some_and(|f| f.starts_with(shared))
+                }),
+                incomplete_folder: self.incomplete_folder,
+                part_suffix: self.part_suffix,
+                peer_connector: self.peer_connector,
             },
+            storage_factory: self
+                .storage_factory
+                .unwrap_or_else(|| Arc::new(FilesystemStorageFactory)),
+            disk_cache: self
+                .disk_cache
+                .unwrap_or_else(|| Arc::new(DiskCache::new(0))),
+            events: self.events,
+            metrics: self.metrics.unwrap_or_default(),
+            ip_filter: self.ip_filter.unwrap_or_default(),
+            connection_limiter: self.connection_limiter.unwrap_or_default(),
         });
         let initializing = Arc::new(TorrentStateInitializing::new(
             info.clone(),
-            self.only_files.clone(),
+            file_priorities.clone(),
         ));
         Ok(Arc::new(ManagedTorrent {
-            only_files: self.only_files,
+            file_priorities: RwLock::new(file_priorities),
+            sequential: AtomicBool::new(self.sequential),
+            moving: Default::default(),
             locked: RwLock::new(ManagedTorrentLocked {
                 state: ManagedTorrentState::Initializing(initializing),
             }),
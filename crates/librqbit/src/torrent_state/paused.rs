@@ -1,20 +1,36 @@
-use std::{fs::File, path::PathBuf, sync::Arc};
+use std::sync::Arc;
 
-use parking_lot::Mutex;
+use crate::{chunk_tracker::ChunkTracker, file_priority::FilePriority, storage::TorrentStorage};
 
-use crate::chunk_tracker::ChunkTracker;
-
-use super::ManagedTorrentInfo;
+use super::{initializing::prepare_files_for_priorities, ManagedTorrentInfo};
 
 pub struct TorrentStatePaused {
     pub(crate) info: Arc<ManagedTorrentInfo>,
-    pub(crate) files: Vec<Arc<Mutex<File>>>,
-    pub(crate) filenames: Vec<PathBuf>,
+    pub(crate) storage: Box<dyn TorrentStorage>,
     pub(crate) chunk_tracker: ChunkTracker,
     pub(crate) have_bytes: u64,
     pub(crate) needed_bytes: u64,
 }
 
+impl TorrentStatePaused {
+    pub(crate) fn set_file_priorities(
+        &mut self,
+        file_priorities: &[FilePriority],
+    ) -> anyhow::Result<()> {
+        let was_finished = self.needed_bytes == 0;
+        let piece_priorities =
+            prepare_files_for_priorities(&self.info, &*self.storage, file_priorities)?;
+        self.needed_bytes = self
+            .chunk_tracker
+            .update_piece_priorities(piece_priorities, |_| false);
+        // Finished torrents keep their files read-only, newly selected ones need writing to.
+        if was_finished && self.needed_bytes > 0 {
+            self.storage.set_read_only(false)?;
+        }
+        Ok(())
+    }
+}
+
 // impl TorrentStatePaused {
 //     pub fn get_have_bytes(&self) -> u64 {
 //         self.have_bytes
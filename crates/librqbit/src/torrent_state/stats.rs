@@ -1,11 +1,11 @@
-use std::time::Duration;
+use std::{path::PathBuf, time::Duration};
 
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 
 use super::{live::stats::snapshot::StatsSnapshot, TorrentStateLive};
 use size_format::SizeFormatterBinary as SF;
 
-#[derive(Serialize, Default, Debug)]
+#[derive(Serialize, Deserialize, Default, Debug)]
 pub struct LiveStats {
     pub snapshot: StatsSnapshot,
     pub average_piece_download_time: Option<Duration>,
@@ -43,7 +43,7 @@ impl From<&TorrentStateLive> for LiveStats {
     }
 }
 
-#[derive(Clone, Copy, Serialize, Debug)]
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq, Eq)]
 pub enum TorrentStatsState {
     #[serde(rename = "initializing")]
     Initializing,
@@ -66,7 +66,7 @@ impl std::fmt::Display for TorrentStatsState {
     }
 }
 
-#[derive(Serialize, Debug)]
+#[derive(Serialize, Deserialize, Debug)]
 pub struct TorrentStats {
     pub state: TorrentStatsState,
     pub error: Option<String>,
@@ -75,6 +75,16 @@ pub struct TorrentStats {
     pub total_bytes: u64,
     pub finished: bool,
     pub live: Option<LiveStats>,
+    /// Set while the torrent's data is being moved.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub moving: Option<MoveStats>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MoveStats {
+    pub destination: PathBuf,
+    pub total_bytes: u64,
+    pub moved_bytes: u64,
 }
 
 impl std::fmt::Display for TorrentStats {
@@ -92,6 +102,15 @@ impl std::fmt::Display for TorrentStats {
         if let Some(live) = &self.live {
             write!(f, " [{live}]")?;
         }
+        if let Some(moving) = &self.moving {
+            write!(
+                f,
+                " [moving to {:?}: {} / {}]",
+                moving.destination,
+                SF::new(moving.moved_bytes),
+                SF::new(moving.total_bytes)
+            )?;
+        }
         Ok(())
     }
 }
@@ -180,6 +199,19 @@ impl Serialize for DurationWithHumanReadable {
     }
 }
 
+impl<'de> Deserialize<'de> for DurationWithHumanReadable {
+    fn deserialize<D>(deserializer: D) -> core::result::Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        #[derive(Deserialize)]
+        struct Tmp {
+            duration: Duration,
+        }
+        Tmp::deserialize(deserializer).map(|t| Self(t.duration))
+    }
+}
+
 #[derive(Default)]
 pub struct Speed {
     pub mbps: f64,
@@ -226,3 +258,16 @@ impl Serialize for Speed {
         .serialize(serializer)
     }
 }
+
+impl<'de> Deserialize<'de> for Speed {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        #[derive(Deserialize)]
+        struct Tmp {
+            mbps: f64,
+        }
+        Tmp::deserialize(deserializer).map(|t| Self::new(t.mbps))
+    }
+}
@@ -53,6 +53,9 @@ pub struct InitLoggingOptions<'a> {
     pub default_rust_log_value: Option<&'a str>,
     pub log_file: Option<&'a str>,
     pub log_file_rust_log: Option<&'a str>,
+    /// Don't log to the console until RUST_LOG is changed through the returned
+    /// sender, e.g. while a TUI owns the terminal.
+    pub disable_console: bool,
 }
 
 pub struct InitLoggingResult {
@@ -71,6 +74,11 @@ pub fn init_logging(opts: InitLoggingOptions) -> anyhow::Result<InitLoggingResul
         )
         .from_env()
         .context("invalid RUST_LOG value")?;
+    let stderr_filter = if opts.disable_console {
+        EnvFilter::new("off")
+    } else {
+        stderr_filter
+    };
 
     let (stderr_filter, reload_stderr_filter) =
         tracing_subscriber::reload::Layer::new(stderr_filter);
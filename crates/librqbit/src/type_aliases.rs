@@ -2,7 +2,9 @@ use std::net::SocketAddr;
 
 use futures::stream::BoxStream;
 
+use crate::torrent_state::peer::PeerSource;
+
 pub type BF = bitvec::vec::BitVec<u8, bitvec::order::Msb0>;
 
 pub type PeerHandle = SocketAddr;
-pub type PeerStream = BoxStream<'static, SocketAddr>;
+pub type PeerStream = BoxStream<'static, (SocketAddr, PeerSource)>;
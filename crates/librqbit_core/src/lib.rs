@@ -4,6 +4,7 @@ pub mod hash_id;
 pub mod lengths;
 pub mod magnet;
 pub mod peer_id;
+pub mod socks5;
 pub mod spawn_utils;
 pub mod speed_estimator;
 pub mod torrent_metainfo;
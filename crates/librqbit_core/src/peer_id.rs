@@ -25,6 +25,24 @@ impl AzureusStyleKind {
     }
 }
 
+impl std::fmt::Display for AzureusStyleKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AzureusStyleKind::Deluge => f.write_str("Deluge"),
+            AzureusStyleKind::LibTorrent => f.write_str("libtorrent"),
+            AzureusStyleKind::Transmission => f.write_str("Transmission"),
+            AzureusStyleKind::Other([c1, c2]) => write!(f, "{c1}{c2}"),
+        }
+    }
+}
+
+impl std::fmt::Display for AzureusStyle {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let [v1, v2, v3, v4] = self.version;
+        write!(f, "{} {v1}.{v2}.{v3}.{v4}", self.kind)
+    }
+}
+
 fn try_decode_azureus_style(p: &Id20) -> Option<AzureusStyle> {
     let p = p.0;
     if !(p[0] == b'-' && p[7] == b'-') {
@@ -43,6 +61,14 @@ pub enum PeerId {
     AzureusStyle(AzureusStyle),
 }
 
+impl std::fmt::Display for PeerId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PeerId::AzureusStyle(a) => write!(f, "{a}"),
+        }
+    }
+}
+
 pub fn try_decode_peer_id(p: Id20) -> Option<PeerId> {
     Some(PeerId::AzureusStyle(try_decode_azureus_style(&p)?))
 }
@@ -57,3 +83,19 @@ pub fn generate_peer_id() -> Id20 {
 
     Id20::new(peer_id)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_client_name() {
+        let id = |s: &[u8; 20]| try_decode_peer_id(Id20::new(*s)).map(|p| p.to_string());
+        assert_eq!(
+            id(b"-TR3000-abcdefghijkl").as_deref(),
+            Some("Transmission 3.0.0.0")
+        );
+        assert_eq!(id(b"-qB4620-abcdefghijkl").as_deref(), Some("qB 4.6.2.0"));
+        assert_eq!(id(b"M7-2-2--abcdefghijkl"), None);
+    }
+}
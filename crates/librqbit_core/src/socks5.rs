@@ -0,0 +1,492 @@
+// A SOCKS5 client (RFC 1928), for peer connections, DHT and UDP trackers. Supports CONNECT
+// and UDP ASSOCIATE, with or without username/password authentication (RFC 1929).
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use anyhow::{bail, Context};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpSocket, TcpStream, UdpSocket},
+};
+use url::Url;
+
+const VERSION: u8 = 5;
+const NO_AUTH: u8 = 0;
+const USERNAME_PASSWORD: u8 = 2;
+const NO_ACCEPTABLE_METHODS: u8 = 0xff;
+const CMD_CONNECT: u8 = 1;
+const CMD_UDP_ASSOCIATE: u8 = 3;
+const ATYP_IPV4: u8 = 1;
+const ATYP_DOMAIN: u8 = 3;
+const ATYP_IPV6: u8 = 4;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Socks5Proxy {
+    /// "host:port"
+    pub addr: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+}
+
+impl Socks5Proxy {
+    /// Parse "socks5://[user:password@]host:port". "socks5h" is accepted too.
+    pub fn from_url(url: &Url) -> anyhow::Result<Self> {
+        if !matches!(url.scheme(), "socks5" | "socks5h") {
+            bail!("not a SOCKS5 proxy URL: {url}");
+        }
+        let host = url.host_str().context("proxy URL has no host")?;
+        let port = url.port().unwrap_or(1080);
+        let decode = |s: &str| -> anyhow::Result<String> {
+            Ok(urlencoding::decode(s)
+                .context("error decoding proxy credentials")?
+                .into_owned())
+        };
+        Ok(Self {
+            addr: format!("{host}:{port}"),
+            username: Some(url.username())
+                .filter(|u| !u.is_empty())
+                .map(decode)
+                .transpose()?,
+            password: url.password().map(decode).transpose()?,
+        })
+    }
+
+    /// Open a TCP connection to target through the proxy.
+    pub async fn connect(&self, target: SocketAddr) -> anyhow::Result<TcpStream> {
+        let stream = self.connect_to_proxy(None).await?;
+        self.connect_over(stream, target).await
+    }
+
+    /// Ask the proxy to connect to target, over stream, an open connection to the proxy.
+    /// For when the connection to the proxy has to be made in a particular way.
+    pub async fn connect_over(
+        &self,
+        mut stream: TcpStream,
+        target: SocketAddr,
+    ) -> anyhow::Result<TcpStream> {
+        self.request(&mut stream, CMD_CONNECT, target).await?;
+        Ok(stream)
+    }
+
+    /// Ask the proxy to relay UDP datagrams. The connection to the proxy and the UDP
+    /// socket are bound to bind_ip, if set.
+    pub async fn udp_associate(&self, bind_ip: Option<IpAddr>) -> anyhow::Result<Socks5UdpSocket> {
+        let mut control = self.connect_to_proxy(bind_ip).await?;
+        let unspecified = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0);
+        let mut relay = self
+            .request(&mut control, CMD_UDP_ASSOCIATE, unspecified)
+            .await?;
+        if relay.ip().is_unspecified() {
+            relay.set_ip(control.peer_addr()?.ip());
+        }
+        let local = match (bind_ip, relay) {
+            (Some(ip), _) => SocketAddr::new(ip, 0),
+            (None, SocketAddr::V4(_)) => unspecified,
+            (None, SocketAddr::V6(_)) => SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 0),
+        };
+        let socket = UdpSocket::bind(local)
+            .await
+            .with_context(|| format!("error binding UDP socket to {local}"))?;
+        Ok(Socks5UdpSocket {
+            control,
+            socket,
+            relay,
+        })
+    }
+
+    async fn connect_to_proxy(&self, bind_ip: Option<IpAddr>) -> anyhow::Result<TcpStream> {
+        let context = || format!("error connecting to SOCKS5 proxy {}", self.addr);
+        let bind_ip = match bind_ip {
+            Some(ip) => ip,
+            None => return TcpStream::connect(&self.addr).await.with_context(context),
+        };
+        let addr = tokio::net::lookup_host(&self.addr)
+            .await
+            .with_context(context)?
+            .find(|a| a.is_ipv4() == bind_ip.is_ipv4())
+            .with_context(|| {
+                format!(
+                    "SOCKS5 proxy {} has no address reachable from {bind_ip}",
+                    self.addr
+                )
+            })?;
+        let socket = if bind_ip.is_ipv4() {
+            TcpSocket::new_v4()
+        } else {
+            TcpSocket::new_v6()
+        }?;
+        socket
+            .bind(SocketAddr::new(bind_ip, 0))
+            .with_context(|| format!("error binding to {bind_ip}"))?;
+        socket.connect(addr).await.with_context(context)
+    }
+
+    async fn authenticate(&self, stream: &mut TcpStream) -> anyhow::Result<()> {
+        let method = if self.username.is_some() {
+            USERNAME_PASSWORD
+        } else {
+            NO_AUTH
+        };
+        stream.write_all(&[VERSION, 1, method]).await?;
+        let mut reply = [0u8; 2];
+        stream.read_exact(&mut reply).await?;
+        match reply {
+            [VERSION, m] if m == method => {}
+            [VERSION, NO_ACCEPTABLE_METHODS] => {
+                bail!("SOCKS5 proxy rejected the authentication method")
+            }
+            _ => bail!("unexpected SOCKS5 proxy reply {reply:?}"),
+        }
+        if method == NO_AUTH {
+            return Ok(());
+        }
+
+        let username = self.username.as_deref().unwrap_or_default().as_bytes();
+        let password = self.password.as_deref().unwrap_or_default().as_bytes();
+        if username.len() > 255 || password.len() > 255 {
+            bail!("SOCKS5 username and password must be at most 255 bytes");
+        }
+        let mut buf = vec![1, username.len() as u8];
+        buf.extend_from_slice(username);
+        buf.push(password.len() as u8);
+        buf.extend_from_slice(password);
+        stream.write_all(&buf).await?;
+        stream.read_exact(&mut reply).await?;
+        if reply[1] != 0 {
+            bail!("SOCKS5 proxy authentication failed");
+        }
+        Ok(())
+    }
+
+    // Returns the address the proxy bound for the request.
+    async fn request(
+        &self,
+        stream: &mut TcpStream,
+        cmd: u8,
+        addr: SocketAddr,
+    ) -> anyhow::Result<SocketAddr> {
+        self.authenticate(stream).await?;
+        let mut buf = vec![VERSION, cmd, 0];
+        write_addr(&mut buf, addr);
+        stream.write_all(&buf).await?;
+
+        let mut reply = [0u8; 3];
+        stream.read_exact(&mut reply).await?;
+        if reply[0] != VERSION {
+            bail!("unexpected SOCKS5 proxy reply {reply:?}");
+        }
+        if reply[1] != 0 {
+            bail!("SOCKS5 proxy error: {}", reply_error(reply[1]));
+        }
+        let atyp = stream.read_u8().await?;
+        let ip = match atyp {
+            ATYP_IPV4 => {
+                let mut ip = [0u8; 4];
+                stream.read_exact(&mut ip).await?;
+                IpAddr::from(ip)
+            }
+            ATYP_IPV6 => {
+                let mut ip = [0u8; 16];
+                stream.read_exact(&mut ip).await?;
+                IpAddr::from(ip)
+            }
+            ATYP_DOMAIN => {
+                // Not useful to us, but has to be read.
+                let len = stream.read_u8().await?;
+                let mut domain = vec![0u8; len as usize];
+                stream.read_exact(&mut domain).await?;
+                IpAddr::V4(Ipv4Addr::UNSPECIFIED)
+            }
+            _ => bail!("unexpected SOCKS5 address type {atyp}"),
+        };
+        let port = stream.read_u16().await?;
+        Ok(SocketAddr::new(ip, port))
+    }
+}
+
+fn write_addr(buf: &mut Vec<u8>, addr: SocketAddr) {
+    match addr.ip() {
+        IpAddr::V4(ip) => {
+            buf.push(ATYP_IPV4);
+            buf.extend_from_slice(&ip.octets());
+        }
+        IpAddr::V6(ip) => {
+            buf.push(ATYP_IPV6);
+            buf.extend_from_slice(&ip.octets());
+        }
+    }
+    buf.extend_from_slice(&addr.port().to_be_bytes());
+}
+
+fn write_domain(buf: &mut Vec<u8>, host: &str, port: u16) -> anyhow::Result<()> {
+    let len: u8 = host
+        .len()
+        .try_into()
+        .with_context(|| format!("host name {host:?} is too long for SOCKS5"))?;
+    buf.extend_from_slice(&[ATYP_DOMAIN, len]);
+    buf.extend_from_slice(host.as_bytes());
+    buf.extend_from_slice(&port.to_be_bytes());
+    Ok(())
+}
+
+fn reply_error(code: u8) -> &'static str {
+    match code {
+        1 => "general failure",
+        2 => "connection not allowed by ruleset",
+        3 => "network unreachable",
+        4 => "host unreachable",
+        5 => "connection refused",
+        6 => "TTL expired",
+        7 => "command not supported",
+        8 => "address type not supported",
+        _ => "unknown error",
+    }
+}
+
+/// A UDP socket relaying its datagrams through a SOCKS5 proxy. The relay lasts as long as the
+/// TCP connection it was requested on, receiving fails once the proxy closes it.
+pub struct Socks5UdpSocket {
+    control: TcpStream,
+    socket: UdpSocket,
+    relay: SocketAddr,
+}
+
+impl Socks5UdpSocket {
+    pub fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    pub async fn send_to(&self, buf: &[u8], target: SocketAddr) -> anyhow::Result<usize> {
+        let mut datagram = Vec::with_capacity(buf.len() + 22);
+        // RSV and FRAG.
+        datagram.extend_from_slice(&[0, 0, 0]);
+        write_addr(&mut datagram, target);
+        datagram.extend_from_slice(buf);
+        self.socket.send_to(&datagram, self.relay).await?;
+        Ok(buf.len())
+    }
+
+    /// Send to host, which the proxy resolves, so that the name isn't looked up locally.
+    pub async fn send_to_host(&self, buf: &[u8], host: &str, port: u16) -> anyhow::Result<usize> {
+        let mut datagram = Vec::with_capacity(buf.len() + host.len() + 7);
+        // RSV and FRAG.
+        datagram.extend_from_slice(&[0, 0, 0]);
+        write_domain(&mut datagram, host, port)?;
+        datagram.extend_from_slice(buf);
+        self.socket.send_to(&datagram, self.relay).await?;
+        Ok(buf.len())
+    }
+
+    pub async fn recv_from(&self, buf: &mut [u8]) -> anyhow::Result<(usize, SocketAddr)> {
+        loop {
+            tokio::select! {
+                biased;
+                r = self.socket.recv_from(buf) => {
+                    let (len, from) = r?;
+                    if from != self.relay {
+                        continue;
+                    }
+                    if let Some((header_len, addr)) = parse_udp_header(&buf[..len]) {
+                        buf.copy_within(header_len..len, 0);
+                        return Ok((len - header_len, addr));
+                    }
+                }
+                r = self.control.readable() => {
+                    r?;
+                    match self.control.try_read(&mut [0u8; 64]) {
+                        Ok(0) => bail!("SOCKS5 proxy closed the UDP relay"),
+                        Ok(_) => {}
+                        Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {}
+                        Err(e) => return Err(e.into()),
+                    }
+                }
+            }
+        }
+    }
+}
+
+// Returns the header length and the sender. Fragments aren't supported and are dropped.
+fn parse_udp_header(buf: &[u8]) -> Option<(usize, SocketAddr)> {
+    let (&[0, 0, 0, atyp], rest) = buf.split_first_chunk::<4>()? else {
+        return None;
+    };
+    let (ip, rest) = match atyp {
+        ATYP_IPV4 => {
+            let (ip, rest) = rest.split_first_chunk::<4>()?;
+            (IpAddr::from(*ip), rest)
+        }
+        ATYP_IPV6 => {
+            let (ip, rest) = rest.split_first_chunk::<16>()?;
+            (IpAddr::from(*ip), rest)
+        }
+        _ => return None,
+    };
+    let (port, rest) = rest.split_first_chunk::<2>()?;
+    Some((
+        buf.len() - rest.len(),
+        SocketAddr::new(ip, u16::from_be_bytes(*port)),
+    ))
+}
+
+/// A UDP socket sending directly, or through a SOCKS5 proxy.
+pub enum DatagramSocket {
+    Direct(UdpSocket),
+    Socks5(Socks5UdpSocket),
+}
+
+impl DatagramSocket {
+    /// Bind to addr, or relay through proxy if set. The relay is set up from addr's IP,
+    /// unless it's unspecified.
+    pub async fn bind(addr: SocketAddr, proxy: Option<&Socks5Proxy>) -> anyhow::Result<Self> {
+        Ok(match proxy {
+            Some(proxy) => DatagramSocket::Socks5(
+                proxy
+                    .udp_associate(Some(addr.ip()).filter(|ip| !ip.is_unspecified()))
+                    .await
+                    .context("error setting up SOCKS5 UDP relay")?,
+            ),
+            None => DatagramSocket::Direct(
+                UdpSocket::bind(addr)
+                    .await
+                    .with_context(|| format!("error binding socket, address {addr}"))?,
+            ),
+        })
+    }
+
+    pub fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        match self {
+            DatagramSocket::Direct(s) => s.local_addr(),
+            DatagramSocket::Socks5(s) => s.local_addr(),
+        }
+    }
+
+    pub async fn send_to(&self, buf: &[u8], target: SocketAddr) -> anyhow::Result<usize> {
+        match self {
+            DatagramSocket::Direct(s) => Ok(s.send_to(buf, target).await?),
+            DatagramSocket::Socks5(s) => s.send_to(buf, target).await,
+        }
+    }
+
+    /// Send to host, which only works through a proxy. Use send_to() with a resolved
+    /// address for direct sockets.
+    pub async fn send_to_host(&self, buf: &[u8], host: &str, port: u16) -> anyhow::Result<usize> {
+        match self {
+            DatagramSocket::Direct(_) => bail!("can't send to {host:?} without resolving it"),
+            DatagramSocket::Socks5(s) => s.send_to_host(buf, host, port).await,
+        }
+    }
+
+    pub async fn recv_from(&self, buf: &mut [u8]) -> anyhow::Result<(usize, SocketAddr)> {
+        match self {
+            DatagramSocket::Direct(s) => Ok(s.recv_from(buf).await?),
+            DatagramSocket::Socks5(s) => s.recv_from(buf).await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, UdpSocket},
+    };
+
+    use super::{parse_udp_header, write_addr, write_domain, Socks5Proxy};
+
+    // A SOCKS5 server that only knows user "u" with password "p", serving one CONNECT or
+    // UDP ASSOCIATE per connection.
+    async fn socks5_stand_in() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (mut s, _) = listener.accept().await.unwrap();
+                tokio::spawn(async move {
+                    let mut buf = [0u8; 512];
+                    s.read_exact(&mut buf[..3]).await.unwrap();
+                    assert_eq!(&buf[..3], &[5, 1, 2]);
+                    s.write_all(&[5, 2]).await.unwrap();
+                    s.read_exact(&mut buf[..5]).await.unwrap();
+                    let auth_ok = buf[..5] == [1, 1, b'u', 1, b'p'];
+                    s.write_all(&[1, if auth_ok { 0 } else { 1 }])
+                        .await
+                        .unwrap();
+                    if !auth_ok {
+                        return;
+                    }
+                    // The request with an IPv4 address.
+                    s.read_exact(&mut buf[5..15]).await.unwrap();
+                    let cmd = buf[6];
+                    let target = SocketAddr::from((
+                        [buf[9], buf[10], buf[11], buf[12]],
+                        u16::from_be_bytes([buf[13], buf[14]]),
+                    ));
+                    if cmd == 1 {
+                        let mut upstream = tokio::net::TcpStream::connect(target).await.unwrap();
+                        s.write_all(&[5, 0, 0, 1, 0, 0, 0, 0, 0, 0]).await.unwrap();
+                        tokio::io::copy_bidirectional(&mut s, &mut upstream)
+                            .await
+                            .unwrap();
+                        return;
+                    }
+                    let relay = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+                    let mut reply = vec![5, 0, 0];
+                    write_addr(&mut reply, relay.local_addr().unwrap());
+                    s.write_all(&reply).await.unwrap();
+                    // Echo datagrams back, as if sent by their target.
+                    let (len, client) = relay.recv_from(&mut buf).await.unwrap();
+                    relay.send_to(&buf[..len], client).await.unwrap();
+                    // Closing the control connection ends the relay.
+                    relay.recv_from(&mut buf).await.unwrap();
+                    drop(s);
+                    let _ = relay.recv_from(&mut buf).await;
+                });
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn test_socks5() {
+        let proxy_addr = socks5_stand_in().await;
+        let proxy =
+            Socks5Proxy::from_url(&format!("socks5://u:p@{proxy_addr}").parse().unwrap()).unwrap();
+
+        let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target_addr = target.local_addr().unwrap();
+        let mut conn = proxy.connect(target_addr).await.unwrap();
+        let (mut accepted, _) = target.accept().await.unwrap();
+        conn.write_all(b"ping").await.unwrap();
+        let mut buf = [0u8; 4];
+        accepted.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+
+        let udp = proxy
+            .udp_associate(Some("127.0.0.1".parse().unwrap()))
+            .await
+            .unwrap();
+        let peer: SocketAddr = "10.1.2.3:6881".parse().unwrap();
+        udp.send_to(b"hello", peer).await.unwrap();
+        let mut buf = [0u8; 64];
+        let (len, from) = udp.recv_from(&mut buf).await.unwrap();
+        assert_eq!((&buf[..len], from), (b"hello".as_slice(), peer));
+        udp.send_to(b"bye", peer).await.unwrap();
+        assert!(udp.recv_from(&mut buf).await.is_err());
+
+        let wrong = Socks5Proxy {
+            password: Some("x".to_owned()),
+            ..proxy
+        };
+        assert!(wrong.connect(target_addr).await.is_err());
+        assert!(parse_udp_header(&[0, 0, 1, 1, 0, 0, 0, 0, 0, 0]).is_none());
+
+        let mut buf = Vec::new();
+        write_domain(&mut buf, "tracker.example", 6969).unwrap();
+        assert_eq!(&buf[..2], &[3, 15]);
+        assert_eq!(&buf[2..17], b"tracker.example");
+        assert_eq!(&buf[17..], &6969u16.to_be_bytes());
+        assert!(write_domain(&mut buf, &"a".repeat(256), 1).is_err());
+    }
+}
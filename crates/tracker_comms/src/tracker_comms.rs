@@ -1,4 +1,4 @@
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
 use std::sync::Arc;
 use std::time::Duration;
 
@@ -18,6 +18,7 @@ use url::Url;
 use crate::tracker_comms_http;
 use crate::tracker_comms_udp;
 use librqbit_core::hash_id::Id20;
+use librqbit_core::socks5::Socks5Proxy;
 
 pub struct TrackerComms {
     info_hash: Id20,
@@ -26,6 +27,17 @@ pub struct TrackerComms {
     force_tracker_interval: Option<Duration>,
     tx: Sender,
     tcp_listen_port: Option<u16>,
+    connect_opts: TrackerConnectOptions,
+}
+
+/// How trackers are reached.
+#[derive(Debug, Default, Clone)]
+pub struct TrackerConnectOptions {
+    /// Send requests from this address.
+    pub bind_ip: Option<IpAddr>,
+    /// Send requests through this proxy: "http://", "https://", "socks5://" or "socks5h://",
+    /// with optional credentials. UDP trackers can't be used with HTTP proxies.
+    pub proxy: Option<Url>,
 }
 
 #[derive(Default)]
@@ -62,6 +74,12 @@ impl TrackerCommsStats {
 
 pub trait TorrentStatsProvider: Send + Sync {
     fn get(&self) -> TrackerCommsStats;
+
+    /// Called when a tracker responds to an announce.
+    fn on_tracker_announce(&self, _tracker: &str) {}
+
+    /// Called when announcing to a tracker fails.
+    fn on_tracker_error(&self, _tracker: &str, _error: &anyhow::Error) {}
 }
 
 impl TorrentStatsProvider for () {
@@ -85,6 +103,7 @@ impl TrackerComms {
         stats: Box<dyn TorrentStatsProvider>,
         force_interval: Option<Duration>,
         tcp_listen_port: Option<u16>,
+        connect_opts: TrackerConnectOptions,
     ) -> Option<BoxStream<'static, SocketAddr>> {
         let trackers = trackers
             .into_iter()
@@ -118,6 +137,7 @@ impl TrackerComms {
                 force_tracker_interval: force_interval,
                 tx,
                 tcp_listen_port,
+                connect_opts,
             });
             let mut futures = FuturesUnordered::new();
             for tracker in trackers {
@@ -172,6 +192,12 @@ impl TrackerComms {
     }
 
     async fn task_single_tracker_monitor_http(&self, mut tracker_url: Url) -> anyhow::Result<()> {
+        // The query is replaced by the announce parameters below.
+        let tracker = {
+            let mut url = tracker_url.clone();
+            url.set_query(None);
+            url.to_string()
+        };
         let mut event = Some(tracker_comms_http::TrackerRequestEvent::Started);
         loop {
             let stats = self.stats.get();
@@ -197,6 +223,7 @@ impl TrackerComms {
             match self.tracker_one_request_http(tracker_url.clone()).await {
                 Ok(interval) => {
                     event = None;
+                    self.stats.on_tracker_announce(&tracker);
                     let interval = self
                         .force_tracker_interval
                         .unwrap_or_else(|| Duration::from_secs(interval));
@@ -209,6 +236,7 @@ impl TrackerComms {
                 }
                 Err(e) => {
                     debug!("error calling the tracker {}: {:#}", tracker_url, e);
+                    self.stats.on_tracker_error(&tracker, &e);
                     tokio::time::sleep(Duration::from_secs(60)).await;
                 }
             };
@@ -216,7 +244,12 @@ impl TrackerComms {
     }
 
     async fn tracker_one_request_http(&self, tracker_url: Url) -> anyhow::Result<u64> {
-        let response: reqwest::Response = reqwest::get(tracker_url).await?;
+        let mut client = reqwest::Client::builder().local_address(self.connect_opts.bind_ip);
+        if let Some(proxy) = &self.connect_opts.proxy {
+            client = client.proxy(reqwest::Proxy::all(proxy.as_str())?);
+        }
+        let client = client.build()?;
+        let response: reqwest::Response = client.get(tracker_url).send().await?;
         if !response.status().is_success() {
             anyhow::bail!("tracker responded with {:?}", response.status());
         }
@@ -241,13 +274,19 @@ impl TrackerComms {
         if url.scheme() != "udp" {
             bail!("expected UDP scheme in {}", url);
         }
-        let hp: (&str, u16) = (
-            url.host_str().context("missing host")?,
-            url.port().context("missing port")?,
-        );
-        let mut requester = UdpTrackerRequester::new(hp)
-            .await
-            .context("error creating UDP tracker requester")?;
+        let host = url.host_str().context("missing host")?;
+        let port = url.port().context("missing port")?;
+        let proxy = match &self.connect_opts.proxy {
+            Some(proxy) => Some(
+                Socks5Proxy::from_url(proxy)
+                    .context("UDP trackers can only be reached through SOCKS5 proxies")?,
+            ),
+            None => None,
+        };
+        let mut requester =
+            UdpTrackerRequester::new(host, port, self.connect_opts.bind_ip, proxy.as_ref())
+                .await
+                .context("error creating UDP tracker requester")?;
 
         let mut sleep_interval: Option<Duration> = None;
         loop {
@@ -282,6 +321,7 @@ impl TrackerComms {
             match requester.announce(request).await {
                 Ok(response) => {
                     trace!(len = response.addrs.len(), "received announce response");
+                    self.stats.on_tracker_announce(url.as_str());
                     for addr in response.addrs {
                         self.tx
                             .send(SocketAddr::V4(addr))
@@ -294,6 +334,7 @@ impl TrackerComms {
                 }
                 Err(e) => {
                     debug!(url = ?url, "error reading announce response: {e:#}");
+                    self.stats.on_tracker_error(url.as_str(), &e);
                     if sleep_interval.is_none() {
                         sleep_interval = Some(
                             self.force_tracker_interval
@@ -1,9 +1,11 @@
-use std::net::{Ipv4Addr, SocketAddrV4};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4};
 
 use anyhow::{bail, Context};
-use librqbit_core::hash_id::Id20;
+use librqbit_core::{
+    hash_id::Id20,
+    socks5::{DatagramSocket, Socks5Proxy},
+};
 use rand::Rng;
-use tokio::net::ToSocketAddrs;
 use tracing::trace;
 
 const ACTION_CONNECT: u32 = 0;
@@ -170,22 +172,52 @@ impl Response {
     }
 }
 
+// Through a proxy, tracker host names are resolved by the proxy, not to leak them to the
+// local DNS.
+enum TrackerAddr {
+    Resolved(SocketAddr),
+    Host(String, u16),
+}
+
+impl TrackerAddr {
+    async fn send(&self, sock: &DatagramSocket, buf: &[u8]) -> anyhow::Result<usize> {
+        match self {
+            TrackerAddr::Resolved(addr) => sock.send_to(buf, *addr).await,
+            TrackerAddr::Host(host, port) => sock.send_to_host(buf, host, *port).await,
+        }
+    }
+}
+
 pub struct UdpTrackerRequester {
-    sock: tokio::net::UdpSocket,
+    sock: DatagramSocket,
+    tracker: TrackerAddr,
     connection_id: ConnectionId,
     read_buf: Vec<u8>,
     write_buf: Vec<u8>,
 }
 
 impl UdpTrackerRequester {
-    // Addr is "host:port"
-    pub async fn new(addr: impl ToSocketAddrs) -> anyhow::Result<Self> {
-        let sock = tokio::net::UdpSocket::bind("0.0.0.0:0")
-            .await
-            .context("error binding UDP socket")?;
-        sock.connect(addr)
-            .await
-            .context("error connecting UDP socket")?;
+    // Sends from bind_ip, or through proxy, if set.
+    pub async fn new(
+        host: &str,
+        port: u16,
+        bind_ip: Option<IpAddr>,
+        proxy: Option<&Socks5Proxy>,
+    ) -> anyhow::Result<Self> {
+        let bind_ip = bind_ip.unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED));
+        // IPv6 hosts in URLs are in brackets.
+        let tracker = match host.trim_matches(['[', ']']).parse::<IpAddr>() {
+            Ok(ip) => TrackerAddr::Resolved(SocketAddr::new(ip, port)),
+            Err(_) if proxy.is_some() => TrackerAddr::Host(host.to_owned(), port),
+            Err(_) => TrackerAddr::Resolved(
+                tokio::net::lookup_host((host, port))
+                    .await
+                    .context("error resolving tracker address")?
+                    .find(|a| a.is_ipv4() == bind_ip.is_ipv4())
+                    .context("tracker has no address of the socket's family")?,
+            ),
+        };
+        let sock = DatagramSocket::bind(SocketAddr::new(bind_ip, 0), proxy).await?;
 
         let tid = new_transaction_id();
         let mut write_buf = Vec::new();
@@ -194,12 +226,12 @@ impl UdpTrackerRequester {
         trace!("sending connect request");
         Request::Connect.serialize(tid, &mut write_buf);
 
-        sock.send(&write_buf)
+        tracker
+            .send(&sock, &write_buf)
             .await
             .context("error sending to socket")?;
 
-        let size = sock
-            .recv(&mut read_buf)
+        let size = recv_from_tracker(&sock, &tracker, &mut read_buf)
             .await
             .context("error receiving from socket")?;
 
@@ -219,6 +251,7 @@ impl UdpTrackerRequester {
 
         Ok(Self {
             sock,
+            tracker,
             connection_id,
             read_buf,
             write_buf,
@@ -239,11 +272,13 @@ impl UdpTrackerRequester {
         self.write_buf.clear();
         let size = request.serialize(tid, &mut self.write_buf);
         trace!(request=?request, tid, "sending");
-        self.sock
-            .send(&self.write_buf[..size])
+        self.tracker
+            .send(&self.sock, &self.write_buf[..size])
             .await
             .context("error sending")?;
-        let size = self.sock.recv(&mut self.read_buf).await.unwrap();
+        let size = recv_from_tracker(&self.sock, &self.tracker, &mut self.read_buf)
+            .await
+            .context("error receiving")?;
 
         let (rtid, response) = Response::parse(&self.read_buf[..size]).unwrap();
         trace!("received response");
@@ -254,6 +289,26 @@ impl UdpTrackerRequester {
     }
 }
 
+async fn recv_from_tracker(
+    sock: &DatagramSocket,
+    tracker: &TrackerAddr,
+    buf: &mut [u8],
+) -> anyhow::Result<usize> {
+    loop {
+        let (size, from) = sock.recv_from(buf).await?;
+        // The address a host name resolved to isn't known here. The proxy only relays
+        // what's sent to this socket, and the transaction id is checked.
+        let from_tracker = match tracker {
+            TrackerAddr::Resolved(addr) => from == *addr,
+            TrackerAddr::Host(..) => true,
+        };
+        if from_tracker {
+            return Ok(size);
+        }
+        trace!(?from, "ignoring datagram from someone else");
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use std::{io::Write, str::FromStr};
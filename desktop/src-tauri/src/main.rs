@@ -104,6 +104,7 @@ async fn api_from_config(
             api.clone(),
             Some(librqbit::http_api::HttpApiOptions {
                 read_only: config.http_api.read_only,
+                ..Default::default()
             }),
         )
         .make_http_api_and_run(config.http_api.listen_addr);
@@ -304,6 +305,7 @@ async fn start() {
         default_rust_log_value: Some("info"),
         log_file: None,
         log_file_rust_log: None,
+        disable_console: false,
     })
     .unwrap();
 